@@ -0,0 +1,175 @@
+use std::io::{Read, Write};
+
+use nopng::CompressionLevel;
+use nopng::compression::{
+    DeflateDecoder, GzipEncoder, ZlibDecoder, adler32, crc32, deflate_compress, deflate_decompress,
+    gzip_compress, gzip_decompress, zlib_compress, zlib_decompress,
+};
+use proptest::prelude::*;
+
+/// Byte strings with long repeats, so that every block type gets exercised.
+fn data_strategy() -> impl Strategy<Value = Vec<u8>> {
+    prop_oneof![
+        proptest::collection::vec(any::<u8>(), 0..2048),
+        proptest::collection::vec((0u8..4, 1usize..300), 0..64).prop_map(|runs| {
+            runs.into_iter()
+                .flat_map(|(byte, len)| std::iter::repeat_n(byte, len))
+                .collect()
+        }),
+    ]
+}
+
+fn level_strategy() -> impl Strategy<Value = CompressionLevel> {
+    prop::sample::select(vec![
+        CompressionLevel::Store,
+        CompressionLevel::Fast,
+        CompressionLevel::Default,
+        CompressionLevel::Best,
+        CompressionLevel::Optimal,
+    ])
+}
+
+fn flate2_level(level: CompressionLevel) -> flate2::Compression {
+    match level {
+        CompressionLevel::Store => flate2::Compression::none(),
+        CompressionLevel::Fast => flate2::Compression::fast(),
+        CompressionLevel::Default => flate2::Compression::default(),
+        CompressionLevel::Best | CompressionLevel::Optimal => flate2::Compression::best(),
+    }
+}
+
+fn read_all(mut reader: impl Read) -> std::io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    reader.read_to_end(&mut out)?;
+    Ok(out)
+}
+
+proptest! {
+    #[test]
+    fn compressed_streams_are_read_by_flate2(data in data_strategy(), level in level_strategy()) {
+        let deflated = deflate_compress(&data, level).expect("infallible");
+        let decoded = read_all(flate2::read::DeflateDecoder::new(&deflated[..])).expect("infallible");
+        prop_assert_eq!(&decoded, &data);
+
+        let zlib = zlib_compress(&data, level).expect("infallible");
+        let decoded = read_all(flate2::read::ZlibDecoder::new(&zlib[..])).expect("infallible");
+        prop_assert_eq!(&decoded, &data);
+
+        let gzip = gzip_compress(&data, level).expect("infallible");
+        let decoded = read_all(flate2::read::GzDecoder::new(&gzip[..])).expect("infallible");
+        prop_assert_eq!(&decoded, &data);
+    }
+
+    #[test]
+    fn flate2_streams_are_decompressed(data in data_strategy(), level in level_strategy()) {
+        let level = flate2_level(level);
+
+        let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), level);
+        encoder.write_all(&data).expect("infallible");
+        let deflated = encoder.finish().expect("infallible");
+        prop_assert_eq!(deflate_decompress(&deflated).expect("infallible"), data.clone());
+
+        let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), level);
+        encoder.write_all(&data).expect("infallible");
+        let zlib = encoder.finish().expect("infallible");
+        prop_assert_eq!(zlib_decompress(&zlib).expect("infallible"), data.clone());
+
+        let mut encoder = flate2::GzBuilder::new()
+            .filename("data.bin")
+            .comment("proptest")
+            .extra(vec![1, 2, 3])
+            .write(Vec::new(), level);
+        encoder.write_all(&data).expect("infallible");
+        let gzip = encoder.finish().expect("infallible");
+        prop_assert_eq!(gzip_decompress(&gzip).expect("infallible"), data);
+    }
+
+    #[test]
+    fn streaming_matches_one_shot(
+        data in data_strategy(),
+        level in level_strategy(),
+        piece_len in 1usize..512,
+    ) {
+        let mut gzip = Vec::new();
+        let mut encoder = GzipEncoder::new(level, &mut gzip);
+        for piece in data.chunks(piece_len) {
+            encoder.write(piece, &mut gzip).expect("infallible");
+        }
+        encoder.finish(&mut gzip).expect("infallible");
+        prop_assert_eq!(gzip_decompress(&gzip).expect("infallible"), data.clone());
+
+        let zlib = zlib_compress(&data, level).expect("infallible");
+        let mut decoder = ZlibDecoder::new();
+        let mut decoded = Vec::new();
+        for piece in zlib.chunks(piece_len) {
+            decoder.write(piece, &mut decoded).expect("infallible");
+        }
+        prop_assert!(decoder.is_finished());
+        prop_assert_eq!(&decoded, &data);
+
+        let deflated = deflate_compress(&data, level).expect("infallible");
+        let mut decoder = DeflateDecoder::new();
+        let mut decoded = Vec::new();
+        for piece in deflated.chunks(piece_len) {
+            decoder.write(piece, &mut decoded).expect("infallible");
+        }
+        prop_assert!(decoder.is_finished());
+        prop_assert_eq!(&decoded, &data);
+    }
+
+    #[test]
+    fn inflate_into_bounded_buffers_matches_one_shot(
+        data in data_strategy(),
+        level in level_strategy(),
+        piece_len in 1usize..512,
+        output_len in 1usize..512,
+    ) {
+        let mut output = vec![0; output_len];
+
+        let deflated = deflate_compress(&data, level).expect("infallible");
+        let mut decoder = DeflateDecoder::new();
+        let mut decoded = Vec::new();
+        let mut rest = &deflated[..];
+        while !decoder.is_finished() {
+            let piece = &rest[..rest.len().min(piece_len)];
+            let (consumed, written) = decoder.inflate(piece, &mut output).expect("infallible");
+            prop_assert!(consumed > 0 || written > 0 || decoder.is_finished());
+            rest = &rest[consumed..];
+            decoded.extend_from_slice(&output[..written]);
+        }
+        prop_assert!(rest.is_empty());
+        prop_assert_eq!(&decoded, &data);
+
+        let zlib = zlib_compress(&data, level).expect("infallible");
+        let mut decoder = ZlibDecoder::new();
+        let mut decoded = Vec::new();
+        let mut rest = &zlib[..];
+        while !decoder.is_finished() {
+            let piece = &rest[..rest.len().min(piece_len)];
+            let (consumed, written) = decoder.inflate(piece, &mut output).expect("infallible");
+            prop_assert!(consumed > 0 || written > 0 || decoder.is_finished());
+            rest = &rest[consumed..];
+            decoded.extend_from_slice(&output[..written]);
+        }
+        prop_assert!(rest.is_empty());
+        prop_assert_eq!(&decoded, &data);
+    }
+
+    #[test]
+    fn checksums_match_flate2(data in data_strategy()) {
+        let mut crc = flate2::Crc::new();
+        crc.update(&data);
+        prop_assert_eq!(crc32(&data), crc.sum());
+
+        let zlib = zlib_compress(&data, CompressionLevel::Fast).expect("infallible");
+        let trailer: [u8; 4] = zlib[zlib.len() - 4..].try_into().expect("infallible");
+        prop_assert_eq!(adler32(&data), u32::from_be_bytes(trailer));
+    }
+
+    #[test]
+    fn decompressors_never_panic_on_arbitrary_bytes(data in proptest::collection::vec(any::<u8>(), 0..1024)) {
+        let _ = deflate_decompress(&data);
+        let _ = zlib_decompress(&data);
+        let _ = gzip_decompress(&data);
+    }
+}
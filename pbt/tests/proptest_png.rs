@@ -1,6 +1,14 @@
 use std::io::Cursor;
 
-use nopng::{ImageSpec, PixelFormat, decode_image, encode_image, reformat_pixels};
+use nopng::{
+    AnimationDecoder, AnimationFrame, AnimationOptions, Background, BlendOp, Chromaticities,
+    DecodeLimits, DecodeOptions, DisposeOp, Dithering, EncodeOptions, ErrorKind, FrameCompositor,
+    FrameControl, IccProfile, ImageSpec, InternationalText, Metadata, OptimizeOptions,
+    PhysicalDimensions, PixelFormat, ReformatOptions, RenderingIntent, SignificantBits, TextEntry,
+    TransferFunction, decode_image, decode_image_as, decode_image_into, decode_image_with_options,
+    encode_animation, encode_image, encode_image_with_options, optimize_png, reformat_image,
+    reformat_pixels,
+};
 use proptest::prelude::*;
 
 fn decode_with_png_crate(bytes: &[u8]) -> Result<(u32, u32, Vec<u8>), png::DecodingError> {
@@ -49,6 +57,44 @@ fn rgba_image_strategy(
     })
 }
 
+/// RGBA8 animation frames, each given as its region `[x, y, width, height]`,
+/// dispose and blend ops, and pixels.
+type Frames = Vec<([u32; 4], u8, u8, Vec<u8>)>;
+
+/// A canvas size and up to five frames, the first of which covers the whole
+/// canvas.
+fn animation_strategy() -> impl Strategy<Value = (u32, u32, Frames)> {
+    (1u32..=8, 1u32..=8).prop_flat_map(|(width, height)| {
+        let frame = (0..width, 0..height)
+            .prop_flat_map(move |(x, y)| (Just(x), Just(y), 1..=width - x, 1..=height - y))
+            .prop_flat_map(|(x, y, frame_width, frame_height)| {
+                let len = (frame_width * frame_height * 4) as usize;
+                (
+                    Just([x, y, frame_width, frame_height]),
+                    0u8..3,
+                    0u8..2,
+                    proptest::collection::vec(any::<u8>(), len),
+                )
+            });
+        let first = (
+            Just([0, 0, width, height]),
+            0u8..3,
+            0u8..2,
+            proptest::collection::vec(any::<u8>(), (width * height * 4) as usize),
+        );
+        (
+            Just(width),
+            Just(height),
+            first,
+            proptest::collection::vec(frame, 0..4),
+        )
+            .prop_map(|(width, height, first, mut frames)| {
+                frames.insert(0, first);
+                (width, height, frames)
+            })
+    })
+}
+
 fn grayscale_levels_strategy() -> impl Strategy<Value = (u32, u32, Vec<u8>)> {
     // 2-bit grayscale: sample values 0, 1, 2, 3.
     let levels = prop::sample::select(vec![0u8, 1, 2, 3]);
@@ -203,9 +249,241 @@ proptest! {
     #[test]
     fn decoder_never_panics_on_arbitrary_bytes(data in proptest::collection::vec(any::<u8>(), 0..2048)) {
         let _ = decode_image(&data);
+        let mut out = vec![0; 256];
+        let _ = decode_image_into(&data, &mut out);
+    }
+
+    #[test]
+    fn decode_limits_accept_exactly_the_images_within_them(
+        (width, height, data) in rgba_image_strategy(16, 16),
+        max_width in 1u32..20,
+        max_height in 1u32..20,
+        max_pixels in 1u64..300,
+        max_decompressed_bytes in 1usize..1200,
+        lenient in any::<bool>(),
+    ) {
+        let spec = ImageSpec::new(width, height, PixelFormat::Rgba8);
+        let encoded = encode_image(&spec, &data).expect("infallible");
+        let options = DecodeOptions {
+            limits: DecodeLimits {
+                max_width,
+                max_height,
+                max_pixels,
+                max_decompressed_bytes,
+                ..Default::default()
+            },
+            lenient,
+        };
+        let within = width <= max_width
+            && height <= max_height
+            && u64::from(width) * u64::from(height) <= max_pixels
+            && height as usize * (1 + width as usize * 4) <= max_decompressed_bytes;
+        match decode_image_with_options(&encoded, &options) {
+            Ok(image) => {
+                prop_assert!(within);
+                prop_assert!(image.warnings.is_empty());
+                prop_assert_eq!(image.pixels, data);
+            }
+            Err(error) => {
+                prop_assert!(!within);
+                prop_assert_eq!(error.kind(), ErrorKind::LimitExceeded, "{}", error);
+            }
+        }
+    }
+
+    #[test]
+    fn decode_into_matches_decode_image((width, height, indices, palette, trns) in indexed_image_strategy(), interlaced in any::<bool>()) {
+        let spec = ImageSpec {
+            width,
+            height,
+            pixel_format: PixelFormat::Indexed8 { palette, trns: Some(trns) },
+            interlaced,
+        };
+        let encoded = encode_image(&spec, &indices).expect("infallible");
+        let rgba_spec = ImageSpec { pixel_format: PixelFormat::Rgba8, ..spec.clone() };
+        let rgba = reformat_pixels(&spec.pixel_format, &indices, &PixelFormat::Rgba8).expect("infallible");
+        let encoded_rgba = encode_image(&rgba_spec, &rgba).expect("infallible");
+
+        for bytes in [encoded, encoded_rgba] {
+            let (expected_spec, expected) = decode_image(&bytes).expect("infallible");
+            let mut out = vec![0; expected.len()];
+            let decoded_spec = decode_image_into(&bytes, &mut out).expect("infallible");
+            prop_assert_eq!(decoded_spec, expected_spec);
+            prop_assert_eq!(out, expected);
+        }
+    }
+
+    #[test]
+    fn decode_as_rgba8_matches_png_crate((width, height, indices, palette, trns) in indexed_image_strategy(), interlaced in any::<bool>()) {
+        let spec = ImageSpec {
+            width,
+            height,
+            pixel_format: PixelFormat::Indexed8 { palette, trns: Some(trns) },
+            interlaced,
+        };
+        let encoded = encode_image(&spec, &indices).expect("infallible");
+        let (decoded_spec, rgba) = decode_image_as(&encoded, &PixelFormat::Rgba8).expect("infallible");
+        let (_, _, expected) = decode_with_png_crate(&encoded).expect("infallible");
+        prop_assert_eq!(decoded_spec.pixel_format, PixelFormat::Rgba8);
+        prop_assert_eq!(rgba, expected);
+    }
+
+    #[test]
+    fn color_metadata_is_read_by_png_crate(
+        gamma in 1u32..=200_000,
+        points in proptest::array::uniform8(0u32..=100_000),
+        intent in 0usize..4,
+        profile in proptest::collection::vec(any::<u8>(), 1..512),
+    ) {
+        let intents = [
+            RenderingIntent::Perceptual,
+            RenderingIntent::RelativeColorimetric,
+            RenderingIntent::Saturation,
+            RenderingIntent::AbsoluteColorimetric,
+        ];
+        let metadata = Metadata {
+            gamma: Some(gamma),
+            chromaticities: Some(Chromaticities {
+                white: [points[0], points[1]],
+                red: [points[2], points[3]],
+                green: [points[4], points[5]],
+                blue: [points[6], points[7]],
+            }),
+            srgb: Some(intents[intent]),
+            icc_profile: Some(IccProfile { name: "ICC profile".into(), profile: profile.clone() }),
+            ..Default::default()
+        };
+        let options = EncodeOptions { metadata: metadata.clone(), ..Default::default() };
+        let spec = ImageSpec::new(1, 1, PixelFormat::Rgb8);
+        let bytes = encode_image_with_options(&spec, &[1, 2, 3], &options).expect("infallible");
+
+        let reader = png::Decoder::new(Cursor::new(&bytes)).read_info().expect("infallible");
+        let info = reader.info();
+        prop_assert_eq!(info.gama_chunk.map(|gamma| gamma.into_scaled()), Some(gamma));
+        let chrm = info.chrm_chunk.expect("infallible");
+        let scaled = [chrm.white, chrm.red, chrm.green, chrm.blue]
+            .map(|(x, y)| [x.into_scaled(), y.into_scaled()]);
+        prop_assert_eq!(scaled.as_flattened(), &points[..]);
+        prop_assert_eq!(info.srgb.map(|intent| intent as usize), Some(intent));
+        prop_assert_eq!(info.icc_profile.as_deref(), Some(&profile[..]));
+
+        let image = decode_image_with_options(&bytes, &DecodeOptions::default()).expect("infallible");
+        prop_assert_eq!(image.metadata, metadata);
+    }
+
+    #[test]
+    fn text_is_read_by_png_crate(
+        keyword in "[A-Za-z][A-Za-z0-9 ]{0,20}[A-Za-z]",
+        latin1 in "[ -~\u{a1}-\u{ff}]{0,64}",
+        utf8 in "\\PC{0,64}",
+        language_tag in "[a-z]{2}(-[A-Z]{2})?",
+        compressed in any::<bool>(),
+    ) {
+        prop_assume!(!keyword.contains("  "));
+        let text = vec![
+            TextEntry { keyword: keyword.clone(), text: latin1.clone(), compressed, international: None },
+            TextEntry {
+                keyword: keyword.clone(),
+                text: utf8.clone(),
+                compressed,
+                international: Some(InternationalText { language_tag: language_tag.clone(), translated_keyword: utf8.clone() }),
+            },
+        ];
+        let options = EncodeOptions {
+            metadata: Metadata { text: text.clone(), ..Default::default() },
+            ..Default::default()
+        };
+        let spec = ImageSpec::new(1, 1, PixelFormat::Gray8);
+        let bytes = encode_image_with_options(&spec, &[0], &options).expect("infallible");
+
+        let reader = png::Decoder::new(Cursor::new(&bytes)).read_info().expect("infallible");
+        let info = reader.info();
+        let latin1_chunk = if compressed {
+            let mut chunk = info.compressed_latin1_text[0].clone();
+            chunk.decompress_text().expect("infallible");
+            (chunk.keyword.clone(), chunk.get_text().expect("infallible"))
+        } else {
+            let chunk = &info.uncompressed_latin1_text[0];
+            (chunk.keyword.clone(), chunk.text.clone())
+        };
+        prop_assert_eq!(latin1_chunk, (keyword.clone(), latin1));
+        let mut itxt = info.utf8_text[0].clone();
+        itxt.decompress_text().expect("infallible");
+        prop_assert_eq!(&itxt.keyword, &keyword);
+        prop_assert_eq!(itxt.compressed, compressed);
+        prop_assert_eq!(&itxt.language_tag, &language_tag);
+        prop_assert_eq!(&itxt.translated_keyword, &utf8);
+        prop_assert_eq!(itxt.get_text().expect("infallible"), utf8);
+
+        let image = decode_image_with_options(&bytes, &DecodeOptions::default()).expect("infallible");
+        prop_assert_eq!(image.metadata.text, text);
+    }
+
+    #[test]
+    fn image_metadata_is_read_by_png_crate(
+        dpi in proptest::array::uniform2(1u32..=10_000),
+        bits in proptest::array::uniform3(1u8..=16),
+        background in proptest::array::uniform3(any::<u16>()),
+        exif in proptest::collection::vec(any::<u8>(), 0..256),
+    ) {
+        let metadata = Metadata {
+            physical_dimensions: Some(PhysicalDimensions::from_dpi(dpi[0], dpi[1])),
+            significant_bits: Some(SignificantBits::Rgb(bits)),
+            background: Some(Background::Rgb(background)),
+            exif: Some(exif),
+            ..Default::default()
+        };
+        let options = EncodeOptions { metadata: metadata.clone(), ..Default::default() };
+        let spec = ImageSpec::new(1, 1, PixelFormat::Rgb16Be);
+        let bytes = encode_image_with_options(&spec, &[0; 6], &options).expect("infallible");
+
+        let reader = png::Decoder::new(Cursor::new(&bytes)).read_info().expect("infallible");
+        let info = reader.info();
+        let pixel_dims = info.pixel_dims.expect("infallible");
+        let expected = metadata.physical_dimensions.expect("infallible");
+        prop_assert_eq!((pixel_dims.xppu, pixel_dims.yppu), (expected.x, expected.y));
+        prop_assert_eq!(pixel_dims.unit, png::Unit::Meter);
+        prop_assert_eq!(info.sbit.as_deref(), Some(&bits[..]));
+        let bkgd: Vec<u8> = background.iter().flat_map(|sample| sample.to_be_bytes()).collect();
+        prop_assert_eq!(info.bkgd.as_deref(), Some(&bkgd[..]));
+
+        let image = decode_image_with_options(&bytes, &DecodeOptions::default()).expect("infallible");
+        prop_assert_eq!(image.metadata, metadata);
+    }
+
+    #[test]
+    fn optimized_png_is_read_by_png_crate_with_the_same_pixels(
+        (width, height, data) in rgba_image_strategy(8, 8),
+        bits in 1u32..=8,
+        gray in any::<bool>(),
+        opaque in any::<bool>(),
+        interlaced in any::<bool>(),
+    ) {
+        // Reduce the precision and channels of random pixels so that every
+        // kind of lossless reduction gets exercised.
+        let quantize = |sample: u8| ((u32::from(sample) >> (8 - bits)) * 255 / ((1 << bits) - 1)) as u8;
+        let data: Vec<u8> = data
+            .chunks_exact(4)
+            .flat_map(|pixel| {
+                let [r, g, b, a] = [pixel[0], pixel[1], pixel[2], pixel[3]].map(quantize);
+                let [g, b] = if gray { [r, r] } else { [g, b] };
+                [r, g, b, if opaque { 255 } else { a }]
+            })
+            .collect();
+        let spec = ImageSpec {
+            interlaced,
+            ..ImageSpec::new(width, height, PixelFormat::Rgba8)
+        };
+        let encoded = encode_image(&spec, &data).expect("infallible");
+
+        let optimized = optimize_png(&encoded, &OptimizeOptions::default()).expect("infallible");
+        prop_assert!(optimized.len() <= encoded.len());
+        let (_, _, ref_rgba) = decode_with_png_crate(&optimized).expect("infallible");
+        prop_assert_eq!(ref_rgba, data);
     }
 
     #[test]
+    #[allow(clippy::let_and_return)]
     fn reformat_identity_returns_same_data((_width, _height, data) in rgba_image_strategy(8, 8)) {
         let formats: Vec<(PixelFormat, Vec<u8>)> = vec![
             (PixelFormat::Rgba8, data.clone()),
@@ -247,6 +525,91 @@ proptest! {
         prop_assert_eq!(nopng_rgba, ref_rgba);
     }
 
+    #[test]
+    fn apng_frames_written_by_png_crate_are_decoded((width, height, frames) in animation_strategy()) {
+        let dispose_ops = [png::DisposeOp::None, png::DisposeOp::Background, png::DisposeOp::Previous];
+        let blend_ops = [png::BlendOp::Source, png::BlendOp::Over];
+        let mut bytes = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut bytes, width, height);
+            encoder.set_color(png::ColorType::Rgba);
+            encoder.set_depth(png::BitDepth::Eight);
+            encoder.set_animated(frames.len() as u32, 0).expect("infallible");
+            let mut writer = encoder.write_header().expect("infallible");
+            for (index, ([x, y, frame_width, frame_height], dispose, blend, pixels)) in frames.iter().enumerate() {
+                writer.set_frame_position(0, 0).expect("infallible");
+                writer.set_frame_dimension(*frame_width, *frame_height).expect("infallible");
+                writer.set_frame_position(*x, *y).expect("infallible");
+                writer.set_frame_delay(index as u16, 25).expect("infallible");
+                writer.set_dispose_op(dispose_ops[usize::from(*dispose)]).expect("infallible");
+                writer.set_blend_op(blend_ops[usize::from(*blend)]).expect("infallible");
+                writer.write_image_data(pixels).expect("infallible");
+            }
+            writer.finish().expect("infallible");
+        }
+
+        let decoder = AnimationDecoder::new(&bytes, &DecodeOptions::default()).expect("infallible");
+        prop_assert_eq!(decoder.animation_control().map(|control| control.num_frames), Some(frames.len() as u32));
+        let decoded: Vec<_> = decoder.map(|frame| frame.expect("infallible")).collect();
+        prop_assert_eq!(decoded.len(), frames.len());
+        for (index, (frame, ([x, y, frame_width, frame_height], dispose, blend, pixels))) in decoded.iter().zip(&frames).enumerate() {
+            let control = frame.control;
+            prop_assert_eq!(
+                [control.x_offset, control.y_offset, control.width, control.height],
+                [*x, *y, *frame_width, *frame_height]
+            );
+            prop_assert_eq!((control.delay_num, control.delay_den), (index as u16, 25));
+            prop_assert_eq!(control.dispose_op as u8, *dispose);
+            prop_assert_eq!(control.blend_op as u8, *blend);
+            prop_assert_eq!(&frame.pixels, pixels);
+        }
+    }
+
+    #[test]
+    fn cropped_apng_is_read_by_png_crate((width, height, frames) in animation_strategy()) {
+        let dispose_ops = [DisposeOp::None, DisposeOp::Background, DisposeOp::Previous];
+        let blend_ops = [BlendOp::Source, BlendOp::Over];
+        let frames: Vec<_> = frames
+            .into_iter()
+            .map(|([x_offset, y_offset, width, height], dispose, blend, pixels)| AnimationFrame {
+                control: FrameControl {
+                    width,
+                    height,
+                    x_offset,
+                    y_offset,
+                    dispose_op: dispose_ops[usize::from(dispose)],
+                    blend_op: blend_ops[usize::from(blend)],
+                    ..FrameControl::full_canvas(width, height)
+                },
+                pixels,
+            })
+            .collect();
+        let spec = ImageSpec::new(width, height, PixelFormat::Rgba8);
+        let animation = AnimationOptions { crop_to_changes: true, ..Default::default() };
+        let bytes = encode_animation(&spec, &frames, &EncodeOptions::default(), &animation).expect("infallible");
+
+        let mut reader = png::Decoder::new(Cursor::new(&bytes)).read_info().expect("infallible");
+        prop_assert_eq!(reader.info().animation_control.map(|control| control.num_frames), Some(frames.len() as u32));
+        let mut expected = FrameCompositor::new(&spec);
+        let mut actual = FrameCompositor::new(&spec);
+        for original in &frames {
+            let mut buf = vec![0; reader.output_buffer_size()];
+            let info = reader.next_frame(&mut buf).expect("infallible");
+            let control = reader.info().frame_control.expect("infallible");
+            let frame = AnimationFrame {
+                control: FrameControl {
+                    width: control.width,
+                    height: control.height,
+                    x_offset: control.x_offset,
+                    y_offset: control.y_offset,
+                    ..original.control
+                },
+                pixels: buf[..info.buffer_size()].to_vec(),
+            };
+            prop_assert_eq!(actual.compose(&frame).expect("infallible"), expected.compose(original).expect("infallible"));
+        }
+    }
+
     #[test]
     fn reformat_to_rgba8_matches_png_crate_for_indexed((width, height, indices, palette, trns) in indexed_image_strategy()) {
         let spec = ImageSpec {
@@ -294,17 +657,164 @@ proptest! {
     }
 
     #[test]
-    fn reformat_to_indexed_is_unsupported((_width, _height, data) in rgba_image_strategy(4, 4)) {
-        let palette = vec![0u8; 3];
-        let indexed_formats = [
-            PixelFormat::Indexed1 { palette: palette.clone(), trns: None },
-            PixelFormat::Indexed2 { palette: palette.clone(), trns: None },
-            PixelFormat::Indexed4 { palette: palette.clone(), trns: None },
-            PixelFormat::Indexed8 { palette: palette.clone(), trns: None },
-        ];
-        for dst in &indexed_formats {
-            let result = reformat_pixels(&PixelFormat::Rgba8, &data, dst);
-            prop_assert!(result.is_err(), "reformat to indexed should fail");
+    fn reformat_to_indexed_picks_the_nearest_palette_entry(
+        (_width, _height, data) in rgba_image_strategy(4, 4),
+        palette in proptest::collection::vec(any::<u8>(), 3..=48).prop_map(|mut palette| {
+            palette.truncate(palette.len() / 3 * 3);
+            palette
+        }),
+    ) {
+        let format = PixelFormat::Indexed4 { palette: palette.clone(), trns: None };
+        let indices = reformat_pixels(&PixelFormat::Rgba8, &data, &format).expect("infallible");
+        let distance = |pixel: &[u8], index: usize| -> u32 {
+            let entry = [palette[index * 3], palette[index * 3 + 1], palette[index * 3 + 2], 255];
+            pixel.iter().zip(entry).map(|(&a, b)| u32::from(a.abs_diff(b)).pow(2)).sum()
+        };
+        for (pixel, &index) in data.chunks_exact(4).zip(&indices) {
+            let best = (0..palette.len() / 3).map(|index| distance(pixel, index)).min();
+            prop_assert_eq!(Some(distance(pixel, usize::from(index))), best);
+        }
+    }
+
+    #[test]
+    fn reformat_image_composites_only_translucent_pixels(
+        (width, height, data) in rgba_image_strategy(8, 8),
+        background in any::<[u16; 3]>(),
+        srgb in any::<bool>(),
+    ) {
+        let spec = ImageSpec::new(width, height, PixelFormat::Rgba8);
+        let options = ReformatOptions {
+            transfer: if srgb { TransferFunction::Srgb } else { TransferFunction::Linear },
+            background: Some(background),
+            ..Default::default()
+        };
+        let (_, rgb) = reformat_image(&spec, &data, &PixelFormat::Rgb16Be, &options).expect("infallible");
+        let (rgb, _) = rgb.as_chunks::<6>();
+        for (pixel, composited) in data.chunks_exact(4).zip(rgb) {
+            let composited: Vec<u16> = composited
+                .chunks_exact(2)
+                .map(|sample| u16::from_be_bytes([sample[0], sample[1]]))
+                .collect();
+            match pixel[3] {
+                255 => prop_assert_eq!(composited, pixel[..3].iter().map(|&sample| u16::from(sample) * 257).collect::<Vec<_>>()),
+                0 => prop_assert_eq!(composited, background.to_vec()),
+                _ => {
+                    // Every channel lies between the pixel and the background.
+                    for ((&sample, &background), composited) in pixel.iter().zip(&background).zip(composited) {
+                        let sample = u16::from(sample) * 257;
+                        prop_assert!(sample.min(background) <= composited && composited <= sample.max(background));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn premultiplied_pixels_survive_encoding(
+        (width, height, data) in rgba_image_strategy(8, 8),
+        wide in any::<bool>(),
+    ) {
+        // Scale the color samples down so that none exceeds alpha.
+        let premultiplied: Vec<u8> = data
+            .chunks_exact(4)
+            .flat_map(|pixel| {
+                let alpha = pixel[3];
+                let scale = |sample: u8| (u16::from(sample) * u16::from(alpha) / 255) as u8;
+                [scale(pixel[0]), scale(pixel[1]), scale(pixel[2]), alpha]
+            })
+            .collect();
+        let (format, premultiplied) = if wide {
+            let format = PixelFormat::Rgba16BePremultiplied;
+            let wide = reformat_pixels(&PixelFormat::Rgba8Premultiplied, &premultiplied, &format)
+                .expect("infallible");
+            (format, wide)
+        } else {
+            (PixelFormat::Rgba8Premultiplied, premultiplied)
+        };
+        let spec = ImageSpec::new(width, height, format.clone());
+        let encoded = encode_image(&spec, &premultiplied).expect("infallible");
+
+        let (_, decoded) = decode_image_as(&encoded, &format).expect("infallible");
+        prop_assert_eq!(&decoded, &premultiplied);
+        let (_, _, ref_rgba) = decode_with_png_crate(&encoded).expect("infallible");
+        let straight = reformat_pixels(&format, &premultiplied, &PixelFormat::Rgba8).expect("infallible");
+        prop_assert_eq!(ref_rgba, straight);
+    }
+
+    #[test]
+    fn dithering_to_premultiplied_keeps_colors_within_alpha(
+        (width, height, data) in rgba16_image_strategy(8, 8),
+        dithering in prop::sample::select(vec![
+            Dithering::FloydSteinberg,
+            Dithering::Atkinson,
+            Dithering::Bayer,
+        ]),
+    ) {
+        // Near-white colors premultiply to samples just below alpha, which
+        // dithering must not push above it.
+        let mut data = data;
+        for pixel in data.chunks_exact_mut(8) {
+            for sample in pixel[..6].chunks_exact_mut(2) {
+                sample[0] |= 0xF0;
+            }
+        }
+        let spec = ImageSpec::new(width, height, PixelFormat::Rgba16Be);
+        let options = ReformatOptions {
+            dithering,
+            ..Default::default()
+        };
+        let (_, premultiplied) =
+            reformat_image(&spec, &data, &PixelFormat::Rgba8Premultiplied, &options).expect("infallible");
+        for pixel in premultiplied.chunks_exact(4) {
+            prop_assert!(pixel[..3].iter().all(|&sample| sample <= pixel[3]), "{:?}", pixel);
+        }
+    }
+
+    #[test]
+    fn reformat_image_builds_a_palette_read_by_png_crate(
+        (width, height, indices, palette, trns) in indexed_image_strategy(),
+        (noise_width, noise_height, noise) in rgba_image_strategy(8, 8),
+        dithering in prop::sample::select(vec![
+            Dithering::None,
+            Dithering::FloydSteinberg,
+            Dithering::Atkinson,
+            Dithering::Bayer,
+        ]),
+    ) {
+        // Images with few enough colors get an exact palette.
+        let format = PixelFormat::Indexed8 { palette, trns: Some(trns) };
+        let rgba = reformat_pixels(&format, &indices, &PixelFormat::Rgba8).expect("infallible");
+        let spec = ImageSpec::new(width, height, PixelFormat::Rgba8);
+        let options = ReformatOptions {
+            dithering,
+            ..Default::default()
+        };
+        let target = PixelFormat::Indexed2 { palette: Vec::new(), trns: None };
+        let (indexed_spec, indexed) = reformat_image(&spec, &rgba, &target, &options).expect("infallible");
+        let encoded = encode_image(&indexed_spec, &indexed).expect("infallible");
+        let (_, _, ref_rgba) = decode_with_png_crate(&encoded).expect("infallible");
+        prop_assert_eq!(ref_rgba, rgba);
+
+        // Others are quantized to a palette that fits the bit depth.
+        let spec = ImageSpec::new(noise_width, noise_height, PixelFormat::Rgba8);
+        let (indexed_spec, indexed) = reformat_image(&spec, &noise, &target, &options).expect("infallible");
+        let encoded = encode_image(&indexed_spec, &indexed).expect("infallible");
+        let (_, _, ref_rgba) = decode_with_png_crate(&encoded).expect("infallible");
+        prop_assert_eq!(ref_rgba.len(), noise.len());
+        let PixelFormat::Indexed2 { palette, trns } = &indexed_spec.pixel_format else {
+            panic!("bug: reformat_image must return the target format");
+        };
+        prop_assert!(palette.len() <= 4 * 3);
+        let entries: Vec<[u8; 4]> = palette
+            .chunks_exact(3)
+            .enumerate()
+            .map(|(index, rgb)| {
+                let alpha = trns.as_ref().and_then(|trns| trns.get(index).copied());
+                [rgb[0], rgb[1], rgb[2], alpha.unwrap_or(u8::MAX)]
+            })
+            .collect();
+        for pixel in ref_rgba.chunks_exact(4) {
+            prop_assert!(entries.iter().any(|entry| entry == pixel), "{:?}", pixel);
         }
     }
 }
@@ -2,17 +2,35 @@ const ADLER32_MOD: u32 = 65521; // Largest prime number less than 65536
 const NMAX: usize = 5552; // Largest n such that 255*n*(n+1)/2 + (n+1)*(65520) <= 2^32 - 1
 
 pub fn calculate(data: &[u8]) -> u32 {
-    let mut s1: u32 = 1;
-    let mut s2: u32 = 0;
+    let mut adler = Adler32::new();
+    adler.update(data);
+    adler.finish()
+}
+
+/// Incremental Adler-32 checksum for data that arrives in pieces.
+#[derive(Debug, Clone)]
+pub struct Adler32 {
+    s1: u32,
+    s2: u32,
+}
 
-    for chunk in data.chunks(NMAX) {
-        for &byte in chunk {
-            s1 += byte as u32;
-            s2 += s1;
+impl Adler32 {
+    pub fn new() -> Self {
+        Self { s1: 1, s2: 0 }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for chunk in data.chunks(NMAX) {
+            for &byte in chunk {
+                self.s1 += byte as u32;
+                self.s2 += self.s1;
+            }
+            self.s1 %= ADLER32_MOD;
+            self.s2 %= ADLER32_MOD;
         }
-        s1 %= ADLER32_MOD;
-        s2 %= ADLER32_MOD;
     }
 
-    (s2 << 16) | s1
+    pub fn finish(&self) -> u32 {
+        (self.s2 << 16) | self.s1
+    }
 }
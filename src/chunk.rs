@@ -1,7 +1,14 @@
-use alloc::format;
+use alloc::string::String;
 use alloc::vec::Vec;
 
-use crate::{adler32, crc, deflate, zlib};
+use crate::crc;
+use crate::png::PNG_SIGNATURE;
+use crate::png_decode::{Cursor, MAX_CHUNK_LEN, read_raw_chunk};
+use crate::png_types::{
+    Background, BlendOp, Chromaticities, Cicp, DisposeOp, Error, ErrorKind, FrameControl,
+    ImageOffset, OffsetUnit, PhysicalDimensions, PhysicalUnit, RenderingIntent, Result,
+    SignificantBits, SuggestedPalette, Timestamp,
+};
 
 #[derive(Debug, Clone)]
 pub(crate) struct IhdrChunk {
@@ -60,6 +67,301 @@ impl TrnsChunk<'_> {
     }
 }
 
+#[derive(Debug, Clone)]
+pub(crate) struct GamaChunk {
+    pub(crate) gamma: u32,
+}
+
+impl GamaChunk {
+    pub(crate) fn append_to(&self, out: &mut Vec<u8>) {
+        append_chunk(out, b"gAMA", &self.gamma.to_be_bytes());
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ChrmChunk {
+    pub(crate) chromaticities: Chromaticities,
+}
+
+impl ChrmChunk {
+    pub(crate) fn append_to(&self, out: &mut Vec<u8>) {
+        let Chromaticities {
+            white,
+            red,
+            green,
+            blue,
+        } = self.chromaticities;
+        let mut data = Vec::with_capacity(32);
+        for value in [white, red, green, blue].as_flattened() {
+            data.extend_from_slice(&value.to_be_bytes());
+        }
+        append_chunk(out, b"cHRM", &data);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct SrgbChunk {
+    pub(crate) intent: RenderingIntent,
+}
+
+impl SrgbChunk {
+    pub(crate) fn append_to(&self, out: &mut Vec<u8>) {
+        let intent = match self.intent {
+            RenderingIntent::Perceptual => 0,
+            RenderingIntent::RelativeColorimetric => 1,
+            RenderingIntent::Saturation => 2,
+            RenderingIntent::AbsoluteColorimetric => 3,
+        };
+        append_chunk(out, b"sRGB", &[intent]);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct IccpChunk<'a> {
+    /// Latin-1 encoded profile name.
+    pub(crate) name: &'a [u8],
+    pub(crate) zlib_data: &'a [u8],
+}
+
+impl IccpChunk<'_> {
+    pub(crate) fn append_to(&self, out: &mut Vec<u8>) {
+        let mut data = Vec::with_capacity(self.name.len() + 2 + self.zlib_data.len());
+        data.extend_from_slice(self.name);
+        data.push(0);
+        data.push(COMPRESSION_METHOD_DEFLATE);
+        data.extend_from_slice(self.zlib_data);
+        append_chunk(out, b"iCCP", &data);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct CicpChunk {
+    pub(crate) cicp: Cicp,
+}
+
+impl CicpChunk {
+    pub(crate) fn append_to(&self, out: &mut Vec<u8>) {
+        let data = [
+            self.cicp.color_primaries,
+            self.cicp.transfer_function,
+            self.cicp.matrix_coefficients,
+            u8::from(self.cicp.full_range),
+        ];
+        append_chunk(out, b"cICP", &data);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct PhysChunk {
+    pub(crate) dimensions: PhysicalDimensions,
+}
+
+impl PhysChunk {
+    pub(crate) fn append_to(&self, out: &mut Vec<u8>) {
+        let mut data = [0u8; 9];
+        data[0..4].copy_from_slice(&self.dimensions.x.to_be_bytes());
+        data[4..8].copy_from_slice(&self.dimensions.y.to_be_bytes());
+        data[8] = match self.dimensions.unit {
+            PhysicalUnit::Unknown => 0,
+            PhysicalUnit::Meter => 1,
+        };
+        append_chunk(out, b"pHYs", &data);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct OffsChunk {
+    pub(crate) offset: ImageOffset,
+}
+
+impl OffsChunk {
+    pub(crate) fn append_to(&self, out: &mut Vec<u8>) {
+        let mut data = [0u8; 9];
+        data[0..4].copy_from_slice(&self.offset.x.to_be_bytes());
+        data[4..8].copy_from_slice(&self.offset.y.to_be_bytes());
+        data[8] = match self.offset.unit {
+            OffsetUnit::Pixel => 0,
+            OffsetUnit::Micrometer => 1,
+        };
+        append_chunk(out, b"oFFs", &data);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct TimeChunk {
+    pub(crate) time: Timestamp,
+}
+
+impl TimeChunk {
+    pub(crate) fn append_to(&self, out: &mut Vec<u8>) {
+        let Timestamp {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+        } = self.time;
+        let [year_hi, year_lo] = year.to_be_bytes();
+        let data = [year_hi, year_lo, month, day, hour, minute, second];
+        append_chunk(out, b"tIME", &data);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct SbitChunk {
+    pub(crate) significant_bits: SignificantBits,
+}
+
+impl SbitChunk {
+    pub(crate) fn append_to(&self, out: &mut Vec<u8>) {
+        let data: &[u8] = match &self.significant_bits {
+            SignificantBits::Gray(gray) => core::slice::from_ref(gray),
+            SignificantBits::GrayAlpha(bits) => bits,
+            SignificantBits::Rgb(bits) => bits,
+            SignificantBits::Rgba(bits) => bits,
+        };
+        append_chunk(out, b"sBIT", data);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct BkgdChunk {
+    pub(crate) background: Background,
+}
+
+impl BkgdChunk {
+    pub(crate) fn append_to(&self, out: &mut Vec<u8>) {
+        let mut data = Vec::with_capacity(6);
+        match self.background {
+            Background::Gray(gray) => data.extend_from_slice(&gray.to_be_bytes()),
+            Background::Rgb(rgb) => {
+                for sample in rgb {
+                    data.extend_from_slice(&sample.to_be_bytes());
+                }
+            }
+            Background::PaletteIndex(index) => data.push(index),
+        }
+        append_chunk(out, b"bKGD", &data);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct HistChunk<'a> {
+    pub(crate) frequencies: &'a [u16],
+}
+
+impl HistChunk<'_> {
+    pub(crate) fn append_to(&self, out: &mut Vec<u8>) {
+        let mut data = Vec::with_capacity(self.frequencies.len() * 2);
+        for frequency in self.frequencies {
+            data.extend_from_slice(&frequency.to_be_bytes());
+        }
+        append_chunk(out, b"hIST", &data);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct SpltChunk<'a> {
+    /// Latin-1 encoded palette name.
+    pub(crate) name: &'a [u8],
+    pub(crate) palette: &'a SuggestedPalette,
+}
+
+impl SpltChunk<'_> {
+    pub(crate) fn append_to(&self, out: &mut Vec<u8>) {
+        let sample_bytes = usize::from(self.palette.sample_depth / 8);
+        let mut data = Vec::with_capacity(
+            self.name.len() + 2 + self.palette.entries.len() * (4 * sample_bytes + 2),
+        );
+        data.extend_from_slice(self.name);
+        data.push(0);
+        data.push(self.palette.sample_depth);
+        for entry in &self.palette.entries {
+            for sample in [entry.red, entry.green, entry.blue, entry.alpha] {
+                data.extend_from_slice(&sample.to_be_bytes()[2 - sample_bytes..]);
+            }
+            data.extend_from_slice(&entry.frequency.to_be_bytes());
+        }
+        append_chunk(out, b"sPLT", &data);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ExifChunk<'a> {
+    pub(crate) data: &'a [u8],
+}
+
+impl ExifChunk<'_> {
+    pub(crate) fn append_to(&self, out: &mut Vec<u8>) {
+        append_chunk(out, b"eXIf", self.data);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct TextChunk<'a> {
+    /// Latin-1 encoded keyword.
+    pub(crate) keyword: &'a [u8],
+    /// Latin-1 encoded text.
+    pub(crate) text: &'a [u8],
+}
+
+impl TextChunk<'_> {
+    pub(crate) fn append_to(&self, out: &mut Vec<u8>) {
+        let mut data = Vec::with_capacity(self.keyword.len() + 1 + self.text.len());
+        data.extend_from_slice(self.keyword);
+        data.push(0);
+        data.extend_from_slice(self.text);
+        append_chunk(out, b"tEXt", &data);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ZtxtChunk<'a> {
+    /// Latin-1 encoded keyword.
+    pub(crate) keyword: &'a [u8],
+    /// zlib-compressed Latin-1 text.
+    pub(crate) zlib_data: &'a [u8],
+}
+
+impl ZtxtChunk<'_> {
+    pub(crate) fn append_to(&self, out: &mut Vec<u8>) {
+        let mut data = Vec::with_capacity(self.keyword.len() + 2 + self.zlib_data.len());
+        data.extend_from_slice(self.keyword);
+        data.push(0);
+        data.push(COMPRESSION_METHOD_DEFLATE);
+        data.extend_from_slice(self.zlib_data);
+        append_chunk(out, b"zTXt", &data);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ItxtChunk<'a> {
+    /// Latin-1 encoded keyword.
+    pub(crate) keyword: &'a [u8],
+    pub(crate) compressed: bool,
+    pub(crate) language_tag: &'a str,
+    pub(crate) translated_keyword: &'a str,
+    /// UTF-8 text, zlib-compressed if `compressed` is set.
+    pub(crate) text: &'a [u8],
+}
+
+impl ItxtChunk<'_> {
+    pub(crate) fn append_to(&self, out: &mut Vec<u8>) {
+        let mut data = Vec::new();
+        data.extend_from_slice(self.keyword);
+        data.push(0);
+        data.push(u8::from(self.compressed));
+        data.push(COMPRESSION_METHOD_DEFLATE);
+        data.extend_from_slice(self.language_tag.as_bytes());
+        data.push(0);
+        data.extend_from_slice(self.translated_keyword.as_bytes());
+        data.push(0);
+        data.extend_from_slice(self.text);
+        append_chunk(out, b"iTXt", &data);
+    }
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct IendChunk;
 
@@ -71,31 +373,280 @@ impl IendChunk {
 
 #[derive(Debug, Clone)]
 pub(crate) struct IdatChunk<'a> {
-    pub(crate) filtered_data: &'a [u8],
+    pub(crate) zlib_data: &'a [u8],
 }
 
 impl IdatChunk<'_> {
-    pub(crate) fn append_to(&self, out: &mut Vec<u8>) -> crate::png_types::Result<()> {
-        let chunk_data = self.chunk_data()?;
-        append_chunk(out, b"IDAT", &chunk_data);
-        Ok(())
+    pub(crate) fn append_to(&self, out: &mut Vec<u8>) {
+        append_chunk(out, b"IDAT", self.zlib_data);
     }
+}
 
-    fn chunk_data(&self) -> crate::png_types::Result<Vec<u8>> {
-        let mut data = Vec::new();
-        data.extend_from_slice(&zlib::ZLIB_HEADER);
-        let deflated = deflate::compress(self.filtered_data).map_err(|error| {
-            crate::png::Error::InvalidData(format!("invalid deflate stream: {error}").into())
-        })?;
-        data.extend_from_slice(&deflated);
-        data.extend_from_slice(&adler32::calculate(self.filtered_data).to_be_bytes());
-        Ok(data)
+#[derive(Debug, Clone)]
+pub(crate) struct ActlChunk {
+    pub(crate) num_frames: u32,
+    pub(crate) num_plays: u32,
+}
+
+impl ActlChunk {
+    pub(crate) fn append_to(&self, out: &mut Vec<u8>) {
+        let mut data = [0; 8];
+        data[..4].copy_from_slice(&self.num_frames.to_be_bytes());
+        data[4..].copy_from_slice(&self.num_plays.to_be_bytes());
+        append_chunk(out, b"acTL", &data);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct FctlChunk<'a> {
+    pub(crate) sequence_number: u32,
+    pub(crate) control: &'a FrameControl,
+}
+
+impl FctlChunk<'_> {
+    pub(crate) fn append_to(&self, out: &mut Vec<u8>) {
+        let control = self.control;
+        let mut data = Vec::with_capacity(26);
+        for value in [
+            self.sequence_number,
+            control.width,
+            control.height,
+            control.x_offset,
+            control.y_offset,
+        ] {
+            data.extend_from_slice(&value.to_be_bytes());
+        }
+        data.extend_from_slice(&control.delay_num.to_be_bytes());
+        data.extend_from_slice(&control.delay_den.to_be_bytes());
+        data.push(match control.dispose_op {
+            DisposeOp::None => 0,
+            DisposeOp::Background => 1,
+            DisposeOp::Previous => 2,
+        });
+        data.push(match control.blend_op {
+            BlendOp::Source => 0,
+            BlendOp::Over => 1,
+        });
+        append_chunk(out, b"fcTL", &data);
+    }
+}
+
+/// Image data of an animation frame other than the default image.
+#[derive(Debug, Clone)]
+pub(crate) struct FdatChunk<'a> {
+    pub(crate) sequence_number: u32,
+    pub(crate) zlib_data: &'a [u8],
+}
+
+impl FdatChunk<'_> {
+    pub(crate) fn append_to(&self, out: &mut Vec<u8>) {
+        let mut data = Vec::with_capacity(4 + self.zlib_data.len());
+        data.extend_from_slice(&self.sequence_number.to_be_bytes());
+        data.extend_from_slice(self.zlib_data);
+        append_chunk(out, b"fdAT", &data);
     }
 }
 
+/// Compression method byte of `iCCP`, `zTXt` and `iTXt` chunks.
+pub(crate) const COMPRESSION_METHOD_DEFLATE: u8 = 0;
+
 fn append_chunk(out: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
     out.extend_from_slice(&(data.len() as u32).to_be_bytes());
     out.extend_from_slice(chunk_type);
     out.extend_from_slice(data);
     out.extend_from_slice(&crc::calculate(&[chunk_type.as_slice(), data]).to_be_bytes());
 }
+
+/// Returns true if `keyword` is a valid Latin-1 keyword: 1 to 79 printable
+/// characters without leading, trailing or consecutive spaces.
+pub(crate) fn is_valid_keyword(keyword: &[u8]) -> bool {
+    (1..=79).contains(&keyword.len())
+        && keyword
+            .iter()
+            .all(|&byte| matches!(byte, 32..=126 | 161..=255))
+        && keyword.first() != Some(&b' ')
+        && keyword.last() != Some(&b' ')
+        && !keyword.windows(2).any(|pair| pair == b"  ")
+}
+
+/// Decodes Latin-1 bytes, which map one-to-one onto the first 256 code points.
+pub(crate) fn latin1_to_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|&byte| char::from(byte)).collect()
+}
+
+/// Encodes `text` as Latin-1, or returns `None` if it contains characters
+/// outside that range.
+pub(crate) fn string_to_latin1(text: &str) -> Option<Vec<u8>> {
+    text.chars().map(|c| u8::try_from(c).ok()).collect()
+}
+
+/// Returns true for ancillary chunks, whose type starts with a lowercase letter.
+pub(crate) fn is_ancillary(chunk_type: &[u8; 4]) -> bool {
+    chunk_type[0] & 0x20 != 0
+}
+
+/// A chunk returned by [`ChunkReader`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawChunk<'a> {
+    /// Four-letter chunk type, such as `*b"IDAT"`.
+    pub chunk_type: [u8; 4],
+    /// Chunk data, without the length, type and CRC fields.
+    pub data: &'a [u8],
+    /// CRC stored after the chunk data.
+    pub crc: u32,
+    /// `true` if `crc` matches the chunk type and data.
+    pub crc_ok: bool,
+}
+
+impl RawChunk<'_> {
+    /// Returns `true` for ancillary chunks, which decoders may skip.
+    pub fn is_ancillary(&self) -> bool {
+        is_ancillary(&self.chunk_type)
+    }
+
+    /// Returns `true` for chunks that editors may copy unchanged after
+    /// modifying critical chunks.
+    pub fn is_safe_to_copy(&self) -> bool {
+        self.chunk_type[3].is_ascii_lowercase()
+    }
+}
+
+/// Iterator over the chunks of a PNG byte stream.
+///
+/// Chunks are returned in file order without interpreting their contents,
+/// so the stream may hold chunks of any type. Iteration stops after the
+/// `IEND` chunk; [`remaining`](Self::remaining) returns any bytes that
+/// follow it. A truncated chunk is returned as an
+/// [`ErrorKind::UnexpectedEof`] error and a chunk longer than 2³¹ − 1 bytes
+/// as an [`ErrorKind::InvalidChunk`] error, after which iteration stops.
+///
+/// # Examples
+///
+/// Strip a private chunk and add another one, leaving the pixels alone:
+///
+/// ```
+/// # let png_bytes = nopng::encode_image(
+/// #     &nopng::ImageSpec::new(1, 1, nopng::PixelFormat::Gray8),
+/// #     &[128],
+/// # )?;
+/// let mut writer = nopng::ChunkWriter::new();
+/// for chunk in nopng::ChunkReader::new(&png_bytes)? {
+///     let chunk = chunk?;
+///     match &chunk.chunk_type {
+///         b"tEXt" => continue,
+///         b"IEND" => writer.write_chunk(b"nhEX", b"private data")?,
+///         _ => {}
+///     }
+///     writer.write_chunk(&chunk.chunk_type, chunk.data)?;
+/// }
+/// let edited = writer.finish();
+/// assert_eq!(nopng::decode_image(&edited)?, nopng::decode_image(&png_bytes)?);
+/// # Ok::<(), nopng::Error>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct ChunkReader<'a> {
+    cursor: Cursor<'a>,
+    finished: bool,
+}
+
+impl<'a> ChunkReader<'a> {
+    /// Creates a reader for `bytes`, which must start with the PNG
+    /// signature.
+    pub fn new(bytes: &'a [u8]) -> Result<Self> {
+        let Some(chunks) = bytes.strip_prefix(&PNG_SIGNATURE) else {
+            return Err(Error::new(ErrorKind::BadSignature, "invalid PNG signature"));
+        };
+        Ok(Self {
+            cursor: Cursor::new(chunks),
+            finished: false,
+        })
+    }
+
+    /// The bytes following the last chunk read.
+    pub fn remaining(&self) -> &'a [u8] {
+        &self.cursor.bytes[self.cursor.offset.min(self.cursor.bytes.len())..]
+    }
+}
+
+impl<'a> Iterator for ChunkReader<'a> {
+    type Item = Result<RawChunk<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished || self.cursor.remaining() == 0 {
+            return None;
+        }
+        let start = self.cursor.offset;
+        let result = read_raw_chunk(&mut self.cursor).map(|(chunk_type, data, crc)| RawChunk {
+            chunk_type,
+            data,
+            crc,
+            crc_ok: crc::calculate(&[&chunk_type[..], data]) == crc,
+        });
+        match &result {
+            Ok(chunk) => self.finished = &chunk.chunk_type == b"IEND",
+            Err(_) => {
+                // Leave the unreadable chunk in `remaining`.
+                self.cursor.offset = start;
+                self.finished = true;
+            }
+        }
+        Some(result)
+    }
+}
+
+/// Builder for a PNG byte stream made of arbitrary chunks.
+///
+/// The PNG signature is written first, and every chunk gets its length and
+/// CRC. Chunk order and contents are not checked; see [`ChunkReader`] for an
+/// example.
+#[derive(Debug, Clone)]
+pub struct ChunkWriter {
+    bytes: Vec<u8>,
+}
+
+impl Default for ChunkWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChunkWriter {
+    /// Creates a writer holding just the PNG signature.
+    pub fn new() -> Self {
+        Self {
+            bytes: PNG_SIGNATURE.to_vec(),
+        }
+    }
+
+    /// Appends a chunk of type `chunk_type` holding `data`.
+    ///
+    /// Fails with [`ErrorKind::InvalidArgument`] if the chunk type is not
+    /// made of four ASCII letters, or if `data` is longer than the 2³¹ − 1
+    /// bytes a chunk can hold.
+    pub fn write_chunk(&mut self, chunk_type: &[u8; 4], data: &[u8]) -> Result<()> {
+        if !chunk_type.iter().all(u8::is_ascii_alphabetic) {
+            return Err(Error::new(
+                ErrorKind::InvalidArgument,
+                "chunk type must be four ASCII letters",
+            ));
+        }
+        if data.len() > MAX_CHUNK_LEN {
+            return Err(Error::new(
+                ErrorKind::InvalidArgument,
+                "chunk data is too long",
+            ));
+        }
+        append_chunk(&mut self.bytes, chunk_type, data);
+        Ok(())
+    }
+
+    /// The bytes written so far.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Returns the PNG byte stream.
+    pub fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
@@ -0,0 +1,109 @@
+//! Raw DEFLATE ([RFC 1951]), zlib ([RFC 1950]) and gzip ([RFC 1952]) streams.
+//!
+//! These are the codecs nopng uses for PNG image data, available on their own
+//! with the `compression` cargo feature. The one-shot functions work on whole
+//! buffers; the encoder and decoder types accept their input in pieces.
+//!
+//! Decoding without an output limit trusts the input not to inflate to an
+//! unreasonable size. Use [`DeflateDecoder::with_output_limit`],
+//! [`ZlibDecoder::with_output_limit`] or [`GzipDecoder::with_output_limit`]
+//! to bound it. [`DeflateDecoder::inflate`] and [`ZlibDecoder::inflate`]
+//! decode into a caller-provided buffer instead of a `Vec`, keeping memory
+//! use bounded by the 32 KiB DEFLATE window whatever the size of the output.
+//!
+//! # Examples
+//!
+//! ```
+//! use nopng::CompressionLevel;
+//! use nopng::compression::{zlib_compress, zlib_decompress};
+//!
+//! let compressed = zlib_compress(b"hello hello hello", CompressionLevel::Default)?;
+//! assert_eq!(zlib_decompress(&compressed)?, b"hello hello hello");
+//! # Ok::<(), nopng::Error>(())
+//! ```
+//!
+//! [RFC 1950]: https://www.rfc-editor.org/rfc/rfc1950
+//! [RFC 1951]: https://www.rfc-editor.org/rfc/rfc1951
+//! [RFC 1952]: https://www.rfc-editor.org/rfc/rfc1952
+
+use alloc::vec::Vec;
+
+use crate::png_types::{CompressionLevel, ErrorKind, Result};
+use crate::{adler32, crc, deflate, gzip, zlib};
+
+pub use crate::deflate::Compressor as DeflateEncoder;
+pub use crate::deflate::Inflater as DeflateDecoder;
+pub use crate::gzip::Decoder as GzipDecoder;
+pub use crate::gzip::Encoder as GzipEncoder;
+pub use crate::zlib::Decoder as ZlibDecoder;
+pub use crate::zlib::Encoder as ZlibEncoder;
+
+/// Compresses `data` into a raw DEFLATE stream.
+pub fn deflate_compress(data: &[u8], level: CompressionLevel) -> Result<Vec<u8>> {
+    deflate::compress(data, level)
+}
+
+/// Decompresses a raw DEFLATE stream.
+///
+/// Returns an [`ErrorKind::UnexpectedEof`] error if `data` ends before the
+/// final block. Bytes following the final block are ignored.
+pub fn deflate_decompress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut decoder = DeflateDecoder::new();
+    let mut out = Vec::new();
+    decoder.write(data, &mut out)?;
+    finished(decoder.is_finished(), out)
+}
+
+/// Compresses `data` into a zlib stream with an Adler-32 checksum.
+pub fn zlib_compress(data: &[u8], level: CompressionLevel) -> Result<Vec<u8>> {
+    zlib::compress(data, level)
+}
+
+/// Decompresses a zlib stream and verifies its Adler-32 checksum.
+///
+/// Returns an [`ErrorKind::UnexpectedEof`] error if `data` ends before the
+/// checksum. Bytes following the checksum are ignored.
+pub fn zlib_decompress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut decoder = ZlibDecoder::new();
+    let mut out = Vec::new();
+    decoder.write(data, &mut out)?;
+    finished(decoder.is_finished(), out)
+}
+
+/// Compresses `data` into a single-member gzip stream with a CRC-32 checksum.
+///
+/// The header records no file name, comment or modification time.
+pub fn gzip_compress(data: &[u8], level: CompressionLevel) -> Result<Vec<u8>> {
+    gzip::compress(data, level)
+}
+
+/// Decompresses the first member of a gzip stream and verifies its CRC-32
+/// checksum and size.
+///
+/// Optional header fields are skipped. Returns an
+/// [`ErrorKind::UnexpectedEof`] error if `data` ends before the trailer.
+/// Bytes following the first member are ignored.
+pub fn gzip_decompress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut decoder = GzipDecoder::new();
+    let mut out = Vec::new();
+    decoder.write(data, &mut out)?;
+    finished(decoder.is_finished(), out)
+}
+
+/// Adler-32 checksum of `data`, as used by zlib.
+pub fn adler32(data: &[u8]) -> u32 {
+    adler32::calculate(data)
+}
+
+/// CRC-32 of `data`, as used by gzip and PNG chunks.
+pub fn crc32(data: &[u8]) -> u32 {
+    crc::calculate(&[data])
+}
+
+fn finished(is_finished: bool, out: Vec<u8>) -> Result<Vec<u8>> {
+    if is_finished {
+        Ok(out)
+    } else {
+        Err(ErrorKind::UnexpectedEof.into())
+    }
+}
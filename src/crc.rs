@@ -29,11 +29,31 @@ fn update_crc(crc: u32, byte: u8) -> u32 {
 }
 
 pub fn calculate(slices: &[&[u8]]) -> u32 {
-    let mut crc = CRC_INITIAL;
+    let mut crc = Crc32::new();
     for &slice in slices {
-        for &byte in slice {
-            crc = update_crc(crc, byte);
+        crc.update(slice);
+    }
+    crc.finish()
+}
+
+/// Incremental CRC-32 for data that arrives in pieces.
+#[derive(Debug, Clone)]
+pub struct Crc32 {
+    crc: u32,
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Self { crc: CRC_INITIAL }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.crc = update_crc(self.crc, byte);
         }
     }
-    crc ^ CRC_INITIAL
+
+    pub fn finish(&self) -> u32 {
+        self.crc ^ CRC_INITIAL
+    }
 }
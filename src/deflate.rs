@@ -1,9 +1,9 @@
-use alloc::format;
 use alloc::vec;
 use alloc::vec::Vec;
 use core::cmp;
+use core::ops::Range;
 
-use crate::png_types::{Error, Result};
+use crate::png_types::{CompressionLevel, Error, ErrorKind, Result};
 
 const MAX_BITS: usize = 15;
 const END_OF_BLOCK: u16 = 256;
@@ -13,7 +13,9 @@ const MIN_MATCH: usize = 3;
 const HASH_BITS: usize = 15;
 const HASH_SIZE: usize = 1 << HASH_BITS;
 const HASH_MASK: usize = HASH_SIZE - 1;
-const MAX_CHAIN_LEN: usize = 32;
+const MAX_STORED_BLOCK_LEN: usize = 65_535;
+/// Amount of new input [`Compressor`] buffers before emitting a block.
+const STREAM_BLOCK_SIZE: usize = 1 << 16;
 const NIL: u32 = u32::MAX;
 const BITWIDTH_CODE_ORDER: [usize; 19] = [
     16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
@@ -82,112 +84,687 @@ const DISTANCE_TABLE: [(u16, u8); 30] = [
     (24_577, 13),
 ];
 
-pub fn compress(data: &[u8]) -> Result<Vec<u8>> {
-    encode_dynamic_literals(data)
+/// Block types a [`CompressionLevel`] encodes with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BlockType {
+    Stored,
+    Fixed,
+    /// Split into blocks where the statistics change, each stored, fixed or
+    /// dynamic, whichever is smallest.
+    Dynamic,
+}
+
+/// Encoder tuning derived from a [`CompressionLevel`].
+#[derive(Debug, Clone, Copy)]
+struct CompressionParams {
+    max_chain_len: usize,
+    lazy_matching: bool,
+    /// Replace the greedy LZ77 parse by [`optimal_symbols`].
+    optimal_parsing: bool,
+    /// Split each segment with [`split_blocks`] instead of emitting it as one block.
+    split_blocks: bool,
+    block_type: BlockType,
+}
+
+impl CompressionParams {
+    fn from_level(level: CompressionLevel) -> Self {
+        match level {
+            CompressionLevel::Store => Self {
+                max_chain_len: 0,
+                lazy_matching: false,
+                optimal_parsing: false,
+                split_blocks: false,
+                block_type: BlockType::Stored,
+            },
+            CompressionLevel::Fast => Self {
+                max_chain_len: 4,
+                lazy_matching: false,
+                optimal_parsing: false,
+                split_blocks: false,
+                block_type: BlockType::Fixed,
+            },
+            CompressionLevel::Default => Self {
+                max_chain_len: 32,
+                lazy_matching: false,
+                optimal_parsing: false,
+                split_blocks: false,
+                block_type: BlockType::Dynamic,
+            },
+            CompressionLevel::Best => Self {
+                max_chain_len: 1024,
+                lazy_matching: true,
+                optimal_parsing: false,
+                split_blocks: true,
+                block_type: BlockType::Dynamic,
+            },
+            CompressionLevel::Optimal => Self {
+                max_chain_len: 1024,
+                lazy_matching: true,
+                optimal_parsing: true,
+                split_blocks: true,
+                block_type: BlockType::Dynamic,
+            },
+        }
+    }
+}
+
+/// Compresses `data` in segments of [`STREAM_BLOCK_SIZE`] bytes, so that
+/// LZ77 symbols are only buffered for one segment at a time.
+pub fn compress(data: &[u8], level: CompressionLevel) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    compress_to(data, level, &mut out)?;
+    Ok(out)
+}
+
+/// Like [`compress`], but appends the DEFLATE stream to `out`.
+///
+/// [`CompressionLevel::Store`] skips the segment buffer: `data` is copied
+/// straight into `out` behind a five-byte header per stored block.
+pub fn compress_to(data: &[u8], level: CompressionLevel, out: &mut Vec<u8>) -> Result<()> {
+    if level == CompressionLevel::Store {
+        out.reserve(stored_bits(data.len(), 0) / 8);
+        let mut writer = BitWriter::with_bytes(core::mem::take(out));
+        write_stored_blocks(&mut writer, data, true);
+        *out = writer.finish();
+        return Ok(());
+    }
+    let mut compressor = Compressor::new(level);
+    compressor.write(data, out)?;
+    compressor.finish(out)
 }
 
-pub fn decompress(input: &[u8]) -> Result<Vec<u8>> {
-    let mut reader = BitReader::new(input);
-    let mut output = Vec::new();
-    let fixed_lit = fixed_literal_decoder()?;
-    let fixed_dist = fixed_distance_decoder()?;
-    loop {
-        let is_final = reader.read_bit()?;
-        let block_type = reader.read_bits(2)?;
-        match block_type {
-            0b00 => decode_raw_block(&mut reader, &mut output)?,
-            0b01 => {
-                decode_compressed_block(&mut reader, &fixed_lit, &fixed_dist, &mut output)?;
+/// Incremental DEFLATE encoder.
+///
+/// Input is buffered until 64 KiB are pending and then emitted as one
+/// non-final block. The last 32 KiB are kept so that later blocks can still
+/// reference them.
+#[derive(Debug)]
+pub struct Compressor {
+    params: CompressionParams,
+    window: Vec<u8>,
+    history_len: usize,
+    writer: BitWriter,
+}
+
+impl Compressor {
+    /// Creates an encoder that compresses at `level`.
+    pub fn new(level: CompressionLevel) -> Self {
+        Self {
+            params: CompressionParams::from_level(level),
+            window: Vec::new(),
+            history_len: 0,
+            writer: BitWriter::new(),
+        }
+    }
+
+    /// Feeds `data` into the encoder, appending any completed output bytes to `out`.
+    pub fn write(&mut self, mut data: &[u8], out: &mut Vec<u8>) -> Result<()> {
+        // Stored blocks hold at most 65,535 bytes and never refer back.
+        let segment_len = match self.params.block_type {
+            BlockType::Stored => MAX_STORED_BLOCK_LEN,
+            BlockType::Fixed | BlockType::Dynamic => STREAM_BLOCK_SIZE,
+        };
+        while !data.is_empty() {
+            let pending = self.window.len() - self.history_len;
+            let take = cmp::min(segment_len - pending, data.len());
+            self.window.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.window.len() - self.history_len == segment_len {
+                self.flush_block(false)?;
             }
-            0b10 => {
-                let (literal, distance) = read_dynamic_decoders(&mut reader)?;
-                decode_compressed_block(&mut reader, &literal, &distance, &mut output)?;
+        }
+        self.writer.drain_bytes_to(out);
+        Ok(())
+    }
+
+    /// Emits the final block and appends the remaining output bytes to `out`.
+    pub fn finish(mut self, out: &mut Vec<u8>) -> Result<()> {
+        self.flush_block(true)?;
+        out.extend_from_slice(&self.writer.finish());
+        Ok(())
+    }
+
+    fn flush_block(&mut self, is_final: bool) -> Result<()> {
+        write_block(
+            &mut self.writer,
+            &self.window,
+            self.history_len,
+            is_final,
+            &self.params,
+        )?;
+        let keep = match self.params.block_type {
+            BlockType::Stored => 0,
+            BlockType::Fixed | BlockType::Dynamic => cmp::min(self.window.len(), WINDOW_SIZE),
+        };
+        self.window.drain(..self.window.len() - keep);
+        self.history_len = self.window.len();
+        Ok(())
+    }
+}
+
+/// Writes `input[start..]` as one or more blocks.
+///
+/// Bytes before `start` are history that matches may refer back to.
+fn write_block(
+    writer: &mut BitWriter,
+    input: &[u8],
+    start: usize,
+    is_final: bool,
+    params: &CompressionParams,
+) -> Result<()> {
+    match params.block_type {
+        BlockType::Stored => {
+            write_stored_blocks(writer, &input[start..], is_final);
+            Ok(())
+        }
+        BlockType::Fixed => {
+            let symbols = lz77_symbols(input, start, params);
+            let bytes = &input[start..];
+            if stored_bits(bytes.len(), writer.bit_count) <= Histogram::new(&symbols).fixed_bits() {
+                write_stored_blocks(writer, bytes, is_final);
+                Ok(())
+            } else {
+                write_fixed_block(writer, &symbols, is_final)
+            }
+        }
+        BlockType::Dynamic => {
+            let symbols = if params.optimal_parsing {
+                optimal_symbols(input, start, params)?
+            } else {
+                lz77_symbols(input, start, params)
+            };
+            if !params.split_blocks {
+                return write_smallest_block(writer, &symbols, &input[start..], is_final);
             }
-            0b11 => {
-                return Err(Error::InvalidData("reserved DEFLATE block type".into()));
+            let blocks = split_blocks(&symbols)?;
+            let mut position = start;
+            for (index, block) in blocks.iter().enumerate() {
+                let symbols = &symbols[block.clone()];
+                let len: usize = symbols.iter().map(|symbol| symbol.len()).sum();
+                let bytes = &input[position..position + len];
+                position += len;
+                let is_final = is_final && index == blocks.len() - 1;
+                write_smallest_block(writer, symbols, bytes, is_final)?;
             }
-            _ => unreachable!(),
+            Ok(())
+        }
+    }
+}
+
+/// Symbols per unit of [`split_blocks`]; blocks hold a whole number of them.
+const SPLIT_GRANULE: usize = 4096;
+
+/// Splits `symbols` into ranges that are cheaper to encode as separate
+/// dynamic blocks, each with its own code tables.
+///
+/// Granules are appended to the current block for as long as encoding them
+/// together costs no more than encoding them apart.
+fn split_blocks(symbols: &[DeflateSymbol]) -> Result<Vec<Range<usize>>> {
+    let mut blocks = Vec::new();
+    let mut start = 0;
+    let mut end = cmp::min(SPLIT_GRANULE, symbols.len());
+    let mut current = Histogram::new(&symbols[start..end]);
+    // Only computed once there is a second granule to compare with.
+    let mut current_bits = None;
+    while end < symbols.len() {
+        let bits = match current_bits {
+            Some(bits) => bits,
+            None => DynamicCodes::new(&current)?.bits(&current),
+        };
+        let next_end = cmp::min(end + SPLIT_GRANULE, symbols.len());
+        let next = Histogram::new(&symbols[end..next_end]);
+        let next_bits = DynamicCodes::new(&next)?.bits(&next);
+        let merged = current.merged(&next);
+        let merged_bits = DynamicCodes::new(&merged)?.bits(&merged);
+        if merged_bits <= bits + next_bits {
+            current = merged;
+            current_bits = Some(merged_bits);
+        } else {
+            blocks.push(start..end);
+            start = end;
+            current = next;
+            current_bits = Some(next_bits);
         }
+        end = next_end;
+    }
+    blocks.push(start..symbols.len());
+    Ok(blocks)
+}
+
+/// Writes `symbols`, which encode `bytes`, as a stored, fixed or dynamic
+/// block, whichever is smallest.
+fn write_smallest_block(
+    writer: &mut BitWriter,
+    symbols: &[DeflateSymbol],
+    bytes: &[u8],
+    is_final: bool,
+) -> Result<()> {
+    let histogram = Histogram::new(symbols);
+    let dynamic = DynamicCodes::new(&histogram)?;
+    let dynamic_bits = dynamic.bits(&histogram);
+    let fixed_bits = histogram.fixed_bits();
+    if stored_bits(bytes.len(), writer.bit_count) <= cmp::min(fixed_bits, dynamic_bits) {
+        write_stored_blocks(writer, bytes, is_final);
+        Ok(())
+    } else if fixed_bits <= dynamic_bits {
+        write_fixed_block(writer, symbols, is_final)
+    } else {
+        dynamic.write(writer, symbols, is_final);
+        Ok(())
+    }
+}
+
+/// Size in bits of `len` bytes written by [`write_stored_blocks`] when the
+/// writer holds `bit_count` bits of a partial byte.
+fn stored_bits(len: usize, bit_count: u8) -> usize {
+    let blocks = cmp::max(1, len.div_ceil(MAX_STORED_BLOCK_LEN));
+    // Block header, padding to a byte boundary, LEN and NLEN.
+    let first = 3 + (8 - (usize::from(bit_count) + 3) % 8) % 8 + 32;
+    first + (blocks - 1) * (8 + 32) + 8 * len
+}
+
+/// Returns a DEFLATE format error. [`Inflater::write`] fills in the bit offset.
+fn invalid_deflate(message: &'static str) -> Error {
+    Error::new(ErrorKind::Deflate { bit_offset: 0 }, message)
+}
+
+/// Incremental DEFLATE decoder that accepts compressed input in pieces.
+///
+/// Decoding advances one block header, stored run or literal/length symbol at
+/// a time, and may pause between any two of them, at any bit of the input.
+/// A step that runs out of input is rolled back and its bytes are kept until
+/// more input arrives, so the stream may be split at any byte boundary.
+///
+/// [`inflate`](Self::inflate) decodes into a caller-provided buffer and stops
+/// when it is full, so memory use stays bounded by the 32 KiB of history kept
+/// for back-references, plus one step of input and output.
+/// [`write`](Self::write) decodes everything it can into a `Vec` instead.
+#[derive(Debug)]
+pub struct Inflater {
+    stash: Vec<u8>,
+    bit_buffer: u64,
+    bit_count: u8,
+    state: InflateState,
+    window: Vec<u8>,
+    flushed: usize,
+    error: Option<Error>,
+    consumed_bytes: u64,
+    total_output: usize,
+    output_limit: usize,
+    fixed_literal: HuffmanDecoder,
+    fixed_distance: HuffmanDecoder,
+}
+
+#[derive(Debug)]
+enum InflateState {
+    BlockHeader,
+    Stored {
+        remaining: usize,
+        is_final: bool,
+    },
+    Fixed {
+        is_final: bool,
+    },
+    Dynamic {
+        literal: HuffmanDecoder,
+        distance: HuffmanDecoder,
+        is_final: bool,
+    },
+    Done,
+}
+
+impl InflateState {
+    fn after_block(is_final: bool) -> Self {
         if is_final {
-            break;
+            Self::Done
+        } else {
+            Self::BlockHeader
         }
     }
-    Ok(output)
 }
 
-fn decode_raw_block(reader: &mut BitReader<'_>, output: &mut Vec<u8>) -> Result<()> {
-    reader.align_to_byte();
-    let len = reader.read_u16_le()?;
-    let nlen = reader.read_u16_le()?;
-    if !len != nlen {
-        return Err(Error::InvalidData(
-            format!("LEN={} is not the one's complement of NLEN={}", len, nlen).into(),
-        ));
+/// Upper bound on the input bytes one decoding step needs (the largest is a
+/// dynamic block header of about 570 bytes).
+const MAX_STEP_INPUT: usize = 1024;
+
+impl Inflater {
+    /// Creates a decoder without an output limit.
+    pub fn new() -> Self {
+        Self::with_output_limit(usize::MAX)
+    }
+
+    /// Creates a decoder that fails with [`ErrorKind::LimitExceeded`] once the
+    /// stream inflates to more than `output_limit` bytes.
+    pub fn with_output_limit(output_limit: usize) -> Self {
+        Self {
+            stash: Vec::new(),
+            bit_buffer: 0,
+            bit_count: 0,
+            state: InflateState::BlockHeader,
+            window: Vec::new(),
+            flushed: 0,
+            error: None,
+            consumed_bytes: 0,
+            total_output: 0,
+            output_limit,
+            fixed_literal: fixed_literal_decoder().expect("bug: fixed literal code is valid"),
+            fixed_distance: fixed_distance_decoder().expect("bug: fixed distance code is valid"),
+        }
+    }
+
+    /// Returns `true` once the final block has been decoded and all of its
+    /// output has been handed out.
+    pub fn is_finished(&self) -> bool {
+        matches!(self.state, InflateState::Done) && self.flushed == self.window.len()
+    }
+
+    /// Input bytes that followed the end of the DEFLATE stream.
+    ///
+    /// Empty until [`is_finished`](Self::is_finished) returns `true`. After
+    /// [`inflate`](Self::inflate), this only holds bytes kept from earlier
+    /// calls, which come before the unconsumed part of the last input in the
+    /// stream.
+    pub fn remaining_input(&self) -> &[u8] {
+        if self.is_finished() { &self.stash } else { &[] }
+    }
+
+    /// Feeds `input` into the decoder, appending all bytes that can be decoded
+    /// so far to `out`.
+    pub fn write(&mut self, input: &[u8], out: &mut Vec<u8>) -> Result<()> {
+        let (consumed, _) = self.pump(input, usize::MAX, |bytes| out.extend_from_slice(bytes))?;
+        if let Some(error) = &self.error {
+            return Err(error.clone());
+        }
+        if self.is_finished() {
+            self.stash.extend_from_slice(&input[consumed..]);
+        }
+        Ok(())
+    }
+
+    /// Decodes as much of `input` as fits into `output`, returning the number
+    /// of input bytes consumed and output bytes written.
+    ///
+    /// Decoding stops when `output` is full, the stream ends, or `input` runs
+    /// out. Bytes of an incomplete step are kept internally and count as
+    /// consumed. If a step fails, the output decoded before it is returned
+    /// first and the error is reported by the next call, and by every call
+    /// after that. The call that finishes the stream may consume and write
+    /// nothing, if its final end-of-block code was already read.
+    #[cfg(feature = "compression")]
+    pub fn inflate(&mut self, input: &[u8], output: &mut [u8]) -> Result<(usize, usize)> {
+        let mut written = 0;
+        self.pump(input, output.len(), |bytes| {
+            output[written..written + bytes.len()].copy_from_slice(bytes);
+            written += bytes.len();
+        })
+    }
+
+    /// Decodes `input`, handing at most `capacity` bytes of output to `emit`.
+    /// Returns the number of input bytes consumed and output bytes emitted.
+    fn pump(
+        &mut self,
+        input: &[u8],
+        capacity: usize,
+        mut emit: impl FnMut(&[u8]),
+    ) -> Result<(usize, usize)> {
+        let mut consumed = 0;
+        let mut written = 0;
+        loop {
+            written += self.flush_window(capacity - written, &mut emit);
+            if written == capacity || matches!(self.state, InflateState::Done) {
+                return Ok((consumed, written));
+            }
+            if let Some(error) = &self.error {
+                return if written == 0 {
+                    Err(error.clone())
+                } else {
+                    Ok((consumed, written))
+                };
+            }
+            // Flushing between rounds keeps the window bounded.
+            let room = cmp::min(capacity - written, WINDOW_SIZE);
+
+            if self.stash.is_empty() {
+                let (used, stalled) = self.decode(&input[consumed..], room);
+                consumed += used;
+                if stalled {
+                    // Less than one step is left; keep it for the next call.
+                    self.stash.extend_from_slice(&input[consumed..]);
+                    consumed = input.len();
+                    written += self.flush_window(capacity - written, &mut emit);
+                    return Ok((consumed, written));
+                }
+                continue;
+            }
+
+            // Complete the pending step from the stash topped up with new
+            // input. Top-up bytes that end up unused go back to `input`.
+            let kept = self.stash.len();
+            let top_up = cmp::min(input.len() - consumed, MAX_STEP_INPUT);
+            let mut stash = core::mem::take(&mut self.stash);
+            stash.extend_from_slice(&input[consumed..consumed + top_up]);
+            let (used, stalled) = self.decode(&stash, room);
+            if stalled {
+                stash.drain(..used);
+                self.stash = stash;
+                consumed += top_up;
+                if top_up == 0 {
+                    return Ok((consumed, written));
+                }
+            } else if used >= kept {
+                consumed += used - kept;
+            } else {
+                stash.truncate(kept);
+                stash.drain(..used);
+                self.stash = stash;
+            }
+        }
+    }
+
+    /// Hands at most `capacity` bytes of undelivered output to `emit` and
+    /// trims the history that is no longer needed for back-references.
+    fn flush_window(&mut self, capacity: usize, emit: &mut impl FnMut(&[u8])) -> usize {
+        let len = cmp::min(self.window.len() - self.flushed, capacity);
+        emit(&self.window[self.flushed..self.flushed + len]);
+        self.flushed += len;
+        if self.flushed > 2 * WINDOW_SIZE {
+            let drop = self.flushed - WINDOW_SIZE;
+            self.window.drain(..drop);
+            self.flushed -= drop;
+        }
+        len
+    }
+
+    /// Runs decoding steps over `input` until at least `room` bytes of output
+    /// are pending, the stream ends, a step fails, or the input runs out.
+    ///
+    /// Returns the number of bytes used, and whether decoding stopped for lack
+    /// of input. A failed step is recorded in `self.error`.
+    fn decode(&mut self, input: &[u8], room: usize) -> (usize, bool) {
+        let mut reader = BitReader::resume(input, self.bit_buffer, self.bit_count);
+        let mut stalled = false;
+        loop {
+            let pending = self.window.len() - self.flushed;
+            if pending >= room {
+                break;
+            }
+            let checkpoint = reader.checkpoint();
+            let before = self.window.len();
+            match self.step(&mut reader, room - pending) {
+                Ok(true) => {
+                    self.total_output += self.window.len() - before;
+                    if self.total_output > self.output_limit {
+                        self.error = Some(Error::new(
+                            ErrorKind::LimitExceeded,
+                            "deflate stream inflates to more than the output limit",
+                        ));
+                        break;
+                    }
+                }
+                Ok(false) => {
+                    stalled = !matches!(self.state, InflateState::Done);
+                    break;
+                }
+                Err(_) if reader.exhausted => {
+                    reader.restore(checkpoint);
+                    stalled = true;
+                    break;
+                }
+                Err(mut error) => {
+                    if let ErrorKind::Deflate { bit_offset } = &mut error.kind {
+                        *bit_offset = (self.consumed_bytes + reader.byte_index as u64) * 8
+                            - u64::from(reader.bit_count);
+                    }
+                    self.error = Some(error);
+                    break;
+                }
+            }
+        }
+        if matches!(self.state, InflateState::Done) {
+            reader.align_to_byte();
+        } else {
+            reader.unread_whole_bytes();
+        }
+        self.bit_buffer = reader.bit_buffer;
+        self.bit_count = reader.bit_count;
+        self.consumed_bytes += reader.byte_index as u64;
+        (reader.byte_index, stalled)
+    }
+
+    /// Decodes one unit of the stream, producing at most `room` bytes of a
+    /// stored block. Returns `false` when no progress is possible without
+    /// more input.
+    fn step(&mut self, reader: &mut BitReader<'_>, room: usize) -> Result<bool> {
+        match &mut self.state {
+            InflateState::BlockHeader => {
+                let is_final = reader.read_bit()?;
+                self.state = match reader.read_bits(2)? {
+                    0b00 => {
+                        reader.align_to_byte();
+                        let len = reader.read_u16_le()?;
+                        let nlen = reader.read_u16_le()?;
+                        if !len != nlen {
+                            return Err(invalid_deflate(
+                                "stored block LEN is not the one's complement of NLEN",
+                            ));
+                        }
+                        InflateState::Stored {
+                            remaining: len as usize,
+                            is_final,
+                        }
+                    }
+                    0b01 => InflateState::Fixed { is_final },
+                    0b10 => {
+                        let (literal, distance) = read_dynamic_decoders(reader)?;
+                        InflateState::Dynamic {
+                            literal,
+                            distance,
+                            is_final,
+                        }
+                    }
+                    0b11 => {
+                        return Err(invalid_deflate("reserved DEFLATE block type"));
+                    }
+                    _ => unreachable!(),
+                };
+            }
+            InflateState::Stored {
+                remaining,
+                is_final,
+            } => {
+                if *remaining > 0 {
+                    let len = cmp::min(*remaining, reader.remaining_bytes()).min(room);
+                    if len == 0 {
+                        return Ok(false);
+                    }
+                    self.window.extend_from_slice(reader.read_bytes(len)?);
+                    *remaining -= len;
+                }
+                if *remaining == 0 {
+                    self.state = InflateState::after_block(*is_final);
+                }
+            }
+            InflateState::Fixed { is_final } => {
+                let is_final = *is_final;
+                if !decode_symbol(
+                    reader,
+                    &self.fixed_literal,
+                    &self.fixed_distance,
+                    &mut self.window,
+                )? {
+                    self.state = InflateState::after_block(is_final);
+                }
+            }
+            InflateState::Dynamic {
+                literal,
+                distance,
+                is_final,
+            } => {
+                if !decode_symbol(reader, literal, distance, &mut self.window)? {
+                    self.state = InflateState::after_block(*is_final);
+                }
+            }
+            InflateState::Done => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+impl Default for Inflater {
+    fn default() -> Self {
+        Self::new()
     }
-    let bytes = reader.read_bytes(len as usize)?;
-    output.extend_from_slice(bytes);
-    Ok(())
 }
 
-fn decode_compressed_block(
+/// Decodes one literal/length symbol (and its distance). Returns `false` at the
+/// end of the block.
+fn decode_symbol(
     reader: &mut BitReader<'_>,
     literal_decoder: &HuffmanDecoder,
     distance_decoder: &HuffmanDecoder,
     output: &mut Vec<u8>,
-) -> Result<()> {
-    loop {
-        let symbol = literal_decoder.decode(reader)?;
-        match symbol {
-            0..=255 => output.push(symbol as u8),
-            END_OF_BLOCK => return Ok(()),
-            257..=285 => {
-                let (base_length, extra_bits) = LENGTH_TABLE[(symbol - 257) as usize];
-                let extra = if extra_bits == 0 {
-                    0
-                } else {
-                    reader.read_bits(extra_bits)?
-                };
-                let length = base_length + extra;
-
-                let distance_symbol = distance_decoder.decode(reader)?;
-                let Some((base_distance, distance_extra_bits)) =
-                    DISTANCE_TABLE.get(distance_symbol as usize).copied()
-                else {
-                    return Err(Error::InvalidData(
-                        format!("invalid distance symbol: {}", distance_symbol).into(),
-                    ));
-                };
-                let distance_extra = if distance_extra_bits == 0 {
-                    0
-                } else {
-                    reader.read_bits(distance_extra_bits)?
-                };
-                let distance = (base_distance + distance_extra) as usize;
-                copy_from_distance(output, distance, length as usize)?;
-            }
-            286 | 287 => {
-                return Err(Error::InvalidData(
-                    format!(
-                        "literal/length symbol {} must not appear in compressed data",
-                        symbol
-                    )
-                    .into(),
-                ));
-            }
-            _ => unreachable!(),
+) -> Result<bool> {
+    let symbol = literal_decoder.decode(reader)?;
+    match symbol {
+        0..=255 => output.push(symbol as u8),
+        END_OF_BLOCK => return Ok(false),
+        257..=285 => {
+            let (base_length, extra_bits) = LENGTH_TABLE[(symbol - 257) as usize];
+            let extra = if extra_bits == 0 {
+                0
+            } else {
+                reader.read_bits(extra_bits)?
+            };
+            let length = base_length + extra;
+
+            let distance_symbol = distance_decoder.decode(reader)?;
+            let Some((base_distance, distance_extra_bits)) =
+                DISTANCE_TABLE.get(distance_symbol as usize).copied()
+            else {
+                return Err(invalid_deflate("invalid distance symbol"));
+            };
+            let distance_extra = if distance_extra_bits == 0 {
+                0
+            } else {
+                reader.read_bits(distance_extra_bits)?
+            };
+            let distance = (base_distance + distance_extra) as usize;
+            copy_from_distance(output, distance, length as usize)?;
+        }
+        286 | 287 => {
+            return Err(invalid_deflate(
+                "literal/length symbols 286 and 287 must not appear in compressed data",
+            ));
         }
+        _ => unreachable!(),
     }
+    Ok(true)
 }
 
 fn copy_from_distance(output: &mut Vec<u8>, distance: usize, length: usize) -> Result<()> {
     if distance == 0 || distance > output.len() {
-        return Err(Error::InvalidData(
-            format!(
-                "too long backward reference: output_len={}, distance={}",
-                output.len(),
-                distance
-            )
-            .into(),
+        return Err(invalid_deflate(
+            "backward reference distance exceeds the decoded output",
         ));
     }
 
@@ -215,9 +792,7 @@ fn read_dynamic_decoders(reader: &mut BitReader<'_>) -> Result<(HuffmanDecoder,
     let bitwidth_code_count = reader.read_bits(4)? + 4;
 
     if distance_code_count as usize > DISTANCE_TABLE.len() {
-        return Err(Error::InvalidData(
-            format!("HDIST is too large: {}", distance_code_count).into(),
-        ));
+        return Err(invalid_deflate("HDIST is too large"));
     }
 
     let mut bitwidth_code_lengths = [0u8; 19];
@@ -239,9 +814,7 @@ fn read_dynamic_decoders(reader: &mut BitReader<'_>) -> Result<(HuffmanDecoder,
             16 => {
                 let repeat = reader.read_bits(2)? + 3;
                 let Some(&last) = all_code_lengths.last() else {
-                    return Err(Error::InvalidData(
-                        "repeat code 16 without a previous code".into(),
-                    ));
+                    return Err(invalid_deflate("repeat code 16 without a previous code"));
                 };
                 all_code_lengths.extend(core::iter::repeat_n(last, repeat as usize));
             }
@@ -256,8 +829,8 @@ fn read_dynamic_decoders(reader: &mut BitReader<'_>) -> Result<(HuffmanDecoder,
             _ => unreachable!(),
         }
         if all_code_lengths.len() > target_len {
-            return Err(Error::InvalidData(
-                "dynamic huffman code lengths exceed the announced table size".into(),
+            return Err(invalid_deflate(
+                "dynamic huffman code lengths exceed the announced table size",
             ));
         }
     }
@@ -272,7 +845,35 @@ fn read_dynamic_decoders(reader: &mut BitReader<'_>) -> Result<(HuffmanDecoder,
 }
 
 fn fixed_literal_decoder() -> Result<HuffmanDecoder> {
-    let mut lengths = vec![0u8; 288];
+    HuffmanDecoder::from_code_lengths(&fixed_literal_lengths(), None, Some(END_OF_BLOCK))
+}
+
+fn fixed_distance_decoder() -> Result<HuffmanDecoder> {
+    HuffmanDecoder::from_code_lengths(&[5u8; 30], Some(7), None)
+}
+
+fn write_stored_blocks(writer: &mut BitWriter, input: &[u8], is_final: bool) {
+    let mut blocks = input.chunks(MAX_STORED_BLOCK_LEN).peekable();
+    if blocks.peek().is_none() {
+        write_stored_block(writer, &[], is_final);
+    }
+    while let Some(block) = blocks.next() {
+        write_stored_block(writer, block, is_final && blocks.peek().is_none());
+    }
+}
+
+fn write_stored_block(writer: &mut BitWriter, block: &[u8], is_final: bool) {
+    writer.write_bit(is_final);
+    writer.write_bits(2, 0b00);
+    writer.align_to_byte();
+    let len = block.len() as u16;
+    writer.write_bytes(&len.to_le_bytes());
+    writer.write_bytes(&(!len).to_le_bytes());
+    writer.write_bytes(block);
+}
+
+fn fixed_literal_lengths() -> [u8; 288] {
+    let mut lengths = [0u8; 288];
     for (index, length) in lengths.iter_mut().enumerate() {
         *length = match index {
             0..=143 => 8,
@@ -282,96 +883,232 @@ fn fixed_literal_decoder() -> Result<HuffmanDecoder> {
             _ => unreachable!(),
         };
     }
-    HuffmanDecoder::from_code_lengths(&lengths, None, Some(END_OF_BLOCK))
+    lengths
 }
 
-fn fixed_distance_decoder() -> Result<HuffmanDecoder> {
-    HuffmanDecoder::from_code_lengths(&[5u8; 30], Some(7), None)
+fn write_fixed_block(
+    writer: &mut BitWriter,
+    symbols: &[DeflateSymbol],
+    is_final: bool,
+) -> Result<()> {
+    let literal_encoder = HuffmanEncoder::from_code_lengths(&fixed_literal_lengths())?;
+    let distance_encoder = HuffmanEncoder::from_code_lengths(&[5u8; 30])?;
+
+    writer.write_bit(is_final);
+    writer.write_bits(2, 0b01);
+    write_symbols(writer, symbols, &literal_encoder, &distance_encoder);
+    Ok(())
+}
+
+#[cfg(test)]
+fn encode_dynamic_literals(input: &[u8], params: &CompressionParams) -> Result<Vec<u8>> {
+    let symbols = lz77_symbols(input, 0, params);
+    let mut writer = BitWriter::new();
+    DynamicCodes::new(&Histogram::new(&symbols))?.write(&mut writer, &symbols, true);
+    Ok(writer.finish())
+}
+
+/// Symbol frequencies of a block, including its end-of-block symbol.
+#[derive(Debug, Clone)]
+struct Histogram {
+    literal: [usize; 286],
+    distance: [usize; 30],
+    /// Total length/distance extra bits, which every block type spends alike.
+    extra_bits: usize,
 }
 
-fn encode_dynamic_literals(input: &[u8]) -> Result<Vec<u8>> {
-    let symbols = lz77_symbols(input);
-    let mut literal_frequencies = [0usize; 286];
-    let mut distance_frequencies = [0usize; 30];
-    let mut has_distance = false;
-    for symbol in &symbols {
-        literal_frequencies[symbol.code() as usize] += 1;
-        if let Some((code, _, _)) = symbol.distance() {
-            distance_frequencies[code as usize] += 1;
-            has_distance = true;
+impl Histogram {
+    fn new(symbols: &[DeflateSymbol]) -> Self {
+        let mut histogram = Self {
+            literal: [0; 286],
+            distance: [0; 30],
+            extra_bits: 0,
+        };
+        for symbol in symbols {
+            histogram.literal[symbol.code() as usize] += 1;
+            if let Some((bits, _)) = symbol.extra_length() {
+                histogram.extra_bits += usize::from(bits);
+            }
+            if let Some((code, bits, _)) = symbol.distance() {
+                histogram.distance[code as usize] += 1;
+                histogram.extra_bits += usize::from(bits);
+            }
         }
+        histogram.literal[END_OF_BLOCK as usize] = 1;
+        histogram
     }
-    literal_frequencies[END_OF_BLOCK as usize] = 1;
 
-    // Windows reportedly dislikes an empty distance table; emit a dummy symbol.
-    if !has_distance {
-        distance_frequencies[0] = 1;
+    fn merged(&self, other: &Self) -> Self {
+        let mut merged = self.clone();
+        for (frequency, other) in merged.literal.iter_mut().zip(&other.literal) {
+            *frequency += other;
+        }
+        for (frequency, other) in merged.distance.iter_mut().zip(&other.distance) {
+            *frequency += other;
+        }
+        merged.literal[END_OF_BLOCK as usize] = 1;
+        merged.extra_bits += other.extra_bits;
+        merged
     }
 
-    let literal_lengths = length_limited_code_lengths(&literal_frequencies, MAX_BITS as u8);
-    let distance_lengths = length_limited_code_lengths(&distance_frequencies, MAX_BITS as u8);
-    let literal_encoder = HuffmanEncoder::from_code_lengths(&literal_lengths)?;
-    let distance_encoder = HuffmanEncoder::from_code_lengths(&distance_lengths)?;
+    /// Size in bits of the block encoded with the fixed Huffman codes.
+    fn fixed_bits(&self) -> usize {
+        let lengths = fixed_literal_lengths();
+        let literal_bits: usize = self
+            .literal
+            .iter()
+            .zip(lengths)
+            .map(|(&frequency, length)| frequency * usize::from(length))
+            .sum();
+        let distance_bits = 5 * self.distance.iter().sum::<usize>();
+        3 + literal_bits + distance_bits + self.extra_bits
+    }
+}
 
-    let literal_code_count = cmp::max(
-        257,
-        literal_encoder.used_max_symbol().unwrap_or(0) as usize + 1,
-    );
-    let distance_code_count = cmp::max(
-        1,
-        distance_encoder.used_max_symbol().unwrap_or(0) as usize + 1,
-    );
+/// Code tables of a dynamic Huffman block.
+#[derive(Debug)]
+struct DynamicCodes {
+    literal: HuffmanEncoder,
+    literal_code_count: usize,
+    distance: HuffmanEncoder,
+    distance_code_count: usize,
+    bitwidth: HuffmanEncoder,
+    bitwidth_code_count: usize,
+    bitwidth_codes: Vec<(u8, u8, u8)>,
+}
 
-    let bitwidth_codes = build_bitwidth_codes(
-        &literal_encoder,
-        literal_code_count,
-        &distance_encoder,
-        distance_code_count,
-    );
-    let mut bitwidth_frequencies = [0usize; 19];
-    for &(code, _, _) in &bitwidth_codes {
-        bitwidth_frequencies[code as usize] += 1;
-    }
-    let bitwidth_lengths = length_limited_code_lengths(&bitwidth_frequencies, 7);
-    let bitwidth_encoder = HuffmanEncoder::from_code_lengths(&bitwidth_lengths)?;
-    let bitwidth_code_count = cmp::max(
-        4,
-        BITWIDTH_CODE_ORDER
+impl DynamicCodes {
+    fn new(histogram: &Histogram) -> Result<Self> {
+        let mut literal_frequencies = histogram.literal;
+        let mut distance_frequencies = histogram.distance;
+
+        // Windows reportedly dislikes an empty distance table; emit a dummy symbol.
+        if distance_frequencies.iter().all(|&frequency| frequency == 0) {
+            distance_frequencies[0] = 1;
+        }
+        ensure_two_symbols(&mut literal_frequencies);
+        ensure_two_symbols(&mut distance_frequencies);
+
+        let literal_lengths = length_limited_code_lengths(&literal_frequencies, MAX_BITS as u8);
+        let distance_lengths = length_limited_code_lengths(&distance_frequencies, MAX_BITS as u8);
+        let literal = HuffmanEncoder::from_code_lengths(&literal_lengths)?;
+        let distance = HuffmanEncoder::from_code_lengths(&distance_lengths)?;
+
+        let literal_code_count = cmp::max(257, literal.used_max_symbol().unwrap_or(0) as usize + 1);
+        let distance_code_count = cmp::max(1, distance.used_max_symbol().unwrap_or(0) as usize + 1);
+
+        let bitwidth_codes =
+            build_bitwidth_codes(&literal, literal_code_count, &distance, distance_code_count);
+        let mut bitwidth_frequencies = [0usize; 19];
+        for &(code, _, _) in &bitwidth_codes {
+            bitwidth_frequencies[code as usize] += 1;
+        }
+        ensure_two_symbols(&mut bitwidth_frequencies);
+        let bitwidth_lengths = length_limited_code_lengths(&bitwidth_frequencies, 7);
+        let bitwidth = HuffmanEncoder::from_code_lengths(&bitwidth_lengths)?;
+        let bitwidth_code_count = cmp::max(
+            4,
+            BITWIDTH_CODE_ORDER
+                .iter()
+                .rposition(|&index| bitwidth.code_width(index as u16) > 0)
+                .map_or(0, |index| index + 1),
+        );
+
+        Ok(Self {
+            literal,
+            literal_code_count,
+            distance,
+            distance_code_count,
+            bitwidth,
+            bitwidth_code_count,
+            bitwidth_codes,
+        })
+    }
+
+    /// Size in bits of the block with the symbols counted in `histogram`.
+    fn bits(&self, histogram: &Histogram) -> usize {
+        let header_bits = 3 + 5 + 5 + 4 + 3 * self.bitwidth_code_count;
+        let table_bits: usize = self
+            .bitwidth_codes
             .iter()
-            .rposition(|&index| bitwidth_encoder.code_width(index as u16) > 0)
-            .map_or(0, |index| index + 1),
-    );
+            .map(|&(code, extra_bits, _)| {
+                usize::from(self.bitwidth.code_width(u16::from(code))) + usize::from(extra_bits)
+            })
+            .sum();
+        let weighted = |encoder: &HuffmanEncoder, frequencies: &[usize]| -> usize {
+            frequencies
+                .iter()
+                .enumerate()
+                .map(|(symbol, &frequency)| {
+                    frequency * usize::from(encoder.code_width(symbol as u16))
+                })
+                .sum()
+        };
+        header_bits
+            + table_bits
+            + weighted(&self.literal, &histogram.literal)
+            + weighted(&self.distance, &histogram.distance)
+            + histogram.extra_bits
+    }
 
-    let mut writer = BitWriter::new();
-    writer.write_bit(true);
-    writer.write_bits(2, 0b10);
-    writer.write_bits(5, (literal_code_count - 257) as u16);
-    writer.write_bits(5, (distance_code_count - 1) as u16);
-    writer.write_bits(4, (bitwidth_code_count - 4) as u16);
-    for &index in BITWIDTH_CODE_ORDER.iter().take(bitwidth_code_count) {
-        writer.write_bits(3, bitwidth_encoder.code_width(index as u16) as u16);
+    fn write(&self, writer: &mut BitWriter, symbols: &[DeflateSymbol], is_final: bool) {
+        writer.write_bit(is_final);
+        writer.write_bits(2, 0b10);
+        writer.write_bits(5, (self.literal_code_count - 257) as u16);
+        writer.write_bits(5, (self.distance_code_count - 1) as u16);
+        writer.write_bits(4, (self.bitwidth_code_count - 4) as u16);
+        for &index in BITWIDTH_CODE_ORDER.iter().take(self.bitwidth_code_count) {
+            writer.write_bits(3, self.bitwidth.code_width(index as u16) as u16);
+        }
+        for &(code, extra_bits, extra) in &self.bitwidth_codes {
+            self.bitwidth.encode(writer, code as u16);
+            if extra_bits > 0 {
+                writer.write_bits(extra_bits, extra as u16);
+            }
+        }
+        write_symbols(writer, symbols, &self.literal, &self.distance);
     }
-    for &(code, extra_bits, extra) in &bitwidth_codes {
-        bitwidth_encoder.encode(&mut writer, code as u16);
-        if extra_bits > 0 {
-            writer.write_bits(extra_bits, extra as u16);
+}
+
+/// Gives unused symbols a frequency of one until at least two are used.
+///
+/// A code with a single symbol is incomplete, and inflaters such as zlib
+/// reject incomplete codes for code lengths and literals.
+fn ensure_two_symbols(frequencies: &mut [usize]) {
+    let mut used = frequencies
+        .iter()
+        .filter(|&&frequency| frequency > 0)
+        .count();
+    for frequency in frequencies.iter_mut() {
+        if used >= 2 {
+            break;
+        }
+        if *frequency == 0 {
+            *frequency = 1;
+            used += 1;
         }
     }
+}
 
-    for symbol in &symbols {
-        literal_encoder.encode(&mut writer, symbol.code());
+fn write_symbols(
+    writer: &mut BitWriter,
+    symbols: &[DeflateSymbol],
+    literal_encoder: &HuffmanEncoder,
+    distance_encoder: &HuffmanEncoder,
+) {
+    for symbol in symbols {
+        literal_encoder.encode(writer, symbol.code());
         if let Some((bits, extra)) = symbol.extra_length() {
             writer.write_bits(bits, extra);
         }
         if let Some((code, bits, extra)) = symbol.distance() {
-            distance_encoder.encode(&mut writer, code as u16);
+            distance_encoder.encode(writer, code as u16);
             if bits > 0 {
                 writer.write_bits(bits, extra);
             }
         }
     }
-    literal_encoder.encode(&mut writer, END_OF_BLOCK);
-    Ok(writer.finish())
+    literal_encoder.encode(writer, END_OF_BLOCK);
 }
 
 fn hash3(input: &[u8], pos: usize) -> usize {
@@ -381,10 +1118,12 @@ fn hash3(input: &[u8], pos: usize) -> usize {
         & HASH_MASK
 }
 
-fn lz77_symbols(input: &[u8]) -> Vec<DeflateSymbol> {
+/// Produces LZ77 symbols for `input[start..]`; `input[..start]` is history
+/// that matches may refer back to.
+fn lz77_symbols(input: &[u8], start: usize, params: &CompressionParams) -> Vec<DeflateSymbol> {
     let mut symbols = Vec::new();
     if input.len() < MIN_MATCH {
-        for &byte in input {
+        for &byte in &input[start..] {
             symbols.push(DeflateSymbol::Literal(byte));
         }
         return symbols;
@@ -392,7 +1131,14 @@ fn lz77_symbols(input: &[u8]) -> Vec<DeflateSymbol> {
 
     let mut head = vec![NIL; HASH_SIZE];
     let mut prev = vec![NIL; WINDOW_SIZE];
-    let mut cursor = 0;
+    for pos in start.saturating_sub(WINDOW_SIZE)..start {
+        if pos + MIN_MATCH <= input.len() {
+            let h = hash3(input, pos);
+            prev[pos & (WINDOW_SIZE - 1)] = head[h];
+            head[h] = pos as u32;
+        }
+    }
+    let mut cursor = start;
 
     while cursor < input.len() {
         if cursor + MIN_MATCH > input.len() {
@@ -401,42 +1147,28 @@ fn lz77_symbols(input: &[u8]) -> Vec<DeflateSymbol> {
             continue;
         }
 
-        let h = hash3(input, cursor);
-        let max_length = (input.len() - cursor).min(MAX_MATCH);
-        let search_start = cursor.saturating_sub(WINDOW_SIZE);
-
-        let mut best_length = 0;
-        let mut best_distance = 0;
-        let mut chain_pos = head[h];
-        let mut chain_count = 0;
-
-        while chain_pos != NIL
-            && (chain_pos as usize) >= search_start
-            && (chain_pos as usize) < cursor
-            && chain_count < MAX_CHAIN_LEN
-        {
-            let candidate = chain_pos as usize;
-            if input[candidate] == input[cursor] {
-                let mut length = 1;
-                while length < max_length && input[candidate + length] == input[cursor + length] {
-                    length += 1;
-                }
-                if length >= MIN_MATCH && length > best_length {
-                    best_length = length;
-                    best_distance = cursor - candidate;
-                    if length == max_length {
-                        break;
-                    }
-                }
-            }
-            chain_pos = prev[candidate & (WINDOW_SIZE - 1)];
-            chain_count += 1;
-        }
+        let (best_length, best_distance) =
+            longest_match(input, cursor, &head, &prev, params.max_chain_len);
 
         // Insert current position into hash chain.
+        let h = hash3(input, cursor);
         prev[cursor & (WINDOW_SIZE - 1)] = head[h];
         head[h] = cursor as u32;
 
+        // Lazy matching: defer to the next position if it yields a longer match.
+        if params.lazy_matching
+            && (MIN_MATCH..MAX_MATCH).contains(&best_length)
+            && cursor + 1 + MIN_MATCH <= input.len()
+        {
+            let (next_length, _) =
+                longest_match(input, cursor + 1, &head, &prev, params.max_chain_len);
+            if next_length > best_length {
+                symbols.push(DeflateSymbol::Literal(input[cursor]));
+                cursor += 1;
+                continue;
+            }
+        }
+
         if best_length >= MIN_MATCH {
             // Insert skipped positions so future matches can find them.
             for i in 1..best_length {
@@ -459,6 +1191,247 @@ fn lz77_symbols(input: &[u8]) -> Vec<DeflateSymbol> {
     symbols
 }
 
+/// Walks the hash chain for `cursor` and returns the longest `(length, distance)` match.
+fn longest_match(
+    input: &[u8],
+    cursor: usize,
+    head: &[u32],
+    prev: &[u32],
+    max_chain_len: usize,
+) -> (usize, usize) {
+    let max_length = (input.len() - cursor).min(MAX_MATCH);
+    let search_start = cursor.saturating_sub(WINDOW_SIZE);
+
+    let mut best_length = 0;
+    let mut best_distance = 0;
+    let mut chain_pos = head[hash3(input, cursor)];
+    let mut chain_count = 0;
+
+    while chain_pos != NIL
+        && (chain_pos as usize) >= search_start
+        && (chain_pos as usize) < cursor
+        && chain_count < max_chain_len
+    {
+        let candidate = chain_pos as usize;
+        if input[candidate] == input[cursor] {
+            let mut length = 1;
+            while length < max_length && input[candidate + length] == input[cursor + length] {
+                length += 1;
+            }
+            if length >= MIN_MATCH && length > best_length {
+                best_length = length;
+                best_distance = cursor - candidate;
+                if length == max_length {
+                    break;
+                }
+            }
+        }
+        chain_pos = prev[candidate & (WINDOW_SIZE - 1)];
+        chain_count += 1;
+    }
+    (best_length, best_distance)
+}
+
+/// Rounds of [`optimal_symbols`], each parsing with the costs of the last.
+const OPTIMAL_ITERATIONS: usize = 4;
+
+/// Produces LZ77 symbols for `input[start..]` that are cheapest under a
+/// Huffman cost model, in the manner of Zopfli.
+///
+/// Starting from the costs of the greedy parse, each round finds the
+/// shortest path through the input, where every position can be reached by a
+/// literal or by any match length ending there, and re-derives the costs from
+/// the symbols it chose. The cheapest parse of all rounds is returned.
+fn optimal_symbols(
+    input: &[u8],
+    start: usize,
+    params: &CompressionParams,
+) -> Result<Vec<DeflateSymbol>> {
+    let mut best = lz77_symbols(input, start, params);
+    let histogram = Histogram::new(&best);
+    let mut best_bits = DynamicCodes::new(&histogram)?.bits(&histogram);
+    let matches = MatchCandidates::new(input, start, params.max_chain_len);
+    let mut costs = SymbolCosts::new(&histogram);
+    for _ in 0..OPTIMAL_ITERATIONS {
+        let symbols = shortest_path(input, start, &matches, &costs);
+        let histogram = Histogram::new(&symbols);
+        let bits = DynamicCodes::new(&histogram)?.bits(&histogram);
+        if bits >= best_bits {
+            break;
+        }
+        costs = SymbolCosts::new(&histogram);
+        best = symbols;
+        best_bits = bits;
+    }
+    Ok(best)
+}
+
+/// Bit costs of symbols, from Huffman code lengths for a histogram in which
+/// every symbol is counted once more, so that unused symbols have a cost too.
+#[derive(Debug)]
+struct SymbolCosts {
+    literal: [u32; 256],
+    /// Indexed by match length, including extra bits.
+    length: [u32; MAX_MATCH + 1],
+    /// Indexed by distance code, excluding extra bits.
+    distance: [u32; 30],
+}
+
+impl SymbolCosts {
+    fn new(histogram: &Histogram) -> Self {
+        let literal_lengths = length_limited_code_lengths(
+            &histogram.literal.map(|frequency| frequency + 1),
+            MAX_BITS as u8,
+        );
+        let distance_lengths = length_limited_code_lengths(
+            &histogram.distance.map(|frequency| frequency + 1),
+            MAX_BITS as u8,
+        );
+        let mut length = [0; MAX_MATCH + 1];
+        for (len, cost) in length.iter_mut().enumerate().skip(MIN_MATCH) {
+            let symbol = length_to_symbol(len as u16);
+            *cost = u32::from(literal_lengths[symbol.code as usize])
+                + u32::from(symbol.extra.map_or(0, |(bits, _)| bits));
+        }
+        Self {
+            literal: core::array::from_fn(|byte| u32::from(literal_lengths[byte])),
+            length,
+            distance: core::array::from_fn(|code| u32::from(distance_lengths[code])),
+        }
+    }
+
+    fn distance_cost(&self, distance: usize) -> u32 {
+        let symbol = distance_to_symbol(distance as u16);
+        self.distance[symbol.code as usize] + u32::from(symbol.extra.map_or(0, |(bits, _)| bits))
+    }
+}
+
+/// For each position of `input[start..]`, the matches found along its hash
+/// chain that are longer than every closer one.
+///
+/// The closest match that reaches a length is always at least as cheap as
+/// the others, so these pairs cover every useful `(length, distance)`.
+#[derive(Debug)]
+struct MatchCandidates {
+    /// Range of `pairs` for each position, as offsets into it.
+    offsets: Vec<u32>,
+    /// `(length, distance)`, with increasing lengths for each position.
+    pairs: Vec<(u16, u16)>,
+}
+
+impl MatchCandidates {
+    fn new(input: &[u8], start: usize, max_chain_len: usize) -> Self {
+        let mut offsets = Vec::with_capacity(input.len() - start + 1);
+        let mut pairs = Vec::new();
+        let mut head = vec![NIL; HASH_SIZE];
+        let mut prev = vec![NIL; WINDOW_SIZE];
+        let insert = |head: &mut [u32], prev: &mut [u32], pos: usize| {
+            if pos + MIN_MATCH <= input.len() {
+                let h = hash3(input, pos);
+                prev[pos & (WINDOW_SIZE - 1)] = head[h];
+                head[h] = pos as u32;
+            }
+        };
+        for pos in start.saturating_sub(WINDOW_SIZE)..start {
+            insert(&mut head, &mut prev, pos);
+        }
+
+        for cursor in start..input.len() {
+            offsets.push(pairs.len() as u32);
+            if cursor + MIN_MATCH <= input.len() {
+                let max_length = (input.len() - cursor).min(MAX_MATCH);
+                let search_start = cursor.saturating_sub(WINDOW_SIZE);
+                let mut best_length = MIN_MATCH - 1;
+                let mut chain_pos = head[hash3(input, cursor)];
+                let mut chain_count = 0;
+                while chain_pos != NIL
+                    && (chain_pos as usize) >= search_start
+                    && (chain_pos as usize) < cursor
+                    && chain_count < max_chain_len
+                {
+                    let candidate = chain_pos as usize;
+                    // Only a candidate that also matches the byte after the
+                    // longest match so far can be longer.
+                    let mut length = 0;
+                    if input[candidate + best_length] == input[cursor + best_length] {
+                        while length < max_length
+                            && input[candidate + length] == input[cursor + length]
+                        {
+                            length += 1;
+                        }
+                    }
+                    if length > best_length {
+                        best_length = length;
+                        pairs.push((length as u16, (cursor - candidate) as u16));
+                        if length == max_length {
+                            break;
+                        }
+                    }
+                    chain_pos = prev[candidate & (WINDOW_SIZE - 1)];
+                    chain_count += 1;
+                }
+            }
+            insert(&mut head, &mut prev, cursor);
+        }
+        offsets.push(pairs.len() as u32);
+        Self { offsets, pairs }
+    }
+}
+
+/// Finds the cheapest sequence of symbols for `input[start..]` under `costs`.
+fn shortest_path(
+    input: &[u8],
+    start: usize,
+    matches: &MatchCandidates,
+    costs: &SymbolCosts,
+) -> Vec<DeflateSymbol> {
+    let len = input.len() - start;
+    // Cheapest cost to reach each position, and the last step taken there:
+    // a match length and distance, or a length of one for a literal.
+    let mut cost = vec![u32::MAX; len + 1];
+    let mut step = vec![(0u16, 0u16); len + 1];
+    cost[0] = 0;
+    for i in 0..len {
+        let here = cost[i];
+        let literal = here + costs.literal[usize::from(input[start + i])];
+        if literal < cost[i + 1] {
+            cost[i + 1] = literal;
+            step[i + 1] = (1, 0);
+        }
+        let pairs = &matches.pairs[matches.offsets[i] as usize..matches.offsets[i + 1] as usize];
+        let mut length = MIN_MATCH;
+        for &(max_length, distance) in pairs {
+            let distance_cost = costs.distance_cost(usize::from(distance));
+            while length <= usize::from(max_length) {
+                let total = here + costs.length[length] + distance_cost;
+                if total < cost[i + length] {
+                    cost[i + length] = total;
+                    step[i + length] = (length as u16, distance);
+                }
+                length += 1;
+            }
+        }
+    }
+
+    let mut symbols = Vec::new();
+    let mut i = len;
+    while i > 0 {
+        let (length, distance) = step[i];
+        let length = usize::from(length);
+        symbols.push(if length == 1 {
+            DeflateSymbol::Literal(input[start + i - 1])
+        } else {
+            DeflateSymbol::Pointer {
+                length,
+                distance: usize::from(distance),
+            }
+        });
+        i -= length;
+    }
+    symbols.reverse();
+    symbols
+}
+
 fn reverse_bits(bits: u16, width: u8) -> u16 {
     let mut from = bits;
     let mut to = 0;
@@ -492,6 +1465,14 @@ enum DeflateSymbol {
 }
 
 impl DeflateSymbol {
+    /// Number of input bytes the symbol stands for.
+    fn len(self) -> usize {
+        match self {
+            DeflateSymbol::Literal(_) => 1,
+            DeflateSymbol::Pointer { length, .. } => length,
+        }
+    }
+
     fn code(self) -> u16 {
         match self {
             DeflateSymbol::Literal(byte) => u16::from(byte),
@@ -784,6 +1765,7 @@ fn package_merge_code_lengths(frequencies: &[usize], max_bitwidth: u8) -> Vec<u8
     widths
 }
 
+#[derive(Debug)]
 struct HuffmanDecoder {
     table: Vec<u16>,
     safely_peek_bits: u8,
@@ -798,12 +1780,10 @@ impl HuffmanDecoder {
     ) -> Result<Self> {
         let max_bits = lengths.iter().copied().max().unwrap_or(0);
         if max_bits == 0 {
-            return Err(Error::InvalidData("huffman table is empty".into()));
+            return Err(invalid_deflate("huffman table is empty"));
         }
         if max_bits as usize > MAX_BITS {
-            return Err(Error::InvalidData(
-                "huffman table uses too many bits".into(),
-            ));
+            return Err(invalid_deflate("huffman table uses too many bits"));
         }
 
         let table_len = 1usize << max_bits;
@@ -829,7 +1809,7 @@ impl HuffmanDecoder {
             for padding in 0..fill_count {
                 let index = ((padding as u16) << width | reversed) as usize;
                 if table[index] != u16::MAX {
-                    return Err(Error::InvalidData("conflicting huffman codes".into()));
+                    return Err(invalid_deflate("conflicting huffman codes"));
                 }
                 table[index] = value;
             }
@@ -858,7 +1838,7 @@ impl HuffmanDecoder {
                 return Ok(value >> 5);
             }
             if width as usize > self.max_bits as usize || value == u16::MAX {
-                return Err(Error::InvalidData("invalid huffman coded stream".into()));
+                return Err(invalid_deflate("invalid huffman coded stream"));
             }
             peek_bits = width;
         }
@@ -870,8 +1850,11 @@ struct BitReader<'a> {
     byte_index: usize,
     bit_buffer: u64,
     bit_count: u8,
+    /// Set when a read failed because the input ran out.
+    exhausted: bool,
 }
 
+#[derive(Debug)]
 struct BitWriter {
     bytes: Vec<u8>,
     bit_buffer: u64,
@@ -880,8 +1863,13 @@ struct BitWriter {
 
 impl BitWriter {
     fn new() -> Self {
+        Self::with_bytes(Vec::new())
+    }
+
+    /// Creates a writer that appends to `bytes`.
+    fn with_bytes(bytes: Vec<u8>) -> Self {
         Self {
-            bytes: Vec::new(),
+            bytes,
             bit_buffer: 0,
             bit_count: 0,
         }
@@ -901,6 +1889,24 @@ impl BitWriter {
         }
     }
 
+    fn align_to_byte(&mut self) {
+        if self.bit_count > 0 {
+            self.bytes.push(self.bit_buffer as u8);
+            self.bit_buffer = 0;
+            self.bit_count = 0;
+        }
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        debug_assert_eq!(self.bit_count, 0);
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    /// Moves all completed bytes to `out`, keeping any partial byte buffered.
+    fn drain_bytes_to(&mut self, out: &mut Vec<u8>) {
+        out.append(&mut self.bytes);
+    }
+
     fn finish(mut self) -> Vec<u8> {
         if self.bit_count > 0 {
             self.bytes.push(self.bit_buffer as u8);
@@ -910,15 +1916,32 @@ impl BitWriter {
 }
 
 impl<'a> BitReader<'a> {
-    fn new(input: &'a [u8]) -> Self {
+    fn resume(input: &'a [u8], bit_buffer: u64, bit_count: u8) -> Self {
         Self {
             input,
             byte_index: 0,
-            bit_buffer: 0,
-            bit_count: 0,
+            bit_buffer,
+            bit_count,
+            exhausted: false,
         }
     }
 
+    fn checkpoint(&self) -> (usize, u64, u8) {
+        (self.byte_index, self.bit_buffer, self.bit_count)
+    }
+
+    fn restore(&mut self, (byte_index, bit_buffer, bit_count): (usize, u64, u8)) {
+        self.byte_index = byte_index;
+        self.bit_buffer = bit_buffer;
+        self.bit_count = bit_count;
+        self.exhausted = false;
+    }
+
+    /// Whole bytes available to [`read_bytes`](Self::read_bytes).
+    fn remaining_bytes(&self) -> usize {
+        self.input.len() - self.byte_index + usize::from(self.bit_count / 8)
+    }
+
     fn read_bit(&mut self) -> Result<bool> {
         Ok(self.read_bits(1)? != 0)
     }
@@ -932,9 +1955,8 @@ impl<'a> BitReader<'a> {
     fn peek_bits(&mut self, bit_count: u8) -> Result<u16> {
         while self.bit_count < bit_count {
             let Some(&next) = self.input.get(self.byte_index) else {
-                return Err(Error::InvalidData(
-                    "unexpected end of deflate stream".into(),
-                ));
+                self.exhausted = true;
+                return Err(invalid_deflate("unexpected end of deflate stream"));
             };
             self.bit_buffer |= u64::from(next) << self.bit_count;
             self.bit_count += 8;
@@ -952,7 +1974,19 @@ impl<'a> BitReader<'a> {
         Ok(())
     }
 
+    /// Gives back whole bytes that were buffered ahead, so that at most seven
+    /// bits are carried over to the next input.
+    fn unread_whole_bytes(&mut self) {
+        let whole = self.bit_count / 8;
+        self.byte_index -= usize::from(whole);
+        self.bit_count -= whole * 8;
+        self.bit_buffer &= (1 << self.bit_count) - 1;
+    }
+
+    /// Drops the bits of the current partial byte, giving back any whole
+    /// bytes that were buffered ahead.
     fn align_to_byte(&mut self) {
+        self.byte_index -= usize::from(self.bit_count / 8);
         self.bit_buffer = 0;
         self.bit_count = 0;
     }
@@ -966,9 +2000,8 @@ impl<'a> BitReader<'a> {
         self.align_to_byte();
         let end = self.byte_index + len;
         let Some(bytes) = self.input.get(self.byte_index..end) else {
-            return Err(Error::InvalidData(
-                "unexpected end of deflate stream".into(),
-            ));
+            self.exhausted = true;
+            return Err(invalid_deflate("unexpected end of deflate stream"));
         };
         self.byte_index = end;
         Ok(bytes)
@@ -977,9 +2010,33 @@ impl<'a> BitReader<'a> {
 
 #[cfg(test)]
 mod tests {
-    use alloc::vec;
-
-    use super::{decompress, encode_dynamic_literals};
+    use alloc::{vec, vec::Vec};
+
+    use super::{
+        BITWIDTH_CODE_ORDER, BitReader, CompressionParams, Compressor, HuffmanDecoder, Inflater,
+        compress, encode_dynamic_literals, lz77_symbols, optimal_symbols, split_blocks,
+    };
+    #[cfg(feature = "compression")]
+    use super::{MAX_MATCH, WINDOW_SIZE};
+    use crate::png_types::{CompressionLevel, ErrorKind, Result};
+
+    const LEVELS: [CompressionLevel; 5] = [
+        CompressionLevel::Store,
+        CompressionLevel::Fast,
+        CompressionLevel::Default,
+        CompressionLevel::Best,
+        CompressionLevel::Optimal,
+    ];
+
+    fn decompress(input: &[u8]) -> Result<Vec<u8>> {
+        let mut inflater = Inflater::new();
+        let mut output = Vec::new();
+        inflater.write(input, &mut output)?;
+        if !inflater.is_finished() {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        Ok(output)
+    }
 
     #[test]
     fn decode_known_fixed_block() {
@@ -1007,7 +2064,7 @@ mod tests {
     #[test]
     fn encode_dynamic_literals_roundtrip() {
         let input = b"banana banana banana banana";
-        let encoded = encode_dynamic_literals(input).expect("infallible");
+        let encoded = encode_dynamic_literals(input, &default_params()).expect("infallible");
         let decoded = decompress(&encoded).expect("infallible");
         assert_eq!(decoded, input);
     }
@@ -1015,9 +2072,343 @@ mod tests {
     #[test]
     fn encode_dynamic_literals_uses_matches_for_repetition() {
         let input = vec![b'a'; 2048];
-        let encoded = encode_dynamic_literals(&input).expect("infallible");
+        let encoded = encode_dynamic_literals(&input, &default_params()).expect("infallible");
         let decoded = decompress(&encoded).expect("infallible");
         assert_eq!(decoded, input);
         assert!(encoded.len() < 64);
     }
+
+    /// Number of symbols with a code in each Huffman table of the dynamic
+    /// block at the start of `encoded`: code lengths, literal/length and
+    /// distance.
+    fn dynamic_table_code_counts(encoded: &[u8]) -> [usize; 3] {
+        let mut reader = BitReader::resume(encoded, 0, 0);
+        let mut read = |bit_count| usize::from(reader.read_bits(bit_count).expect("infallible"));
+        assert_eq!(read(3), 0b101, "final dynamic block");
+        let literal_count = read(5) + 257;
+        let distance_count = read(5) + 1;
+        let bitwidth_count = read(4) + 4;
+        let mut bitwidth_lengths = [0u8; 19];
+        for &index in &BITWIDTH_CODE_ORDER[..bitwidth_count] {
+            bitwidth_lengths[index] = read(3) as u8;
+        }
+        let bitwidth_decoder = HuffmanDecoder::from_code_lengths(&bitwidth_lengths, Some(1), None)
+            .expect("infallible");
+        let mut lengths = Vec::new();
+        while lengths.len() < literal_count + distance_count {
+            match bitwidth_decoder.decode(&mut reader).expect("infallible") {
+                code @ 0..=15 => lengths.push(code as u8),
+                16 => {
+                    let last = *lengths.last().expect("infallible");
+                    let repeat = usize::from(reader.read_bits(2).expect("infallible")) + 3;
+                    lengths.extend(core::iter::repeat_n(last, repeat));
+                }
+                17 => {
+                    let repeat = usize::from(reader.read_bits(3).expect("infallible")) + 3;
+                    lengths.extend(core::iter::repeat_n(0, repeat));
+                }
+                _ => {
+                    let repeat = usize::from(reader.read_bits(7).expect("infallible")) + 11;
+                    lengths.extend(core::iter::repeat_n(0, repeat));
+                }
+            }
+        }
+        let used = |lengths: &[u8]| lengths.iter().filter(|&&length| length > 0).count();
+        [
+            used(&bitwidth_lengths),
+            used(&lengths[..literal_count]),
+            used(&lengths[literal_count..]),
+        ]
+    }
+
+    #[test]
+    fn dynamic_blocks_have_no_single_code_tables() {
+        // Empty input only needs the end-of-block symbol, and input without
+        // repeats no distance code. zlib rejects a literal/length or code
+        // length table with a single code as incomplete.
+        for input in [&b""[..], b"a", b"abcdefgh"] {
+            let encoded = encode_dynamic_literals(input, &default_params()).expect("infallible");
+            let counts = dynamic_table_code_counts(&encoded);
+            assert!(
+                counts.iter().all(|&count| count >= 2),
+                "{input:?}: {counts:?}"
+            );
+            assert_eq!(decompress(&encoded).expect("infallible"), input);
+        }
+    }
+
+    #[test]
+    fn compress_roundtrips_at_every_level() {
+        let mut input = b"banana banana banana banana ".repeat(100);
+        input.extend((0..70_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8));
+        for level in LEVELS {
+            let encoded = compress(&input, level).expect("infallible");
+            let decoded = decompress(&encoded).expect("infallible");
+            assert_eq!(decoded, input, "{level:?}");
+        }
+        for level in LEVELS {
+            let encoded = compress(&[], level).expect("infallible");
+            assert!(decompress(&encoded).expect("infallible").is_empty());
+        }
+    }
+
+    #[test]
+    fn higher_levels_do_not_compress_worse() {
+        let input: Vec<u8> = (0..20_000u32)
+            .map(|i| ((i % 251) ^ (i / 97)) as u8)
+            .collect();
+        let sizes = LEVELS.map(|level| compress(&input, level).expect("infallible").len());
+        assert!(sizes[0] > input.len());
+        assert!(sizes[1] < sizes[0]);
+        assert!(sizes[3] <= sizes[2]);
+        assert!(sizes[4] <= sizes[3]);
+    }
+
+    #[test]
+    fn optimal_parsing_beats_lazy_matching_on_text() {
+        const WORDS: [&[u8]; 8] = [
+            b"the ", b"then ", b"hen ", b"there ", b"here ", b"her ", b"other ", b"the",
+        ];
+        let input: Vec<u8> = noise(4_000)
+            .iter()
+            .flat_map(|&byte| WORDS[usize::from(byte % 8)].iter().copied())
+            .collect();
+        let best = compress(&input, CompressionLevel::Best).expect("infallible");
+        let optimal = compress(&input, CompressionLevel::Optimal).expect("infallible");
+        assert!(
+            optimal.len() < best.len(),
+            "{} < {}",
+            optimal.len(),
+            best.len()
+        );
+        assert_eq!(decompress(&optimal).expect("infallible"), input);
+
+        let params = CompressionParams::from_level(CompressionLevel::Optimal);
+        let symbols = optimal_symbols(&input, 0, &params).expect("infallible");
+        let len: usize = symbols.iter().map(|symbol| symbol.len()).sum();
+        assert_eq!(len, input.len());
+    }
+
+    #[test]
+    fn compress_splits_blocks_where_content_changes() {
+        let mut input = noise(30_000);
+        input.extend(
+            noise(30_000)
+                .iter()
+                .map(|&byte| b"ACGT"[usize::from(byte % 4)]),
+        );
+        let params = CompressionParams::from_level(CompressionLevel::Best);
+        let symbols = lz77_symbols(&input, 0, &params);
+        assert!(split_blocks(&symbols).expect("infallible").len() > 1);
+
+        let split = compress(&input, CompressionLevel::Best).expect("infallible");
+        let single = encode_dynamic_literals(&input, &params).expect("infallible");
+        assert!(split.len() < single.len());
+        assert_eq!(decompress(&split).expect("infallible"), input);
+    }
+
+    #[test]
+    fn default_level_writes_one_block_per_segment() {
+        let mut input = noise(30_000);
+        input.extend(
+            noise(30_000)
+                .iter()
+                .map(|&byte| b"ACGT"[usize::from(byte % 4)]),
+        );
+        let encoded = compress(&input, CompressionLevel::Default).expect("infallible");
+        let single = encode_dynamic_literals(&input, &default_params()).expect("infallible");
+        assert_eq!(encoded, single);
+    }
+
+    #[test]
+    fn compress_stores_incompressible_blocks() {
+        let input = noise(100_000);
+        for level in [
+            CompressionLevel::Fast,
+            CompressionLevel::Default,
+            CompressionLevel::Best,
+        ] {
+            let encoded = compress(&input, level).expect("infallible");
+            assert!(encoded.len() <= input.len() + 5 * 3, "{level:?}");
+            assert_eq!(
+                decompress(&encoded).expect("infallible"),
+                input,
+                "{level:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn store_copies_input_behind_block_headers() {
+        let input = noise(150_000);
+        let encoded = compress(&input, CompressionLevel::Store).expect("infallible");
+        assert_eq!(encoded.len(), input.len() + 5 * 3);
+        assert_eq!(encoded[..5], [0b000, 0xFF, 0xFF, 0x00, 0x00]);
+        assert_eq!(encoded[5..5 + 65_535], input[..65_535]);
+        assert_eq!(decompress(&encoded).expect("infallible"), input);
+
+        let mut compressor = Compressor::new(CompressionLevel::Store);
+        let mut streamed = Vec::new();
+        for piece in input.chunks(10_000) {
+            compressor.write(piece, &mut streamed).expect("infallible");
+        }
+        compressor.finish(&mut streamed).expect("infallible");
+        assert_eq!(streamed, encoded);
+    }
+
+    #[test]
+    fn compressor_output_spans_blocks_and_roundtrips() {
+        let input: Vec<u8> = (0..200_000u32)
+            .map(|i| ((i / 3) % 256) as u8 ^ (i / 50_000) as u8)
+            .collect();
+        for level in LEVELS {
+            let mut compressor = Compressor::new(level);
+            let mut encoded = Vec::new();
+            for piece in input.chunks(7_777) {
+                compressor.write(piece, &mut encoded).expect("infallible");
+            }
+            assert!(!encoded.is_empty());
+            compressor.finish(&mut encoded).expect("infallible");
+            assert_eq!(
+                decompress(&encoded).expect("infallible"),
+                input,
+                "{level:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn inflater_accepts_input_split_at_any_byte() {
+        let input: Vec<u8> = (0..50_000u32)
+            .map(|i| ((i / 7) % 256) as u8 ^ (i / 10_000) as u8)
+            .collect();
+        for level in LEVELS {
+            let mut encoded = compress(&input, level).expect("infallible");
+            encoded.extend_from_slice(b"tail");
+            let mut inflater = Inflater::new();
+            let mut decoded = Vec::new();
+            for byte in &encoded {
+                inflater
+                    .write(core::slice::from_ref(byte), &mut decoded)
+                    .expect("infallible");
+            }
+            assert!(inflater.is_finished(), "{level:?}");
+            assert_eq!(inflater.remaining_input(), b"tail", "{level:?}");
+            assert_eq!(decoded, input, "{level:?}");
+        }
+    }
+
+    #[test]
+    fn inflater_reports_truncated_stream() {
+        let encoded =
+            compress(b"banana banana banana", CompressionLevel::Default).expect("infallible");
+        assert!(decompress(&encoded[..encoded.len() - 1]).is_err());
+        let mut inflater = Inflater::new();
+        let mut decoded = Vec::new();
+        inflater
+            .write(&encoded[..encoded.len() - 1], &mut decoded)
+            .expect("infallible");
+        assert!(!inflater.is_finished());
+    }
+
+    #[test]
+    fn inflater_enforces_output_limit() {
+        let input = vec![0; 100_000];
+        let encoded = compress(&input, CompressionLevel::Default).expect("infallible");
+        let mut decoded = Vec::new();
+        let error = Inflater::with_output_limit(50_000)
+            .write(&encoded, &mut decoded)
+            .expect_err("infallible");
+        assert_eq!(error.kind(), ErrorKind::LimitExceeded);
+        assert!(decoded.len() < input.len());
+        let mut inflater = Inflater::with_output_limit(input.len());
+        inflater.write(&encoded, &mut decoded).expect("infallible");
+        assert!(inflater.is_finished());
+    }
+
+    #[test]
+    fn inflater_reports_bit_offset_of_errors() {
+        // A stored block holding "ab", then a final block with the reserved
+        // type 0b11 whose header ends at bit 7 * 8 + 3.
+        let input = [0x00, 0x02, 0x00, 0xFD, 0xFF, b'a', b'b', 0x07];
+        for split in 0..input.len() {
+            let mut inflater = Inflater::new();
+            let mut decoded = Vec::new();
+            inflater
+                .write(&input[..split], &mut decoded)
+                .expect("infallible");
+            let error = inflater
+                .write(&input[split..], &mut decoded)
+                .expect_err("infallible");
+            assert_eq!(error.kind(), ErrorKind::Deflate { bit_offset: 59 });
+            assert_eq!(decoded, b"ab");
+        }
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn inflate_pauses_when_output_is_full() {
+        let input: Vec<u8> = (0..150_000u32)
+            .map(|i| ((i / 5) % 256) as u8 ^ (i / 40_000) as u8)
+            .collect();
+        for level in LEVELS {
+            let mut encoded = compress(&input, level).expect("infallible");
+            encoded.extend_from_slice(b"tail");
+            for (piece_len, output_len) in [(1, 1), (3, 300), (1000, 7), (usize::MAX, 4096)] {
+                let mut inflater = Inflater::new();
+                let mut output = vec![0; output_len];
+                let mut decoded = Vec::new();
+                let mut rest = &encoded[..];
+                while !inflater.is_finished() {
+                    let piece = &rest[..rest.len().min(piece_len)];
+                    let (consumed, written) =
+                        inflater.inflate(piece, &mut output).expect("infallible");
+                    // Only the final end-of-block code, left in the bit
+                    // buffer by a call that filled `output`, decodes
+                    // without consuming or writing anything.
+                    assert!(
+                        consumed > 0 || written > 0 || inflater.is_finished(),
+                        "{level:?}"
+                    );
+                    rest = &rest[consumed..];
+                    decoded.extend_from_slice(&output[..written]);
+                    assert!(inflater.window.len() <= 2 * WINDOW_SIZE + output_len + MAX_MATCH);
+                }
+                assert_eq!(decoded, input, "{level:?}");
+                assert_eq!([inflater.remaining_input(), rest].concat(), b"tail");
+            }
+        }
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn inflate_returns_output_before_reporting_an_error() {
+        // A stored block holding "ab", then a reserved block type.
+        let input = [0x00, 0x02, 0x00, 0xFD, 0xFF, b'a', b'b', 0x07];
+        let mut inflater = Inflater::new();
+        let mut output = [0; 16];
+        assert_eq!(inflater.inflate(&input, &mut output), Ok((8, 2)));
+        assert_eq!(&output[..2], b"ab");
+        for _ in 0..2 {
+            let error = inflater.inflate(&[], &mut output).expect_err("infallible");
+            assert_eq!(error.kind(), ErrorKind::Deflate { bit_offset: 59 });
+        }
+    }
+
+    /// Incompressible bytes from a xorshift generator.
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_F491_u32 ^ len as u32;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                (state >> 24) as u8
+            })
+            .collect()
+    }
+
+    fn default_params() -> CompressionParams {
+        CompressionParams::from_level(CompressionLevel::Default)
+    }
 }
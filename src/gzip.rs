@@ -0,0 +1,449 @@
+use alloc::vec::Vec;
+
+use crate::crc::{self, Crc32};
+use crate::deflate::{self, Compressor, Inflater};
+use crate::png_types::{CompressionLevel, Error, ErrorKind, Result};
+
+const MAGIC: [u8; 2] = [0x1F, 0x8B];
+const METHOD_DEFLATE: u8 = 8;
+
+const FLAG_HCRC: u8 = 0x02;
+const FLAG_EXTRA: u8 = 0x04;
+const FLAG_NAME: u8 = 0x08;
+const FLAG_COMMENT: u8 = 0x10;
+const FLAG_RESERVED: u8 = 0xE0;
+
+// OS=255 (unknown)
+const OS_UNKNOWN: u8 = 255;
+
+/// Returns the ten-byte gzip member header for data compressed at `level`.
+///
+/// No file name, comment or modification time is recorded.
+pub fn header(level: CompressionLevel) -> [u8; 10] {
+    // [XFL] 2 = maximum compression, 4 = fastest algorithm
+    let xfl = match level {
+        CompressionLevel::Store | CompressionLevel::Fast => 4,
+        CompressionLevel::Default => 0,
+        CompressionLevel::Best | CompressionLevel::Optimal => 2,
+    };
+    [
+        MAGIC[0],
+        MAGIC[1],
+        METHOD_DEFLATE,
+        0,
+        0,
+        0,
+        0,
+        0,
+        xfl,
+        OS_UNKNOWN,
+    ]
+}
+
+/// Compresses `data` into a single gzip member (header, DEFLATE data, CRC-32
+/// and size).
+pub fn compress(data: &[u8], level: CompressionLevel) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    out.extend_from_slice(&header(level));
+    deflate::compress_to(data, level, &mut out)?;
+    out.extend_from_slice(&crc::calculate(&[data]).to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    Ok(out)
+}
+
+/// Incremental gzip encoder built on [`Compressor`].
+#[derive(Debug)]
+pub struct Encoder {
+    compressor: Compressor,
+    checksum: Crc32,
+    size: u32,
+}
+
+impl Encoder {
+    /// Creates an encoder and appends the gzip header to `out`.
+    pub fn new(level: CompressionLevel, out: &mut Vec<u8>) -> Self {
+        out.extend_from_slice(&header(level));
+        Self {
+            compressor: Compressor::new(level),
+            checksum: Crc32::new(),
+            size: 0,
+        }
+    }
+
+    /// Feeds `data` into the encoder, appending any completed output bytes to `out`.
+    pub fn write(&mut self, data: &[u8], out: &mut Vec<u8>) -> Result<()> {
+        self.checksum.update(data);
+        self.size = self.size.wrapping_add(data.len() as u32);
+        self.compressor.write(data, out)
+    }
+
+    /// Finishes the DEFLATE stream and appends the CRC-32 and size trailer to
+    /// `out`.
+    pub fn finish(self, out: &mut Vec<u8>) -> Result<()> {
+        self.compressor.finish(out)?;
+        out.extend_from_slice(&self.checksum.finish().to_le_bytes());
+        out.extend_from_slice(&self.size.to_le_bytes());
+        Ok(())
+    }
+}
+
+/// Incremental gzip decoder built on [`Inflater`].
+///
+/// Decodes a single gzip member. The header, DEFLATE data and trailer may be
+/// split across any number of [`write`](Self::write) calls.
+#[derive(Debug)]
+pub struct Decoder {
+    header: HeaderReader,
+    inflater: Inflater,
+    checksum: Crc32,
+    size: u32,
+    trailer: Vec<u8>,
+    finished: bool,
+}
+
+impl Decoder {
+    /// Creates a decoder without an output limit.
+    pub fn new() -> Self {
+        Self::with_output_limit(usize::MAX)
+    }
+
+    /// Creates a decoder that fails with [`ErrorKind::LimitExceeded`] once the
+    /// stream inflates to more than `output_limit` bytes.
+    pub fn with_output_limit(output_limit: usize) -> Self {
+        Self {
+            header: HeaderReader::new(),
+            inflater: Inflater::with_output_limit(output_limit),
+            checksum: Crc32::new(),
+            size: 0,
+            trailer: Vec::with_capacity(8),
+            finished: false,
+        }
+    }
+
+    /// Returns `true` once the trailer has been read and verified.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Feeds `input` into the decoder, appending the decompressed bytes to `out`.
+    ///
+    /// Bytes following the trailer are ignored. Fails with
+    /// [`ErrorKind::GzipHeader`] if the header is invalid or longer than
+    /// 128 KiB.
+    pub fn write(&mut self, input: &[u8], out: &mut Vec<u8>) -> Result<()> {
+        let mut input = input;
+        if !self.header.is_done() {
+            let consumed = self.header.read(input)?;
+            input = &input[consumed..];
+            if !self.header.is_done() {
+                return Ok(());
+            }
+        }
+        self.write_body(input, out)
+    }
+
+    fn write_body(&mut self, input: &[u8], out: &mut Vec<u8>) -> Result<()> {
+        if !self.inflater.is_finished() {
+            let start = out.len();
+            self.inflater.write(input, out)?;
+            self.checksum.update(&out[start..]);
+            self.size = self.size.wrapping_add((out.len() - start) as u32);
+            if self.inflater.is_finished() {
+                let trailing = self.inflater.remaining_input().to_vec();
+                self.read_trailer(&trailing)?;
+            }
+        } else {
+            self.read_trailer(input)?;
+        }
+        Ok(())
+    }
+
+    fn read_trailer(&mut self, input: &[u8]) -> Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        let take = (8 - self.trailer.len()).min(input.len());
+        self.trailer.extend_from_slice(&input[..take]);
+        if self.trailer.len() == 8 {
+            let (crc, size) = self.trailer.split_at(4);
+            let expected_crc =
+                u32::from_le_bytes(crc.try_into().expect("bug: gzip CRC must be 4 bytes"));
+            let expected_size =
+                u32::from_le_bytes(size.try_into().expect("bug: gzip size must be 4 bytes"));
+            if self.checksum.finish() != expected_crc {
+                return Err(Error::new(ErrorKind::GzipTrailer, "CRC-32 mismatch"));
+            }
+            if self.size != expected_size {
+                return Err(Error::new(ErrorKind::GzipTrailer, "size mismatch"));
+            }
+            self.finished = true;
+        }
+        Ok(())
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Longest header [`Decoder`] accepts: room for the largest extra field and a
+/// generous file name and comment.
+const MAX_HEADER_LEN: usize = 128 * 1024;
+
+/// Header field that [`HeaderReader`] is reading.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HeaderField {
+    Fixed,
+    ExtraLen,
+    Extra { remaining: usize },
+    Name,
+    Comment,
+    Crc,
+    Done,
+}
+
+/// Incremental parser for a gzip member header, which skips the optional
+/// fields without storing them.
+#[derive(Debug)]
+struct HeaderReader {
+    field: HeaderField,
+    /// The fixed-size field being read, and how many of its bytes have
+    /// arrived.
+    buffer: [u8; 10],
+    filled: usize,
+    flags: u8,
+    checksum: Crc32,
+    len: usize,
+}
+
+impl HeaderReader {
+    fn new() -> Self {
+        Self {
+            field: HeaderField::Fixed,
+            buffer: [0; 10],
+            filled: 0,
+            flags: 0,
+            checksum: Crc32::new(),
+            len: 0,
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.field == HeaderField::Done
+    }
+
+    /// Reads header bytes from the start of `input`, returning how many were
+    /// part of the header.
+    fn read(&mut self, input: &[u8]) -> Result<usize> {
+        let mut consumed = 0;
+        while !self.is_done() && consumed < input.len() {
+            let rest = &input[consumed..];
+            let field = self.field;
+            let taken = match field {
+                HeaderField::Fixed | HeaderField::ExtraLen | HeaderField::Crc => {
+                    let size = if field == HeaderField::Fixed { 10 } else { 2 };
+                    let taken = (size - self.filled).min(rest.len());
+                    self.buffer[self.filled..self.filled + taken].copy_from_slice(&rest[..taken]);
+                    self.filled += taken;
+                    taken
+                }
+                HeaderField::Extra { remaining } => {
+                    let taken = remaining.min(rest.len());
+                    self.field = HeaderField::Extra {
+                        remaining: remaining - taken,
+                    };
+                    taken
+                }
+                HeaderField::Name | HeaderField::Comment => {
+                    // Zero-terminated Latin-1 text.
+                    match rest.iter().position(|&b| b == 0) {
+                        Some(end) => {
+                            self.advance();
+                            end + 1
+                        }
+                        None => rest.len(),
+                    }
+                }
+                HeaderField::Done => unreachable!("bug: loop stops at the end of the header"),
+            };
+            if field != HeaderField::Crc {
+                self.checksum.update(&rest[..taken]);
+            }
+            consumed += taken;
+            self.len += taken;
+            if self.len > MAX_HEADER_LEN {
+                return Err(Error::new(ErrorKind::GzipHeader, "header is too long"));
+            }
+            self.finish_field()?;
+        }
+        Ok(consumed)
+    }
+
+    /// Interprets the current field once all of its bytes have been read.
+    fn finish_field(&mut self) -> Result<()> {
+        let invalid = |message| Err(Error::new(ErrorKind::GzipHeader, message));
+        match self.field {
+            HeaderField::Fixed if self.filled == 10 => {
+                if self.buffer[..2] != MAGIC {
+                    return invalid("not a gzip stream");
+                }
+                if self.buffer[2] != METHOD_DEFLATE {
+                    return invalid("unsupported compression method");
+                }
+                self.flags = self.buffer[3];
+                if self.flags & FLAG_RESERVED != 0 {
+                    return invalid("reserved flag bits are set");
+                }
+                self.filled = 0;
+                self.advance();
+            }
+            HeaderField::ExtraLen if self.filled == 2 => {
+                let xlen = u16::from_le_bytes([self.buffer[0], self.buffer[1]]);
+                self.filled = 0;
+                self.field = HeaderField::Extra {
+                    remaining: usize::from(xlen),
+                };
+                if xlen == 0 {
+                    self.advance();
+                }
+            }
+            HeaderField::Extra { remaining: 0 } => self.advance(),
+            HeaderField::Crc if self.filled == 2 => {
+                if self.checksum.finish() as u16
+                    != u16::from_le_bytes([self.buffer[0], self.buffer[1]])
+                {
+                    return invalid("header CRC mismatch");
+                }
+                self.advance();
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Moves to the next field that the header flags say is present.
+    fn advance(&mut self) {
+        let flags = self.flags;
+        self.field = match self.field {
+            HeaderField::Fixed if flags & FLAG_EXTRA != 0 => HeaderField::ExtraLen,
+            HeaderField::Fixed | HeaderField::Extra { .. } if flags & FLAG_NAME != 0 => {
+                HeaderField::Name
+            }
+            HeaderField::Fixed | HeaderField::Extra { .. } | HeaderField::Name
+                if flags & FLAG_COMMENT != 0 =>
+            {
+                HeaderField::Comment
+            }
+            HeaderField::Crc | HeaderField::Done => HeaderField::Done,
+            _ if flags & FLAG_HCRC != 0 => HeaderField::Crc,
+            _ => HeaderField::Done,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::{Decoder, Encoder, compress};
+    use crate::png_types::{CompressionLevel, ErrorKind, Result};
+
+    fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+        let mut decoder = Decoder::new();
+        let mut decoded = Vec::new();
+        decoder.write(data, &mut decoded)?;
+        assert!(decoder.is_finished());
+        Ok(decoded)
+    }
+
+    #[test]
+    fn decode_known_member() {
+        // `printf 'Hello World!' | gzip -n`
+        let input = [
+            0x1F, 0x8B, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0xF3, 0x48, 0xCD, 0xC9,
+            0xC9, 0x57, 0x08, 0xCF, 0x2F, 0xCA, 0x49, 0x51, 0x04, 0x00, 0xA3, 0x1C, 0x29, 0x1C,
+            0x0C, 0x00, 0x00, 0x00,
+        ];
+        assert_eq!(decompress(&input).expect("infallible"), b"Hello World!");
+    }
+
+    #[test]
+    fn decoder_skips_optional_header_fields_split_at_any_byte() {
+        let input = b"optional gzip header fields ".repeat(40);
+        let member = compress(&input, CompressionLevel::Default).expect("infallible");
+        let mut header = member[..10].to_vec();
+        header[3] = 0x1E; // FHCRC | FEXTRA | FNAME | FCOMMENT
+        header.extend_from_slice(&[3, 0, b'a', b'b', b'c']);
+        header.extend_from_slice(b"name.txt\0comment\0");
+        let crc = crate::crc::calculate(&[&header]) as u16;
+        header.extend_from_slice(&crc.to_le_bytes());
+        let encoded = [header.as_slice(), &member[10..]].concat();
+
+        assert_eq!(decompress(&encoded).expect("infallible"), input);
+        for piece_len in [1, 3, 64] {
+            let mut decoder = Decoder::new();
+            let mut decoded = Vec::new();
+            for piece in encoded.chunks(piece_len) {
+                assert!(!decoder.is_finished());
+                decoder.write(piece, &mut decoded).expect("infallible");
+            }
+            assert!(decoder.is_finished());
+            assert_eq!(decoded, input);
+        }
+
+        let mut corrupt = encoded;
+        corrupt[12] ^= 1;
+        let error = decompress(&corrupt).expect_err("infallible");
+        assert_eq!(error.kind(), ErrorKind::GzipHeader);
+    }
+
+    #[test]
+    fn decoder_rejects_overlong_header() {
+        let member = compress(b"name", CompressionLevel::Fast).expect("infallible");
+        let mut header = member[..10].to_vec();
+        header[3] = 0x08; // FNAME
+        let name = [b'a'; 4096];
+        let mut decoder = Decoder::new();
+        let mut decoded = Vec::new();
+        decoder.write(&header, &mut decoded).expect("infallible");
+        let error = (0..64)
+            .find_map(|_| decoder.write(&name, &mut decoded).err())
+            .expect("infallible");
+        assert_eq!(error.kind(), ErrorKind::GzipHeader);
+        assert!(decoded.is_empty());
+    }
+
+    #[test]
+    fn encoder_matches_one_shot_compression() {
+        let input = b"streamed gzip ".repeat(10_000);
+        let mut encoded = Vec::new();
+        let mut encoder = Encoder::new(CompressionLevel::Fast, &mut encoded);
+        for piece in input.chunks(1000) {
+            encoder.write(piece, &mut encoded).expect("infallible");
+        }
+        encoder.finish(&mut encoded).expect("infallible");
+        assert_eq!(decompress(&encoded).expect("infallible"), input);
+        let one_shot = compress(&input, CompressionLevel::Fast).expect("infallible");
+        assert_eq!(encoded[..10], one_shot[..10]);
+        assert_eq!(encoded[encoded.len() - 8..], one_shot[one_shot.len() - 8..]);
+    }
+
+    #[test]
+    fn decoder_rejects_corrupt_trailer_and_header() {
+        let encoded = compress(b"trailer", CompressionLevel::Fast).expect("infallible");
+        for index in [encoded.len() - 8, encoded.len() - 1] {
+            let mut corrupt = encoded.clone();
+            corrupt[index] ^= 1;
+            let error = decompress(&corrupt).expect_err("infallible");
+            assert_eq!(error.kind(), ErrorKind::GzipTrailer);
+        }
+        for (index, value) in [(0, 0x1E), (2, 7), (3, 0x20)] {
+            let mut corrupt = encoded.clone();
+            corrupt[index] = value;
+            let error = decompress(&corrupt).expect_err("infallible");
+            assert_eq!(error.kind(), ErrorKind::GzipHeader, "{index}");
+        }
+    }
+}
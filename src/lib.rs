@@ -25,6 +25,9 @@
 //! # )?;
 //! let (spec, pixels) = nopng::decode_image(&png_bytes)?;
 //! let rgba = nopng::reformat_pixels(&spec.pixel_format, &pixels, &nopng::PixelFormat::Rgba8)?;
+//!
+//! // Or convert each row while decoding, skipping the native-format buffer
+//! let (spec, rgba) = nopng::decode_image_as(&png_bytes, &nopng::PixelFormat::Rgba8)?;
 //! # Ok::<(), nopng::Error>(())
 //! ```
 //!
@@ -112,19 +115,80 @@ extern crate alloc;
 
 mod adler32;
 mod chunk;
+#[cfg(feature = "compression")]
+pub mod compression;
 mod crc;
 mod deflate;
+#[cfg(feature = "compression")]
+mod gzip;
+mod pixel_color;
+mod pixel_quantize;
 mod pixel_reformat;
 mod png;
+mod png_animation;
 mod png_decode;
 mod png_encode;
+mod png_optimize;
+mod png_stream;
 mod png_types;
 mod zlib;
 
+pub use chunk::ChunkReader;
+pub use chunk::ChunkWriter;
+pub use chunk::RawChunk;
+pub use png::DecodedImage;
+pub use png::DecodedInto;
 pub use png::Error;
+pub use png::ErrorKind;
 pub use png::ImageSpec;
 pub use png::decode_image;
+pub use png::decode_image_as;
+pub use png::decode_image_as_with_options;
+pub use png::decode_image_into;
+pub use png::decode_image_into_with_options;
+pub use png::decode_image_with_options;
+pub use png::encode_animation;
 pub use png::encode_image;
+pub use png::encode_image_with_options;
 pub use png::inspect_image;
+pub use png::optimize_png;
+pub use png::reformat_image;
 pub use png::reformat_pixels;
+pub use png_animation::AnimationDecoder;
+pub use png_animation::AnimationFrame;
+pub use png_animation::FrameCompositor;
+pub use png_stream::DecodedRow;
+pub use png_stream::StreamDecoder;
+pub use png_stream::StreamEncoder;
+pub use png_types::AnimationControl;
+pub use png_types::AnimationOptions;
+pub use png_types::Background;
+pub use png_types::BlendOp;
+pub use png_types::Chromaticities;
+pub use png_types::Cicp;
+pub use png_types::CompressionLevel;
+pub use png_types::DecodeLimits;
+pub use png_types::DecodeOptions;
+pub use png_types::DisposeOp;
+pub use png_types::Dithering;
+pub use png_types::EncodeOptions;
+pub use png_types::FilterStrategy;
+pub use png_types::FrameControl;
+pub use png_types::IccProfile;
+pub use png_types::ImageOffset;
+pub use png_types::InternationalText;
+pub use png_types::LumaCoefficients;
+pub use png_types::Metadata;
+pub use png_types::OffsetUnit;
+pub use png_types::OptimizeOptions;
+pub use png_types::PhysicalDimensions;
+pub use png_types::PhysicalUnit;
 pub use png_types::PixelFormat;
+pub use png_types::ReformatOptions;
+pub use png_types::RenderingIntent;
+pub use png_types::SignificantBits;
+pub use png_types::SuggestedPalette;
+pub use png_types::SuggestedPaletteEntry;
+pub use png_types::TextEntry;
+pub use png_types::Timestamp;
+pub use png_types::TransferFunction;
@@ -0,0 +1,207 @@
+use alloc::vec::Vec;
+
+use crate::pixel_reformat::reformat;
+use crate::png_types::{
+    Error, ErrorKind, LumaCoefficients, PixelFormat, ReformatOptions, Result, TransferFunction,
+};
+
+/// Converts color to gray and composites alpha away as `options` asks, for a
+/// conversion from `src_fmt` to `dst_fmt`.
+///
+/// Returns 16-bit pixels with the channels of `dst_fmt` (gray or RGB, with
+/// alpha if `dst_fmt` keeps it), or `None` if the conversion does neither in
+/// a way that differs from [`reformat`].
+pub(crate) fn mix_colors(
+    src_fmt: &PixelFormat,
+    src: &[u8],
+    dst_fmt: &PixelFormat,
+    options: &ReformatOptions,
+) -> Result<Option<(PixelFormat, Vec<u8>)>> {
+    if options.transfer == TransferFunction::Gamma(0) {
+        return Err(Error::new(
+            ErrorKind::InvalidArgument,
+            "transfer function gamma must be non-zero",
+        ));
+    }
+    let to_gray = is_gray(dst_fmt) && !is_gray(src_fmt);
+    let weighted = to_gray
+        && (options.transfer != TransferFunction::Linear
+            || options.luma != LumaCoefficients::Bt601);
+    let composite = options.background.is_some() && has_alpha(src_fmt) && !has_alpha(dst_fmt);
+    if !weighted && !composite {
+        return Ok(None);
+    }
+
+    let table = linear_table(options.transfer);
+    let background = options
+        .background
+        .filter(|_| composite)
+        .map(|background| background.map(|sample| table[usize::from(sample)]));
+    let [kr, kg, kb] = match options.luma {
+        LumaCoefficients::Bt601 => [0.299, 0.587, 0.114],
+        LumaCoefficients::Bt709 => [0.2126, 0.7152, 0.0722],
+    };
+    let keep_alpha = has_alpha(dst_fmt);
+    let format = match (to_gray, keep_alpha) {
+        (true, true) => PixelFormat::GrayAlpha16Be,
+        (true, false) => PixelFormat::Gray16Be,
+        (false, true) => PixelFormat::Rgba16Be,
+        (false, false) => PixelFormat::Rgb16Be,
+    };
+
+    let rgba = reformat(src_fmt, src, &PixelFormat::Rgba16Be)?;
+    let (pixels, _) = rgba.as_chunks::<8>();
+    let mut out = Vec::with_capacity(pixels.len() * format.bytes_per_pixel());
+    for pixel in pixels {
+        let [r, g, b, alpha] =
+            core::array::from_fn(|i| u16::from_be_bytes([pixel[2 * i], pixel[2 * i + 1]]));
+        let mut linear = [r, g, b].map(|sample| table[usize::from(sample)]);
+        if let Some(background) = background {
+            // Alpha is coverage, so blending happens in linear light.
+            let coverage = f32::from(alpha) / f32::from(u16::MAX);
+            for (sample, background) in linear.iter_mut().zip(background) {
+                *sample = *sample * coverage + background * (1.0 - coverage);
+            }
+        }
+        if to_gray {
+            let luma = kr * linear[0] + kg * linear[1] + kb * linear[2];
+            out.extend_from_slice(&encode(&table, luma).to_be_bytes());
+        } else {
+            for sample in linear {
+                out.extend_from_slice(&encode(&table, sample).to_be_bytes());
+            }
+        }
+        if keep_alpha {
+            out.extend_from_slice(&alpha.to_be_bytes());
+        }
+    }
+    Ok(Some((format, out)))
+}
+
+fn is_gray(format: &PixelFormat) -> bool {
+    matches!(
+        format,
+        PixelFormat::Gray1
+            | PixelFormat::Gray2
+            | PixelFormat::Gray4
+            | PixelFormat::Gray8
+            | PixelFormat::Gray16Be
+            | PixelFormat::GrayAlpha8
+            | PixelFormat::GrayAlpha16Be
+    )
+}
+
+fn has_alpha(format: &PixelFormat) -> bool {
+    match format {
+        PixelFormat::GrayAlpha8
+        | PixelFormat::GrayAlpha16Be
+        | PixelFormat::Rgba8
+        | PixelFormat::Rgba16Be
+        | PixelFormat::Rgba8Premultiplied
+        | PixelFormat::Rgba16BePremultiplied => true,
+        PixelFormat::Indexed1 { trns, .. }
+        | PixelFormat::Indexed2 { trns, .. }
+        | PixelFormat::Indexed4 { trns, .. }
+        | PixelFormat::Indexed8 { trns, .. } => trns.is_some(),
+        _ => false,
+    }
+}
+
+/// Linear light intensity of every 16-bit sample value.
+fn linear_table(transfer: TransferFunction) -> Vec<f32> {
+    (0..=u16::MAX)
+        .map(|sample| {
+            let encoded = f64::from(sample) / f64::from(u16::MAX);
+            let linear = match transfer {
+                TransferFunction::Linear => encoded,
+                TransferFunction::Srgb if encoded <= 0.04045 => encoded / 12.92,
+                TransferFunction::Srgb => pow((encoded + 0.055) / 1.055, 2.4),
+                TransferFunction::Gamma(gamma) => pow(encoded, 100_000.0 / f64::from(gamma)),
+            };
+            linear as f32
+        })
+        .collect()
+}
+
+/// The 16-bit sample whose linear intensity in `table` is closest to
+/// `linear`.
+fn encode(table: &[f32], linear: f32) -> u16 {
+    let above = table.partition_point(|&value| value < linear);
+    let below = above.saturating_sub(1);
+    let above = above.min(table.len() - 1);
+    let closest = if linear - table[below] <= table[above] - linear {
+        below
+    } else {
+        above
+    };
+    closest as u16
+}
+
+/// `base` raised to `exponent`, for `base` in `0.0..=1.0`. `core` has no
+/// floating-point `powf`, so this goes through [`ln`] and [`exp`].
+fn pow(base: f64, exponent: f64) -> f64 {
+    if base <= 0.0 {
+        0.0
+    } else {
+        exp(exponent * ln(base))
+    }
+}
+
+/// Natural logarithm of a positive, normal `x`.
+fn ln(x: f64) -> f64 {
+    let bits = x.to_bits();
+    let mut exponent = ((bits >> 52) & 0x7FF) as i32 - 1023;
+    let mut mantissa = f64::from_bits((bits & 0x000F_FFFF_FFFF_FFFF) | 0x3FF0_0000_0000_0000);
+    if mantissa > core::f64::consts::SQRT_2 {
+        mantissa /= 2.0;
+        exponent += 1;
+    }
+    // ln(m) = 2 atanh(s) with s = (m - 1) / (m + 1), and |s| < 0.18.
+    let s = (mantissa - 1.0) / (mantissa + 1.0);
+    let mut term = s;
+    let mut sum = 0.0;
+    for k in 0..12 {
+        sum += term / f64::from(2 * k + 1);
+        term *= s * s;
+    }
+    2.0 * sum + f64::from(exponent) * core::f64::consts::LN_2
+}
+
+/// `e` raised to `x`, for `x <= 0`.
+fn exp(x: f64) -> f64 {
+    let k = (x / core::f64::consts::LN_2) as i32;
+    if k < -1000 {
+        return 0.0;
+    }
+    // e^x = 2^k e^r with |r| < ln 2.
+    let r = x - f64::from(k) * core::f64::consts::LN_2;
+    let mut term = 1.0;
+    let mut sum = 1.0;
+    for n in 1..24 {
+        term *= r / f64::from(n);
+        sum += term;
+    }
+    sum * f64::from_bits(((k + 1023) as u64) << 52)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pow_matches_known_values() {
+        for (base, exponent, expected) in [
+            (0.5, 2.0, 0.25),
+            (0.25, 0.5, 0.5),
+            (0.8, 2.4, 0.585_350_466_467),
+            (1.0, 2.2, 1.0),
+            (1.0 / 65535.0, 2.4, 2.757_173_342_886e-12),
+        ] {
+            let actual = pow(base, exponent);
+            assert!(
+                (actual - expected) / expected < 1e-9 && (expected - actual) / expected < 1e-9,
+                "{base}^{exponent} = {actual}, expected {expected}"
+            );
+        }
+    }
+}
@@ -0,0 +1,341 @@
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::pixel_reformat::validate_indexed_format;
+use crate::png_types::{Dithering, PixelFormat, Result};
+
+/// Error-diffusion kernel: `(dx, dy, weight)` taps that share out the
+/// quantization error of a pixel, and the sum of their weights.
+pub(crate) struct DiffusionKernel {
+    taps: &'static [(isize, usize, i32)],
+    divisor: i32,
+}
+
+pub(crate) const FLOYD_STEINBERG: DiffusionKernel = DiffusionKernel {
+    taps: &[(1, 0, 7), (-1, 1, 3), (0, 1, 5), (1, 1, 1)],
+    divisor: 16,
+};
+
+/// Spreads only 6/8 of the error, which keeps more contrast than
+/// Floyd–Steinberg.
+pub(crate) const ATKINSON: DiffusionKernel = DiffusionKernel {
+    taps: &[
+        (1, 0, 1),
+        (2, 0, 1),
+        (-1, 1, 1),
+        (0, 1, 1),
+        (1, 1, 1),
+        (0, 2, 1),
+    ],
+    divisor: 8,
+};
+
+/// 4×4 Bayer threshold matrix, with values in `0..16`.
+const BAYER: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+impl Dithering {
+    fn kernel(self) -> Option<&'static DiffusionKernel> {
+        match self {
+            Self::FloydSteinberg => Some(&FLOYD_STEINBERG),
+            Self::Atkinson => Some(&ATKINSON),
+            Self::None | Self::Bayer => None,
+        }
+    }
+}
+
+/// Bayer threshold for the pixel at `index` in rows of `width`, as an odd
+/// number of 32nds in `1..32`.
+fn bayer_threshold(index: usize, width: usize) -> u64 {
+    let (x, y) = (index % width.max(1), index / width.max(1));
+    u64::from(BAYER[y % 4][x % 4]) * 2 + 1
+}
+
+/// Reduces big-endian 16-bit samples, `N` per pixel in rows of `width`, to
+/// `depth` bits (at most 8), one byte per sample.
+///
+/// Values that are exactly representable at `depth` are kept as they are,
+/// whatever the dithering.
+pub(crate) fn reduce_depth<const N: usize>(
+    samples: &[u8],
+    width: usize,
+    depth: u8,
+    dithering: Dithering,
+) -> Vec<u8> {
+    let max = (1u32 << depth) - 1;
+    let step = u32::from(u16::MAX) / max;
+    let (pixels, _) = samples.as_chunks::<2>();
+    let (pixels, _) = pixels.as_chunks::<N>();
+    let pixels: Vec<[i32; N]> = pixels
+        .iter()
+        .map(|pixel| pixel.map(|sample| i32::from(u16::from_be_bytes(sample))))
+        .collect();
+    let nearest = |value: i32| (value as u32 * max + u32::from(u16::MAX) / 2) / u32::from(u16::MAX);
+    let levels: Vec<[u8; N]> = if let Some(kernel) = dithering.kernel() {
+        diffuse(&pixels, width, u16::MAX.into(), kernel, |wanted| {
+            let level = wanted.map(nearest);
+            (
+                level.map(|level| level as u8),
+                level.map(|level| (level * step) as i32),
+            )
+        })
+    } else if dithering == Dithering::Bayer {
+        pixels
+            .iter()
+            .enumerate()
+            .map(|(index, pixel)| {
+                let threshold = bayer_threshold(index, width) * u64::from(u16::MAX);
+                pixel.map(|value| {
+                    ((value as u64 * u64::from(max) * 32 + threshold) / (u64::from(u16::MAX) * 32))
+                        as u8
+                })
+            })
+            .collect()
+    } else {
+        pixels
+            .iter()
+            .map(|pixel| pixel.map(|value| nearest(value) as u8))
+            .collect()
+    };
+    levels.into_iter().flatten().collect()
+}
+
+/// Converts RGBA8 pixels to indices into the palette of the indexed `format`.
+///
+/// An empty palette is replaced by one built from the pixels: an exact one
+/// if there are few enough colors, and a median-cut approximation otherwise.
+/// `width` is only used for dithering. Returns the format with its palette
+/// filled in, and one index per pixel.
+pub(crate) fn to_indexed(
+    rgba: &[u8],
+    width: usize,
+    format: &PixelFormat,
+    dithering: Dithering,
+) -> Result<(PixelFormat, Vec<u8>)> {
+    let (palette, trns) = match format {
+        PixelFormat::Indexed1 { palette, trns }
+        | PixelFormat::Indexed2 { palette, trns }
+        | PixelFormat::Indexed4 { palette, trns }
+        | PixelFormat::Indexed8 { palette, trns } => (palette, trns.as_deref()),
+        _ => unreachable!("bug: target format is not indexed"),
+    };
+    let depth = format.bit_depth();
+    let (pixels, _) = rgba.as_chunks::<4>();
+
+    let (format, entries) = if palette.is_empty() {
+        let mut histogram = BTreeMap::new();
+        for pixel in pixels {
+            *histogram.entry(*pixel).or_insert(0u64) += 1;
+        }
+        let max_colors = 1 << depth;
+        if histogram.len() <= max_colors {
+            let (format, index) = exact_palette(histogram.into_keys().collect(), depth);
+            let data = pixels.iter().map(|pixel| index[pixel]).collect();
+            return Ok((format, data));
+        }
+        let entries = sorted_by_opacity(median_cut(histogram.into_iter().collect(), max_colors));
+        (indexed_format(depth, &entries), entries)
+    } else {
+        validate_indexed_format(depth, &[], palette, trns)?;
+        let (colors, _) = palette.as_chunks::<3>();
+        let entries = colors
+            .iter()
+            .enumerate()
+            .map(|(index, &[r, g, b])| {
+                let alpha = trns.and_then(|trns| trns.get(index).copied());
+                [r, g, b, alpha.unwrap_or(u8::MAX)]
+            })
+            .collect();
+        (format.clone(), entries)
+    };
+
+    let data = match dithering {
+        Dithering::None => {
+            let mut cache = BTreeMap::new();
+            pixels
+                .iter()
+                .map(|pixel| {
+                    *cache
+                        .entry(*pixel)
+                        .or_insert_with(|| nearest(&entries, pixel.map(i32::from)))
+                })
+                .collect()
+        }
+        Dithering::Bayer => {
+            // Spread the thresholds over the gap between palette colors, as
+            // if they formed an evenly spaced cube.
+            let side = (2..)
+                .find(|side| side * side * side >= entries.len())
+                .unwrap_or(2);
+            let gap = 255 / (side as i32 - 1);
+            pixels
+                .iter()
+                .enumerate()
+                .map(|(index, pixel)| {
+                    let offset = (bayer_threshold(index, width) as i32 - 16) * gap / 32;
+                    nearest(&entries, pixel.map(|value| i32::from(value) + offset))
+                })
+                .collect()
+        }
+        Dithering::FloydSteinberg | Dithering::Atkinson => {
+            let kernel = dithering.kernel().expect("bug: dithering diffuses errors");
+            let pixels: Vec<[i32; 4]> = pixels.iter().map(|pixel| pixel.map(i32::from)).collect();
+            diffuse(&pixels, width, 255, kernel, |wanted| {
+                let index = nearest(&entries, wanted);
+                (index, entries[usize::from(index)].map(i32::from))
+            })
+        }
+    };
+    Ok((format, data))
+}
+
+/// Quantizes `pixels`, rows of `width` pixels, spreading the difference
+/// between each wanted value and what `quantize` picks for it onto the
+/// neighbours given by `kernel`. Channels are clamped to `0..=max`.
+///
+/// `quantize` returns the output for a pixel and the channel values that
+/// output stands for.
+pub(crate) fn diffuse<const N: usize, T>(
+    pixels: &[[i32; N]],
+    width: usize,
+    max: i32,
+    kernel: &DiffusionKernel,
+    mut quantize: impl FnMut([i32; N]) -> (T, [i32; N]),
+) -> Vec<T> {
+    // Errors are kept scaled by the divisor for the rows the kernel reaches.
+    let rows = kernel.taps.iter().map(|&(_, dy, _)| dy).max().unwrap_or(0) + 1;
+    let mut errors = vec![[0; N]; rows * width];
+    let mut out = Vec::with_capacity(pixels.len());
+    for (y, row) in pixels.chunks(width.max(1)).enumerate() {
+        for (x, pixel) in row.iter().enumerate() {
+            let error = core::mem::replace(&mut errors[(y % rows) * width + x], [0; N]);
+            let wanted: [i32; N] = core::array::from_fn(|channel| {
+                (pixel[channel] + error[channel] / kernel.divisor).clamp(0, max)
+            });
+            let (value, actual) = quantize(wanted);
+            out.push(value);
+            for &(dx, dy, weight) in kernel.taps {
+                let Some(target_x) = x.checked_add_signed(dx).filter(|&x| x < width) else {
+                    continue;
+                };
+                let target = &mut errors[((y + dy) % rows) * width + target_x];
+                for channel in 0..N {
+                    target[channel] += (wanted[channel] - actual[channel]) * weight;
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Reduces the colors of a histogram to at most `max_colors` by repeatedly
+/// splitting the box of colors with the widest channel range at its
+/// pixel-weighted median. Each box becomes its weighted average color.
+fn median_cut(histogram: Vec<([u8; 4], u64)>, max_colors: usize) -> Vec<[u8; 4]> {
+    let mut boxes = vec![histogram];
+    while boxes.len() < max_colors {
+        let widest = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, colors)| colors.len() > 1)
+            .map(|(index, colors)| {
+                let (channel, range) = widest_channel(colors);
+                let count: u64 = colors.iter().map(|&(_, count)| count).sum();
+                (index, channel, u64::from(range) * count)
+            })
+            .max_by_key(|&(_, _, score)| score);
+        let Some((index, channel, _)) = widest else {
+            break;
+        };
+        let mut colors = boxes.swap_remove(index);
+        colors.sort_unstable_by_key(|(color, _)| color[channel]);
+        let total: u64 = colors.iter().map(|&(_, count)| count).sum();
+        let mut seen = 0;
+        let median = colors
+            .iter()
+            .position(|&(_, count)| {
+                seen += count;
+                seen * 2 >= total
+            })
+            .unwrap_or(0);
+        let upper = colors.split_off((median + 1).clamp(1, colors.len() - 1));
+        boxes.push(colors);
+        boxes.push(upper);
+    }
+    boxes.iter().map(|colors| average(colors)).collect()
+}
+
+fn widest_channel(colors: &[([u8; 4], u64)]) -> (usize, u8) {
+    (0..4)
+        .map(|channel| {
+            let values = colors.iter().map(|(color, _)| color[channel]);
+            let range = values.clone().max().unwrap_or(0) - values.min().unwrap_or(0);
+            (channel, range)
+        })
+        .max_by_key(|&(_, range)| range)
+        .expect("bug: colors have four channels")
+}
+
+fn average(colors: &[([u8; 4], u64)]) -> [u8; 4] {
+    let total: u64 = colors.iter().map(|&(_, count)| count).sum();
+    core::array::from_fn(|channel| {
+        let sum: u64 = colors
+            .iter()
+            .map(|&(color, count)| u64::from(color[channel]) * count)
+            .sum();
+        ((sum + total / 2) / total) as u8
+    })
+}
+
+/// Index of the palette entry closest to `color` in RGBA space.
+fn nearest(entries: &[[u8; 4]], color: [i32; 4]) -> u8 {
+    let distance = |entry: &[u8; 4]| -> i32 {
+        (0..4)
+            .map(|channel| (i32::from(entry[channel]) - color[channel]).pow(2))
+            .sum()
+    };
+    entries
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, entry)| distance(entry))
+        .map(|(index, _)| index as u8)
+        .expect("bug: palette is not empty")
+}
+
+/// Builds an indexed format at `depth` whose palette holds exactly the
+/// distinct `colors`, and the index of each color in it.
+pub(crate) fn exact_palette(
+    colors: Vec<[u8; 4]>,
+    depth: u8,
+) -> (PixelFormat, BTreeMap<[u8; 4], u8>) {
+    let entries = sorted_by_opacity(colors);
+    let index = entries
+        .iter()
+        .enumerate()
+        .map(|(index, color)| (*color, index as u8))
+        .collect();
+    (indexed_format(depth, &entries), index)
+}
+
+/// Moves translucent entries first, so that `tRNS` can stop at the last of
+/// them.
+fn sorted_by_opacity(mut entries: Vec<[u8; 4]>) -> Vec<[u8; 4]> {
+    entries.sort_by_key(|color| color[3] == u8::MAX);
+    entries
+}
+
+fn indexed_format(depth: u8, entries: &[[u8; 4]]) -> PixelFormat {
+    let palette = entries.iter().flat_map(|&[r, g, b, _]| [r, g, b]).collect();
+    let trns: Vec<u8> = entries
+        .iter()
+        .map(|color| color[3])
+        .take_while(|&alpha| alpha != u8::MAX)
+        .collect();
+    let trns = (!trns.is_empty()).then_some(trns);
+    match depth {
+        1 => PixelFormat::Indexed1 { palette, trns },
+        2 => PixelFormat::Indexed2 { palette, trns },
+        4 => PixelFormat::Indexed4 { palette, trns },
+        _ => PixelFormat::Indexed8 { palette, trns },
+    }
+}
@@ -1,6 +1,8 @@
 use alloc::vec::Vec;
 
-use crate::png_types::{Error, PixelFormat, Result};
+use crate::pixel_color::mix_colors;
+use crate::pixel_quantize::{reduce_depth, to_indexed};
+use crate::png_types::{Dithering, Error, ErrorKind, PixelFormat, ReformatOptions, Result};
 
 pub(crate) fn reformat(
     src_fmt: &PixelFormat,
@@ -11,10 +13,33 @@ pub(crate) fn reformat(
     if src_fmt == dst_fmt {
         return Ok(src.to_vec());
     }
+    // Premultiplied samples only change depth between the two premultiplied
+    // formats; anything else starts from straight alpha.
+    match (src_fmt, dst_fmt) {
+        (PixelFormat::Rgba8Premultiplied, PixelFormat::Rgba16BePremultiplied) => {
+            return to_rgba16be(&PixelFormat::Rgba8, src);
+        }
+        (PixelFormat::Rgba16BePremultiplied, PixelFormat::Rgba8Premultiplied) => {
+            return to_rgba8(&PixelFormat::Rgba16Be, src);
+        }
+        (PixelFormat::Rgba8Premultiplied, _) => {
+            return reformat(&PixelFormat::Rgba8, &unpremultiply_rgba8(src), dst_fmt);
+        }
+        (PixelFormat::Rgba16BePremultiplied, _) => {
+            return reformat(
+                &PixelFormat::Rgba16Be,
+                &unpremultiply_rgba16be(src),
+                dst_fmt,
+            );
+        }
+        _ => {}
+    }
     // Route through RGBA8 or RGBA16Be intermediate for simplicity.
     match dst_fmt {
         PixelFormat::Rgba8 => to_rgba8(src_fmt, src),
         PixelFormat::Rgba16Be => to_rgba16be(src_fmt, src),
+        PixelFormat::Rgba8Premultiplied => Ok(premultiply_rgba8(&to_rgba8(src_fmt, src)?)),
+        PixelFormat::Rgba16BePremultiplied => Ok(premultiply_rgba16be(&to_rgba16be(src_fmt, src)?)),
         PixelFormat::Rgb8 => to_rgb8(src_fmt, src),
         PixelFormat::Rgb16Be => to_rgb16be(src_fmt, src),
         PixelFormat::Gray1
@@ -27,9 +52,95 @@ pub(crate) fn reformat(
         PixelFormat::Indexed1 { .. }
         | PixelFormat::Indexed2 { .. }
         | PixelFormat::Indexed4 { .. }
-        | PixelFormat::Indexed8 { .. } => Err(Error::Unsupported(
-            "reformatting to indexed format is not supported".into(),
-        )),
+        | PixelFormat::Indexed8 { .. } => {
+            check_target(dst_fmt)?;
+            let rgba = reformat(src_fmt, src, &PixelFormat::Rgba8)?;
+            to_indexed(&rgba, 0, dst_fmt, Dithering::None).map(|(_, indices)| indices)
+        }
+    }
+}
+
+/// Rejects a `dst_fmt` that [`reformat`] cannot convert to, whatever the
+/// pixels: an indexed format without a palette.
+pub(crate) fn check_target(dst_fmt: &PixelFormat) -> Result<()> {
+    match dst_fmt {
+        PixelFormat::Indexed1 { palette, .. }
+        | PixelFormat::Indexed2 { palette, .. }
+        | PixelFormat::Indexed4 { palette, .. }
+        | PixelFormat::Indexed8 { palette, .. }
+            if palette.is_empty() =>
+        {
+            Err(Error::new(
+                ErrorKind::InvalidArgument,
+                "an indexed target format needs a palette; use reformat_image to build one",
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Like [`reformat`], for pixels in rows of `width`, applying `options`. An
+/// indexed `dst_fmt` with an empty palette gets one built from the pixels;
+/// the returned format carries it.
+pub(crate) fn reformat_with_options(
+    src_fmt: &PixelFormat,
+    src: &[u8],
+    width: u32,
+    dst_fmt: &PixelFormat,
+    options: &ReformatOptions,
+) -> Result<(PixelFormat, Vec<u8>)> {
+    if let Some((mixed_fmt, mixed)) = mix_colors(src_fmt, src, dst_fmt, options)? {
+        let options = ReformatOptions {
+            dithering: options.dithering,
+            ..Default::default()
+        };
+        return reformat_with_options(&mixed_fmt, &mixed, width, dst_fmt, &options);
+    }
+    match dst_fmt {
+        PixelFormat::Indexed1 { .. }
+        | PixelFormat::Indexed2 { .. }
+        | PixelFormat::Indexed4 { .. }
+        | PixelFormat::Indexed8 { .. }
+            if src_fmt != dst_fmt =>
+        {
+            let rgba = reformat(src_fmt, src, &PixelFormat::Rgba8)?;
+            to_indexed(&rgba, width as usize, dst_fmt, options.dithering)
+        }
+        _ if options.dithering != Dithering::None
+            && dst_fmt.bit_depth() < 16
+            && src_fmt != dst_fmt =>
+        {
+            // Convert at 16 bits first, then dither down to the target depth.
+            let width = width as usize;
+            let depth = dst_fmt.bit_depth();
+            let dithering = options.dithering;
+            let data = match dst_fmt {
+                PixelFormat::GrayAlpha8 => {
+                    let wide = reformat(src_fmt, src, &PixelFormat::GrayAlpha16Be)?;
+                    reduce_depth::<2>(&wide, width, depth, dithering)
+                }
+                PixelFormat::Rgb8 => {
+                    let wide = reformat(src_fmt, src, &PixelFormat::Rgb16Be)?;
+                    reduce_depth::<3>(&wide, width, depth, dithering)
+                }
+                PixelFormat::Rgba8 => {
+                    let wide = reformat(src_fmt, src, &PixelFormat::Rgba16Be)?;
+                    reduce_depth::<4>(&wide, width, depth, dithering)
+                }
+                PixelFormat::Rgba8Premultiplied => {
+                    // Dithering premultiplied samples could round a color
+                    // above its alpha, so dither straight ones instead.
+                    let wide = reformat(src_fmt, src, &PixelFormat::Rgba16Be)?;
+                    premultiply_rgba8(&reduce_depth::<4>(&wide, width, depth, dithering))
+                }
+                _ => {
+                    let wide = reformat(src_fmt, src, &PixelFormat::Gray16Be)?;
+                    reduce_depth::<1>(&wide, width, depth, dithering)
+                }
+            };
+            Ok((dst_fmt.clone(), data))
+        }
+        _ => Ok((dst_fmt.clone(), reformat(src_fmt, src, dst_fmt)?)),
     }
 }
 
@@ -38,6 +149,10 @@ pub(crate) fn reformat(
 fn to_rgba8(src_fmt: &PixelFormat, src: &[u8]) -> Result<Vec<u8>> {
     match src_fmt {
         PixelFormat::Rgba8 => Ok(src.to_vec()),
+        PixelFormat::Rgba8Premultiplied => Ok(unpremultiply_rgba8(src)),
+        PixelFormat::Rgba16BePremultiplied => {
+            to_rgba8(&PixelFormat::Rgba16Be, &unpremultiply_rgba16be(src))
+        }
         PixelFormat::Rgba16Be => {
             let (chunks, remainder) = src.as_chunks::<8>();
             debug_assert!(remainder.is_empty());
@@ -108,7 +223,7 @@ fn to_rgba8(src_fmt: &PixelFormat, src: &[u8]) -> Result<Vec<u8>> {
             for &index in src {
                 let rgb_offset = usize::from(index) * 3;
                 if rgb_offset + 3 > palette.len() {
-                    return Err(Error::InvalidData("palette index out of range".into()));
+                    return Err(ErrorKind::PaletteIndexOutOfRange.into());
                 }
                 let r = palette[rgb_offset];
                 let g = palette[rgb_offset + 1];
@@ -130,6 +245,7 @@ fn to_rgba8(src_fmt: &PixelFormat, src: &[u8]) -> Result<Vec<u8>> {
 fn to_rgba16be(src_fmt: &PixelFormat, src: &[u8]) -> Result<Vec<u8>> {
     match src_fmt {
         PixelFormat::Rgba16Be => Ok(src.to_vec()),
+        PixelFormat::Rgba16BePremultiplied => Ok(unpremultiply_rgba16be(src)),
         PixelFormat::Rgba8 => {
             let (chunks, _) = src.as_chunks::<4>();
             let mut out = Vec::with_capacity(chunks.len() * 8);
@@ -247,6 +363,14 @@ fn to_gray(src_fmt: &PixelFormat, src: &[u8], dst_depth: u8) -> Result<Vec<u8>>
                 .collect());
         }
     }
+    if dst_depth < 8 {
+        let max = (1u16 << dst_depth) - 1;
+        let gray8 = to_gray(src_fmt, src, 8)?;
+        return Ok(gray8
+            .iter()
+            .map(|&sample| ((u16::from(sample) * max + 127) / 255) as u8)
+            .collect());
+    }
     if dst_depth == 8 {
         let rgba8 = to_rgba8(src_fmt, src)?;
         let (chunks, _) = rgba8.as_chunks::<4>();
@@ -267,8 +391,9 @@ fn to_gray(src_fmt: &PixelFormat, src: &[u8], dst_depth: u8) -> Result<Vec<u8>>
         }
         return Ok(out);
     }
-    Err(Error::Unsupported(
-        "unsupported gray bit depth conversion".into(),
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "unsupported gray bit depth conversion",
     ))
 }
 
@@ -312,6 +437,74 @@ fn to_grayalpha16be(src_fmt: &PixelFormat, src: &[u8]) -> Result<Vec<u8>> {
 
 // ── Helper functions ────────────────────────────────────────────────────
 
+/// Scales the color samples of RGBA8 pixels by their alpha, rounding to the
+/// nearest value.
+fn premultiply_rgba8(src: &[u8]) -> Vec<u8> {
+    let (chunks, _) = src.as_chunks::<4>();
+    let mut out = Vec::with_capacity(src.len());
+    for &[r, g, b, a] in chunks {
+        for sample in [r, g, b] {
+            out.push(((u32::from(sample) * u32::from(a) + 127) / 255) as u8);
+        }
+        out.push(a);
+    }
+    out
+}
+
+/// Undoes [`premultiply_rgba8`], rounding to the nearest value. Samples
+/// greater than alpha are clamped, and fully transparent pixels become
+/// transparent black.
+fn unpremultiply_rgba8(src: &[u8]) -> Vec<u8> {
+    let (chunks, _) = src.as_chunks::<4>();
+    let mut out = Vec::with_capacity(src.len());
+    for &[r, g, b, a] in chunks {
+        for sample in [r, g, b] {
+            let straight = match u32::from(a) {
+                0 => 0,
+                a => ((u32::from(sample) * 255 + a / 2) / a).min(255),
+            };
+            out.push(straight as u8);
+        }
+        out.push(a);
+    }
+    out
+}
+
+/// 16-bit counterpart of [`premultiply_rgba8`].
+fn premultiply_rgba16be(src: &[u8]) -> Vec<u8> {
+    let (chunks, _) = src.as_chunks::<8>();
+    let mut out = Vec::with_capacity(src.len());
+    for chunk in chunks {
+        let a = u32::from(u16::from_be_bytes([chunk[6], chunk[7]]));
+        for sample in chunk[..6].as_chunks::<2>().0 {
+            let sample = u32::from(u16::from_be_bytes(*sample));
+            let premultiplied = (u64::from(sample) * u64::from(a) + 32767) / 65535;
+            out.extend_from_slice(&(premultiplied as u16).to_be_bytes());
+        }
+        out.extend_from_slice(&chunk[6..]);
+    }
+    out
+}
+
+/// 16-bit counterpart of [`unpremultiply_rgba8`].
+fn unpremultiply_rgba16be(src: &[u8]) -> Vec<u8> {
+    let (chunks, _) = src.as_chunks::<8>();
+    let mut out = Vec::with_capacity(src.len());
+    for chunk in chunks {
+        let a = u64::from(u16::from_be_bytes([chunk[6], chunk[7]]));
+        for sample in chunk[..6].as_chunks::<2>().0 {
+            let sample = u64::from(u16::from_be_bytes(*sample));
+            let straight = match a {
+                0 => 0,
+                a => ((sample * 65535 + a / 2) / a).min(65535),
+            };
+            out.extend_from_slice(&(straight as u16).to_be_bytes());
+        }
+        out.extend_from_slice(&chunk[6..]);
+    }
+    out
+}
+
 pub(crate) fn scale_sample_to_u8(sample: u16, bit_depth: u8) -> u8 {
     if bit_depth == 8 {
         sample as u8
@@ -320,10 +513,6 @@ pub(crate) fn scale_sample_to_u8(sample: u16, bit_depth: u8) -> u8 {
     }
 }
 
-pub(crate) fn downsample_u16(sample: u16) -> u8 {
-    (sample >> 8) as u8
-}
-
 pub(crate) fn upscale_u8_to_u16(sample: u8) -> u16 {
     u16::from(sample) * 257
 }
@@ -345,14 +534,16 @@ pub(crate) fn validate_format_and_data(
     height: u32,
 ) -> Result<()> {
     if width == 0 || height == 0 {
-        return Err(Error::InvalidData(
-            "image dimensions must be non-zero".into(),
+        return Err(Error::new(
+            ErrorKind::InvalidArgument,
+            "image dimensions must be non-zero",
         ));
     }
     let expected = format.data_len(width, height);
     if data.len() != expected {
-        return Err(Error::InvalidData(
-            "image size does not match pixel buffer length".into(),
+        return Err(Error::new(
+            ErrorKind::InvalidArgument,
+            "image size does not match pixel buffer length",
         ));
     }
     match format {
@@ -371,63 +562,86 @@ pub(crate) fn validate_format_and_data(
         PixelFormat::Indexed8 { palette, trns, .. } => {
             validate_indexed_format(8, data, palette, trns.as_deref())?;
         }
+        PixelFormat::Rgba8Premultiplied => {
+            let (pixels, _) = data.as_chunks::<4>();
+            validate_premultiplied(pixels.iter().map(|pixel| pixel.map(u16::from)))?;
+        }
+        PixelFormat::Rgba16BePremultiplied => {
+            let (pixels, _) = data.as_chunks::<8>();
+            validate_premultiplied(pixels.iter().map(|pixel| {
+                core::array::from_fn(|i| u16::from_be_bytes([pixel[2 * i], pixel[2 * i + 1]]))
+            }))?;
+        }
         _ => {}
     }
     Ok(())
 }
 
+/// Rejects premultiplied pixels with a color sample greater than alpha.
+fn validate_premultiplied(mut pixels: impl Iterator<Item = [u16; 4]>) -> Result<()> {
+    if pixels.all(|[r, g, b, a]| r <= a && g <= a && b <= a) {
+        Ok(())
+    } else {
+        Err(Error::new(
+            ErrorKind::InvalidArgument,
+            "premultiplied color sample exceeds alpha",
+        ))
+    }
+}
+
 fn validate_sample_range(samples: &[u8], bit_depth: u8) -> Result<()> {
     let max = (1u16 << bit_depth) - 1;
     if samples.iter().all(|&s| u16::from(s) <= max) {
         Ok(())
     } else {
-        Err(Error::InvalidData(
-            alloc::format!("sample does not fit in {bit_depth} bits").into(),
+        Err(Error::new(
+            ErrorKind::InvalidArgument,
+            "sample does not fit in the bit depth",
         ))
     }
 }
 
-fn validate_indexed_format(
+pub(crate) fn validate_indexed_format(
     bit_depth: u8,
     indices: &[u8],
     palette: &[u8],
     trns: Option<&[u8]>,
 ) -> Result<()> {
     if palette.is_empty() || !palette.len().is_multiple_of(3) {
-        return Err(Error::InvalidData(
-            "indexed palette length must be a non-zero multiple of 3".into(),
+        return Err(Error::new(
+            ErrorKind::InvalidArgument,
+            "indexed palette length must be a non-zero multiple of 3",
         ));
     }
     let palette_len = palette.len() / 3;
     if palette_len > 256 {
-        return Err(Error::InvalidData(
-            "indexed palette length must be in 1..=256".into(),
+        return Err(Error::new(
+            ErrorKind::InvalidArgument,
+            "indexed palette length must be in 1..=256",
         ));
     }
     if let Some(trns) = trns
         && trns.len() > palette_len
     {
-        return Err(Error::InvalidData(
-            "indexed transparency table is longer than the palette".into(),
+        return Err(Error::new(
+            ErrorKind::InvalidArgument,
+            "indexed transparency table is longer than the palette",
         ));
     }
     let capacity = 1usize << bit_depth;
     if palette_len > capacity {
-        return Err(Error::InvalidData(
-            alloc::format!(
-                "palette of size {} does not fit in {}-bit indexed pixels",
-                palette_len,
-                bit_depth
-            )
-            .into(),
+        return Err(Error::new(
+            ErrorKind::InvalidArgument,
+            "palette does not fit in the indexed bit depth",
         ));
     }
     if !indices
         .iter()
         .all(|&index| usize::from(index) < palette_len)
     {
-        return Err(Error::InvalidData(
-            "indexed pixel buffer contains an out-of-range palette index".into(),
+        return Err(Error::new(
+            ErrorKind::PaletteIndexOutOfRange,
+            "indexed pixel buffer contains an out-of-range palette index",
         ));
     }
     Ok(())
@@ -1,10 +1,17 @@
 use alloc::vec::Vec;
 
 use crate::chunk::{IdatChunk, IendChunk, IhdrChunk, PlteChunk, TrnsChunk};
-use crate::pixel_reformat::{reformat, validate_format_and_data};
+use crate::pixel_reformat::{reformat, reformat_with_options, validate_format_and_data};
+use crate::png_encode::{
+    append_metadata_chunks, append_palette_metadata_chunks, validate_metadata,
+};
 
-use crate::png_types::Result;
-pub use crate::png_types::{Error, PixelFormat};
+use crate::png_animation::AnimationFrame;
+use crate::png_types::{
+    AnimationOptions, DecodeOptions, EncodeOptions, Metadata, OptimizeOptions, ReformatOptions,
+    Result,
+};
+pub use crate::png_types::{Error, ErrorKind, PixelFormat};
 
 pub(crate) const PNG_SIGNATURE: [u8; 8] = [0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A];
 pub(crate) const ADAM7_PASSES: [Adam7Pass; 7] = [
@@ -64,7 +71,7 @@ pub(crate) const ADAM7_PASSES: [Adam7Pass; 7] = [
 /// # Constraints
 ///
 /// `width` and `height` must both be non-zero. [`encode_image`] and
-/// [`decode_image`] return [`Error::InvalidData`] when given a zero dimension.
+/// [`decode_image`] return an error when given a zero dimension.
 /// The `pixel_format` (and its embedded palette/trns for indexed variants) is
 /// likewise validated at encode/decode time.
 ///
@@ -128,7 +135,7 @@ impl ImageSpec {
         self.pixel_format.data_len(self.width, self.height)
     }
 
-    fn from_header_and_ancillary(
+    pub(crate) fn from_header_and_ancillary(
         header: &crate::png_decode::PngHeader,
         ancillary: &crate::png_decode::AncillaryChunks,
     ) -> Self {
@@ -142,6 +149,35 @@ impl ImageSpec {
     }
 }
 
+/// An image returned by [`decode_image_with_options`] or
+/// [`decode_image_as_with_options`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodedImage {
+    /// Dimensions and pixel format of the image: the native format, or the
+    /// one requested from [`decode_image_as_with_options`].
+    pub spec: ImageSpec,
+    /// Pixel data in `spec.pixel_format`.
+    pub pixels: Vec<u8>,
+    /// Color-space information and other ancillary chunks.
+    pub metadata: Metadata,
+    /// Problems worked around while decoding. Always empty unless
+    /// [`DecodeOptions::lenient`] is set.
+    pub warnings: Vec<Error>,
+}
+
+/// An image returned by [`decode_image_into_with_options`], whose pixels went
+/// into the caller's buffer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodedInto {
+    /// Dimensions and native pixel format of the image.
+    pub spec: ImageSpec,
+    /// Color-space information and other ancillary chunks.
+    pub metadata: Metadata,
+    /// Problems worked around while decoding. Always empty unless
+    /// [`DecodeOptions::lenient`] is set.
+    pub warnings: Vec<Error>,
+}
+
 /// Reads PNG metadata from the PNG signature, `IHDR`, `PLTE`, and `tRNS`
 /// chunks, stopping at the first `IDAT`.
 ///
@@ -154,7 +190,7 @@ pub fn inspect_image(bytes: &[u8]) -> Result<ImageSpec> {
 }
 
 /// Determines the decode output `PixelFormat` from header + ancillary chunks.
-fn pixel_format_from_header(
+pub(crate) fn pixel_format_from_header(
     header: &crate::png_decode::PngHeader,
     ancillary: &crate::png_decode::AncillaryChunks,
 ) -> PixelFormat {
@@ -243,7 +279,37 @@ fn pixel_format_from_header(
 /// # Ok::<(), nopng::Error>(())
 /// ```
 pub fn decode_image(bytes: &[u8]) -> Result<(ImageSpec, Vec<u8>)> {
-    let (header, _ancillary, native_format, data) = crate::png_decode::decode_png(bytes)?;
+    let image = decode_image_with_options(bytes, &DecodeOptions::default())?;
+    Ok((image.spec, image.pixels))
+}
+
+/// Decodes PNG bytes like [`decode_image`], using `options`.
+///
+/// Use [`DecodeOptions::limits`] to bound the memory and work spent on
+/// untrusted input. Exceeding a limit returns an [`ErrorKind::LimitExceeded`]
+/// error. Set [`DecodeOptions::lenient`] to recover from damaged files; the
+/// problems worked around are listed in [`DecodedImage::warnings`].
+///
+/// ```
+/// # let png_bytes = nopng::encode_image(
+/// #     &nopng::ImageSpec::new(4, 4, nopng::PixelFormat::Gray8),
+/// #     &[0; 16],
+/// # )?;
+/// let options = nopng::DecodeOptions {
+///     limits: nopng::DecodeLimits {
+///         max_pixels: 8,
+///         ..Default::default()
+///     },
+///     ..Default::default()
+/// };
+/// let error = nopng::decode_image_with_options(&png_bytes, &options).unwrap_err();
+/// assert_eq!(error.kind(), nopng::ErrorKind::LimitExceeded);
+/// # Ok::<(), nopng::Error>(())
+/// ```
+pub fn decode_image_with_options(bytes: &[u8], options: &DecodeOptions) -> Result<DecodedImage> {
+    let mut warnings = Vec::new();
+    let (header, ancillary, native_format, data) =
+        crate::png_decode::decode_png(bytes, options, &mut warnings)?;
     let spec = ImageSpec {
         width: header.width,
         height: header.height,
@@ -251,7 +317,149 @@ pub fn decode_image(bytes: &[u8]) -> Result<(ImageSpec, Vec<u8>)> {
         interlaced: header.interlace_method == 1,
     };
     validate_format_and_data(&spec.pixel_format, &data, spec.width, spec.height)?;
-    Ok((spec, data))
+    Ok(DecodedImage {
+        spec,
+        pixels: data,
+        metadata: ancillary.metadata,
+        warnings,
+    })
+}
+
+/// Decodes PNG bytes directly into the requested [`PixelFormat`].
+///
+/// This gives the same result as [`decode_image`] followed by
+/// [`reformat_pixels`], but rows are converted a few at a time as they are
+/// unfiltered, so no image-sized buffer in the native format is allocated. The returned
+/// [`ImageSpec`] carries `format` as its pixel format.
+///
+/// Converting to an indexed format maps every pixel to the closest entry of
+/// the palette in `format`, which must not be empty.
+///
+/// # Examples
+///
+/// ```
+/// # let png_bytes = nopng::encode_image(
+/// #     &nopng::ImageSpec::new(1, 1, nopng::PixelFormat::Gray8),
+/// #     &[128],
+/// # )?;
+/// let (spec, rgba) = nopng::decode_image_as(&png_bytes, &nopng::PixelFormat::Rgba8)?;
+/// assert_eq!(spec.pixel_format, nopng::PixelFormat::Rgba8);
+/// assert_eq!(rgba, [128, 128, 128, 255]);
+/// # Ok::<(), nopng::Error>(())
+/// ```
+pub fn decode_image_as(bytes: &[u8], format: &PixelFormat) -> Result<(ImageSpec, Vec<u8>)> {
+    let image = decode_image_as_with_options(bytes, format, &DecodeOptions::default())?;
+    Ok((image.spec, image.pixels))
+}
+
+/// Decodes PNG bytes like [`decode_image_as`], using `options`.
+///
+/// See [`decode_image_with_options`] for what the options control.
+///
+/// ```
+/// # let png_bytes = nopng::encode_image(
+/// #     &nopng::ImageSpec::new(4, 4, nopng::PixelFormat::Gray8),
+/// #     &[0; 16],
+/// # )?;
+/// let options = nopng::DecodeOptions {
+///     limits: nopng::DecodeLimits {
+///         max_pixels: 8,
+///         ..Default::default()
+///     },
+///     ..Default::default()
+/// };
+/// let error = nopng::decode_image_as_with_options(&png_bytes, &nopng::PixelFormat::Rgba8, &options)
+///     .unwrap_err();
+/// assert_eq!(error.kind(), nopng::ErrorKind::LimitExceeded);
+/// # Ok::<(), nopng::Error>(())
+/// ```
+pub fn decode_image_as_with_options(
+    bytes: &[u8],
+    format: &PixelFormat,
+    options: &DecodeOptions,
+) -> Result<DecodedImage> {
+    let mut warnings = Vec::new();
+    let (header, ancillary, data) =
+        crate::png_decode::decode_png_as(bytes, format, options, &mut warnings)?;
+    let spec = ImageSpec {
+        width: header.width,
+        height: header.height,
+        pixel_format: format.clone(),
+        interlaced: header.interlace_method == 1,
+    };
+    validate_format_and_data(&spec.pixel_format, &data, spec.width, spec.height)?;
+    Ok(DecodedImage {
+        spec,
+        pixels: data,
+        metadata: ancillary.metadata,
+        warnings,
+    })
+}
+
+/// Decodes PNG bytes into a caller-provided pixel buffer.
+///
+/// Pixels are written in the same native format that [`decode_image`] returns,
+/// and `out` must be exactly [`ImageSpec::data_len`] bytes for the spec that
+/// [`inspect_image`] reports. The image is decoded row by row, so no buffer the
+/// size of the image is allocated besides `out`.
+///
+/// Returns an [`ErrorKind::InvalidArgument`] error if `out` has the wrong
+/// length.
+///
+/// # Examples
+///
+/// ```
+/// # let png_bytes = nopng::encode_image(
+/// #     &nopng::ImageSpec::new(2, 1, nopng::PixelFormat::Rgb8),
+/// #     &[255, 0, 0, 0, 0, 255],
+/// # )?;
+/// let spec = nopng::inspect_image(&png_bytes)?;
+/// let mut pixels = vec![0; spec.data_len()];
+/// let spec = nopng::decode_image_into(&png_bytes, &mut pixels)?;
+/// assert_eq!(spec.pixel_format, nopng::PixelFormat::Rgb8);
+/// assert_eq!(pixels, [255, 0, 0, 0, 0, 255]);
+/// # Ok::<(), nopng::Error>(())
+/// ```
+pub fn decode_image_into(bytes: &[u8], out: &mut [u8]) -> Result<ImageSpec> {
+    let decoded = decode_image_into_with_options(bytes, out, &DecodeOptions::default())?;
+    Ok(decoded.spec)
+}
+
+/// Decodes PNG bytes like [`decode_image_into`], using `options`.
+///
+/// See [`decode_image_with_options`] for what the options control.
+///
+/// ```
+/// # let png_bytes = nopng::encode_image(
+/// #     &nopng::ImageSpec::new(4, 4, nopng::PixelFormat::Gray8),
+/// #     &[0; 16],
+/// # )?;
+/// let options = nopng::DecodeOptions {
+///     limits: nopng::DecodeLimits {
+///         max_pixels: 8,
+///         ..Default::default()
+///     },
+///     ..Default::default()
+/// };
+/// let mut pixels = vec![0; 16];
+/// let error = nopng::decode_image_into_with_options(&png_bytes, &mut pixels, &options)
+///     .unwrap_err();
+/// assert_eq!(error.kind(), nopng::ErrorKind::LimitExceeded);
+/// # Ok::<(), nopng::Error>(())
+/// ```
+pub fn decode_image_into_with_options(
+    bytes: &[u8],
+    out: &mut [u8],
+    options: &DecodeOptions,
+) -> Result<DecodedInto> {
+    let mut warnings = Vec::new();
+    let (header, ancillary) =
+        crate::png_decode::decode_png_into(bytes, out, options, &mut warnings)?;
+    Ok(DecodedInto {
+        spec: ImageSpec::from_header_and_ancillary(&header, &ancillary),
+        metadata: ancillary.metadata,
+        warnings,
+    })
 }
 
 /// Converts pixel data from one [`PixelFormat`] to another.
@@ -259,10 +467,10 @@ pub fn decode_image(bytes: &[u8]) -> Result<(ImageSpec, Vec<u8>)> {
 /// This function works on any pixel data, not just data from [`decode_image`].
 /// It can be used to convert between formats before encoding or after decoding.
 ///
-/// # Limitations
-///
 /// Converting **to** an indexed format (`Indexed1`/`Indexed2`/`Indexed4`/`Indexed8`)
-/// is not supported and returns [`Error::Unsupported`].
+/// maps every pixel to the closest entry of the target's palette. An empty
+/// palette returns an [`ErrorKind::InvalidArgument`] error; use
+/// [`reformat_image`] to build a palette from the pixels.
 ///
 /// # Examples
 ///
@@ -283,12 +491,80 @@ pub fn reformat_pixels(
     reformat(src_fmt, src, dst_fmt)
 }
 
+/// Converts an image to another [`PixelFormat`], returning the new spec and
+/// pixel data.
+///
+/// This works like [`reformat_pixels`], but knows the image layout, which
+/// allows [`ReformatOptions::dithering`] and building palettes. Converting
+/// to an indexed format with an empty palette fills the palette in (with a
+/// `tRNS` table for translucent colors): exactly when the image has few
+/// enough colors for the bit depth, and with a median-cut quantizer
+/// otherwise. A non-empty palette is used as it is.
+///
+/// # Examples
+///
+/// ```
+/// let spec = nopng::ImageSpec::new(2, 1, nopng::PixelFormat::Rgba8);
+/// let pixels = [255, 0, 0, 255, 0, 0, 255, 0];
+/// let target = nopng::PixelFormat::Indexed1 { palette: Vec::new(), trns: None };
+/// let (spec, indices) = nopng::reformat_image(&spec, &pixels, &target, &Default::default())?;
+/// assert_eq!(
+///     spec.pixel_format,
+///     nopng::PixelFormat::Indexed1 {
+///         palette: vec![0, 0, 255, 255, 0, 0],
+///         trns: Some(vec![0]),
+///     },
+/// );
+/// assert_eq!(indices, [1, 0]);
+/// # Ok::<(), nopng::Error>(())
+/// ```
+pub fn reformat_image(
+    spec: &ImageSpec,
+    data: &[u8],
+    dst_fmt: &PixelFormat,
+    options: &ReformatOptions,
+) -> Result<(ImageSpec, Vec<u8>)> {
+    validate_format_and_data(&spec.pixel_format, data, spec.width, spec.height)?;
+    let (pixel_format, data) =
+        reformat_with_options(&spec.pixel_format, data, spec.width, dst_fmt, options)?;
+    Ok((
+        ImageSpec {
+            pixel_format,
+            ..spec.clone()
+        },
+        data,
+    ))
+}
+
 /// Encodes an image described by `spec` into PNG bytes.
 ///
 /// The `data` buffer must contain pixel data in the format described by
 /// `spec.pixel_format`, with length matching [`ImageSpec::data_len()`].
+///
+/// This uses [`EncodeOptions::default()`]; see [`encode_image_with_options`]
+/// to trade encoding speed for output size.
 pub fn encode_image(spec: &ImageSpec, data: &[u8]) -> Result<Vec<u8>> {
+    encode_image_with_options(spec, data, &EncodeOptions::default())
+}
+
+/// Encodes an image described by `spec` into PNG bytes using `options`.
+///
+/// ```
+/// let spec = nopng::ImageSpec::new(2, 2, nopng::PixelFormat::Gray8);
+/// let options = nopng::EncodeOptions {
+///     compression: nopng::CompressionLevel::Best,
+///     ..Default::default()
+/// };
+/// let png_bytes = nopng::encode_image_with_options(&spec, &[0, 64, 128, 255], &options)?;
+/// # Ok::<(), nopng::Error>(())
+/// ```
+pub fn encode_image_with_options(
+    spec: &ImageSpec,
+    data: &[u8],
+    options: &EncodeOptions,
+) -> Result<Vec<u8>> {
     validate_format_and_data(&spec.pixel_format, data, spec.width, spec.height)?;
+    validate_metadata(&options.metadata, &spec.pixel_format)?;
 
     let encoded = crate::png_encode::EncodedImage::from_format_and_data(
         spec.width,
@@ -296,6 +572,7 @@ pub fn encode_image(spec: &ImageSpec, data: &[u8]) -> Result<Vec<u8>> {
         &spec.pixel_format,
         data,
         spec.interlaced,
+        options,
     )?;
     let mut bytes = Vec::new();
     bytes.extend_from_slice(&PNG_SIGNATURE);
@@ -308,21 +585,103 @@ pub fn encode_image(spec: &ImageSpec, data: &[u8]) -> Result<Vec<u8>> {
         interlace_method: encoded.interlace_method,
     }
     .append_to(&mut bytes);
+    append_metadata_chunks(&mut bytes, &options.metadata, options.compression)?;
     if let Some(palette) = encoded.palette.as_deref() {
         PlteChunk { palette }.append_to(&mut bytes);
     }
     if let Some(trns) = encoded.trns.as_deref() {
         TrnsChunk { data: trns }.append_to(&mut bytes);
     }
+    append_palette_metadata_chunks(&mut bytes, &options.metadata);
+    let zlib_data = crate::zlib::compress(&encoded.filtered_data, options.compression)?;
     IdatChunk {
-        filtered_data: &encoded.filtered_data,
+        zlib_data: &zlib_data,
     }
-    .append_to(&mut bytes)?;
+    .append_to(&mut bytes);
     IendChunk.append_to(&mut bytes);
 
     Ok(bytes)
 }
 
+/// Encodes an animated PNG (APNG) from `frames`, drawn on a canvas described
+/// by `spec`.
+///
+/// Each frame holds `control.width` by `control.height` pixels in
+/// `spec.pixel_format`, placed on the canvas at its offset. The first frame
+/// is also the default image shown by decoders without APNG support, so it
+/// must cover the whole canvas. `options` applies to every frame; metadata
+/// is written once.
+///
+/// # Examples
+///
+/// ```
+/// let spec = nopng::ImageSpec::new(2, 1, nopng::PixelFormat::Rgba8);
+/// let frame = |pixels: &[u8]| nopng::AnimationFrame {
+///     control: nopng::FrameControl {
+///         delay_num: 1,
+///         delay_den: 2,
+///         ..nopng::FrameControl::full_canvas(2, 1)
+///     },
+///     pixels: pixels.to_vec(),
+/// };
+/// let frames = [
+///     frame(&[255, 0, 0, 255, 0, 0, 255, 255]),
+///     frame(&[255, 0, 0, 255, 0, 255, 0, 255]),
+/// ];
+/// let animation = nopng::AnimationOptions {
+///     crop_to_changes: true,
+///     ..Default::default()
+/// };
+/// let png_bytes = nopng::encode_animation(&spec, &frames, &Default::default(), &animation)?;
+///
+/// // Only the pixel that changed is stored for the second frame.
+/// let decoder = nopng::AnimationDecoder::new(&png_bytes, &Default::default())?;
+/// let second = decoder.last().unwrap()?;
+/// assert_eq!((second.control.x_offset, second.control.width), (1, 1));
+/// # Ok::<(), nopng::Error>(())
+/// ```
+pub fn encode_animation(
+    spec: &ImageSpec,
+    frames: &[AnimationFrame],
+    options: &EncodeOptions,
+    animation: &AnimationOptions,
+) -> Result<Vec<u8>> {
+    crate::png_animation::encode_frames(spec, frames, options, animation)
+}
+
+/// Re-encodes PNG bytes in the smallest lossless form it can find.
+///
+/// The image is decoded and stored again in each of its candidate pixel
+/// formats, without interlacing: its original format, the smallest
+/// non-indexed format that holds every pixel exactly (for example RGB for
+/// an opaque RGBA image, grayscale when every pixel is gray, or 8-bit
+/// samples when the 16-bit ones repeat their high byte) and a palette of at
+/// most 256 colors when possible. Every candidate is compressed with each
+/// filter strategy and compression level in `options`, and the smallest
+/// result is returned; this is `bytes` itself if nothing is smaller and
+/// [`OptimizeOptions::strip_metadata`] is not set.
+///
+/// Metadata is carried over, adapted to the chosen pixel format. `bKGD` and
+/// `sBIT` are dropped when they cannot be expressed in it, and `hIST` when
+/// the palette changes. Chunks that nopng does not parse are not kept.
+/// Animated PNGs return an [`ErrorKind::Unsupported`] error.
+///
+/// # Examples
+///
+/// ```
+/// let spec = nopng::ImageSpec::new(2, 1, nopng::PixelFormat::Rgba16Be);
+/// let png_bytes = nopng::encode_image(&spec, &[0xFF; 16])?;
+///
+/// let optimized = nopng::optimize_png(&png_bytes, &Default::default())?;
+/// let (spec, pixels) = nopng::decode_image(&optimized)?;
+/// assert_eq!(spec.pixel_format, nopng::PixelFormat::Gray1);
+/// assert_eq!(pixels, [1, 1]);
+/// # Ok::<(), nopng::Error>(())
+/// ```
+pub fn optimize_png(bytes: &[u8], options: &OptimizeOptions) -> Result<Vec<u8>> {
+    crate::png_optimize::optimize(bytes, options)
+}
+
 pub(crate) fn adam7_axis_size(size: u32, start: u8, step: u8) -> u32 {
     if size <= u32::from(start) {
         0
@@ -344,10 +703,12 @@ mod tests {
     use alloc::{vec, vec::Vec};
 
     use super::{
-        Error, IhdrChunk, ImageSpec, PNG_SIGNATURE, PixelFormat, decode_image, encode_image,
+        ErrorKind, IhdrChunk, ImageSpec, PNG_SIGNATURE, PixelFormat, decode_image, decode_image_as,
+        decode_image_into, decode_image_with_options, encode_image, encode_image_with_options,
         inspect_image,
     };
     use crate::pixel_reformat::reformat;
+    use crate::png_types::{CompressionLevel, DecodeLimits, DecodeOptions, EncodeOptions};
 
     #[test]
     fn roundtrip_rgba_writer_and_reader() {
@@ -431,9 +792,8 @@ mod tests {
             interlaced: false,
         };
         let error = encode_image(&spec, &data).unwrap_err();
-        assert!(
-            matches!(error, Error::InvalidData(message) if message.contains("pixel buffer length"))
-        );
+        assert_eq!(error.kind(), ErrorKind::InvalidArgument);
+        assert!(error.message().contains("pixel buffer length"));
     }
 
     #[test]
@@ -449,7 +809,7 @@ mod tests {
             interlaced: false,
         };
         let error = encode_image(&spec, &data).unwrap_err();
-        assert!(matches!(error, Error::InvalidData(message) if message.contains("out-of-range")));
+        assert_eq!(error.kind(), ErrorKind::PaletteIndexOutOfRange);
     }
 
     #[test]
@@ -474,7 +834,12 @@ mod tests {
     #[test]
     fn image_spec_rejects_truncated_ihdr() {
         let error = inspect_image(&PNG_SIGNATURE).expect_err("infallible");
-        assert!(matches!(error, Error::InvalidData(message) if message.contains("IHDR")));
+        assert_eq!(
+            error.kind(),
+            ErrorKind::ChunkOrdering {
+                chunk_type: *b"IHDR"
+            }
+        );
     }
 
     #[test]
@@ -487,7 +852,8 @@ mod tests {
             interlaced: false,
         };
         let error = encode_image(&spec, &data).unwrap_err();
-        assert!(matches!(error, Error::InvalidData(message) if message.contains("non-zero")));
+        assert_eq!(error.kind(), ErrorKind::InvalidArgument);
+        assert!(error.message().contains("non-zero"));
     }
 
     #[test]
@@ -500,7 +866,8 @@ mod tests {
             interlaced: false,
         };
         let error = encode_image(&spec, &data).unwrap_err();
-        assert!(matches!(error, Error::InvalidData(message) if message.contains("non-zero")));
+        assert_eq!(error.kind(), ErrorKind::InvalidArgument);
+        assert!(error.message().contains("non-zero"));
     }
 
     #[test]
@@ -617,11 +984,221 @@ mod tests {
         assert_eq!(decoded_rgba, original_rgba);
     }
 
+    #[test]
+    fn roundtrip_with_every_compression_level() {
+        let data: Vec<u8> = (0..16 * 16 * 3).map(|i| (i * 7 % 256) as u8).collect();
+        let spec = ImageSpec::new(16, 16, PixelFormat::Rgb8);
+        for (level, flevel) in [
+            (CompressionLevel::Store, 0),
+            (CompressionLevel::Fast, 1),
+            (CompressionLevel::Default, 2),
+            (CompressionLevel::Best, 3),
+            (CompressionLevel::Optimal, 3),
+        ] {
+            let options = EncodeOptions {
+                compression: level,
+                ..Default::default()
+            };
+            let bytes = encode_image_with_options(&spec, &data, &options).expect("infallible");
+            let idat = find_chunk(&bytes, b"IDAT").expect("infallible");
+            assert_eq!(idat[1] >> 6, flevel);
+            let (_, decoded_data) = decode_image(&bytes).expect("infallible");
+            assert_eq!(decoded_data, data);
+        }
+    }
+
     struct IhdrInfo {
         bit_depth: u8,
         color_type: u8,
     }
 
+    #[test]
+    fn decode_into_matches_decode_image() {
+        let rgb: Vec<u8> = (0..9 * 7 * 3).map(|i| (i * 5) as u8).collect();
+        let gray4: Vec<u8> = (0..9 * 7).map(|i| (i % 16) as u8).collect();
+        let images = [
+            (ImageSpec::new(9, 7, PixelFormat::Rgb8), rgb.clone()),
+            (
+                ImageSpec {
+                    interlaced: true,
+                    ..ImageSpec::new(9, 7, PixelFormat::Rgb8)
+                },
+                rgb,
+            ),
+            (
+                ImageSpec {
+                    interlaced: true,
+                    ..ImageSpec::new(9, 7, PixelFormat::Gray4)
+                },
+                gray4.clone(),
+            ),
+            (ImageSpec::new(9, 7, PixelFormat::Gray4), gray4),
+        ];
+        for (spec, data) in images {
+            let bytes = encode_image(&spec, &data).expect("infallible");
+            let (expected_spec, expected) = decode_image(&bytes).expect("infallible");
+            let mut out = vec![0; inspect_image(&bytes).expect("infallible").data_len()];
+            let decoded_spec = decode_image_into(&bytes, &mut out).expect("infallible");
+            assert_eq!(decoded_spec, expected_spec);
+            assert_eq!(out, expected);
+        }
+    }
+
+    #[test]
+    fn decode_into_rejects_wrong_buffer_length() {
+        let spec = ImageSpec::new(2, 2, PixelFormat::Rgba8);
+        let bytes = encode_image(&spec, &[7; 16]).expect("infallible");
+        for len in [15, 17] {
+            let mut out = vec![0; len];
+            let error = decode_image_into(&bytes, &mut out).expect_err("infallible");
+            assert_eq!(error.kind(), ErrorKind::InvalidArgument);
+        }
+    }
+
+    #[test]
+    fn decode_as_matches_decode_then_reformat() {
+        let palette = vec![0, 0, 0, 255, 0, 0, 0, 255, 0, 0, 0, 255];
+        let indexed = PixelFormat::Indexed2 {
+            palette,
+            trns: Some(vec![0, 128]),
+        };
+        // The larger size spans several conversion batches.
+        for (width, height) in [(9, 7), (150, 80)] {
+            let pixels = width as usize * height as usize;
+            let indices: Vec<u8> = (0..pixels).map(|i| (i % 4) as u8).collect();
+            let rgb16: Vec<u8> = (0..pixels * 6).map(|i| (i * 7) as u8).collect();
+            for interlaced in [false, true] {
+                for (format, data) in [(indexed.clone(), &indices), (PixelFormat::Rgb16Be, &rgb16)]
+                {
+                    let spec = ImageSpec {
+                        interlaced,
+                        ..ImageSpec::new(width, height, format)
+                    };
+                    let bytes = encode_image(&spec, data).expect("infallible");
+                    let (native_spec, native) = decode_image(&bytes).expect("infallible");
+                    for target in [
+                        PixelFormat::Rgba8,
+                        PixelFormat::Rgb8,
+                        PixelFormat::Gray16Be,
+                        PixelFormat::GrayAlpha8,
+                        native_spec.pixel_format.clone(),
+                    ] {
+                        let (decoded_spec, decoded) =
+                            decode_image_as(&bytes, &target).expect("infallible");
+                        let expected = reformat(&native_spec.pixel_format, &native, &target)
+                            .expect("infallible");
+                        assert_eq!(decoded_spec.pixel_format, target);
+                        assert_eq!(decoded_spec.interlaced, interlaced);
+                        assert_eq!(decoded, expected);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn decode_as_maps_to_indexed_target() {
+        let spec = ImageSpec::new(2, 2, PixelFormat::Rgb8);
+        let bytes = encode_image(&spec, &[9, 9, 9, 200, 10, 10, 0, 0, 0, 250, 250, 250])
+            .expect("infallible");
+        let target = PixelFormat::Indexed2 {
+            palette: vec![9, 9, 9, 255, 0, 0, 255, 255, 255],
+            trns: None,
+        };
+        let (decoded_spec, indices) = decode_image_as(&bytes, &target).expect("infallible");
+        assert_eq!(decoded_spec.pixel_format, target);
+        assert_eq!(indices, [0, 1, 0, 2]);
+
+        let target = PixelFormat::Indexed8 {
+            palette: Vec::new(),
+            trns: None,
+        };
+        let error = decode_image_as(&bytes, &target).expect_err("infallible");
+        assert_eq!(error.kind(), ErrorKind::InvalidArgument);
+    }
+
+    #[test]
+    fn decode_limits_reject_large_images() {
+        let spec = ImageSpec::new(6, 4, PixelFormat::Rgb8);
+        let bytes = encode_image(&spec, &[3; 72]).expect("infallible");
+        let exact = DecodeLimits {
+            max_width: 6,
+            max_height: 4,
+            max_pixels: 24,
+            // One filter byte per row.
+            max_decompressed_bytes: 76,
+            max_chunks: 3,
+            max_ancillary_bytes: 0,
+        };
+        let options = DecodeOptions {
+            limits: exact,
+            ..Default::default()
+        };
+        assert!(decode_image_with_options(&bytes, &options).is_ok());
+
+        for limits in [
+            DecodeLimits {
+                max_width: 5,
+                ..exact
+            },
+            DecodeLimits {
+                max_height: 3,
+                ..exact
+            },
+            DecodeLimits {
+                max_pixels: 23,
+                ..exact
+            },
+            DecodeLimits {
+                max_decompressed_bytes: 75,
+                ..exact
+            },
+            DecodeLimits {
+                max_chunks: 2,
+                ..exact
+            },
+        ] {
+            let options = DecodeOptions {
+                limits,
+                ..Default::default()
+            };
+            let error = decode_image_with_options(&bytes, &options).expect_err("infallible");
+            assert_eq!(error.kind(), ErrorKind::LimitExceeded, "{limits:?}");
+        }
+    }
+
+    #[test]
+    fn decode_limits_count_ancillary_chunk_bytes() {
+        let spec = ImageSpec::new(
+            2,
+            1,
+            PixelFormat::Indexed8 {
+                palette: vec![0, 0, 0, 255, 255, 255],
+                trns: Some(vec![0, 255]),
+            },
+        );
+        let bytes = encode_image(&spec, &[0, 1]).expect("infallible");
+        let limits = DecodeLimits {
+            max_ancillary_bytes: 1,
+            ..Default::default()
+        };
+        let options = DecodeOptions {
+            limits,
+            ..Default::default()
+        };
+        let error = decode_image_with_options(&bytes, &options).expect_err("infallible");
+        assert_eq!(error.kind(), ErrorKind::LimitExceeded);
+        assert!(error.message().contains("ancillary"));
+        let options = DecodeOptions {
+            limits: DecodeLimits {
+                max_ancillary_bytes: 2,
+                ..limits
+            },
+            ..Default::default()
+        };
+        assert!(decode_image_with_options(&bytes, &options).is_ok());
+    }
+
     fn read_ihdr(bytes: &[u8]) -> IhdrInfo {
         let ihdr = find_chunk(bytes, b"IHDR").expect("infallible");
         IhdrInfo {
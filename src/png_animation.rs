@@ -0,0 +1,573 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use crate::chunk::{
+    ActlChunk, FctlChunk, FdatChunk, IdatChunk, IendChunk, IhdrChunk, PlteChunk, TrnsChunk,
+};
+use crate::pixel_reformat::{reformat, validate_format_and_data};
+use crate::png::{ImageSpec, PNG_SIGNATURE};
+use crate::png_decode::{
+    AncillaryChunks, FrameData, ParsedPng, PngHeader, decode_rows, decoded_len, parse_png, recover,
+};
+use crate::png_encode::{
+    EncodedImage, append_metadata_chunks, append_palette_metadata_chunks, validate_metadata,
+};
+use crate::png_types::{
+    AnimationControl, AnimationOptions, BlendOp, DecodeOptions, DisposeOp, EncodeOptions, Error,
+    ErrorKind, FrameControl, Metadata, PixelFormat, Result,
+};
+use crate::zlib;
+
+/// Decoder for animated PNG (APNG) images that yields one frame at a time.
+///
+/// The chunk structure is checked up front, and the image data of each frame
+/// is inflated and unfiltered when the frame is requested. A PNG without an
+/// `acTL` chunk is treated as an animation with a single frame: its default
+/// image. When the default image is not part of the animation, it is skipped.
+///
+/// Frames hold only the region described by their [`FrameControl`]; use a
+/// [`FrameCompositor`] to render them onto the full canvas.
+///
+/// # Examples
+///
+/// ```
+/// # let png_bytes = nopng::encode_image(
+/// #     &nopng::ImageSpec::new(2, 2, nopng::PixelFormat::Rgba8),
+/// #     &[255; 16],
+/// # )?;
+/// let decoder = nopng::AnimationDecoder::new(&png_bytes, &Default::default())?;
+/// let mut compositor = nopng::FrameCompositor::new(decoder.spec());
+/// for frame in decoder {
+///     let frame = frame?;
+///     let rgba = compositor.compose(&frame)?;
+///     println!("{} bytes shown for {} ms", rgba.len(), frame.control.delay_millis());
+/// }
+/// # Ok::<(), nopng::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct AnimationDecoder<'a> {
+    header: PngHeader,
+    ancillary: AncillaryChunks,
+    spec: ImageSpec,
+    animation: Option<AnimationControl>,
+    frames: vec::IntoIter<FrameData<'a>>,
+    options: DecodeOptions,
+    warnings: Vec<Error>,
+}
+
+/// One frame returned by [`AnimationDecoder`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnimationFrame {
+    /// Region, delay and disposal of the frame.
+    pub control: FrameControl,
+    /// Pixel data of the frame's region, `control.width` by `control.height`
+    /// pixels in the pixel format of [`AnimationDecoder::spec`].
+    pub pixels: Vec<u8>,
+}
+
+impl<'a> AnimationDecoder<'a> {
+    /// Parses the chunks of `bytes`.
+    ///
+    /// In lenient mode a frame count that disagrees with the `acTL` chunk is
+    /// reported as a warning instead of an error.
+    pub fn new(bytes: &'a [u8], options: &DecodeOptions) -> Result<Self> {
+        let mut warnings = Vec::new();
+        let ParsedPng {
+            header,
+            ancillary,
+            idat_chunks,
+            animation,
+            mut frames,
+        } = parse_png(bytes, options, &mut warnings)?;
+        match animation {
+            None => {
+                frames = vec![FrameData {
+                    control: FrameControl::full_canvas(header.width, header.height),
+                    data: idat_chunks,
+                }]
+            }
+            Some(animation) if frames.len() != animation.num_frames as usize => recover(
+                Error::new(
+                    ErrorKind::ChunkOrdering {
+                        chunk_type: *b"fcTL",
+                    },
+                    "fewer frames than announced in acTL",
+                ),
+                options,
+                &mut warnings,
+            )?,
+            Some(_) => {}
+        }
+        Ok(Self {
+            spec: ImageSpec::from_header_and_ancillary(&header, &ancillary),
+            header,
+            ancillary,
+            animation,
+            frames: frames.into_iter(),
+            options: options.clone(),
+            warnings,
+        })
+    }
+
+    /// Canvas size and the native pixel format of every frame.
+    pub fn spec(&self) -> &ImageSpec {
+        &self.spec
+    }
+
+    /// Color-space information and other ancillary chunks.
+    pub fn metadata(&self) -> &Metadata {
+        &self.ancillary.metadata
+    }
+
+    /// The `acTL` chunk, or `None` if the PNG is not animated.
+    pub fn animation_control(&self) -> Option<&AnimationControl> {
+        self.animation.as_ref()
+    }
+
+    /// Problems worked around so far. Always empty unless
+    /// [`DecodeOptions::lenient`] is set.
+    pub fn warnings(&self) -> &[Error] {
+        &self.warnings
+    }
+
+    fn decode_frame(&mut self, frame: FrameData<'_>) -> Result<AnimationFrame> {
+        let header = PngHeader {
+            width: frame.control.width,
+            height: frame.control.height,
+            ..self.header
+        };
+        let bpp = self.spec.pixel_format.bytes_per_pixel();
+        let mut pixels = vec![0; decoded_len(&header, bpp)?];
+        decode_rows(
+            &header,
+            &self.ancillary,
+            &frame.data,
+            &self.options,
+            &mut self.warnings,
+            |position, row| {
+                position.scatter(&mut pixels, header.width, row, bpp);
+                Ok(())
+            },
+        )?;
+        Ok(AnimationFrame {
+            control: frame.control,
+            pixels,
+        })
+    }
+}
+
+impl Iterator for AnimationDecoder<'_> {
+    type Item = Result<AnimationFrame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let frame = self.frames.next()?;
+        Some(self.decode_frame(frame))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.frames.size_hint()
+    }
+}
+
+impl ExactSizeIterator for AnimationDecoder<'_> {}
+
+/// Renders animation frames onto an RGBA8 canvas, applying each frame's
+/// [`BlendOp`] and, before the next frame, its [`DisposeOp`].
+///
+/// The canvas starts out fully transparent black.
+#[derive(Debug, Clone)]
+pub struct FrameCompositor {
+    width: u32,
+    height: u32,
+    pixel_format: PixelFormat,
+    canvas: Vec<u8>,
+    /// Region covered by the previous frame, saved before it was drawn if it
+    /// is disposed of with [`DisposeOp::Previous`].
+    saved: Vec<u8>,
+    previous: Option<FrameControl>,
+}
+
+impl FrameCompositor {
+    /// Creates a compositor for frames of an animation with the given canvas
+    /// size and pixel format.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the canvas size overflows `usize`.
+    pub fn new(spec: &ImageSpec) -> Self {
+        Self {
+            width: spec.width,
+            height: spec.height,
+            pixel_format: spec.pixel_format.clone(),
+            canvas: vec![0; PixelFormat::Rgba8.data_len(spec.width, spec.height)],
+            saved: Vec::new(),
+            previous: None,
+        }
+    }
+
+    /// The canvas as RGBA8 pixels.
+    pub fn canvas(&self) -> &[u8] {
+        &self.canvas
+    }
+
+    /// Disposes of the previous frame, draws `frame` and returns the canvas
+    /// as RGBA8 pixels.
+    pub fn compose(&mut self, frame: &AnimationFrame) -> Result<&[u8]> {
+        let control = &frame.control;
+        if !fits_canvas(control, self.width, self.height) {
+            return Err(Error::new(
+                ErrorKind::InvalidArgument,
+                "frame does not fit in the canvas",
+            ));
+        }
+        let rgba = reformat(&self.pixel_format, &frame.pixels, &PixelFormat::Rgba8)?;
+        if rgba.len() != PixelFormat::Rgba8.data_len(control.width, control.height) {
+            return Err(Error::new(
+                ErrorKind::InvalidArgument,
+                "frame pixel data length does not match the frame size",
+            ));
+        }
+
+        if let Some(previous) = self.previous.take() {
+            match previous.dispose_op {
+                DisposeOp::None => {}
+                DisposeOp::Background => {
+                    for row in region_rows(self.width, 4, &previous) {
+                        self.canvas[row].fill(0);
+                    }
+                }
+                DisposeOp::Previous => {
+                    let row_len = previous.width as usize * 4;
+                    let saved = core::mem::take(&mut self.saved);
+                    for (row, saved) in
+                        region_rows(self.width, 4, &previous).zip(saved.chunks(row_len))
+                    {
+                        self.canvas[row].copy_from_slice(saved);
+                    }
+                }
+            }
+        }
+        if control.dispose_op == DisposeOp::Previous {
+            self.saved.clear();
+            for row in region_rows(self.width, 4, control) {
+                self.saved.extend_from_slice(&self.canvas[row]);
+            }
+        }
+
+        let row_len = control.width as usize * 4;
+        for (row, src) in region_rows(self.width, 4, control).zip(rgba.chunks(row_len)) {
+            let dst = &mut self.canvas[row];
+            match control.blend_op {
+                BlendOp::Source => dst.copy_from_slice(src),
+                BlendOp::Over => {
+                    for (dst, src) in dst.chunks_exact_mut(4).zip(src.chunks_exact(4)) {
+                        blend_over(dst, src);
+                    }
+                }
+            }
+        }
+        self.previous = Some(*control);
+        Ok(&self.canvas)
+    }
+}
+
+/// Ranges of a canvas `canvas_width` pixels wide, stored with `pixel_len`
+/// elements per pixel, covered by each row of a frame.
+fn region_rows(
+    canvas_width: u32,
+    pixel_len: usize,
+    control: &FrameControl,
+) -> impl Iterator<Item = Range<usize>> + use<> {
+    let stride = canvas_width as usize * pixel_len;
+    let start = control.x_offset as usize * pixel_len;
+    let len = control.width as usize * pixel_len;
+    let top = control.y_offset as usize;
+    (top..top + control.height as usize).map(move |y| y * stride + start..y * stride + start + len)
+}
+
+/// Composites the RGBA8 pixel `src` over `dst`.
+fn blend_over(dst: &mut [u8], src: &[u8]) {
+    let src_alpha = u32::from(src[3]);
+    match src_alpha {
+        0 => return,
+        255 => {
+            dst.copy_from_slice(src);
+            return;
+        }
+        _ => {}
+    }
+    // Alpha values are scaled by 255 to keep the arithmetic in integers.
+    let src_weight = src_alpha * 255;
+    let dst_weight = u32::from(dst[3]) * (255 - src_alpha);
+    let alpha = src_weight + dst_weight;
+    for channel in 0..3 {
+        let color = u32::from(src[channel]) * src_weight + u32::from(dst[channel]) * dst_weight;
+        dst[channel] = ((color + alpha / 2) / alpha) as u8;
+    }
+    dst[3] = ((alpha + 127) / 255) as u8;
+}
+
+/// Encodes `frames` as an APNG. See [`encode_animation`](crate::encode_animation).
+pub(crate) fn encode_frames(
+    spec: &ImageSpec,
+    frames: &[AnimationFrame],
+    options: &EncodeOptions,
+    animation: &AnimationOptions,
+) -> Result<Vec<u8>> {
+    validate_metadata(&options.metadata, &spec.pixel_format)?;
+    let Some(first) = frames.first() else {
+        return Err(Error::new(
+            ErrorKind::InvalidArgument,
+            "an animation needs at least one frame",
+        ));
+    };
+    let control = &first.control;
+    if (
+        control.width,
+        control.height,
+        control.x_offset,
+        control.y_offset,
+    ) != (spec.width, spec.height, 0, 0)
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidArgument,
+            "the first frame must cover the whole canvas",
+        ));
+    }
+    let num_frames = u32::try_from(frames.len())
+        .map_err(|_| Error::new(ErrorKind::InvalidArgument, "too many frames"))?;
+    for frame in frames {
+        let control = &frame.control;
+        if control.width == 0
+            || control.height == 0
+            || !fits_canvas(control, spec.width, spec.height)
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidArgument,
+                "frame does not fit in the canvas",
+            ));
+        }
+        validate_format_and_data(
+            &spec.pixel_format,
+            &frame.pixels,
+            control.width,
+            control.height,
+        )?;
+    }
+
+    let mut changes = animation
+        .crop_to_changes
+        .then(|| ChangeTracker::new(spec.width, spec.height));
+    let mut bytes = Vec::new();
+    let mut sequence_number = 0;
+    for (index, frame) in frames.iter().enumerate() {
+        let (control, cropped) = match changes.as_mut() {
+            Some(changes) => changes.apply(frame, &spec.pixel_format, index > 0)?,
+            None => (frame.control, None),
+        };
+        let pixels = cropped.as_deref().unwrap_or(&frame.pixels);
+        let encoded = EncodedImage::from_format_and_data(
+            control.width,
+            control.height,
+            &spec.pixel_format,
+            pixels,
+            spec.interlaced,
+            options,
+        )?;
+        let zlib_data = zlib::compress(&encoded.filtered_data, options.compression)?;
+
+        if index == 0 {
+            bytes.extend_from_slice(&PNG_SIGNATURE);
+            IhdrChunk {
+                width: spec.width,
+                height: spec.height,
+                bit_depth: encoded.bit_depth,
+                color_type: encoded.color_type,
+                interlace_method: encoded.interlace_method,
+            }
+            .append_to(&mut bytes);
+            ActlChunk {
+                num_frames,
+                num_plays: animation.num_plays,
+            }
+            .append_to(&mut bytes);
+            append_metadata_chunks(&mut bytes, &options.metadata, options.compression)?;
+            if let Some(palette) = encoded.palette.as_deref() {
+                PlteChunk { palette }.append_to(&mut bytes);
+            }
+            if let Some(trns) = encoded.trns.as_deref() {
+                TrnsChunk { data: trns }.append_to(&mut bytes);
+            }
+            append_palette_metadata_chunks(&mut bytes, &options.metadata);
+        }
+        FctlChunk {
+            sequence_number,
+            control: &control,
+        }
+        .append_to(&mut bytes);
+        sequence_number += 1;
+        if index == 0 {
+            IdatChunk {
+                zlib_data: &zlib_data,
+            }
+            .append_to(&mut bytes);
+        } else {
+            FdatChunk {
+                sequence_number,
+                zlib_data: &zlib_data,
+            }
+            .append_to(&mut bytes);
+            sequence_number += 1;
+        }
+    }
+    IendChunk.append_to(&mut bytes);
+    Ok(bytes)
+}
+
+fn fits_canvas(control: &FrameControl, width: u32, height: u32) -> bool {
+    let fits = |offset: u32, size: u32, canvas: u32| {
+        offset.checked_add(size).is_some_and(|end| end <= canvas)
+    };
+    fits(control.x_offset, control.width, width) && fits(control.y_offset, control.height, height)
+}
+
+/// The canvas as decoders will render it, used to crop frames to the pixels
+/// they change. Pixels are kept as RGBA16, which every pixel format converts
+/// to losslessly; a pixel is `None` where decoders may disagree after
+/// blending.
+#[derive(Debug)]
+struct ChangeTracker {
+    width: u32,
+    canvas: Vec<Option<[u16; 4]>>,
+    saved: Vec<Option<[u16; 4]>>,
+    previous: Option<FrameControl>,
+}
+
+impl ChangeTracker {
+    fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            canvas: vec![Some([0; 4]); width as usize * height as usize],
+            saved: Vec::new(),
+            previous: None,
+        }
+    }
+
+    /// Renders `frame`, returning the frame control to write and, if `crop`
+    /// is set and the frame can be shrunk, its cropped pixels.
+    fn apply(
+        &mut self,
+        frame: &AnimationFrame,
+        format: &PixelFormat,
+        crop: bool,
+    ) -> Result<(FrameControl, Option<Vec<u8>>)> {
+        if let Some(previous) = self.previous.take() {
+            match previous.dispose_op {
+                DisposeOp::None => {}
+                DisposeOp::Background => {
+                    for row in region_rows(self.width, 1, &previous) {
+                        self.canvas[row].fill(Some([0; 4]));
+                    }
+                }
+                DisposeOp::Previous => {
+                    let saved = core::mem::take(&mut self.saved);
+                    let rows = region_rows(self.width, 1, &previous);
+                    for (row, saved) in rows.zip(saved.chunks(previous.width as usize)) {
+                        self.canvas[row].copy_from_slice(saved);
+                    }
+                }
+            }
+        }
+
+        let rgba = reformat(format, &frame.pixels, &PixelFormat::Rgba16Be)?;
+        let (samples, _) = rgba.as_chunks::<2>();
+        let (pixels, _) = samples.as_chunks::<4>();
+        let pixels: Vec<[u16; 4]> = pixels
+            .iter()
+            .map(|pixel| pixel.map(u16::from_be_bytes))
+            .collect();
+        let control = frame.control;
+        let blend_op = control.blend_op;
+        let mut cropped = None;
+        let mut written = control;
+        if crop && control.dispose_op != DisposeOp::Background {
+            let frame_width = control.width as usize;
+            let mut bounds: Option<[u32; 4]> = None;
+            for (y, (row, src)) in region_rows(self.width, 1, &control)
+                .zip(pixels.chunks(frame_width))
+                .enumerate()
+            {
+                for (x, (dst, src)) in self.canvas[row].iter().zip(src).enumerate() {
+                    if !changes_pixel(blend_op, *dst, *src) {
+                        continue;
+                    }
+                    let (x, y) = (x as u32, y as u32);
+                    let [left, top, right, bottom] = bounds.get_or_insert([x, y, x, y]);
+                    *left = (*left).min(x);
+                    *top = (*top).min(y);
+                    *right = (*right).max(x);
+                    *bottom = (*bottom).max(y);
+                }
+            }
+            // A frame that changes nothing still needs one pixel, which
+            // leaves the canvas as it is.
+            let [left, top, right, bottom] = bounds.unwrap_or([0; 4]);
+            written = FrameControl {
+                width: right - left + 1,
+                height: bottom - top + 1,
+                x_offset: control.x_offset + left,
+                y_offset: control.y_offset + top,
+                ..control
+            };
+            if written != control {
+                let bpp = format.bytes_per_pixel();
+                let stride = frame_width * bpp;
+                let start = left as usize * bpp;
+                let len = written.width as usize * bpp;
+                cropped = Some(
+                    (top..=bottom)
+                        .flat_map(|y| {
+                            let row = y as usize * stride + start;
+                            &frame.pixels[row..row + len]
+                        })
+                        .copied()
+                        .collect(),
+                );
+            }
+        }
+
+        if written.dispose_op == DisposeOp::Previous {
+            self.saved.clear();
+            for row in region_rows(self.width, 1, &written) {
+                self.saved.extend_from_slice(&self.canvas[row]);
+            }
+        }
+        for (row, src) in
+            region_rows(self.width, 1, &control).zip(pixels.chunks(control.width as usize))
+        {
+            for (dst, src) in self.canvas[row].iter_mut().zip(src) {
+                *dst = match blend_op {
+                    BlendOp::Source => Some(*src),
+                    BlendOp::Over => match src[3] {
+                        0 => *dst,
+                        u16::MAX => Some(*src),
+                        _ => None,
+                    },
+                };
+            }
+        }
+        self.previous = Some(written);
+        Ok((written, cropped))
+    }
+}
+
+/// Returns `true` unless drawing `src` with `blend_op` is known to leave the
+/// canvas pixel `dst` as it is.
+fn changes_pixel(blend_op: BlendOp, dst: Option<[u16; 4]>, src: [u16; 4]) -> bool {
+    match (blend_op, src[3]) {
+        (BlendOp::Over, 0) => false,
+        (BlendOp::Source, _) | (BlendOp::Over, u16::MAX) => dst != Some(src),
+        (BlendOp::Over, _) => true,
+    }
+}
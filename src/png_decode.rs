@@ -1,13 +1,24 @@
-use alloc::format;
+use alloc::string::String;
 use alloc::vec;
 use alloc::vec::Vec;
 
-use crate::chunk::IhdrChunk;
-use crate::pixel_reformat::{scale_sample_to_u8, upscale_u8_to_u16};
-use crate::png_types::{Error, PixelFormat, Result};
-use crate::{adler32, crc, deflate};
+use crate::chunk::{
+    COMPRESSION_METHOD_DEFLATE, IhdrChunk, is_ancillary, is_valid_keyword, latin1_to_string,
+};
+use crate::pixel_reformat::{check_target, reformat, scale_sample_to_u8};
+use crate::png_types::{
+    AnimationControl, Background, BlendOp, Chromaticities, Cicp, DecodeLimits, DecodeOptions,
+    DisposeOp, Error, ErrorKind, FrameControl, IccProfile, ImageOffset, InternationalText,
+    Metadata, OffsetUnit, PhysicalDimensions, PhysicalUnit, PixelFormat, RenderingIntent, Result,
+    SignificantBits, SuggestedPalette, SuggestedPaletteEntry, TextEntry, Timestamp,
+};
+use crate::{crc, zlib};
 
-use crate::png::{ADAM7_PASSES, Adam7Pass, PNG_SIGNATURE, adam7_axis_size};
+use crate::png::{ADAM7_PASSES, PNG_SIGNATURE, adam7_axis_size};
+
+/// Maximum number of compressed bytes inflated at once by the row-by-row
+/// decoders, so that only a few scanlines are buffered at a time.
+pub(crate) const INFLATE_INPUT_STEP: usize = 4096;
 
 // Short aliases for color type constants used in match patterns.
 const CT_GRAY: u8 = IhdrChunk::COLOR_TYPE_GRAYSCALE;
@@ -28,10 +39,13 @@ pub(crate) struct PngHeader {
 }
 
 impl PngHeader {
-    pub(crate) fn parse(chunk_data: &[u8]) -> Result<Self> {
+    pub(crate) fn parse(chunk_data: &[u8], limits: &DecodeLimits) -> Result<Self> {
         if chunk_data.len() != 13 {
-            return Err(Error::InvalidData(
-                "IHDR chunk must contain 13 bytes".into(),
+            return Err(Error::new(
+                ErrorKind::InvalidChunk {
+                    chunk_type: *b"IHDR",
+                },
+                "IHDR chunk must contain 13 bytes",
             ));
         }
         let width = u32::from_be_bytes(
@@ -45,8 +59,11 @@ impl PngHeader {
                 .expect("bug: IHDR height must be 4 bytes"),
         );
         if width == 0 || height == 0 {
-            return Err(Error::InvalidData(
-                "image dimensions must be non-zero".into(),
+            return Err(Error::new(
+                ErrorKind::InvalidChunk {
+                    chunk_type: *b"IHDR",
+                },
+                "image dimensions must be non-zero",
             ));
         }
 
@@ -59,28 +76,27 @@ impl PngHeader {
             filter_method: chunk_data[11],
             interlace_method: chunk_data[12],
         };
-        header.validate()?;
+        header.validate(limits)?;
         Ok(header)
     }
 
-    pub(crate) fn validate(&self) -> Result<()> {
+    pub(crate) fn validate(&self, limits: &DecodeLimits) -> Result<()> {
         if self.compression_method != 0 {
-            return Err(Error::Unsupported(
-                format!(
-                    "unsupported compression method: {}",
-                    self.compression_method
-                )
-                .into(),
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "unsupported compression method",
             ));
         }
         if self.filter_method != 0 {
-            return Err(Error::Unsupported(
-                format!("unsupported filter method: {}", self.filter_method).into(),
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "unsupported filter method",
             ));
         }
         if self.interlace_method > 1 {
-            return Err(Error::Unsupported(
-                format!("unsupported interlace method: {}", self.interlace_method).into(),
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "unsupported interlace method",
             ));
         }
         match (self.color_type, self.bit_depth) {
@@ -88,15 +104,28 @@ impl PngHeader {
             | (CT_INDEXED, 1 | 2 | 4 | 8)
             | (CT_RGB, 8 | 16)
             | (CT_GRAY_ALPHA, 8 | 16)
-            | (CT_RGBA, 8 | 16) => Ok(()),
-            _ => Err(Error::Unsupported(
-                format!(
-                    "unsupported color type/bit depth combination: color_type={}, bit_depth={}",
-                    self.color_type, self.bit_depth
-                )
-                .into(),
-            )),
+            | (CT_RGBA, 8 | 16) => {}
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "unsupported color type/bit depth combination",
+                ));
+            }
+        }
+        if self.width > limits.max_width || self.height > limits.max_height {
+            return Err(Error::new(
+                ErrorKind::LimitExceeded,
+                "image dimensions exceed the width or height limit",
+            ));
+        }
+        let pixels = u64::from(self.width) * u64::from(self.height);
+        if pixels > limits.max_pixels {
+            return Err(Error::new(
+                ErrorKind::LimitExceeded,
+                "image has more pixels than the limit",
+            ));
         }
+        Ok(())
     }
 
     pub(crate) fn samples_per_pixel(&self) -> usize {
@@ -137,12 +166,18 @@ enum Transparency {
 pub(crate) struct AncillaryChunks {
     palette: Option<Vec<[u8; 3]>>,
     transparency: Option<Transparency>,
+    pub(crate) metadata: Metadata,
 }
 
 impl AncillaryChunks {
     fn set_palette(&mut self, palette: Vec<[u8; 3]>) -> Result<()> {
         if self.palette.is_some() {
-            return Err(Error::InvalidData("duplicate PLTE chunk".into()));
+            return Err(Error::new(
+                ErrorKind::ChunkOrdering {
+                    chunk_type: *b"PLTE",
+                },
+                "duplicate PLTE chunk",
+            ));
         }
         self.palette = Some(palette);
         Ok(())
@@ -150,19 +185,32 @@ impl AncillaryChunks {
 
     fn set_transparency(&mut self, transparency: Transparency) -> Result<()> {
         if self.transparency.is_some() {
-            return Err(Error::InvalidData("duplicate tRNS chunk".into()));
+            return Err(Error::new(
+                ErrorKind::ChunkOrdering {
+                    chunk_type: *b"tRNS",
+                },
+                "duplicate tRNS chunk",
+            ));
         }
         self.transparency = Some(transparency);
         Ok(())
     }
 
-    fn validate(&self, header: &PngHeader) -> Result<()> {
+    pub(crate) fn validate(&self, header: &PngHeader) -> Result<()> {
         if header.color_type == CT_INDEXED && self.palette.is_none() {
-            return Err(Error::InvalidData("missing PLTE for palette image".into()));
+            return Err(Error::new(
+                ErrorKind::ChunkOrdering {
+                    chunk_type: *b"PLTE",
+                },
+                "missing PLTE for palette image",
+            ));
         }
         if matches!(header.color_type, CT_GRAY | CT_GRAY_ALPHA) && self.palette.is_some() {
-            return Err(Error::InvalidData(
-                "PLTE chunk is not allowed for grayscale images".into(),
+            return Err(Error::new(
+                ErrorKind::ChunkOrdering {
+                    chunk_type: *b"PLTE",
+                },
+                "PLTE chunk is not allowed for grayscale images",
             ));
         }
         match (&self.transparency, header.color_type) {
@@ -171,14 +219,20 @@ impl AncillaryChunks {
             (Some(Transparency::Palette(alpha)), CT_INDEXED) => {
                 let palette_len = self.palette.as_ref().map_or(0, Vec::len);
                 if alpha.len() > palette_len {
-                    return Err(Error::InvalidData(
-                        "tRNS length exceeds palette length".into(),
+                    return Err(Error::new(
+                        ErrorKind::InvalidChunk {
+                            chunk_type: *b"tRNS",
+                        },
+                        "tRNS length exceeds palette length",
                     ));
                 }
             }
             (Some(_), _) => {
-                return Err(Error::InvalidData(
-                    format!("tRNS is not allowed for color type {}", header.color_type).into(),
+                return Err(Error::new(
+                    ErrorKind::ChunkOrdering {
+                        chunk_type: *b"tRNS",
+                    },
+                    "tRNS is not allowed for this color type",
                 ));
             }
             (None, _) => {}
@@ -203,19 +257,48 @@ impl AncillaryChunks {
     pub(crate) fn has_transparency(&self) -> bool {
         self.transparency.is_some()
     }
+
+    /// Restates `sBIT` and `bKGD` for the decoded pixel format, as `tRNS`
+    /// adds an alpha channel to grayscale and truecolor images and widens
+    /// low-bit grayscale samples to 8 bits.
+    fn expand_metadata_for_transparency(&mut self, bit_depth: u8) {
+        if !matches!(
+            self.transparency,
+            Some(Transparency::Grayscale(_) | Transparency::Truecolor(_))
+        ) {
+            return;
+        }
+        let alpha_bits = bit_depth.max(8);
+        self.metadata.significant_bits = match self.metadata.significant_bits {
+            Some(SignificantBits::Gray(gray)) => {
+                Some(SignificantBits::GrayAlpha([gray, alpha_bits]))
+            }
+            Some(SignificantBits::Rgb([red, green, blue])) => {
+                Some(SignificantBits::Rgba([red, green, blue, alpha_bits]))
+            }
+            significant_bits => significant_bits,
+        };
+        if bit_depth < 8
+            && let Some(Background::Gray(gray)) = self.metadata.background
+        {
+            let gray = scale_sample_to_u8(gray, bit_depth);
+            self.metadata.background = Some(Background::Gray(u16::from(gray)));
+        }
+    }
 }
 
-struct Cursor<'a> {
-    bytes: &'a [u8],
-    offset: usize,
+#[derive(Debug, Clone)]
+pub(crate) struct Cursor<'a> {
+    pub(crate) bytes: &'a [u8],
+    pub(crate) offset: usize,
 }
 
 impl<'a> Cursor<'a> {
-    fn new(bytes: &'a [u8]) -> Self {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
         Self { bytes, offset: 0 }
     }
 
-    fn remaining(&self) -> usize {
+    pub(crate) fn remaining(&self) -> usize {
         self.bytes.len().saturating_sub(self.offset)
     }
 
@@ -234,197 +317,907 @@ impl<'a> Cursor<'a> {
         let end = self
             .offset
             .checked_add(len)
-            .ok_or_else(|| Error::InvalidData("PNG chunk size overflow".into()))?;
+            .ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "PNG chunk size overflow"))?;
         let Some(bytes) = self.bytes.get(self.offset..end) else {
-            return Err(Error::InvalidData("unexpected end of PNG stream".into()));
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "unexpected end of PNG stream",
+            ));
         };
         self.offset = end;
         Ok(bytes)
     }
 }
 
-/// Decodes a PNG byte stream into a header and pixel data.
+/// Decodes a PNG byte stream into a header and pixel data in the native
+/// pixel format.
+///
+/// Problems worked around in lenient mode are appended to `warnings`.
 pub(crate) fn decode_png(
     bytes: &[u8],
+    options: &DecodeOptions,
+    warnings: &mut Vec<Error>,
 ) -> Result<(PngHeader, AncillaryChunks, PixelFormat, Vec<u8>)> {
-    let (header, ancillary, idat_data) = parse_png(bytes)?;
-    let expected_filtered = expected_filtered_len(&header)?;
-    expected_raw_len(&header)?;
-    let filtered = decompress_zlib(&idat_data)?;
-    if filtered.len() != expected_filtered {
-        return Err(Error::InvalidData(
-            format!(
-                "unexpected filtered data size: expected {}, got {}",
-                expected_filtered,
-                filtered.len()
-            )
-            .into(),
+    let ParsedPng {
+        header,
+        ancillary,
+        idat_chunks,
+        ..
+    } = parse_png(bytes, options, warnings)?;
+    let format = crate::png::pixel_format_from_header(&header, &ancillary);
+    let bpp = format.bytes_per_pixel();
+    let mut data = vec![0; decoded_len(&header, bpp)?];
+    decode_rows(
+        &header,
+        &ancillary,
+        &idat_chunks,
+        options,
+        warnings,
+        |position, pixels| {
+            position.scatter(&mut data, header.width, pixels, bpp);
+            Ok(())
+        },
+    )?;
+    Ok((header, ancillary, format, data))
+}
+
+/// Decodes a PNG byte stream, converting rows to `target` in batches of
+/// about [`CONVERT_BATCH_BYTES`] as they are unfiltered.
+pub(crate) fn decode_png_as(
+    bytes: &[u8],
+    target: &PixelFormat,
+    options: &DecodeOptions,
+    warnings: &mut Vec<Error>,
+) -> Result<(PngHeader, AncillaryChunks, Vec<u8>)> {
+    let ParsedPng {
+        header,
+        ancillary,
+        idat_chunks,
+        ..
+    } = parse_png(bytes, options, warnings)?;
+    // Reject unsupported conversions before doing any decoding work.
+    check_target(target)?;
+    let mut rows = RowConverter {
+        native: crate::png::pixel_format_from_header(&header, &ancillary),
+        target,
+        image_width: header.width,
+        positions: Vec::new(),
+        pixels: Vec::new(),
+    };
+    let mut data = vec![0; decoded_len(&header, target.bytes_per_pixel())?];
+    decode_rows(
+        &header,
+        &ancillary,
+        &idat_chunks,
+        options,
+        warnings,
+        |position, pixels| {
+            rows.positions.push(position);
+            rows.pixels.extend_from_slice(pixels);
+            if rows.pixels.len() >= CONVERT_BATCH_BYTES {
+                rows.flush(&mut data)?;
+            }
+            Ok(())
+        },
+    )?;
+    rows.flush(&mut data)?;
+    Ok((header, ancillary, data))
+}
+
+/// Native-format bytes [`decode_png_as`] collects before converting them.
+const CONVERT_BATCH_BYTES: usize = 64 * 1024;
+
+/// Native-format rows waiting to be converted by [`decode_png_as`], so that
+/// they are converted a batch at a time rather than one row at a time.
+struct RowConverter<'a> {
+    native: PixelFormat,
+    target: &'a PixelFormat,
+    image_width: u32,
+    positions: Vec<RowPosition>,
+    pixels: Vec<u8>,
+}
+
+impl RowConverter<'_> {
+    /// Converts the collected rows and copies them to their places in `image`.
+    fn flush(&mut self, image: &mut [u8]) -> Result<()> {
+        let converted = reformat(&self.native, &self.pixels, self.target)?;
+        let bpp = self.target.bytes_per_pixel();
+        let mut offset = 0;
+        for position in self.positions.drain(..) {
+            let len = position.width as usize * bpp;
+            position.scatter(
+                image,
+                self.image_width,
+                &converted[offset..offset + len],
+                bpp,
+            );
+            offset += len;
+        }
+        self.pixels.clear();
+        Ok(())
+    }
+}
+
+/// Decodes a PNG byte stream row by row, writing native-format pixels
+/// straight into `out`.
+pub(crate) fn decode_png_into(
+    bytes: &[u8],
+    out: &mut [u8],
+    options: &DecodeOptions,
+    warnings: &mut Vec<Error>,
+) -> Result<(PngHeader, AncillaryChunks)> {
+    let ParsedPng {
+        header,
+        ancillary,
+        idat_chunks,
+        ..
+    } = parse_png(bytes, options, warnings)?;
+    let bpp = crate::png::pixel_format_from_header(&header, &ancillary).bytes_per_pixel();
+    let expected_len = decoded_len(&header, bpp)?;
+    if out.len() != expected_len {
+        return Err(Error::new(
+            ErrorKind::InvalidArgument,
+            "output buffer length does not match image size",
         ));
     }
-    let (format, data) = decode_to_format_and_data(&header, &filtered, &ancillary)?;
-    Ok((header, ancillary, format, data))
+    if options.lenient {
+        // Rows lost to truncated image data are left zeroed.
+        out.fill(0);
+    }
+    decode_rows(
+        &header,
+        &ancillary,
+        &idat_chunks,
+        options,
+        warnings,
+        |position, pixels| {
+            position.scatter(out, header.width, pixels, bpp);
+            Ok(())
+        },
+    )?;
+    Ok((header, ancillary))
+}
+
+/// Fails if `len` bytes of filtered image data would exceed the decompression
+/// limit.
+pub(crate) fn check_decompressed_len(len: usize, limits: &DecodeLimits) -> Result<()> {
+    if len > limits.max_decompressed_bytes {
+        return Err(Error::new(
+            ErrorKind::LimitExceeded,
+            "image data inflates to more than the decompression limit",
+        ));
+    }
+    Ok(())
+}
+
+pub(crate) fn decoded_len(header: &PngHeader, bpp: usize) -> Result<usize> {
+    (header.width as usize)
+        .checked_mul(header.height as usize)
+        .and_then(|pixels| pixels.checked_mul(bpp))
+        .ok_or_else(|| Error::new(ErrorKind::LimitExceeded, "decoded image size overflow"))
+}
+
+/// Inflates the image data a piece at a time and passes every scanline,
+/// converted to the native pixel format, to `on_row`. Only a few scanlines of
+/// filtered data are buffered at once.
+///
+/// In lenient mode, corrupt or truncated image data ends decoding early with
+/// a warning; rows that were not decoded are left untouched.
+pub(crate) fn decode_rows(
+    header: &PngHeader,
+    ancillary: &AncillaryChunks,
+    idat_chunks: &[&[u8]],
+    options: &DecodeOptions,
+    warnings: &mut Vec<Error>,
+    mut on_row: impl FnMut(RowPosition, &[u8]) -> Result<()>,
+) -> Result<()> {
+    let expected_filtered = expected_filtered_len(header)?;
+    check_decompressed_len(expected_filtered, &options.limits)?;
+    expected_raw_len(header)?;
+    let mut zlib = zlib::Decoder::with_output_limit(options.limits.max_decompressed_bytes);
+    let mut scanlines = ScanlineDecoder::new(*header);
+    let mut filtered = Vec::new();
+    let mut pixels = Vec::new();
+    let mut inflated = 0usize;
+    let mut stopped = false;
+    for piece in idat_chunks
+        .iter()
+        .flat_map(|chunk| chunk.chunks(INFLATE_INPUT_STEP))
+    {
+        let start = filtered.len();
+        let result = zlib.write(piece, &mut filtered);
+        inflated += filtered.len() - start;
+        if let Err(error) = result {
+            recover(error, options, warnings)?;
+            stopped = true;
+        }
+        if inflated > expected_filtered {
+            recover(
+                Error::new(
+                    ErrorKind::InvalidImageData,
+                    "decompressed image data is larger than the image size",
+                ),
+                options,
+                warnings,
+            )?;
+            filtered.truncate(filtered.len() - (inflated - expected_filtered));
+            inflated = expected_filtered;
+            stopped = true;
+        }
+        let mut offset = 0;
+        loop {
+            match scanlines.decode_next(&filtered[offset..]) {
+                Ok(Some((position, consumed))) => {
+                    offset += consumed;
+                    pixels.clear();
+                    append_converted_pixels(
+                        &mut pixels,
+                        header,
+                        position.width,
+                        scanlines.row(),
+                        ancillary,
+                    )?;
+                    on_row(position, &pixels)?;
+                }
+                Ok(None) => break,
+                Err(error) => {
+                    recover(error, options, warnings)?;
+                    stopped = true;
+                    break;
+                }
+            }
+        }
+        filtered.drain(..offset);
+        if stopped {
+            return Ok(());
+        }
+    }
+    if !zlib.is_finished() {
+        recover(
+            Error::new(ErrorKind::UnexpectedEof, "unexpected end of deflate stream"),
+            options,
+            warnings,
+        )?;
+    } else if inflated != expected_filtered {
+        recover(
+            Error::new(
+                ErrorKind::InvalidImageData,
+                "decompressed image data does not match the image size",
+            ),
+            options,
+            warnings,
+        )?;
+    }
+    Ok(())
+}
+
+/// Records `error` as a warning in lenient mode, and returns it otherwise.
+/// Limit violations are never recovered from.
+pub(crate) fn recover(
+    error: Error,
+    options: &DecodeOptions,
+    warnings: &mut Vec<Error>,
+) -> Result<()> {
+    if options.lenient && error.kind() != ErrorKind::LimitExceeded {
+        warnings.push(error);
+        Ok(())
+    } else {
+        Err(error)
+    }
 }
 
 /// Parses PNG header and metadata chunks (IHDR, PLTE, tRNS), stopping at IDAT.
 pub(crate) fn parse_png_metadata(bytes: &[u8]) -> Result<(PngHeader, AncillaryChunks)> {
     if bytes.len() < PNG_SIGNATURE.len() || bytes[..PNG_SIGNATURE.len()] != PNG_SIGNATURE {
-        return Err(Error::InvalidData("invalid PNG signature".into()));
+        return Err(Error::new(ErrorKind::BadSignature, "invalid PNG signature"));
     }
 
     let mut cursor = Cursor::new(&bytes[PNG_SIGNATURE.len()..]);
-    let mut header = None;
-    let mut ancillary = AncillaryChunks::default();
+    let mut chunks = ChunkSequence::default();
 
     while cursor.remaining() > 0 {
-        let length = cursor.read_u32()? as usize;
-        let chunk_type = cursor.read_array::<4>()?;
-        let chunk_data = cursor.read_bytes(length)?;
-        let expected_crc = cursor.read_u32()?;
-
-        let actual_crc = crc::calculate(&[&chunk_type[..], chunk_data]);
-        if actual_crc != expected_crc {
-            return Err(Error::InvalidData(
-                format!(
-                    "CRC mismatch for chunk {}",
-                    core::str::from_utf8(&chunk_type).unwrap_or("????"),
-                )
-                .into(),
-            ));
+        let (chunk_type, chunk_data) = read_chunk(&mut cursor)?;
+        if matches!(&chunk_type, b"IDAT" | b"IEND") {
+            break;
         }
+        chunks.accept(&chunk_type, chunk_data)?;
+    }
 
-        match &chunk_type {
-            b"IHDR" => {
-                if header.is_some() {
-                    return Err(Error::InvalidData("duplicate IHDR chunk".into()));
+    let header = chunks.header.ok_or_else(|| {
+        Error::new(
+            ErrorKind::ChunkOrdering {
+                chunk_type: *b"IHDR",
+            },
+            "missing IHDR chunk",
+        )
+    })?;
+    Ok((header, chunks.ancillary))
+}
+
+/// The chunks of a PNG stream, with the image data left compressed.
+#[derive(Debug)]
+pub(crate) struct ParsedPng<'a> {
+    pub(crate) header: PngHeader,
+    pub(crate) ancillary: AncillaryChunks,
+    /// Data of the `IDAT` chunks, which hold the default image.
+    pub(crate) idat_chunks: Vec<&'a [u8]>,
+    /// The `acTL` chunk of an animated PNG.
+    pub(crate) animation: Option<AnimationControl>,
+    /// Frames of an animated PNG, in order. The data of a frame whose `fcTL`
+    /// precedes the first `IDAT` is the default image.
+    pub(crate) frames: Vec<FrameData<'a>>,
+}
+
+/// The frame control and compressed image data of one APNG frame.
+#[derive(Debug)]
+pub(crate) struct FrameData<'a> {
+    pub(crate) control: FrameControl,
+    pub(crate) data: Vec<&'a [u8]>,
+}
+
+pub(crate) fn parse_png<'a>(
+    bytes: &'a [u8],
+    options: &DecodeOptions,
+    warnings: &mut Vec<Error>,
+) -> Result<ParsedPng<'a>> {
+    if bytes.len() < PNG_SIGNATURE.len() || bytes[..PNG_SIGNATURE.len()] != PNG_SIGNATURE {
+        return Err(Error::new(ErrorKind::BadSignature, "invalid PNG signature"));
+    }
+
+    let mut cursor = Cursor::new(&bytes[PNG_SIGNATURE.len()..]);
+    let mut chunks = ChunkSequence::new(options.limits);
+    let mut idat_chunks = Vec::new();
+    let mut frames = Vec::new();
+
+    while cursor.remaining() > 0 {
+        let start = cursor.offset;
+        let (chunk_type, chunk_data, expected_crc) = match read_raw_chunk(&mut cursor) {
+            Ok(chunk) => chunk,
+            Err(error) if options.lenient && error.kind() == ErrorKind::UnexpectedEof => {
+                // Keep whatever image data the truncated chunk still holds.
+                if let Some(data) = truncated_idat_data(&cursor.bytes[start..]) {
+                    chunks.accept(b"IDAT", data)?;
+                    idat_chunks.push(data);
                 }
-                header = Some(PngHeader::parse(chunk_data)?);
+                warnings.push(error);
+                break;
             }
-            b"PLTE" => {
-                let Some(header) = header else {
-                    return Err(Error::InvalidData("PLTE chunk before IHDR".into()));
-                };
-                if matches!(header.color_type, CT_GRAY | CT_GRAY_ALPHA) {
-                    return Err(Error::InvalidData(
-                        "PLTE chunk is not allowed for grayscale images".into(),
-                    ));
-                }
-                ancillary.set_palette(parse_palette(chunk_data)?)?;
+            Err(error) => return Err(error),
+        };
+        let actual_crc = crc::calculate(&[&chunk_type[..], chunk_data]);
+        if let Err(error) = check_chunk_crc(&chunk_type, actual_crc, expected_crc) {
+            if !(options.lenient && is_ancillary(&chunk_type)) {
+                return Err(error);
             }
-            b"tRNS" => {
-                let Some(header) = header else {
-                    return Err(Error::InvalidData("tRNS chunk before IHDR".into()));
-                };
-                ancillary.set_transparency(parse_transparency(chunk_data, &header, &ancillary)?)?;
+            // Corrupt ancillary chunks are dropped, as libpng does.
+            warnings.push(error);
+            continue;
+        }
+        if let Err(error) = chunks.accept(&chunk_type, chunk_data) {
+            if !(options.lenient
+                && is_ancillary(&chunk_type)
+                && error.kind() != ErrorKind::LimitExceeded)
+            {
+                return Err(error);
+            }
+            // So are malformed or misplaced ones.
+            warnings.push(error);
+            continue;
+        }
+        match &chunk_type {
+            b"IDAT" if !chunk_data.is_empty() => idat_chunks.push(chunk_data),
+            b"fcTL" => {
+                if let Some(control) = chunks.frame_control.take() {
+                    frames.push(FrameData {
+                        control,
+                        data: Vec::new(),
+                    });
+                }
             }
-            b"IDAT" | b"IEND" => break,
+            b"fdAT" if chunks.animation.is_some() && chunk_data.len() > 4 => frames
+                .last_mut()
+                .expect("bug: fdAT is only accepted after fcTL")
+                .data
+                .push(&chunk_data[4..]),
             _ => {}
         }
+        if chunks.seen_iend {
+            break;
+        }
     }
 
-    let header = header.ok_or_else(|| Error::InvalidData("missing IHDR chunk".into()))?;
-    Ok((header, ancillary))
+    if options.lenient && !chunks.seen_iend {
+        warnings.push(Error::new(
+            ErrorKind::ChunkOrdering {
+                chunk_type: *b"IEND",
+            },
+            "missing IEND chunk",
+        ));
+        chunks.seen_iend = true;
+    }
+    let header = chunks.finish()?;
+    if idat_chunks.is_empty() {
+        return Err(Error::new(
+            ErrorKind::ChunkOrdering {
+                chunk_type: *b"IDAT",
+            },
+            "missing IDAT chunk",
+        ));
+    }
+    if chunks.default_image_is_frame
+        && let Some(first) = frames.first_mut()
+    {
+        first.data.clone_from(&idat_chunks);
+    }
+    Ok(ParsedPng {
+        header,
+        ancillary: chunks.ancillary,
+        idat_chunks,
+        animation: chunks.animation,
+        frames,
+    })
 }
 
-fn parse_png(bytes: &[u8]) -> Result<(PngHeader, AncillaryChunks, Vec<u8>)> {
-    if bytes.len() < PNG_SIGNATURE.len() || bytes[..PNG_SIGNATURE.len()] != PNG_SIGNATURE {
-        return Err(Error::InvalidData("invalid PNG signature".into()));
+fn read_chunk<'a>(cursor: &mut Cursor<'a>) -> Result<([u8; 4], &'a [u8])> {
+    let (chunk_type, chunk_data, expected_crc) = read_raw_chunk(cursor)?;
+    check_chunk_crc(
+        &chunk_type,
+        crc::calculate(&[&chunk_type[..], chunk_data]),
+        expected_crc,
+    )?;
+    Ok((chunk_type, chunk_data))
+}
+
+/// Reads a chunk without checking its CRC, returning the stored CRC.
+pub(crate) fn read_raw_chunk<'a>(cursor: &mut Cursor<'a>) -> Result<([u8; 4], &'a [u8], u32)> {
+    let length = cursor.read_u32()? as usize;
+    let chunk_type = cursor.read_array::<4>()?;
+    check_chunk_length(&chunk_type, length)?;
+    let chunk_data = cursor.read_bytes(length)?;
+    let expected_crc = cursor.read_u32()?;
+    Ok((chunk_type, chunk_data, expected_crc))
+}
+
+/// Returns the data present in a truncated `IDAT` chunk starting at `bytes`.
+fn truncated_idat_data(bytes: &[u8]) -> Option<&[u8]> {
+    if bytes.get(4..8)? != b"IDAT" {
+        return None;
     }
+    let length = u32::from_be_bytes(bytes[..4].try_into().expect("bug: length is 4 bytes"));
+    let data = &bytes[8..];
+    let data = &data[..data.len().min(length as usize)];
+    (!data.is_empty()).then_some(data)
+}
 
-    let mut cursor = Cursor::new(&bytes[PNG_SIGNATURE.len()..]);
-    let mut header = None;
-    let mut idat_data = Vec::new();
-    let mut ancillary = AncillaryChunks::default();
-    let mut seen_idat = false;
-    let mut seen_iend = false;
+/// Maximum length of a chunk's data.
+pub(crate) const MAX_CHUNK_LEN: usize = (1 << 31) - 1;
+
+/// Rejects chunk lengths above the 2³¹ − 1 bytes the format allows.
+pub(crate) fn check_chunk_length(chunk_type: &[u8; 4], length: usize) -> Result<()> {
+    if length > MAX_CHUNK_LEN {
+        return Err(Error::new(
+            ErrorKind::InvalidChunk {
+                chunk_type: *chunk_type,
+            },
+            "chunk length exceeds 2^31 - 1 bytes",
+        ));
+    }
+    Ok(())
+}
 
-    while cursor.remaining() > 0 {
-        let length = cursor.read_u32()? as usize;
-        let chunk_type = cursor.read_array::<4>()?;
-        let chunk_data = cursor.read_bytes(length)?;
-        let expected_crc = cursor.read_u32()?;
+pub(crate) fn check_chunk_crc(chunk_type: &[u8; 4], actual: u32, expected: u32) -> Result<()> {
+    if actual != expected {
+        return Err(ErrorKind::CrcMismatch {
+            chunk_type: *chunk_type,
+        }
+        .into());
+    }
+    Ok(())
+}
 
-        let actual_crc = crc::calculate(&[&chunk_type[..], chunk_data]);
-        if actual_crc != expected_crc {
-            return Err(Error::InvalidData(
-                format!(
-                    "CRC mismatch for chunk {}",
-                    core::str::from_utf8(&chunk_type).unwrap_or("????"),
-                )
-                .into(),
+/// Critical chunks seen so far, with the ordering rules between them and the
+/// chunk count and size limits.
+#[derive(Debug, Default)]
+pub(crate) struct ChunkSequence {
+    pub(crate) header: Option<PngHeader>,
+    pub(crate) ancillary: AncillaryChunks,
+    pub(crate) seen_idat: bool,
+    pub(crate) seen_iend: bool,
+    pub(crate) limits: DecodeLimits,
+    pub(crate) animation: Option<AnimationControl>,
+    /// The frame control of the last accepted `fcTL` chunk, until taken.
+    pub(crate) frame_control: Option<FrameControl>,
+    /// `true` if the first frame is the default image.
+    pub(crate) default_image_is_frame: bool,
+    frame_count: u32,
+    next_sequence_number: u32,
+    /// `true` while `fdAT` chunks may follow, that is after an `fcTL` chunk
+    /// that follows the `IDAT` chunks.
+    accepts_frame_data: bool,
+    chunk_count: usize,
+    ancillary_bytes: usize,
+}
+
+impl ChunkSequence {
+    pub(crate) fn new(limits: DecodeLimits) -> Self {
+        Self {
+            limits,
+            ..Self::default()
+        }
+    }
+
+    /// Counts a chunk of `length` data bytes against the limits. Called by
+    /// [`accept`](Self::accept), and separately by streaming readers before
+    /// they buffer a chunk's data.
+    pub(crate) fn check_limits(&self, chunk_type: &[u8; 4], length: usize) -> Result<()> {
+        if self.chunk_count >= self.limits.max_chunks {
+            return Err(Error::new(
+                ErrorKind::LimitExceeded,
+                "PNG has more chunks than the limit",
+            ));
+        }
+        if is_ancillary_metadata(chunk_type)
+            && self.ancillary_bytes.saturating_add(length) > self.limits.max_ancillary_bytes
+        {
+            return Err(Error::new(
+                ErrorKind::LimitExceeded,
+                "ancillary chunks exceed the size limit",
             ));
         }
+        Ok(())
+    }
 
-        match &chunk_type {
+    /// Records a chunk. The data of `IDAT` chunks is not inspected.
+    pub(crate) fn accept(&mut self, chunk_type: &[u8; 4], chunk_data: &[u8]) -> Result<()> {
+        self.check_limits(chunk_type, chunk_data.len())?;
+        self.chunk_count += 1;
+        if is_ancillary_metadata(chunk_type) {
+            self.ancillary_bytes += chunk_data.len();
+        }
+        match chunk_type {
             b"IHDR" => {
-                if header.is_some() {
-                    return Err(Error::InvalidData("duplicate IHDR chunk".into()));
+                if self.header.is_some() {
+                    return Err(Error::new(
+                        ErrorKind::ChunkOrdering {
+                            chunk_type: *b"IHDR",
+                        },
+                        "duplicate IHDR chunk",
+                    ));
                 }
-                if seen_idat {
-                    return Err(Error::InvalidData("IHDR chunk after IDAT".into()));
+                if self.seen_idat {
+                    return Err(Error::new(
+                        ErrorKind::ChunkOrdering {
+                            chunk_type: *b"IHDR",
+                        },
+                        "IHDR chunk after IDAT",
+                    ));
                 }
-                header = Some(PngHeader::parse(chunk_data)?);
+                self.header = Some(PngHeader::parse(chunk_data, &self.limits)?);
             }
             b"PLTE" => {
-                let Some(header) = header else {
-                    return Err(Error::InvalidData("PLTE chunk before IHDR".into()));
+                let Some(header) = self.header else {
+                    return Err(Error::new(
+                        ErrorKind::ChunkOrdering {
+                            chunk_type: *b"PLTE",
+                        },
+                        "PLTE chunk before IHDR",
+                    ));
                 };
-                if seen_idat {
-                    return Err(Error::InvalidData("PLTE appears after IDAT".into()));
+                if self.seen_idat {
+                    return Err(Error::new(
+                        ErrorKind::ChunkOrdering {
+                            chunk_type: *b"PLTE",
+                        },
+                        "PLTE appears after IDAT",
+                    ));
                 }
                 if matches!(header.color_type, CT_GRAY | CT_GRAY_ALPHA) {
-                    return Err(Error::InvalidData(
-                        "PLTE chunk is not allowed for grayscale images".into(),
+                    return Err(Error::new(
+                        ErrorKind::ChunkOrdering {
+                            chunk_type: *b"PLTE",
+                        },
+                        "PLTE chunk is not allowed for grayscale images",
                     ));
                 }
-                ancillary.set_palette(parse_palette(chunk_data)?)?;
+                self.ancillary.set_palette(parse_palette(chunk_data)?)?;
             }
             b"tRNS" => {
-                let Some(header) = header else {
-                    return Err(Error::InvalidData("tRNS chunk before IHDR".into()));
+                let Some(header) = self.header else {
+                    return Err(Error::new(
+                        ErrorKind::ChunkOrdering {
+                            chunk_type: *b"tRNS",
+                        },
+                        "tRNS chunk before IHDR",
+                    ));
                 };
-                if seen_idat {
-                    return Err(Error::InvalidData("tRNS appears after IDAT".into()));
+                if self.seen_idat {
+                    return Err(Error::new(
+                        ErrorKind::ChunkOrdering {
+                            chunk_type: *b"tRNS",
+                        },
+                        "tRNS appears after IDAT",
+                    ));
                 }
-                ancillary.set_transparency(parse_transparency(chunk_data, &header, &ancillary)?)?;
+                let transparency = parse_transparency(chunk_data, &header, &self.ancillary)?;
+                self.ancillary.set_transparency(transparency)?;
+            }
+            b"gAMA" | b"cHRM" | b"sRGB" | b"iCCP" | b"cICP" | b"sBIT" | b"pHYs" | b"oFFs"
+            | b"sPLT" | b"eXIf" | b"bKGD" | b"hIST" | b"tIME" | b"tEXt" | b"zTXt" | b"iTXt" => {
+                let header = self.check_metadata_position(chunk_type)?;
+                let palette_len = self.ancillary.palette.as_ref().map(Vec::len);
+                let mut budget = self.decompression_budget();
+                parse_metadata_chunk(
+                    &mut self.ancillary.metadata,
+                    &header,
+                    palette_len,
+                    chunk_type,
+                    chunk_data,
+                    &mut budget,
+                )?;
+                self.ancillary_bytes = self.limits.max_ancillary_bytes - budget;
             }
             b"IDAT" => {
-                if header.is_none() {
-                    return Err(Error::InvalidData("IDAT chunk before IHDR".into()));
+                let Some(header) = self.header else {
+                    return Err(Error::new(
+                        ErrorKind::ChunkOrdering {
+                            chunk_type: *b"IDAT",
+                        },
+                        "IDAT chunk before IHDR",
+                    ));
+                };
+                if !self.seen_idat {
+                    self.ancillary
+                        .expand_metadata_for_transparency(header.bit_depth);
                 }
-                seen_idat = true;
-                idat_data.extend_from_slice(chunk_data);
+                self.seen_idat = true;
             }
-            b"IEND" => {
-                seen_iend = true;
-                break;
+            b"acTL" => {
+                self.check_metadata_position(chunk_type)?;
+                if self.animation.is_some() {
+                    return Err(Error::new(
+                        ErrorKind::ChunkOrdering {
+                            chunk_type: *b"acTL",
+                        },
+                        "duplicate chunk",
+                    ));
+                }
+                self.animation = Some(parse_animation_control(chunk_data)?);
             }
+            b"fcTL" | b"fdAT" => self.accept_frame_chunk(chunk_type, chunk_data)?,
+            b"IEND" => self.seen_iend = true,
             _ => {}
         }
+        Ok(())
     }
 
-    if !seen_iend {
-        return Err(Error::InvalidData("missing IEND chunk".into()));
+    /// Records an `fcTL` or `fdAT` chunk. Both are ignored unless an `acTL`
+    /// chunk came first, as the image is then not animated.
+    fn accept_frame_chunk(&mut self, chunk_type: &[u8; 4], chunk_data: &[u8]) -> Result<()> {
+        let (Some(header), Some(animation)) = (self.header, self.animation) else {
+            return Ok(());
+        };
+        let ordering = |message| {
+            Err(Error::new(
+                ErrorKind::ChunkOrdering {
+                    chunk_type: *chunk_type,
+                },
+                message,
+            ))
+        };
+        let Some((sequence_number, data)) = chunk_data.split_first_chunk::<4>() else {
+            return Err(Error::new(
+                ErrorKind::InvalidChunk {
+                    chunk_type: *chunk_type,
+                },
+                "chunk is missing its sequence number",
+            ));
+        };
+        if u32::from_be_bytes(*sequence_number) != self.next_sequence_number {
+            return ordering("APNG sequence number is out of order");
+        }
+        if chunk_type == b"fcTL" {
+            if self.frame_count == animation.num_frames {
+                return ordering("more frames than announced in acTL");
+            }
+            if !self.seen_idat && self.frame_count > 0 {
+                return ordering("only one fcTL chunk may precede IDAT");
+            }
+            // fdAT chunks of a malformed frame are rejected too.
+            self.accepts_frame_data = false;
+            let control = parse_frame_control(data, &header, !self.seen_idat)?;
+            self.frame_control = Some(control);
+            self.default_image_is_frame |= !self.seen_idat;
+            self.accepts_frame_data = self.seen_idat;
+            self.frame_count += 1;
+        } else if !self.accepts_frame_data {
+            return ordering("fdAT chunk must follow an fcTL chunk after IDAT");
+        }
+        self.next_sequence_number += 1;
+        Ok(())
     }
-    let header = header.ok_or_else(|| Error::InvalidData("missing IHDR chunk".into()))?;
-    if idat_data.is_empty() {
-        return Err(Error::InvalidData("missing IDAT chunk".into()));
+
+    /// Number of bytes that compressed ancillary data may still inflate to.
+    fn decompression_budget(&self) -> usize {
+        self.limits
+            .max_ancillary_bytes
+            .saturating_sub(self.ancillary_bytes)
     }
-    ancillary.validate(&header)?;
-    Ok((header, ancillary, idat_data))
+
+    /// Checks the ordering rules of an ancillary chunk parsed into
+    /// [`Metadata`], returning the header it follows.
+    fn check_metadata_position(&self, chunk_type: &[u8; 4]) -> Result<PngHeader> {
+        let ordering = |message| {
+            Err(Error::new(
+                ErrorKind::ChunkOrdering {
+                    chunk_type: *chunk_type,
+                },
+                message,
+            ))
+        };
+        let Some(header) = self.header else {
+            return ordering("chunk appears before IHDR");
+        };
+        let before_palette = matches!(
+            chunk_type,
+            b"gAMA" | b"cHRM" | b"sRGB" | b"iCCP" | b"cICP" | b"sBIT"
+        );
+        let anywhere = matches!(chunk_type, b"tIME" | b"tEXt" | b"zTXt" | b"iTXt");
+        let after_palette =
+            chunk_type == b"hIST" || (chunk_type == b"bKGD" && header.color_type == CT_INDEXED);
+        if before_palette && self.ancillary.palette.is_some() {
+            ordering("chunk must appear before PLTE")
+        } else if !anywhere && self.seen_idat {
+            ordering("chunk must appear before IDAT")
+        } else if after_palette && self.ancillary.palette.is_none() {
+            ordering("chunk must appear after PLTE")
+        } else {
+            Ok(header)
+        }
+    }
+
+    /// Checks that the stream was complete and returns its header.
+    pub(crate) fn finish(&self) -> Result<PngHeader> {
+        if !self.seen_iend {
+            return Err(Error::new(
+                ErrorKind::ChunkOrdering {
+                    chunk_type: *b"IEND",
+                },
+                "missing IEND chunk",
+            ));
+        }
+        let header = self.header.ok_or_else(|| {
+            Error::new(
+                ErrorKind::ChunkOrdering {
+                    chunk_type: *b"IHDR",
+                },
+                "missing IHDR chunk",
+            )
+        })?;
+        self.ancillary.validate(&header)?;
+        Ok(header)
+    }
+}
+
+/// Returns true for ancillary chunks counted against
+/// [`DecodeLimits::max_ancillary_bytes`]. `fdAT` holds image data, which is
+/// bounded by the image size instead.
+fn is_ancillary_metadata(chunk_type: &[u8; 4]) -> bool {
+    is_ancillary(chunk_type) && chunk_type != b"fdAT"
+}
+
+fn parse_animation_control(chunk_data: &[u8]) -> Result<AnimationControl> {
+    let invalid = |message| {
+        Error::new(
+            ErrorKind::InvalidChunk {
+                chunk_type: *b"acTL",
+            },
+            message,
+        )
+    };
+    let Some((num_frames, num_plays)) = chunk_data
+        .split_first_chunk::<4>()
+        .and_then(|(num_frames, rest)| Some((num_frames, <[u8; 4]>::try_from(rest).ok()?)))
+    else {
+        return Err(invalid("acTL chunk must contain 8 bytes"));
+    };
+    let num_frames = u32::from_be_bytes(*num_frames);
+    if num_frames == 0 {
+        return Err(invalid("animation has no frames"));
+    }
+    Ok(AnimationControl {
+        num_frames,
+        num_plays: u32::from_be_bytes(num_plays),
+    })
+}
+
+/// Parses the data of an `fcTL` chunk following its sequence number. The
+/// frame of the default image must cover the whole canvas.
+fn parse_frame_control(
+    data: &[u8],
+    header: &PngHeader,
+    is_default_image: bool,
+) -> Result<FrameControl> {
+    let invalid = |message| {
+        Error::new(
+            ErrorKind::InvalidChunk {
+                chunk_type: *b"fcTL",
+            },
+            message,
+        )
+    };
+    if data.len() != 22 {
+        return Err(invalid("fcTL chunk must contain 26 bytes"));
+    }
+    let read_u32 = |offset: usize| {
+        u32::from_be_bytes(
+            data[offset..offset + 4]
+                .try_into()
+                .expect("bug: slice is 4 bytes"),
+        )
+    };
+    let read_u16 = |offset: usize| u16::from_be_bytes([data[offset], data[offset + 1]]);
+    let dispose_op = match data[20] {
+        0 => DisposeOp::None,
+        1 => DisposeOp::Background,
+        2 => DisposeOp::Previous,
+        _ => return Err(invalid("unknown dispose_op")),
+    };
+    let blend_op = match data[21] {
+        0 => BlendOp::Source,
+        1 => BlendOp::Over,
+        _ => return Err(invalid("unknown blend_op")),
+    };
+    let control = FrameControl {
+        width: read_u32(0),
+        height: read_u32(4),
+        x_offset: read_u32(8),
+        y_offset: read_u32(12),
+        delay_num: read_u16(16),
+        delay_den: read_u16(18),
+        dispose_op,
+        blend_op,
+    };
+    let fits = |offset: u32, size: u32, canvas: u32| {
+        size > 0 && offset.checked_add(size).is_some_and(|end| end <= canvas)
+    };
+    if !fits(control.x_offset, control.width, header.width)
+        || !fits(control.y_offset, control.height, header.height)
+    {
+        return Err(invalid("frame does not fit in the image"));
+    }
+    if is_default_image
+        && (
+            control.width,
+            control.height,
+            control.x_offset,
+            control.y_offset,
+        ) != (header.width, header.height, 0, 0)
+    {
+        return Err(invalid(
+            "frame of the default image must cover the whole image",
+        ));
+    }
+    Ok(control)
 }
 
 fn parse_palette(chunk_data: &[u8]) -> Result<Vec<[u8; 3]>> {
     if chunk_data.is_empty() || !chunk_data.len().is_multiple_of(3) {
-        return Err(Error::InvalidData(
-            "PLTE length must be a non-zero multiple of 3".into(),
+        return Err(Error::new(
+            ErrorKind::InvalidChunk {
+                chunk_type: *b"PLTE",
+            },
+            "PLTE length must be a non-zero multiple of 3",
         ));
     }
     let (palette_chunks, remainder) = chunk_data.as_chunks::<3>();
     debug_assert!(remainder.is_empty());
     let palette = palette_chunks.to_vec();
     if palette.len() > 256 {
-        return Err(Error::InvalidData(
-            "PLTE must not contain more than 256 entries".into(),
+        return Err(Error::new(
+            ErrorKind::InvalidChunk {
+                chunk_type: *b"PLTE",
+            },
+            "PLTE must not contain more than 256 entries",
         ));
     }
     Ok(palette)
@@ -438,8 +1231,11 @@ fn parse_transparency(
     match header.color_type {
         CT_GRAY => {
             if chunk_data.len() != 2 {
-                return Err(Error::InvalidData(
-                    "grayscale tRNS chunk must contain 2 bytes".into(),
+                return Err(Error::new(
+                    ErrorKind::InvalidChunk {
+                        chunk_type: *b"tRNS",
+                    },
+                    "grayscale tRNS chunk must contain 2 bytes",
                 ));
             }
             let sample = u16::from_be_bytes([chunk_data[0], chunk_data[1]]);
@@ -449,16 +1245,22 @@ fn parse_transparency(
                 (1u16 << header.bit_depth) - 1
             };
             if sample > max {
-                return Err(Error::InvalidData(
-                    "invalid grayscale transparency sample".into(),
+                return Err(Error::new(
+                    ErrorKind::InvalidChunk {
+                        chunk_type: *b"tRNS",
+                    },
+                    "invalid grayscale transparency sample",
                 ));
             }
             Ok(Transparency::Grayscale(sample))
         }
         CT_RGB => {
             if chunk_data.len() != 6 {
-                return Err(Error::InvalidData(
-                    "truecolor tRNS chunk must contain 6 bytes".into(),
+                return Err(Error::new(
+                    ErrorKind::InvalidChunk {
+                        chunk_type: *b"tRNS",
+                    },
+                    "truecolor tRNS chunk must contain 6 bytes",
                 ));
             }
             Ok(Transparency::Truecolor([
@@ -469,90 +1271,424 @@ fn parse_transparency(
         }
         CT_INDEXED => {
             if ancillary.palette.is_none() {
-                return Err(Error::InvalidData(
-                    "tRNS chunk must appear after PLTE".into(),
+                return Err(Error::new(
+                    ErrorKind::ChunkOrdering {
+                        chunk_type: *b"tRNS",
+                    },
+                    "tRNS chunk must appear after PLTE",
                 ));
             }
             Ok(Transparency::Palette(chunk_data.to_vec()))
         }
-        _ => Err(Error::InvalidData(
-            format!("tRNS is not allowed for color type {}", header.color_type).into(),
+        _ => Err(Error::new(
+            ErrorKind::ChunkOrdering {
+                chunk_type: *b"tRNS",
+            },
+            "tRNS is not allowed for this color type",
         )),
     }
 }
 
-fn decompress_zlib(data: &[u8]) -> Result<Vec<u8>> {
-    if data.len() < 6 {
-        return Err(Error::InvalidData("zlib stream is too short".into()));
-    }
-
-    let cmf = data[0];
-    let flg = data[1];
-    let header = u16::from(cmf) << 8 | u16::from(flg);
-    if header % 31 != 0 {
-        return Err(Error::InvalidData(
-            "zlib header check bits are invalid".into(),
-        ));
-    }
-    if cmf & 0x0F != 8 {
-        return Err(Error::Unsupported(
-            format!("unsupported zlib compression method: {}", cmf & 0x0F).into(),
+/// Parses an ancillary chunk into `metadata`. The decompressed size of ICC
+/// profiles and text is deducted from `budget`.
+fn parse_metadata_chunk(
+    metadata: &mut Metadata,
+    header: &PngHeader,
+    palette_len: Option<usize>,
+    chunk_type: &[u8; 4],
+    chunk_data: &[u8],
+    budget: &mut usize,
+) -> Result<()> {
+    let duplicate = match chunk_type {
+        b"gAMA" => metadata.gamma.is_some(),
+        b"cHRM" => metadata.chromaticities.is_some(),
+        b"sRGB" => metadata.srgb.is_some(),
+        b"iCCP" => metadata.icc_profile.is_some(),
+        b"cICP" => metadata.cicp.is_some(),
+        b"pHYs" => metadata.physical_dimensions.is_some(),
+        b"oFFs" => metadata.offset.is_some(),
+        b"tIME" => metadata.modification_time.is_some(),
+        b"sBIT" => metadata.significant_bits.is_some(),
+        b"bKGD" => metadata.background.is_some(),
+        b"hIST" => metadata.histogram.is_some(),
+        b"eXIf" => metadata.exif.is_some(),
+        _ => false,
+    };
+    if duplicate {
+        return Err(Error::new(
+            ErrorKind::ChunkOrdering {
+                chunk_type: *chunk_type,
+            },
+            "duplicate chunk",
         ));
     }
-    if cmf >> 4 > 7 {
-        return Err(Error::Unsupported("zlib window size is too large".into()));
+    let invalid = |message| {
+        Error::new(
+            ErrorKind::InvalidChunk {
+                chunk_type: *chunk_type,
+            },
+            message,
+        )
+    };
+    let read_u32 = |offset: usize| {
+        u32::from_be_bytes(
+            chunk_data[offset..offset + 4]
+                .try_into()
+                .expect("bug: chunk field must be 4 bytes"),
+        )
+    };
+    match chunk_type {
+        b"gAMA" => {
+            if chunk_data.len() != 4 {
+                return Err(invalid("gAMA chunk must contain 4 bytes"));
+            }
+            let gamma = read_u32(0);
+            if gamma == 0 {
+                return Err(invalid("gamma must be non-zero"));
+            }
+            metadata.gamma = Some(gamma);
+        }
+        b"cHRM" => {
+            if chunk_data.len() != 32 {
+                return Err(invalid("cHRM chunk must contain 32 bytes"));
+            }
+            let point = |index: usize| [read_u32(index * 8), read_u32(index * 8 + 4)];
+            metadata.chromaticities = Some(Chromaticities {
+                white: point(0),
+                red: point(1),
+                green: point(2),
+                blue: point(3),
+            });
+        }
+        b"sRGB" => {
+            let intent = match chunk_data {
+                [0] => RenderingIntent::Perceptual,
+                [1] => RenderingIntent::RelativeColorimetric,
+                [2] => RenderingIntent::Saturation,
+                [3] => RenderingIntent::AbsoluteColorimetric,
+                [_] => return Err(invalid("unknown rendering intent")),
+                _ => return Err(invalid("sRGB chunk must contain 1 byte")),
+            };
+            metadata.srgb = Some(intent);
+        }
+        b"iCCP" => {
+            let (name, rest) = split_keyword(chunk_type, chunk_data)?;
+            let Some((&COMPRESSION_METHOD_DEFLATE, zlib_data)) = rest.split_first() else {
+                return Err(invalid("unsupported ICC profile compression method"));
+            };
+            let profile = inflate_chunk_data(chunk_type, zlib_data, budget)?;
+            metadata.icc_profile = Some(IccProfile {
+                name: latin1_to_string(name),
+                profile,
+            });
+        }
+        b"cICP" => {
+            let &[
+                color_primaries,
+                transfer_function,
+                matrix_coefficients,
+                full_range,
+            ] = chunk_data
+            else {
+                return Err(invalid("cICP chunk must contain 4 bytes"));
+            };
+            if matrix_coefficients != 0 {
+                return Err(invalid("cICP matrix coefficients must be 0 for RGB images"));
+            }
+            if full_range > 1 {
+                return Err(invalid("invalid cICP full range flag"));
+            }
+            metadata.cicp = Some(Cicp {
+                color_primaries,
+                transfer_function,
+                matrix_coefficients,
+                full_range: full_range == 1,
+            });
+        }
+        b"pHYs" => {
+            if chunk_data.len() != 9 {
+                return Err(invalid("pHYs chunk must contain 9 bytes"));
+            }
+            let unit = match chunk_data[8] {
+                0 => PhysicalUnit::Unknown,
+                1 => PhysicalUnit::Meter,
+                _ => return Err(invalid("unknown pHYs unit")),
+            };
+            metadata.physical_dimensions = Some(PhysicalDimensions {
+                x: read_u32(0),
+                y: read_u32(4),
+                unit,
+            });
+        }
+        b"oFFs" => {
+            if chunk_data.len() != 9 {
+                return Err(invalid("oFFs chunk must contain 9 bytes"));
+            }
+            let unit = match chunk_data[8] {
+                0 => OffsetUnit::Pixel,
+                1 => OffsetUnit::Micrometer,
+                _ => return Err(invalid("unknown oFFs unit")),
+            };
+            metadata.offset = Some(ImageOffset {
+                x: read_u32(0) as i32,
+                y: read_u32(4) as i32,
+                unit,
+            });
+        }
+        b"tIME" => {
+            let &[year_hi, year_lo, month, day, hour, minute, second] = chunk_data else {
+                return Err(invalid("tIME chunk must contain 7 bytes"));
+            };
+            let time = Timestamp {
+                year: u16::from_be_bytes([year_hi, year_lo]),
+                month,
+                day,
+                hour,
+                minute,
+                second,
+            };
+            if !time.is_valid() {
+                return Err(invalid("modification time is out of range"));
+            }
+            metadata.modification_time = Some(time);
+        }
+        b"sBIT" => {
+            let significant_bits = match (header.color_type, chunk_data) {
+                (CT_GRAY, &[gray]) => SignificantBits::Gray(gray),
+                (CT_GRAY_ALPHA, &[gray, alpha]) => SignificantBits::GrayAlpha([gray, alpha]),
+                (CT_RGB | CT_INDEXED, &[red, green, blue]) => {
+                    SignificantBits::Rgb([red, green, blue])
+                }
+                (CT_RGBA, &[red, green, blue, alpha]) => {
+                    SignificantBits::Rgba([red, green, blue, alpha])
+                }
+                _ => return Err(invalid("sBIT length does not match the color type")),
+            };
+            let sample_depth = if header.color_type == CT_INDEXED {
+                8
+            } else {
+                header.bit_depth
+            };
+            if chunk_data
+                .iter()
+                .any(|&bits| bits == 0 || bits > sample_depth)
+            {
+                return Err(invalid("significant bits exceed the sample depth"));
+            }
+            metadata.significant_bits = Some(significant_bits);
+        }
+        b"bKGD" => {
+            let max_sample = if header.bit_depth == 16 {
+                u16::MAX
+            } else {
+                (1 << header.bit_depth) - 1
+            };
+            let sample = |index: usize| {
+                let sample = u16::from_be_bytes([chunk_data[index], chunk_data[index + 1]]);
+                if sample > max_sample {
+                    return Err(invalid("background sample exceeds the bit depth"));
+                }
+                Ok(sample)
+            };
+            let background = match (header.color_type, chunk_data.len()) {
+                (CT_GRAY | CT_GRAY_ALPHA, 2) => Background::Gray(sample(0)?),
+                (CT_RGB | CT_RGBA, 6) => Background::Rgb([sample(0)?, sample(2)?, sample(4)?]),
+                (CT_INDEXED, 1) => {
+                    if usize::from(chunk_data[0]) >= palette_len.unwrap_or(0) {
+                        return Err(invalid("background palette index is out of range"));
+                    }
+                    Background::PaletteIndex(chunk_data[0])
+                }
+                _ => return Err(invalid("bKGD length does not match the color type")),
+            };
+            metadata.background = Some(background);
+        }
+        b"hIST" => {
+            if Some(chunk_data.len()) != palette_len.map(|len| len * 2) {
+                return Err(invalid("hIST needs one entry per palette entry"));
+            }
+            let (frequencies, _) = chunk_data.as_chunks::<2>();
+            metadata.histogram = Some(frequencies.iter().map(|&f| u16::from_be_bytes(f)).collect());
+        }
+        b"sPLT" => {
+            let (name, rest) = split_keyword(chunk_type, chunk_data)?;
+            let Some((&sample_depth, entries)) = rest.split_first() else {
+                return Err(invalid("sPLT chunk is truncated"));
+            };
+            let entry_len = match sample_depth {
+                8 => 6,
+                16 => 10,
+                _ => return Err(invalid("invalid sPLT sample depth")),
+            };
+            if !entries.len().is_multiple_of(entry_len) {
+                return Err(invalid("sPLT entries are truncated"));
+            }
+            let sample_bytes = entry_len / 5;
+            let entries = entries
+                .chunks_exact(entry_len)
+                .map(|entry| {
+                    let sample = |index: usize| {
+                        let bytes = &entry[index * sample_bytes..(index + 1) * sample_bytes];
+                        bytes
+                            .iter()
+                            .fold(0u16, |value, &byte| value << 8 | u16::from(byte))
+                    };
+                    SuggestedPaletteEntry {
+                        red: sample(0),
+                        green: sample(1),
+                        blue: sample(2),
+                        alpha: sample(3),
+                        frequency: u16::from_be_bytes([entry[entry_len - 2], entry[entry_len - 1]]),
+                    }
+                })
+                .collect();
+            metadata.suggested_palettes.push(SuggestedPalette {
+                name: latin1_to_string(name),
+                sample_depth,
+                entries,
+            });
+        }
+        b"eXIf" => metadata.exif = Some(chunk_data.to_vec()),
+        _ => {
+            let text = parse_text_chunk(chunk_type, chunk_data, budget)?;
+            metadata.text.push(text);
+        }
     }
-    if (flg & 0x20) != 0 {
-        return Err(Error::Unsupported(
-            "zlib preset dictionary is not supported".into(),
-        ));
+    Ok(())
+}
+
+/// Parses a `tEXt`, `zTXt` or `iTXt` chunk. The decompressed size of
+/// compressed text is deducted from `budget`.
+fn parse_text_chunk(
+    chunk_type: &[u8; 4],
+    chunk_data: &[u8],
+    budget: &mut usize,
+) -> Result<TextEntry> {
+    let invalid = |message| {
+        Error::new(
+            ErrorKind::InvalidChunk {
+                chunk_type: *chunk_type,
+            },
+            message,
+        )
+    };
+    let (keyword, rest) = split_keyword(chunk_type, chunk_data)?;
+    let keyword = latin1_to_string(keyword);
+    match chunk_type {
+        b"tEXt" => Ok(TextEntry {
+            keyword,
+            text: latin1_to_string(rest),
+            compressed: false,
+            international: None,
+        }),
+        b"zTXt" => {
+            let Some((&COMPRESSION_METHOD_DEFLATE, zlib_data)) = rest.split_first() else {
+                return Err(invalid("unsupported text compression method"));
+            };
+            let text = inflate_chunk_data(chunk_type, zlib_data, budget)?;
+            Ok(TextEntry {
+                keyword,
+                text: latin1_to_string(&text),
+                compressed: true,
+                international: None,
+            })
+        }
+        _ => {
+            let (compressed, rest) = match rest {
+                [0, _, rest @ ..] => (false, rest),
+                [1, COMPRESSION_METHOD_DEFLATE, rest @ ..] => (true, rest),
+                [1, _, ..] => return Err(invalid("unsupported text compression method")),
+                _ => return Err(invalid("invalid iTXt compression flag")),
+            };
+            let mut fields = rest.splitn(3, |&byte| byte == 0);
+            let (Some(language_tag), Some(translated_keyword), Some(text)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                return Err(invalid("iTXt chunk is truncated"));
+            };
+            let text = if compressed {
+                inflate_chunk_data(chunk_type, text, budget)?
+            } else {
+                text.to_vec()
+            };
+            let utf8 = |bytes: Vec<u8>| {
+                String::from_utf8(bytes).map_err(|_| invalid("iTXt text is not valid UTF-8"))
+            };
+            Ok(TextEntry {
+                keyword,
+                text: utf8(text)?,
+                compressed,
+                international: Some(InternationalText {
+                    language_tag: utf8(language_tag.to_vec())?,
+                    translated_keyword: utf8(translated_keyword.to_vec())?,
+                }),
+            })
+        }
     }
+}
 
-    let deflate_bytes = &data[2..data.len() - 4];
-    let decoded = deflate::decompress(deflate_bytes)
-        .map_err(|error| Error::InvalidData(format!("invalid deflate stream: {error}").into()))?;
-    let expected_adler = u32::from_be_bytes(
-        data[data.len() - 4..]
-            .try_into()
-            .expect("bug: zlib trailer must be 4 bytes"),
-    );
-    let actual_adler = adler32::calculate(&decoded);
-    if actual_adler != expected_adler {
-        return Err(Error::InvalidData("zlib adler32 checksum mismatch".into()));
+/// Splits the null-terminated keyword off the start of `chunk_data`,
+/// checking that it is valid.
+fn split_keyword<'a>(chunk_type: &[u8; 4], chunk_data: &'a [u8]) -> Result<(&'a [u8], &'a [u8])> {
+    let keyword_len = chunk_data.iter().take(80).position(|&byte| byte == 0);
+    match keyword_len {
+        Some(len) if is_valid_keyword(&chunk_data[..len]) => {
+            Ok((&chunk_data[..len], &chunk_data[len + 1..]))
+        }
+        _ => Err(Error::new(
+            ErrorKind::InvalidChunk {
+                chunk_type: *chunk_type,
+            },
+            "keyword is missing or invalid",
+        )),
     }
-    Ok(decoded)
 }
 
-fn decode_to_format_and_data(
-    header: &PngHeader,
-    filtered: &[u8],
-    ancillary: &AncillaryChunks,
-) -> Result<(PixelFormat, Vec<u8>)> {
-    if header.interlace_method == 0 {
-        let raw = unfilter_scanlines(header, header.width, header.height, filtered)?;
-        convert_to_format_and_data(header, header.width, &raw, ancillary)
-    } else {
-        decode_adam7_to_format_and_data(header, filtered, ancillary)
+/// Decompresses the zlib stream stored in an ancillary chunk, failing with
+/// [`ErrorKind::LimitExceeded`] if it inflates to more than `budget` bytes.
+/// The decompressed size is deducted from `budget`.
+fn inflate_chunk_data(
+    chunk_type: &[u8; 4],
+    zlib_data: &[u8],
+    budget: &mut usize,
+) -> Result<Vec<u8>> {
+    let mut decoder = zlib::Decoder::with_output_limit(*budget);
+    let mut data = Vec::new();
+    match decoder.write(zlib_data, &mut data) {
+        Err(error) if error.kind() == ErrorKind::LimitExceeded => Err(error),
+        Ok(()) if decoder.is_finished() => {
+            *budget -= data.len();
+            Ok(data)
+        }
+        _ => Err(Error::new(
+            ErrorKind::InvalidChunk {
+                chunk_type: *chunk_type,
+            },
+            "compressed chunk data is corrupt or truncated",
+        )),
     }
 }
 
-fn convert_to_format_and_data(
+/// Converts unfiltered scanlines of `width` pixels to the native pixel format
+/// and appends the pixels to `out`.
+pub(crate) fn append_converted_pixels(
+    out: &mut Vec<u8>,
     header: &PngHeader,
     width: u32,
     raw: &[u8],
     ancillary: &AncillaryChunks,
-) -> Result<(PixelFormat, Vec<u8>)> {
+) -> Result<()> {
     match (header.color_type, header.bit_depth) {
-        (CT_GRAY, 1 | 2 | 4) => convert_grayscale_low_bit(header, width, raw, ancillary),
+        (CT_GRAY, 1 | 2 | 4) => convert_grayscale_low_bit(out, header, width, raw, ancillary),
         (CT_GRAY, 8) => {
             let transparent = match ancillary.transparency {
                 Some(Transparency::Grayscale(value)) => Some(value),
                 _ => None,
             };
             if let Some(transparent) = transparent {
-                let mut data = Vec::with_capacity(raw.len() * 2);
+                out.reserve(raw.len() * 2);
                 for &gray in raw {
-                    data.extend_from_slice(&[
+                    out.extend_from_slice(&[
                         gray,
                         if u16::from(gray) == transparent {
                             0
@@ -561,9 +1697,10 @@ fn convert_to_format_and_data(
                         },
                     ]);
                 }
-                Ok((PixelFormat::GrayAlpha8, data))
+                Ok(())
             } else {
-                Ok((PixelFormat::Gray8, raw.to_vec()))
+                out.extend_from_slice(raw);
+                Ok(())
             }
         }
         (CT_GRAY, 16) => {
@@ -575,17 +1712,18 @@ fn convert_to_format_and_data(
                 // Output GrayAlpha16Be: [g_hi, g_lo, a_hi, a_lo, ...]
                 let (samples, remainder) = raw.as_chunks::<2>();
                 debug_assert!(remainder.is_empty());
-                let mut data = Vec::with_capacity(samples.len() * 4);
+                out.reserve(samples.len() * 4);
                 for chunk in samples {
                     let gray = u16::from_be_bytes(*chunk);
                     let alpha: u16 = if gray == transparent { 0 } else { u16::MAX };
-                    data.extend_from_slice(chunk);
-                    data.extend_from_slice(&alpha.to_be_bytes());
+                    out.extend_from_slice(chunk);
+                    out.extend_from_slice(&alpha.to_be_bytes());
                 }
-                Ok((PixelFormat::GrayAlpha16Be, data))
+                Ok(())
             } else {
                 // Pass raw BE bytes through directly.
-                Ok((PixelFormat::Gray16Be, raw.to_vec()))
+                out.extend_from_slice(raw);
+                Ok(())
             }
         }
         (CT_RGB, 8) => {
@@ -594,7 +1732,7 @@ fn convert_to_format_and_data(
                 _ => None,
             };
             if let Some(transparent) = transparent {
-                let mut data = Vec::with_capacity(raw.len() / 3 * 4);
+                out.reserve(raw.len() / 3 * 4);
                 let (pixels, remainder) = raw.as_chunks::<3>();
                 debug_assert!(remainder.is_empty());
                 for &[r, g, b] in pixels {
@@ -603,11 +1741,12 @@ fn convert_to_format_and_data(
                     } else {
                         255
                     };
-                    data.extend_from_slice(&[r, g, b, alpha]);
+                    out.extend_from_slice(&[r, g, b, alpha]);
                 }
-                Ok((PixelFormat::Rgba8, data))
+                Ok(())
             } else {
-                Ok((PixelFormat::Rgb8, raw.to_vec()))
+                out.extend_from_slice(raw);
+                Ok(())
             }
         }
         (CT_RGB, 16) => {
@@ -619,7 +1758,7 @@ fn convert_to_format_and_data(
                 // Output Rgba16Be: [r_hi, r_lo, g_hi, g_lo, b_hi, b_lo, a_hi, a_lo, ...]
                 let (pixels, remainder) = raw.as_chunks::<6>();
                 debug_assert!(remainder.is_empty());
-                let mut data = Vec::with_capacity(pixels.len() * 8);
+                out.reserve(pixels.len() * 8);
                 for &[r0, r1, g0, g1, b0, b1] in pixels {
                     let rgb = [
                         u16::from_be_bytes([r0, r1]),
@@ -627,427 +1766,266 @@ fn convert_to_format_and_data(
                         u16::from_be_bytes([b0, b1]),
                     ];
                     let alpha: u16 = if rgb == transparent { 0 } else { u16::MAX };
-                    data.extend_from_slice(&[r0, r1, g0, g1, b0, b1]);
-                    data.extend_from_slice(&alpha.to_be_bytes());
+                    out.extend_from_slice(&[r0, r1, g0, g1, b0, b1]);
+                    out.extend_from_slice(&alpha.to_be_bytes());
                 }
-                Ok((PixelFormat::Rgba16Be, data))
+                Ok(())
             } else {
                 // Pass raw BE bytes through directly.
-                Ok((PixelFormat::Rgb16Be, raw.to_vec()))
+                out.extend_from_slice(raw);
+                Ok(())
             }
         }
-        (CT_INDEXED, 1 | 2 | 4 | 8) => convert_indexed(header, width, raw, ancillary),
-        (CT_GRAY_ALPHA, 8) => Ok((PixelFormat::GrayAlpha8, raw.to_vec())),
-        (CT_GRAY_ALPHA, 16) => {
-            // Raw bytes are already BE: [g_hi, g_lo, a_hi, a_lo, ...]
-            Ok((PixelFormat::GrayAlpha16Be, raw.to_vec()))
-        }
-        (CT_RGBA, 8) => Ok((PixelFormat::Rgba8, raw.to_vec())),
-        (CT_RGBA, 16) => {
-            // Raw bytes are already BE: [r_hi, r_lo, g_hi, g_lo, b_hi, b_lo, a_hi, a_lo, ...]
-            Ok((PixelFormat::Rgba16Be, raw.to_vec()))
+        (CT_INDEXED, 1 | 2 | 4 | 8) => convert_indexed(out, header, width, raw, ancillary),
+        // Raw bytes are already in the output layout (16-bit samples are BE).
+        (CT_GRAY_ALPHA, 8 | 16) | (CT_RGBA, 8 | 16) => {
+            out.extend_from_slice(raw);
+            Ok(())
         }
         _ => unreachable!(),
     }
 }
 
 fn convert_grayscale_low_bit(
+    out: &mut Vec<u8>,
     header: &PngHeader,
     width: u32,
     raw: &[u8],
     ancillary: &AncillaryChunks,
-) -> Result<(PixelFormat, Vec<u8>)> {
+) -> Result<()> {
     let transparent = match ancillary.transparency {
         Some(Transparency::Grayscale(value)) => Some(value),
         _ => None,
     };
     let row_stride = packed_stride_for_width(header, width)?;
-    let mut unpacked = Vec::with_capacity(width as usize * (raw.len() / row_stride.max(1)));
+    if row_stride == 0 {
+        // Empty Adam7 pass.
+        return Ok(());
+    }
     for row in raw.chunks_exact(row_stride) {
-        unpacked.extend(unpack_samples(row, width as usize, header.bit_depth));
-    }
-    if let Some(transparent) = transparent {
-        let mut data = Vec::with_capacity(unpacked.len() * 2);
-        for sample in unpacked {
-            let gray = scale_sample_to_u8(u16::from(sample), header.bit_depth);
-            let alpha = if u16::from(sample) == transparent {
-                0
-            } else {
-                255
-            };
-            data.extend_from_slice(&[gray, alpha]);
+        let samples = unpack_samples(row, width as usize, header.bit_depth);
+        if let Some(transparent) = transparent {
+            for sample in samples {
+                let gray = scale_sample_to_u8(u16::from(sample), header.bit_depth);
+                let alpha = if u16::from(sample) == transparent {
+                    0
+                } else {
+                    255
+                };
+                out.extend_from_slice(&[gray, alpha]);
+            }
+        } else {
+            out.extend(samples);
         }
-        Ok((PixelFormat::GrayAlpha8, data))
-    } else {
-        Ok((gray_format_from_bit_depth(header.bit_depth), unpacked))
     }
+    Ok(())
 }
 
 fn convert_indexed(
+    out: &mut Vec<u8>,
     header: &PngHeader,
     width: u32,
     raw: &[u8],
     ancillary: &AncillaryChunks,
-) -> Result<(PixelFormat, Vec<u8>)> {
-    let palette = ancillary
-        .palette
-        .as_ref()
-        .ok_or_else(|| Error::InvalidData("missing PLTE for palette image".into()))?;
-    let trns = match ancillary.transparency.as_ref() {
-        Some(Transparency::Palette(alpha)) => Some(alpha.clone()),
-        _ => None,
-    };
+) -> Result<()> {
+    if ancillary.palette.is_none() {
+        return Err(Error::new(
+            ErrorKind::ChunkOrdering {
+                chunk_type: *b"PLTE",
+            },
+            "missing PLTE for palette image",
+        ));
+    }
     let row_stride = packed_stride_for_width(header, width)?;
-    let mut unpacked = Vec::with_capacity(width as usize * (raw.len() / row_stride.max(1)));
+    if row_stride == 0 {
+        // Empty Adam7 pass.
+        return Ok(());
+    }
     for row in raw.chunks_exact(row_stride) {
-        unpacked.extend(unpack_samples(row, width as usize, header.bit_depth));
+        out.extend(unpack_samples(row, width as usize, header.bit_depth));
     }
-    let flat_palette = flatten_palette(palette);
-    let format = indexed_format_from_bit_depth(header.bit_depth, flat_palette, trns);
-    Ok((format, unpacked))
+    Ok(())
 }
 
-/// Convert raw data into RGBA16Be intermediate for Adam7 scatter (fallback path).
-fn convert_to_rgba16be(
-    header: &PngHeader,
-    width: u32,
-    height: u32,
-    raw: &[u8],
-    ancillary: &AncillaryChunks,
-) -> Result<Vec<u8>> {
-    let pixel_count = (width as usize)
-        .checked_mul(height as usize)
-        .ok_or_else(|| Error::InvalidData("pixel count overflow".into()))?;
-    let mut rgba = Vec::with_capacity(pixel_count * 8);
-    match (header.color_type, header.bit_depth) {
-        (CT_GRAY, 1 | 2 | 4) => {
-            let row_stride = packed_stride_for_width(header, width)?;
-            let transparent = match ancillary.transparency {
-                Some(Transparency::Grayscale(value)) => Some(value),
-                _ => None,
-            };
-            for row in raw.chunks_exact(row_stride) {
-                for sample in unpack_samples(row, width as usize, header.bit_depth) {
-                    let gray =
-                        upscale_u8_to_u16(scale_sample_to_u8(u16::from(sample), header.bit_depth));
-                    let alpha: u16 = if Some(u16::from(sample)) == transparent {
-                        0
-                    } else {
-                        u16::MAX
-                    };
-                    let gb = gray.to_be_bytes();
-                    rgba.extend_from_slice(&[gb[0], gb[1], gb[0], gb[1], gb[0], gb[1]]);
-                    rgba.extend_from_slice(&alpha.to_be_bytes());
-                }
+/// Reverses the PNG filter of one scanline. `prev` is the previous
+/// unfiltered scanline, or `None` for the first one.
+pub(crate) fn unfilter_row(
+    filter: u8,
+    src: &[u8],
+    prev: Option<&[u8]>,
+    dst: &mut [u8],
+    bpp: usize,
+) -> Result<()> {
+    let stride = dst.len();
+    match filter {
+        0 => dst.copy_from_slice(src),
+        1 => {
+            for i in 0..stride {
+                let left = if i >= bpp { dst[i - bpp] } else { 0 };
+                dst[i] = src[i].wrapping_add(left);
             }
         }
-        (CT_GRAY, 8) => {
-            let transparent = match ancillary.transparency {
-                Some(Transparency::Grayscale(value)) => Some(value),
-                _ => None,
-            };
-            for &gray in raw {
-                let gray16 = upscale_u8_to_u16(gray);
-                let alpha: u16 = if Some(u16::from(crate::pixel_reformat::downsample_u16(gray16)))
-                    == transparent
-                {
-                    0
-                } else {
-                    u16::MAX
-                };
-                let gb = gray16.to_be_bytes();
-                rgba.extend_from_slice(&[gb[0], gb[1], gb[0], gb[1], gb[0], gb[1]]);
-                rgba.extend_from_slice(&alpha.to_be_bytes());
+        2 => {
+            for i in 0..stride {
+                let up = prev.map_or(0, |row| row[i]);
+                dst[i] = src[i].wrapping_add(up);
             }
         }
-        (CT_GRAY, 16) => {
-            let transparent = match ancillary.transparency {
-                Some(Transparency::Grayscale(value)) => Some(value),
-                _ => None,
-            };
-            let (pixels, _) = raw.as_chunks::<2>();
-            for chunk in pixels {
-                let gray = u16::from_be_bytes(*chunk);
-                let alpha: u16 = if Some(gray) == transparent {
-                    0
-                } else {
-                    u16::MAX
-                };
-                rgba.extend_from_slice(chunk); // r
-                rgba.extend_from_slice(chunk); // g
-                rgba.extend_from_slice(chunk); // b
-                rgba.extend_from_slice(&alpha.to_be_bytes());
+        3 => {
+            for i in 0..stride {
+                let left = if i >= bpp { dst[i - bpp] } else { 0 };
+                let up = prev.map_or(0, |row| row[i]);
+                dst[i] = src[i].wrapping_add(((u16::from(left) + u16::from(up)) / 2) as u8);
             }
         }
-        (CT_RGB, 8) => {
-            let transparent = match ancillary.transparency {
-                Some(Transparency::Truecolor(value)) => Some(value),
-                _ => None,
-            };
-            let (pixels, _) = raw.as_chunks::<3>();
-            for &[r, g, b] in pixels {
-                let rgb = [u16::from(r), u16::from(g), u16::from(b)];
-                let alpha: u16 = if Some(rgb) == transparent {
-                    0
+        4 => {
+            for i in 0..stride {
+                let left = if i >= bpp { dst[i - bpp] } else { 0 };
+                let up = prev.map_or(0, |row| row[i]);
+                let up_left = if i >= bpp {
+                    prev.map_or(0, |row| row[i - bpp])
                 } else {
-                    u16::MAX
-                };
-                rgba.extend_from_slice(&upscale_u8_to_u16(r).to_be_bytes());
-                rgba.extend_from_slice(&upscale_u8_to_u16(g).to_be_bytes());
-                rgba.extend_from_slice(&upscale_u8_to_u16(b).to_be_bytes());
-                rgba.extend_from_slice(&alpha.to_be_bytes());
-            }
-        }
-        (CT_RGB, 16) => {
-            let transparent = match ancillary.transparency {
-                Some(Transparency::Truecolor(value)) => Some(value),
-                _ => None,
-            };
-            let (pixels, _) = raw.as_chunks::<6>();
-            for &[r0, r1, g0, g1, b0, b1] in pixels {
-                let rgb = [
-                    u16::from_be_bytes([r0, r1]),
-                    u16::from_be_bytes([g0, g1]),
-                    u16::from_be_bytes([b0, b1]),
-                ];
-                let alpha: u16 = if Some(rgb) == transparent {
                     0
-                } else {
-                    u16::MAX
                 };
-                rgba.extend_from_slice(&[r0, r1, g0, g1, b0, b1]);
-                rgba.extend_from_slice(&alpha.to_be_bytes());
-            }
-        }
-        (CT_INDEXED, 1 | 2 | 4 | 8) => {
-            let (fmt, indices) = convert_indexed(header, width, raw, ancillary)?;
-            let expanded = crate::pixel_reformat::reformat(&fmt, &indices, &PixelFormat::Rgba16Be)?;
-            rgba.extend(expanded);
-        }
-        (CT_GRAY_ALPHA, 8) => {
-            let (pixels, _) = raw.as_chunks::<2>();
-            for &[gray, alpha] in pixels {
-                let g16 = upscale_u8_to_u16(gray);
-                let a16 = upscale_u8_to_u16(alpha);
-                let gb = g16.to_be_bytes();
-                rgba.extend_from_slice(&[gb[0], gb[1], gb[0], gb[1], gb[0], gb[1]]);
-                rgba.extend_from_slice(&a16.to_be_bytes());
-            }
-        }
-        (CT_GRAY_ALPHA, 16) => {
-            let (pixels, _) = raw.as_chunks::<4>();
-            for &[g0, g1, a0, a1] in pixels {
-                rgba.extend_from_slice(&[g0, g1, g0, g1, g0, g1, a0, a1]);
-            }
-        }
-        (CT_RGBA, 8) => {
-            let (pixels, _) = raw.as_chunks::<4>();
-            for &[r, g, b, a] in pixels {
-                rgba.extend_from_slice(&upscale_u8_to_u16(r).to_be_bytes());
-                rgba.extend_from_slice(&upscale_u8_to_u16(g).to_be_bytes());
-                rgba.extend_from_slice(&upscale_u8_to_u16(b).to_be_bytes());
-                rgba.extend_from_slice(&upscale_u8_to_u16(a).to_be_bytes());
+                dst[i] = src[i].wrapping_add(paeth_predictor(left, up, up_left));
             }
         }
-        (CT_RGBA, 16) => {
-            // Already in RGBA16Be format.
-            rgba.extend_from_slice(raw);
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidImageData,
+                "unsupported PNG filter type",
+            ));
         }
-        _ => unreachable!(),
     }
-    Ok(rgba)
+    Ok(())
 }
 
-fn expected_rgba16be_len(header: &PngHeader) -> Result<usize> {
-    (header.width as usize)
-        .checked_mul(header.height as usize)
-        .and_then(|pixels| pixels.checked_mul(8))
-        .ok_or_else(|| Error::InvalidData("decoded RGBA16Be size overflow".into()))
+/// Where a decoded scanline belongs in the full image.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct RowPosition {
+    /// Adam7 pass index, `None` for non-interlaced images.
+    pub(crate) pass: Option<u8>,
+    pub(crate) y: u32,
+    pub(crate) x_start: u32,
+    pub(crate) x_step: u32,
+    /// Number of pixels in the scanline.
+    pub(crate) width: u32,
 }
 
-/// Convert scattered RGBA16Be back to source-near format.
-fn format_from_rgba16be_source(
-    header: &PngHeader,
-    ancillary: &AncillaryChunks,
-    rgba: &[u8],
-) -> (PixelFormat, Vec<u8>) {
-    match (
-        header.color_type,
-        header.bit_depth,
-        ancillary.transparency.is_some(),
-    ) {
-        (CT_GRAY, 16, false) => {
-            // Extract gray channel (first 2 bytes of each 8-byte chunk).
-            let (chunks, _) = rgba.as_chunks::<8>();
-            let mut data = Vec::with_capacity(chunks.len() * 2);
-            for chunk in chunks {
-                data.extend_from_slice(&chunk[..2]);
-            }
-            (PixelFormat::Gray16Be, data)
-        }
-        (CT_GRAY, _, true) => {
-            if header.bit_depth == 16 {
-                // GrayAlpha16Be
-                let (chunks, _) = rgba.as_chunks::<8>();
-                let mut data = Vec::with_capacity(chunks.len() * 4);
-                for chunk in chunks {
-                    data.extend_from_slice(&chunk[..2]); // gray
-                    data.extend_from_slice(&chunk[6..8]); // alpha
-                }
-                (PixelFormat::GrayAlpha16Be, data)
-            } else {
-                // GrayAlpha8
-                let (chunks, _) = rgba.as_chunks::<8>();
-                let mut data = Vec::with_capacity(chunks.len() * 2);
-                for chunk in chunks {
-                    data.push(chunk[0]); // gray high byte
-                    data.push(chunk[6]); // alpha high byte
-                }
-                (PixelFormat::GrayAlpha8, data)
-            }
-        }
-        (CT_RGB, 16, false) => {
-            // Rgb16Be: extract first 6 bytes of each 8-byte chunk.
-            let (chunks, _) = rgba.as_chunks::<8>();
-            let mut data = Vec::with_capacity(chunks.len() * 6);
-            for chunk in chunks {
-                data.extend_from_slice(&chunk[..6]);
-            }
-            (PixelFormat::Rgb16Be, data)
+impl RowPosition {
+    /// Copies a converted row of `bpp`-byte pixels to its place in `image`.
+    pub(crate) fn scatter(&self, image: &mut [u8], image_width: u32, pixels: &[u8], bpp: usize) {
+        let row_start = self.y as usize * image_width as usize;
+        if self.x_step == 1 {
+            let start = (row_start + self.x_start as usize) * bpp;
+            image[start..start + pixels.len()].copy_from_slice(pixels);
+            return;
         }
-        (CT_RGB, _, true) => {
-            if header.bit_depth == 16 {
-                (PixelFormat::Rgba16Be, rgba.to_vec())
-            } else {
-                // Downsample to Rgba8
-                let (chunks, _) = rgba.as_chunks::<8>();
-                let mut data = Vec::with_capacity(chunks.len() * 4);
-                for chunk in chunks {
-                    data.extend_from_slice(&[chunk[0], chunk[2], chunk[4], chunk[6]]);
-                }
-                (PixelFormat::Rgba8, data)
-            }
+        for (i, pixel) in pixels.chunks_exact(bpp).enumerate() {
+            let x = self.x_start as usize + i * self.x_step as usize;
+            let start = (row_start + x) * bpp;
+            image[start..start + bpp].copy_from_slice(pixel);
         }
-        (CT_GRAY_ALPHA, 8, _) => {
-            // Downsample to GrayAlpha8
-            let (chunks, _) = rgba.as_chunks::<8>();
-            let mut data = Vec::with_capacity(chunks.len() * 2);
-            for chunk in chunks {
-                data.push(chunk[0]); // gray high byte
-                data.push(chunk[6]); // alpha high byte
-            }
-            (PixelFormat::GrayAlpha8, data)
-        }
-        (CT_GRAY_ALPHA, 16, _) => {
-            let (chunks, _) = rgba.as_chunks::<8>();
-            let mut data = Vec::with_capacity(chunks.len() * 4);
-            for chunk in chunks {
-                data.extend_from_slice(&chunk[..2]); // gray
-                data.extend_from_slice(&chunk[6..8]); // alpha
-            }
-            (PixelFormat::GrayAlpha16Be, data)
-        }
-        (CT_RGBA, 8, _) => {
-            // Downsample to Rgba8
-            let (chunks, _) = rgba.as_chunks::<8>();
-            let mut data = Vec::with_capacity(chunks.len() * 4);
-            for chunk in chunks {
-                data.extend_from_slice(&[chunk[0], chunk[2], chunk[4], chunk[6]]);
-            }
-            (PixelFormat::Rgba8, data)
+    }
+}
+
+/// Unfilters scanlines one at a time, following the Adam7 pass layout for
+/// interlaced images.
+#[derive(Debug)]
+pub(crate) struct ScanlineDecoder {
+    header: PngHeader,
+    pass: usize,
+    pass_y: u32,
+    prev_row: Vec<u8>,
+    raw_row: Vec<u8>,
+}
+
+impl ScanlineDecoder {
+    pub(crate) fn new(header: PngHeader) -> Self {
+        Self {
+            header,
+            pass: 0,
+            pass_y: 0,
+            prev_row: Vec::new(),
+            raw_row: Vec::new(),
         }
-        (CT_RGBA, 16, _) => (PixelFormat::Rgba16Be, rgba.to_vec()),
-        _ => {
-            // Low-bit grayscale (no tRNS): downsample to original bit depth.
-            let (chunks, _) = rgba.as_chunks::<8>();
-            let data: Vec<u8> = chunks.iter().map(|chunk| chunk[0]).collect();
-            let samples = match header.bit_depth {
-                1 => data.iter().copied().map(|v| v / 255).collect(),
-                2 => data.iter().copied().map(|v| v / 85).collect(),
-                4 => data.iter().copied().map(|v| v / 17).collect(),
-                8 => data,
-                _ => unreachable!(),
-            };
-            (gray_format_from_bit_depth(header.bit_depth), samples)
+    }
+
+    /// Pass index and position of the next scanline, or `None` once every
+    /// scanline has been decoded. Empty Adam7 passes are skipped.
+    fn next_position(&self) -> Option<(usize, RowPosition)> {
+        let header = &self.header;
+        if header.interlace_method == 0 {
+            return (self.pass_y < header.height).then_some((
+                0,
+                RowPosition {
+                    pass: None,
+                    y: self.pass_y,
+                    x_start: 0,
+                    x_step: 1,
+                    width: header.width,
+                },
+            ));
         }
+        (self.pass..ADAM7_PASSES.len()).find_map(|index| {
+            let pass = ADAM7_PASSES[index];
+            let pass_y = if index == self.pass { self.pass_y } else { 0 };
+            let width = adam7_axis_size(header.width, pass.x_start, pass.x_step);
+            let height = adam7_axis_size(header.height, pass.y_start, pass.y_step);
+            (width > 0 && pass_y < height).then_some((
+                index,
+                RowPosition {
+                    pass: Some(index as u8),
+                    y: u32::from(pass.y_start) + pass_y * u32::from(pass.y_step),
+                    x_start: u32::from(pass.x_start),
+                    x_step: u32::from(pass.x_step),
+                    width,
+                },
+            ))
+        })
     }
-}
 
-fn unfilter_scanlines(
-    header: &PngHeader,
-    width: u32,
-    height: u32,
-    filtered: &[u8],
-) -> Result<Vec<u8>> {
-    let stride = packed_stride_for_width(header, width)?;
-    let expected_len = (stride + 1)
-        .checked_mul(height as usize)
-        .ok_or_else(|| Error::InvalidData("filtered data size overflow".into()))?;
-    if filtered.len() != expected_len {
-        return Err(Error::InvalidData(
-            format!(
-                "unexpected filtered data size: expected {}, got {}",
-                expected_len,
-                filtered.len()
-            )
-            .into(),
-        ));
+    /// Returns `true` once every scanline has been decoded.
+    pub(crate) fn is_finished(&self) -> bool {
+        self.next_position().is_none()
     }
 
-    let bpp = header.filter_bpp();
-    let mut raw = vec![0; stride * height as usize];
-    for row in 0..height as usize {
-        let filter = filtered[row * (stride + 1)];
-        let src = &filtered[row * (stride + 1) + 1..(row + 1) * (stride + 1)];
-        let row_start = row * stride;
-        let (before, current_and_after) = raw.split_at_mut(row_start);
-        let dst = &mut current_and_after[..stride];
-        let prev: Option<&[u8]> = if row == 0 {
-            None
-        } else {
-            Some(&before[before.len() - stride..])
+    /// Unfilters the next scanline from the start of `filtered`.
+    ///
+    /// Returns its position and the number of filtered bytes consumed, or
+    /// `None` if `filtered` does not yet hold a whole scanline or the image is
+    /// complete. The unfiltered bytes are available from [`row`](Self::row).
+    pub(crate) fn decode_next(&mut self, filtered: &[u8]) -> Result<Option<(RowPosition, usize)>> {
+        let Some((pass, position)) = self.next_position() else {
+            return Ok(None);
         };
-        match filter {
-            0 => dst.copy_from_slice(src),
-            1 => {
-                for i in 0..stride {
-                    let left = if i >= bpp { dst[i - bpp] } else { 0 };
-                    dst[i] = src[i].wrapping_add(left);
-                }
-            }
-            2 => {
-                for i in 0..stride {
-                    let up = prev.map_or(0, |row| row[i]);
-                    dst[i] = src[i].wrapping_add(up);
-                }
-            }
-            3 => {
-                for i in 0..stride {
-                    let left = if i >= bpp { dst[i - bpp] } else { 0 };
-                    let up = prev.map_or(0, |row| row[i]);
-                    dst[i] = src[i].wrapping_add(((u16::from(left) + u16::from(up)) / 2) as u8);
-                }
-            }
-            4 => {
-                for i in 0..stride {
-                    let left = if i >= bpp { dst[i - bpp] } else { 0 };
-                    let up = prev.map_or(0, |row| row[i]);
-                    let up_left = if i >= bpp {
-                        prev.map_or(0, |row| row[i - bpp])
-                    } else {
-                        0
-                    };
-                    dst[i] = src[i].wrapping_add(paeth_predictor(left, up, up_left));
-                }
-            }
-            _ => {
-                return Err(Error::InvalidData(
-                    format!("unsupported PNG filter type: {}", filter).into(),
-                ));
-            }
+        if pass != self.pass {
+            self.pass = pass;
+            self.pass_y = 0;
         }
+        let stride = packed_stride_for_width(&self.header, position.width)?;
+        let Some(scanline) = filtered.get(..stride + 1) else {
+            return Ok(None);
+        };
+        self.raw_row.clear();
+        self.raw_row.resize(stride, 0);
+        let prev = (self.pass_y > 0).then_some(self.prev_row.as_slice());
+        unfilter_row(
+            scanline[0],
+            &scanline[1..],
+            prev,
+            &mut self.raw_row,
+            self.header.filter_bpp(),
+        )?;
+        core::mem::swap(&mut self.raw_row, &mut self.prev_row);
+        self.pass_y += 1;
+        Ok(Some((position, stride + 1)))
+    }
+
+    /// The most recently decoded scanline, unfiltered but still packed.
+    pub(crate) fn row(&self) -> &[u8] {
+        &self.prev_row
     }
-    Ok(raw)
 }
 
 fn unpack_samples(bytes: &[u8], width: usize, bit_depth: u8) -> impl Iterator<Item = u8> + '_ {
@@ -1094,7 +2072,9 @@ pub(crate) fn expected_filtered_len(header: &PngHeader) -> Result<usize> {
                     pass_width,
                     pass_height,
                 )?)
-                .ok_or_else(|| Error::InvalidData("filtered data size overflow".into()))?;
+                .ok_or_else(|| {
+                    Error::new(ErrorKind::LimitExceeded, "filtered data size overflow")
+                })?;
         }
         Ok(total)
     }
@@ -1104,259 +2084,21 @@ fn expected_filtered_len_for_size(header: &PngHeader, width: u32, height: u32) -
     let stride = packed_stride_for_width(header, width)?;
     (stride + 1)
         .checked_mul(height as usize)
-        .ok_or_else(|| Error::InvalidData("filtered data size overflow".into()))
+        .ok_or_else(|| Error::new(ErrorKind::LimitExceeded, "filtered data size overflow"))
 }
 
-fn expected_raw_len(header: &PngHeader) -> Result<usize> {
+pub(crate) fn expected_raw_len(header: &PngHeader) -> Result<usize> {
     let stride = packed_stride_for_width(header, header.width)?;
     stride
         .checked_mul(header.height as usize)
-        .ok_or_else(|| Error::InvalidData("raw image size overflow".into()))
+        .ok_or_else(|| Error::new(ErrorKind::LimitExceeded, "raw image size overflow"))
 }
 
 pub(crate) fn packed_stride_for_width(header: &PngHeader, width: u32) -> Result<usize> {
     (width as usize)
         .checked_mul(header.bits_per_pixel())
         .map(|bits| bits.div_ceil(8))
-        .ok_or_else(|| Error::InvalidData("scanline stride overflow".into()))
-}
-
-fn decode_adam7_to_format_and_data(
-    header: &PngHeader,
-    filtered: &[u8],
-    ancillary: &AncillaryChunks,
-) -> Result<(PixelFormat, Vec<u8>)> {
-    let pixel_count = (header.width as usize)
-        .checked_mul(header.height as usize)
-        .ok_or_else(|| Error::InvalidData("pixel count overflow".into()))?;
-    let mut offset = 0usize;
-
-    match (
-        header.color_type,
-        header.bit_depth,
-        ancillary.transparency.is_some(),
-    ) {
-        (CT_GRAY, 1, false) | (CT_GRAY, 2, false) | (CT_GRAY, 4, false) => {
-            let mut samples = vec![0u8; pixel_count];
-            for pass in ADAM7_PASSES {
-                let (pass_width, pass_height, pass_filtered) =
-                    adam7_pass_window(header, filtered, &mut offset, pass)?;
-                let pass_raw = unfilter_scanlines(header, pass_width, pass_height, pass_filtered)?;
-                let (_, pass_data) =
-                    convert_grayscale_low_bit(header, pass_width, &pass_raw, ancillary)?;
-                scatter_scalar_u8(
-                    &mut samples,
-                    header.width,
-                    pass,
-                    pass_width,
-                    pass_height,
-                    &pass_data,
-                );
-            }
-            finish_adam7_offset(filtered, offset)?;
-            Ok((gray_format_from_bit_depth(header.bit_depth), samples))
-        }
-        (CT_INDEXED, 1 | 2 | 4 | 8, _) => {
-            let mut indices = vec![0u8; pixel_count];
-            for pass in ADAM7_PASSES {
-                let (pass_width, pass_height, pass_filtered) =
-                    adam7_pass_window(header, filtered, &mut offset, pass)?;
-                let pass_raw = unfilter_scanlines(header, pass_width, pass_height, pass_filtered)?;
-                let (_, pass_data) = convert_indexed(header, pass_width, &pass_raw, ancillary)?;
-                scatter_scalar_u8(
-                    &mut indices,
-                    header.width,
-                    pass,
-                    pass_width,
-                    pass_height,
-                    &pass_data,
-                );
-            }
-            finish_adam7_offset(filtered, offset)?;
-            let palette = ancillary
-                .palette
-                .as_ref()
-                .ok_or_else(|| Error::InvalidData("missing PLTE for palette image".into()))?
-                .clone();
-            let flat_palette = flatten_palette(&palette);
-            let trns = match ancillary.transparency.as_ref() {
-                Some(Transparency::Palette(alpha)) => Some(alpha.clone()),
-                _ => None,
-            };
-            let format = indexed_format_from_bit_depth(header.bit_depth, flat_palette, trns);
-            Ok((format, indices))
-        }
-        // 8-bit types without tRNS: scatter raw bytes directly.
-        (CT_GRAY, 8, false) => {
-            let data = adam7_scatter_raw_bytes(header, filtered, &mut offset, 1)?;
-            finish_adam7_offset(filtered, offset)?;
-            Ok((PixelFormat::Gray8, data))
-        }
-        (CT_GRAY_ALPHA, 8, _) => {
-            let data = adam7_scatter_raw_bytes(header, filtered, &mut offset, 2)?;
-            finish_adam7_offset(filtered, offset)?;
-            Ok((PixelFormat::GrayAlpha8, data))
-        }
-        (CT_RGB, 8, false) => {
-            let data = adam7_scatter_raw_bytes(header, filtered, &mut offset, 3)?;
-            finish_adam7_offset(filtered, offset)?;
-            Ok((PixelFormat::Rgb8, data))
-        }
-        (CT_RGBA, 8, _) => {
-            let data = adam7_scatter_raw_bytes(header, filtered, &mut offset, 4)?;
-            finish_adam7_offset(filtered, offset)?;
-            Ok((PixelFormat::Rgba8, data))
-        }
-        _ => {
-            // Fallback: use RGBA16Be intermediate and scatter.
-            let mut rgba = vec![0u8; expected_rgba16be_len(header)?];
-            for pass in ADAM7_PASSES {
-                let (pass_width, pass_height, pass_filtered) =
-                    adam7_pass_window(header, filtered, &mut offset, pass)?;
-                let pass_raw = unfilter_scanlines(header, pass_width, pass_height, pass_filtered)?;
-                let pass_rgba =
-                    convert_to_rgba16be(header, pass_width, pass_height, &pass_raw, ancillary)?;
-                scatter_bytes(
-                    &mut rgba,
-                    header.width,
-                    pass,
-                    pass_width,
-                    pass_height,
-                    &pass_rgba,
-                    8, // RGBA16Be = 8 bytes per pixel
-                );
-            }
-            finish_adam7_offset(filtered, offset)?;
-            Ok(format_from_rgba16be_source(header, ancillary, &rgba))
-        }
-    }
-}
-
-fn adam7_pass_window<'a>(
-    header: &PngHeader,
-    filtered: &'a [u8],
-    offset: &mut usize,
-    pass: Adam7Pass,
-) -> Result<(u32, u32, &'a [u8])> {
-    let pass_width = adam7_axis_size(header.width, pass.x_start, pass.x_step);
-    let pass_height = adam7_axis_size(header.height, pass.y_start, pass.y_step);
-    if pass_width == 0 || pass_height == 0 {
-        return Ok((0, 0, &[]));
-    }
-    let pass_stride = packed_stride_for_width(header, pass_width)?;
-    let pass_filtered_len = (pass_stride + 1)
-        .checked_mul(pass_height as usize)
-        .ok_or_else(|| Error::InvalidData("Adam7 pass size overflow".into()))?;
-    let pass_filtered = filtered
-        .get(*offset..*offset + pass_filtered_len)
-        .ok_or_else(|| Error::InvalidData("truncated Adam7 data".into()))?;
-    *offset += pass_filtered_len;
-    Ok((pass_width, pass_height, pass_filtered))
-}
-
-fn finish_adam7_offset(filtered: &[u8], offset: usize) -> Result<()> {
-    if offset != filtered.len() {
-        Err(Error::InvalidData(
-            format!(
-                "unexpected Adam7 data size: consumed {}, got {}",
-                offset,
-                filtered.len()
-            )
-            .into(),
-        ))
-    } else {
-        Ok(())
-    }
-}
-
-fn scatter_scalar_u8(
-    full: &mut [u8],
-    full_width: u32,
-    pass: Adam7Pass,
-    pass_width: u32,
-    pass_height: u32,
-    pass_values: &[u8],
-) {
-    for pass_y in 0..pass_height as usize {
-        for pass_x in 0..pass_width as usize {
-            let x = pass.x_start as usize + pass_x * pass.x_step as usize;
-            let y = pass.y_start as usize + pass_y * pass.y_step as usize;
-            full[y * full_width as usize + x] = pass_values[pass_y * pass_width as usize + pass_x];
-        }
-    }
-}
-
-fn adam7_scatter_raw_bytes(
-    header: &PngHeader,
-    filtered: &[u8],
-    offset: &mut usize,
-    bytes_per_pixel: usize,
-) -> Result<Vec<u8>> {
-    let pixel_count = (header.width as usize)
-        .checked_mul(header.height as usize)
-        .ok_or_else(|| Error::InvalidData("pixel count overflow".into()))?;
-    let mut data = vec![0u8; pixel_count * bytes_per_pixel];
-    for pass in ADAM7_PASSES {
-        let (pass_width, pass_height, pass_filtered) =
-            adam7_pass_window(header, filtered, offset, pass)?;
-        let pass_raw = unfilter_scanlines(header, pass_width, pass_height, pass_filtered)?;
-        scatter_bytes(
-            &mut data,
-            header.width,
-            pass,
-            pass_width,
-            pass_height,
-            &pass_raw,
-            bytes_per_pixel,
-        );
-    }
-    Ok(data)
-}
-
-fn scatter_bytes(
-    full: &mut [u8],
-    full_width: u32,
-    pass: Adam7Pass,
-    pass_width: u32,
-    pass_height: u32,
-    pass_data: &[u8],
-    bytes_per_pixel: usize,
-) {
-    for pass_y in 0..pass_height as usize {
-        for pass_x in 0..pass_width as usize {
-            let x = pass.x_start as usize + pass_x * pass.x_step as usize;
-            let y = pass.y_start as usize + pass_y * pass.y_step as usize;
-            let dst = (y * full_width as usize + x) * bytes_per_pixel;
-            let src = (pass_y * pass_width as usize + pass_x) * bytes_per_pixel;
-            full[dst..dst + bytes_per_pixel]
-                .copy_from_slice(&pass_data[src..src + bytes_per_pixel]);
-        }
-    }
-}
-
-fn gray_format_from_bit_depth(bit_depth: u8) -> PixelFormat {
-    match bit_depth {
-        1 => PixelFormat::Gray1,
-        2 => PixelFormat::Gray2,
-        4 => PixelFormat::Gray4,
-        8 => PixelFormat::Gray8,
-        16 => PixelFormat::Gray16Be,
-        _ => unreachable!("bug: validated grayscale bit depth must be 1/2/4/8/16"),
-    }
-}
-
-fn indexed_format_from_bit_depth(
-    bit_depth: u8,
-    palette: Vec<u8>,
-    trns: Option<Vec<u8>>,
-) -> PixelFormat {
-    match bit_depth {
-        1 => PixelFormat::Indexed1 { palette, trns },
-        2 => PixelFormat::Indexed2 { palette, trns },
-        4 => PixelFormat::Indexed4 { palette, trns },
-        8 => PixelFormat::Indexed8 { palette, trns },
-        _ => unreachable!("bug: validated indexed bit depth must be 1/2/4/8"),
-    }
+        .ok_or_else(|| Error::new(ErrorKind::LimitExceeded, "scanline stride overflow"))
 }
 
 fn flatten_palette(palette: &[[u8; 3]]) -> Vec<u8> {
@@ -1,7 +1,16 @@
 use alloc::vec::Vec;
 
-use crate::chunk::IhdrChunk;
-use crate::png_types::{PixelFormat, Result};
+use crate::chunk::{
+    BkgdChunk, ChrmChunk, CicpChunk, ExifChunk, GamaChunk, HistChunk, IccpChunk, IhdrChunk,
+    ItxtChunk, OffsChunk, PhysChunk, SbitChunk, SpltChunk, SrgbChunk, TextChunk, TimeChunk,
+    ZtxtChunk, is_valid_keyword, string_to_latin1,
+};
+use crate::pixel_reformat::reformat;
+use crate::png_types::{
+    Background, CompressionLevel, EncodeOptions, Error, ErrorKind, FilterStrategy, Metadata,
+    PixelFormat, Result, SignificantBits,
+};
+use crate::{deflate, zlib};
 
 use crate::png::{ADAM7_PASSES, adam7_axis_size};
 
@@ -22,6 +31,7 @@ impl EncodedImage {
         format: &PixelFormat,
         data: &[u8],
         interlaced: bool,
+        options: &EncodeOptions,
     ) -> Result<Self> {
         let interlace_method = u8::from(interlaced);
         match format {
@@ -33,9 +43,11 @@ impl EncodedImage {
                 let bd = format.bit_depth();
                 let bpp = if bd < 8 { 1 } else { format.bytes_per_pixel() };
                 let filtered_data = if interlaced {
-                    build_scanline_filtered_data_adam7(width, height, data, bd, bpp, format)
+                    build_scanline_filtered_data_adam7(
+                        width, height, data, bd, bpp, format, options,
+                    )
                 } else {
-                    build_scanline_filtered_data(width, height, data, bd, bpp, format)
+                    build_scanline_filtered_data(width, height, data, bd, bpp, format, options)
                 };
                 Ok(Self {
                     bit_depth: bd,
@@ -49,9 +61,9 @@ impl EncodedImage {
             PixelFormat::GrayAlpha8 => {
                 let bpp = 2;
                 let filtered_data = if interlaced {
-                    build_scanline_filtered_data_adam7(width, height, data, 8, bpp, format)
+                    build_scanline_filtered_data_adam7(width, height, data, 8, bpp, format, options)
                 } else {
-                    build_scanline_filtered_data(width, height, data, 8, bpp, format)
+                    build_scanline_filtered_data(width, height, data, 8, bpp, format, options)
                 };
                 Ok(Self {
                     bit_depth: 8,
@@ -65,9 +77,11 @@ impl EncodedImage {
             PixelFormat::GrayAlpha16Be => {
                 let bpp = 4;
                 let filtered_data = if interlaced {
-                    build_scanline_filtered_data_adam7(width, height, data, 16, bpp, format)
+                    build_scanline_filtered_data_adam7(
+                        width, height, data, 16, bpp, format, options,
+                    )
                 } else {
-                    build_scanline_filtered_data(width, height, data, 16, bpp, format)
+                    build_scanline_filtered_data(width, height, data, 16, bpp, format, options)
                 };
                 Ok(Self {
                     bit_depth: 16,
@@ -81,9 +95,9 @@ impl EncodedImage {
             PixelFormat::Rgb8 => {
                 let bpp = 3;
                 let filtered_data = if interlaced {
-                    build_scanline_filtered_data_adam7(width, height, data, 8, bpp, format)
+                    build_scanline_filtered_data_adam7(width, height, data, 8, bpp, format, options)
                 } else {
-                    build_scanline_filtered_data(width, height, data, 8, bpp, format)
+                    build_scanline_filtered_data(width, height, data, 8, bpp, format, options)
                 };
                 Ok(Self {
                     bit_depth: 8,
@@ -97,9 +111,11 @@ impl EncodedImage {
             PixelFormat::Rgb16Be => {
                 let bpp = 6;
                 let filtered_data = if interlaced {
-                    build_scanline_filtered_data_adam7(width, height, data, 16, bpp, format)
+                    build_scanline_filtered_data_adam7(
+                        width, height, data, 16, bpp, format, options,
+                    )
                 } else {
-                    build_scanline_filtered_data(width, height, data, 16, bpp, format)
+                    build_scanline_filtered_data(width, height, data, 16, bpp, format, options)
                 };
                 Ok(Self {
                     bit_depth: 16,
@@ -113,9 +129,9 @@ impl EncodedImage {
             PixelFormat::Rgba8 => {
                 let bpp = 4;
                 let filtered_data = if interlaced {
-                    build_scanline_filtered_data_adam7(width, height, data, 8, bpp, format)
+                    build_scanline_filtered_data_adam7(width, height, data, 8, bpp, format, options)
                 } else {
-                    build_scanline_filtered_data(width, height, data, 8, bpp, format)
+                    build_scanline_filtered_data(width, height, data, 8, bpp, format, options)
                 };
                 Ok(Self {
                     bit_depth: 8,
@@ -129,9 +145,11 @@ impl EncodedImage {
             PixelFormat::Rgba16Be => {
                 let bpp = 8;
                 let filtered_data = if interlaced {
-                    build_scanline_filtered_data_adam7(width, height, data, 16, bpp, format)
+                    build_scanline_filtered_data_adam7(
+                        width, height, data, 16, bpp, format, options,
+                    )
                 } else {
-                    build_scanline_filtered_data(width, height, data, 16, bpp, format)
+                    build_scanline_filtered_data(width, height, data, 16, bpp, format, options)
                 };
                 Ok(Self {
                     bit_depth: 16,
@@ -142,6 +160,20 @@ impl EncodedImage {
                     trns: None,
                 })
             }
+            PixelFormat::Rgba8Premultiplied | PixelFormat::Rgba16BePremultiplied => {
+                let straight_format = format
+                    .straight_alpha()
+                    .expect("bug: format is premultiplied");
+                let straight = reformat(format, data, &straight_format)?;
+                Self::from_format_and_data(
+                    width,
+                    height,
+                    &straight_format,
+                    &straight,
+                    interlaced,
+                    options,
+                )
+            }
             PixelFormat::Indexed1 { palette, trns }
             | PixelFormat::Indexed2 { palette, trns }
             | PixelFormat::Indexed4 { palette, trns }
@@ -149,9 +181,11 @@ impl EncodedImage {
                 let bd = format.bit_depth();
                 let bpp = 1;
                 let filtered_data = if interlaced {
-                    build_scanline_filtered_data_adam7(width, height, data, bd, bpp, format)
+                    build_scanline_filtered_data_adam7(
+                        width, height, data, bd, bpp, format, options,
+                    )
                 } else {
-                    build_scanline_filtered_data(width, height, data, bd, bpp, format)
+                    build_scanline_filtered_data(width, height, data, bd, bpp, format, options)
                 };
                 // Convert flat palette to [[u8; 3]]
                 let (palette_chunks, _) = palette.as_chunks::<3>();
@@ -170,6 +204,250 @@ impl EncodedImage {
     }
 }
 
+/// Checks that every field of `metadata` can be written to a PNG stream
+/// holding pixels in `format`.
+pub(crate) fn validate_metadata(metadata: &Metadata, format: &PixelFormat) -> Result<()> {
+    let invalid = |message| Err(Error::new(ErrorKind::InvalidArgument, message));
+    let is_keyword =
+        |name: &str| string_to_latin1(name).is_some_and(|name| is_valid_keyword(&name));
+    if metadata.gamma == Some(0) {
+        return invalid("gamma must be non-zero");
+    }
+    if let Some(icc_profile) = &metadata.icc_profile
+        && !is_keyword(&icc_profile.name)
+    {
+        return invalid("ICC profile name is not a valid keyword");
+    }
+    if let Some(cicp) = &metadata.cicp
+        && cicp.matrix_coefficients != 0
+    {
+        return invalid("cICP matrix coefficients must be 0 for RGB images");
+    }
+    for text in &metadata.text {
+        if !is_keyword(&text.keyword) {
+            return invalid("text keyword is not a valid keyword");
+        }
+        match &text.international {
+            None if string_to_latin1(&text.text).is_none() => {
+                return invalid("text without a language must be Latin-1");
+            }
+            Some(international)
+                if international.language_tag.contains('\0')
+                    || international.translated_keyword.contains('\0') =>
+            {
+                return invalid(
+                    "language tag and translated keyword must not contain null characters",
+                );
+            }
+            _ => {}
+        }
+    }
+    if let Some(time) = &metadata.modification_time
+        && !time.is_valid()
+    {
+        return invalid("modification time is out of range");
+    }
+
+    let color_type = color_type_for_format(format);
+    let bit_depth = format.bit_depth();
+    let max_sample = if bit_depth == 16 {
+        u16::MAX
+    } else {
+        (1 << bit_depth) - 1
+    };
+    let palette_len = match format {
+        PixelFormat::Indexed1 { palette, .. }
+        | PixelFormat::Indexed2 { palette, .. }
+        | PixelFormat::Indexed4 { palette, .. }
+        | PixelFormat::Indexed8 { palette, .. } => Some(palette.len() / 3),
+        _ => None,
+    };
+    if let Some(significant_bits) = &metadata.significant_bits {
+        let bits: &[u8] = match (significant_bits, color_type) {
+            (SignificantBits::Gray(bits), IhdrChunk::COLOR_TYPE_GRAYSCALE) => {
+                core::slice::from_ref(bits)
+            }
+            (SignificantBits::GrayAlpha(bits), IhdrChunk::COLOR_TYPE_GRAYSCALE_ALPHA) => bits,
+            (
+                SignificantBits::Rgb(bits),
+                IhdrChunk::COLOR_TYPE_RGB | IhdrChunk::COLOR_TYPE_INDEXED,
+            ) => bits,
+            (SignificantBits::Rgba(bits), IhdrChunk::COLOR_TYPE_RGBA) => bits,
+            _ => return invalid("significant bits do not match the pixel format"),
+        };
+        let sample_depth = if palette_len.is_some() { 8 } else { bit_depth };
+        if bits.iter().any(|&bits| bits == 0 || bits > sample_depth) {
+            return invalid("significant bits must be between 1 and the sample depth");
+        }
+    }
+    if let Some(background) = metadata.background {
+        let fits = match (background, color_type) {
+            (
+                Background::Gray(gray),
+                IhdrChunk::COLOR_TYPE_GRAYSCALE | IhdrChunk::COLOR_TYPE_GRAYSCALE_ALPHA,
+            ) => gray <= max_sample,
+            (Background::Rgb(rgb), IhdrChunk::COLOR_TYPE_RGB | IhdrChunk::COLOR_TYPE_RGBA) => {
+                rgb.iter().all(|&sample| sample <= max_sample)
+            }
+            (Background::PaletteIndex(index), IhdrChunk::COLOR_TYPE_INDEXED) => {
+                palette_len.is_some_and(|len| usize::from(index) < len)
+            }
+            _ => false,
+        };
+        if !fits {
+            return invalid("background color does not match the pixel format");
+        }
+    }
+    if let Some(histogram) = &metadata.histogram
+        && palette_len != Some(histogram.len())
+    {
+        return invalid("histogram needs one entry per palette entry");
+    }
+    for palette in &metadata.suggested_palettes {
+        if !is_keyword(&palette.name) {
+            return invalid("suggested palette name is not a valid keyword");
+        }
+        let fits = match palette.sample_depth {
+            8 => palette.entries.iter().all(|entry| {
+                [entry.red, entry.green, entry.blue, entry.alpha]
+                    .iter()
+                    .all(|&sample| sample <= 0xFF)
+            }),
+            16 => true,
+            _ => false,
+        };
+        if !fits {
+            return invalid("suggested palette samples do not match its sample depth");
+        }
+    }
+    Ok(())
+}
+
+/// Appends a chunk for every field of `metadata` that must or may precede
+/// `PLTE`, including one per text entry. `metadata` must have been checked
+/// with [`validate_metadata`].
+pub(crate) fn append_metadata_chunks(
+    out: &mut Vec<u8>,
+    metadata: &Metadata,
+    level: CompressionLevel,
+) -> Result<()> {
+    let latin1 = |text: &str| string_to_latin1(text).expect("bug: text must be validated");
+    if let Some(cicp) = metadata.cicp {
+        CicpChunk { cicp }.append_to(out);
+    }
+    if let Some(icc_profile) = &metadata.icc_profile {
+        let zlib_data = zlib::compress(&icc_profile.profile, level)?;
+        IccpChunk {
+            name: &latin1(&icc_profile.name),
+            zlib_data: &zlib_data,
+        }
+        .append_to(out);
+    }
+    if let Some(intent) = metadata.srgb {
+        SrgbChunk { intent }.append_to(out);
+    }
+    if let Some(gamma) = metadata.gamma {
+        GamaChunk { gamma }.append_to(out);
+    }
+    if let Some(chromaticities) = metadata.chromaticities {
+        ChrmChunk { chromaticities }.append_to(out);
+    }
+    if let Some(significant_bits) = metadata.significant_bits {
+        SbitChunk { significant_bits }.append_to(out);
+    }
+    if let Some(dimensions) = metadata.physical_dimensions {
+        PhysChunk { dimensions }.append_to(out);
+    }
+    if let Some(offset) = metadata.offset {
+        OffsChunk { offset }.append_to(out);
+    }
+    for palette in &metadata.suggested_palettes {
+        SpltChunk {
+            name: &latin1(&palette.name),
+            palette,
+        }
+        .append_to(out);
+    }
+    if let Some(exif) = &metadata.exif {
+        ExifChunk { data: exif }.append_to(out);
+    }
+    if let Some(time) = metadata.modification_time {
+        TimeChunk { time }.append_to(out);
+    }
+    for text in &metadata.text {
+        let keyword = latin1(&text.keyword);
+        match &text.international {
+            None => {
+                let latin1 = latin1(&text.text);
+                if text.compressed {
+                    let zlib_data = zlib::compress(&latin1, level)?;
+                    ZtxtChunk {
+                        keyword: &keyword,
+                        zlib_data: &zlib_data,
+                    }
+                    .append_to(out);
+                } else {
+                    TextChunk {
+                        keyword: &keyword,
+                        text: &latin1,
+                    }
+                    .append_to(out);
+                }
+            }
+            Some(international) => {
+                let compressed;
+                ItxtChunk {
+                    keyword: &keyword,
+                    compressed: text.compressed,
+                    language_tag: &international.language_tag,
+                    translated_keyword: &international.translated_keyword,
+                    text: if text.compressed {
+                        compressed = zlib::compress(text.text.as_bytes(), level)?;
+                        &compressed
+                    } else {
+                        text.text.as_bytes()
+                    },
+                }
+                .append_to(out);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Appends the chunks of `metadata` that must follow `PLTE` and `tRNS`.
+pub(crate) fn append_palette_metadata_chunks(out: &mut Vec<u8>, metadata: &Metadata) {
+    if let Some(background) = metadata.background {
+        BkgdChunk { background }.append_to(out);
+    }
+    if let Some(frequencies) = &metadata.histogram {
+        HistChunk { frequencies }.append_to(out);
+    }
+}
+
+/// IHDR color type used to encode `format`.
+pub(crate) fn color_type_for_format(format: &PixelFormat) -> u8 {
+    match format {
+        PixelFormat::Gray1
+        | PixelFormat::Gray2
+        | PixelFormat::Gray4
+        | PixelFormat::Gray8
+        | PixelFormat::Gray16Be => IhdrChunk::COLOR_TYPE_GRAYSCALE,
+        PixelFormat::GrayAlpha8 | PixelFormat::GrayAlpha16Be => {
+            IhdrChunk::COLOR_TYPE_GRAYSCALE_ALPHA
+        }
+        PixelFormat::Rgb8 | PixelFormat::Rgb16Be => IhdrChunk::COLOR_TYPE_RGB,
+        PixelFormat::Rgba8
+        | PixelFormat::Rgba16Be
+        | PixelFormat::Rgba8Premultiplied
+        | PixelFormat::Rgba16BePremultiplied => IhdrChunk::COLOR_TYPE_RGBA,
+        PixelFormat::Indexed1 { .. }
+        | PixelFormat::Indexed2 { .. }
+        | PixelFormat::Indexed4 { .. }
+        | PixelFormat::Indexed8 { .. } => IhdrChunk::COLOR_TYPE_INDEXED,
+    }
+}
+
 /// Build filtered scanline data for a non-interlaced image.
 fn build_scanline_filtered_data(
     width: u32,
@@ -178,6 +456,7 @@ fn build_scanline_filtered_data(
     bit_depth: u8,
     bpp: usize,
     format: &PixelFormat,
+    options: &EncodeOptions,
 ) -> Vec<u8> {
     let bytes_per_pixel = format.bytes_per_pixel();
     let needs_packing = bit_depth < 8;
@@ -199,7 +478,7 @@ fn build_scanline_filtered_data(
         } else {
             Some(prev_row.as_slice())
         };
-        write_filtered_row(&mut filtered, &raw_row, prev, bpp);
+        write_filtered_row(&mut filtered, &raw_row, prev, bpp, options);
         prev_row.clear();
         prev_row.extend_from_slice(&raw_row);
     }
@@ -214,6 +493,7 @@ fn build_scanline_filtered_data_adam7(
     bit_depth: u8,
     bpp: usize,
     format: &PixelFormat,
+    options: &EncodeOptions,
 ) -> Vec<u8> {
     let bytes_per_pixel = format.bytes_per_pixel();
     let needs_packing = bit_depth < 8;
@@ -247,7 +527,7 @@ fn build_scanline_filtered_data_adam7(
             } else {
                 Some(prev_row.as_slice())
             };
-            write_filtered_row(&mut filtered, &raw_row, prev, bpp);
+            write_filtered_row(&mut filtered, &raw_row, prev, bpp, options);
             prev_row.clear();
             prev_row.extend_from_slice(&raw_row);
         }
@@ -255,84 +535,119 @@ fn build_scanline_filtered_data_adam7(
     filtered
 }
 
-/// Select the best filter from None (0), Sub (1), Up (2), and Paeth (4)
-/// using minimum-sum-of-absolutes heuristic.
-fn write_filtered_row(out: &mut Vec<u8>, raw: &[u8], prev: Option<&[u8]>, bpp: usize) {
-    let abs_cost = |b: u8| u32::from((b as i8).unsigned_abs());
+const FILTER_NONE: u8 = 0;
+const FILTER_SUB: u8 = 1;
+const FILTER_UP: u8 = 2;
+const FILTER_AVERAGE: u8 = 3;
+const FILTER_PAETH: u8 = 4;
+const FILTER_TYPES: [u8; 5] = [
+    FILTER_NONE,
+    FILTER_SUB,
+    FILTER_UP,
+    FILTER_AVERAGE,
+    FILTER_PAETH,
+];
+
+/// Number of preceding filtered rows fed to the compressor alongside each
+/// candidate in [`FilterStrategy::BruteForce`] mode.
+pub(crate) const BRUTE_FORCE_CONTEXT_ROWS: usize = 4;
 
-    // Filter 0: None
-    let none_cost: u32 = raw.iter().map(|&b| abs_cost(b)).sum();
+/// Append one filtered scanline (filter type byte followed by filtered bytes)
+/// to `out`, choosing the filter type according to `options.filter`.
+pub(crate) fn write_filtered_row(
+    out: &mut Vec<u8>,
+    raw: &[u8],
+    prev: Option<&[u8]>,
+    bpp: usize,
+    options: &EncodeOptions,
+) {
+    let filter_type = match options.filter {
+        // Stored blocks are the same size whatever the filter, so there is
+        // nothing to choose between.
+        FilterStrategy::Adaptive | FilterStrategy::BruteForce
+            if options.compression == CompressionLevel::Store =>
+        {
+            FILTER_NONE
+        }
+        FilterStrategy::None => FILTER_NONE,
+        FilterStrategy::Sub => FILTER_SUB,
+        FilterStrategy::Up => FILTER_UP,
+        FilterStrategy::Average => FILTER_AVERAGE,
+        FilterStrategy::Paeth => FILTER_PAETH,
+        FilterStrategy::Adaptive => select_filter_by_min_sum(raw, prev, bpp),
+        FilterStrategy::BruteForce => {
+            select_filter_by_compressed_size(out, raw, prev, bpp, options)
+        }
+    };
+    out.reserve(1 + raw.len());
+    out.push(filter_type);
+    apply_filter(out, filter_type, raw, prev, bpp);
+}
 
-    // Filter 1: Sub
-    let sub_cost: u32 = raw
-        .iter()
-        .enumerate()
-        .map(|(i, &b)| {
-            let left = if i >= bpp { raw[i - bpp] } else { 0 };
-            abs_cost(b.wrapping_sub(left))
+/// Minimum-sum-of-absolutes heuristic over all five filter types.
+fn select_filter_by_min_sum(raw: &[u8], prev: Option<&[u8]>, bpp: usize) -> u8 {
+    let abs_cost = |b: u8| u32::from((b as i8).unsigned_abs());
+    FILTER_TYPES
+        .into_iter()
+        .min_by_key(|&filter_type| {
+            (0..raw.len())
+                .map(|i| abs_cost(filter_byte(filter_type, raw, prev, bpp, i)))
+                .sum::<u32>()
         })
-        .sum();
+        .expect("bug: filter candidate set must be non-empty")
+}
 
-    // Filter 2: Up
-    let up_cost: u32 = raw
-        .iter()
-        .enumerate()
-        .map(|(i, &b)| {
-            let up = prev.map_or(0, |p| p[i]);
-            abs_cost(b.wrapping_sub(up))
+/// Compress each candidate (after a few preceding filtered rows) and keep the
+/// filter type that yields the smallest output.
+fn select_filter_by_compressed_size(
+    filtered_so_far: &[u8],
+    raw: &[u8],
+    prev: Option<&[u8]>,
+    bpp: usize,
+    options: &EncodeOptions,
+) -> u8 {
+    let context_len = filtered_so_far
+        .len()
+        .min(BRUTE_FORCE_CONTEXT_ROWS * (raw.len() + 1));
+    let context = &filtered_so_far[filtered_so_far.len() - context_len..];
+    let mut candidate = Vec::with_capacity(context_len + 1 + raw.len());
+    FILTER_TYPES
+        .into_iter()
+        .min_by_key(|&filter_type| {
+            candidate.clear();
+            candidate.extend_from_slice(context);
+            candidate.push(filter_type);
+            apply_filter(&mut candidate, filter_type, raw, prev, bpp);
+            deflate::compress(&candidate, options.compression).map_or(usize::MAX, |c| c.len())
         })
-        .sum();
+        .expect("bug: filter candidate set must be non-empty")
+}
 
-    // Filter 4: Paeth
-    let paeth_cost: u32 = raw
-        .iter()
-        .enumerate()
-        .map(|(i, &b)| {
-            let left = if i >= bpp { raw[i - bpp] } else { 0 };
-            let up = prev.map_or(0, |p| p[i]);
+fn apply_filter(out: &mut Vec<u8>, filter_type: u8, raw: &[u8], prev: Option<&[u8]>, bpp: usize) {
+    if filter_type == FILTER_NONE {
+        out.extend_from_slice(raw);
+    } else {
+        out.extend((0..raw.len()).map(|i| filter_byte(filter_type, raw, prev, bpp, i)));
+    }
+}
+
+fn filter_byte(filter_type: u8, raw: &[u8], prev: Option<&[u8]>, bpp: usize, i: usize) -> u8 {
+    let b = raw[i];
+    let left = if i >= bpp { raw[i - bpp] } else { 0 };
+    let up = prev.map_or(0, |p| p[i]);
+    match filter_type {
+        FILTER_SUB => b.wrapping_sub(left),
+        FILTER_UP => b.wrapping_sub(up),
+        FILTER_AVERAGE => b.wrapping_sub(((u16::from(left) + u16::from(up)) / 2) as u8),
+        FILTER_PAETH => {
             let up_left = if i >= bpp {
                 prev.map_or(0, |p| p[i - bpp])
             } else {
                 0
             };
-            abs_cost(b.wrapping_sub(paeth_predictor(left, up, up_left)))
-        })
-        .sum();
-
-    let (best_filter, best_cost) = [
-        (0u8, none_cost),
-        (1, sub_cost),
-        (2, up_cost),
-        (4, paeth_cost),
-    ]
-    .into_iter()
-    .min_by_key(|&(_, cost)| cost)
-    .expect("bug: filter candidate set must be non-empty");
-
-    out.reserve(1 + raw.len());
-    out.push(best_filter);
-
-    if best_cost == none_cost && best_filter == 0 {
-        out.extend_from_slice(raw);
-    } else {
-        for (i, &b) in raw.iter().enumerate() {
-            let left = if i >= bpp { raw[i - bpp] } else { 0 };
-            let up = prev.map_or(0, |p| p[i]);
-            let filtered_byte = match best_filter {
-                1 => b.wrapping_sub(left),
-                2 => b.wrapping_sub(up),
-                4 => {
-                    let up_left = if i >= bpp {
-                        prev.map_or(0, |p| p[i - bpp])
-                    } else {
-                        0
-                    };
-                    b.wrapping_sub(paeth_predictor(left, up, up_left))
-                }
-                _ => b,
-            };
-            out.push(filtered_byte);
+            b.wrapping_sub(paeth_predictor(left, up, up_left))
         }
+        _ => b,
     }
 }
 
@@ -353,8 +668,8 @@ fn paeth_predictor(a: u8, b: u8, c: u8) -> u8 {
     }
 }
 
-fn pack_samples_to(out: &mut Vec<u8>, samples: &[u8], bit_depth: u8) {
-    if bit_depth == 8 {
+pub(crate) fn pack_samples_to(out: &mut Vec<u8>, samples: &[u8], bit_depth: u8) {
+    if bit_depth >= 8 {
         out.extend_from_slice(samples);
         return;
     }
@@ -373,3 +688,107 @@ fn pack_samples_to(out: &mut Vec<u8>, samples: &[u8], bit_depth: u8) {
         out.push((acc << (8 - bits)) as u8);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::EncodedImage;
+    use crate::png::{ADAM7_PASSES, adam7_axis_size};
+    use crate::png_types::{CompressionLevel, EncodeOptions, FilterStrategy, PixelFormat};
+
+    fn gradient(width: u32, height: u32) -> Vec<u8> {
+        (0..width * height * 3)
+            .map(|i| (i * 5 + i / 7) as u8)
+            .collect()
+    }
+
+    /// Returns the filter type byte of every scanline (Adam7 passes included).
+    fn filter_types(width: u32, height: u32, interlaced: bool, filtered: &[u8]) -> Vec<u8> {
+        let mut sizes = Vec::new();
+        if interlaced {
+            for pass in ADAM7_PASSES {
+                let pass_width = adam7_axis_size(width, pass.x_start, pass.x_step);
+                let pass_height = adam7_axis_size(height, pass.y_start, pass.y_step);
+                if pass_width > 0 {
+                    sizes.extend((0..pass_height).map(|_| pass_width as usize * 3));
+                }
+            }
+        } else {
+            sizes.extend((0..height).map(|_| width as usize * 3));
+        }
+        let mut offset = 0;
+        let mut types = Vec::new();
+        for stride in sizes {
+            types.push(filtered[offset]);
+            offset += stride + 1;
+        }
+        assert_eq!(offset, filtered.len());
+        types
+    }
+
+    #[test]
+    fn fixed_filter_strategies_apply_to_every_scanline() {
+        for (filter, filter_type) in [
+            (FilterStrategy::None, 0),
+            (FilterStrategy::Sub, 1),
+            (FilterStrategy::Up, 2),
+            (FilterStrategy::Average, 3),
+            (FilterStrategy::Paeth, 4),
+        ] {
+            for interlaced in [false, true] {
+                let options = EncodeOptions {
+                    filter,
+                    ..Default::default()
+                };
+                let image = EncodedImage::from_format_and_data(
+                    9,
+                    7,
+                    &PixelFormat::Rgb8,
+                    &gradient(9, 7),
+                    interlaced,
+                    &options,
+                )
+                .expect("infallible");
+                let types = filter_types(9, 7, interlaced, &image.filtered_data);
+                assert!(types.iter().all(|&t| t == filter_type), "{filter:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn store_level_skips_the_filter_search() {
+        for filter in [FilterStrategy::Adaptive, FilterStrategy::BruteForce] {
+            let options = EncodeOptions {
+                filter,
+                compression: CompressionLevel::Store,
+                ..Default::default()
+            };
+            let image = EncodedImage::from_format_and_data(
+                9,
+                7,
+                &PixelFormat::Rgb8,
+                &gradient(9, 7),
+                false,
+                &options,
+            )
+            .expect("infallible");
+            let types = filter_types(9, 7, false, &image.filtered_data);
+            assert!(types.iter().all(|&t| t == 0), "{filter:?}");
+        }
+    }
+
+    #[test]
+    fn brute_force_output_roundtrips() {
+        let data = gradient(32, 32);
+        let spec = crate::png::ImageSpec::new(32, 32, PixelFormat::Rgb8);
+        let options = EncodeOptions {
+            filter: FilterStrategy::BruteForce,
+            ..Default::default()
+        };
+        let bytes =
+            crate::png::encode_image_with_options(&spec, &data, &options).expect("infallible");
+        let (_, decoded) = crate::png::decode_image(&bytes).expect("infallible");
+        assert_eq!(decoded, data);
+    }
+}
@@ -0,0 +1,299 @@
+use alloc::collections::BTreeSet;
+use alloc::vec::Vec;
+
+use crate::chunk::ChunkReader;
+use crate::pixel_quantize::exact_palette;
+use crate::pixel_reformat::reformat;
+use crate::png::{ImageSpec, decode_image_with_options, encode_image_with_options};
+use crate::png_encode::validate_metadata;
+use crate::png_types::{
+    Background, DecodeOptions, EncodeOptions, Error, ErrorKind, Metadata, OptimizeOptions,
+    PixelFormat, Result, SignificantBits,
+};
+
+/// Re-encodes a PNG in the smallest lossless form found; see
+/// [`optimize_png`](crate::optimize_png).
+pub(crate) fn optimize(bytes: &[u8], options: &OptimizeOptions) -> Result<Vec<u8>> {
+    if options.filters.is_empty() || options.compression_levels.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidArgument,
+            "at least one filter strategy and compression level is required",
+        ));
+    }
+    if ChunkReader::new(bytes)?.any(|chunk| chunk.is_ok_and(|chunk| &chunk.chunk_type == b"acTL")) {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "animated PNGs cannot be optimized",
+        ));
+    }
+
+    let image = decode_image_with_options(bytes, &DecodeOptions::default())?;
+    let original = &image.spec.pixel_format;
+    let mut metadata = image.metadata;
+    if options.strip_metadata {
+        metadata.text.clear();
+        metadata.modification_time = None;
+        metadata.exif = None;
+    }
+    let background = metadata
+        .background
+        .and_then(|background| background_to_rgb16(background, original));
+
+    let rgba = reformat(original, &image.pixels, &PixelFormat::Rgba16Be)?;
+    let (samples, _) = rgba.as_chunks::<8>();
+    let pixels: Vec<[u16; 4]> = samples
+        .iter()
+        .map(|pixel| core::array::from_fn(|i| u16::from_be_bytes([pixel[2 * i], pixel[2 * i + 1]])))
+        .collect();
+
+    let mut candidates = Vec::new();
+    candidates.push((original.clone(), image.pixels));
+    let reduced = reduced_format(&pixels);
+    if reduced != *original {
+        let data = pack_pixels(&pixels, &reduced);
+        candidates.push((reduced, data));
+    }
+    if let Some((indexed, data)) = indexed_candidate(&pixels)
+        && indexed != *original
+    {
+        candidates.push((indexed, data));
+    }
+
+    // The input itself is a candidate unless metadata has to be stripped
+    // from it.
+    let mut smallest = (!options.strip_metadata).then(|| bytes.to_vec());
+    for (format, data) in candidates {
+        let spec = ImageSpec::new(image.spec.width, image.spec.height, format);
+        let metadata = metadata_for_format(&metadata, background, original, &spec.pixel_format);
+        for &filter in &options.filters {
+            for &compression in &options.compression_levels {
+                let encoded = encode_image_with_options(
+                    &spec,
+                    &data,
+                    &EncodeOptions {
+                        compression,
+                        filter,
+                        metadata: metadata.clone(),
+                    },
+                )?;
+                if smallest
+                    .as_ref()
+                    .is_none_or(|smallest| encoded.len() < smallest.len())
+                {
+                    smallest = Some(encoded);
+                }
+            }
+        }
+    }
+    Ok(smallest.expect("bug: at least one candidate is always encoded"))
+}
+
+/// Picks the smallest non-indexed format that stores `pixels` exactly.
+fn reduced_format(pixels: &[[u16; 4]]) -> PixelFormat {
+    let opaque = pixels.iter().all(|pixel| pixel[3] == u16::MAX);
+    let gray = pixels
+        .iter()
+        .all(|pixel| pixel[0] == pixel[1] && pixel[1] == pixel[2]);
+    let fits = |depth, channels: &[usize]| {
+        pixels.iter().all(|pixel| {
+            channels
+                .iter()
+                .all(|&channel| sample_at_depth(pixel[channel], depth).is_some())
+        })
+    };
+    match (gray, opaque) {
+        (true, true) => [
+            (1, PixelFormat::Gray1),
+            (2, PixelFormat::Gray2),
+            (4, PixelFormat::Gray4),
+            (8, PixelFormat::Gray8),
+        ]
+        .into_iter()
+        .find(|&(depth, _)| fits(depth, &[0]))
+        .map_or(PixelFormat::Gray16Be, |(_, format)| format),
+        (true, false) if fits(8, &[0, 3]) => PixelFormat::GrayAlpha8,
+        (true, false) => PixelFormat::GrayAlpha16Be,
+        (false, true) if fits(8, &[0, 1, 2]) => PixelFormat::Rgb8,
+        (false, true) => PixelFormat::Rgb16Be,
+        (false, false) if fits(8, &[0, 1, 2, 3]) => PixelFormat::Rgba8,
+        (false, false) => PixelFormat::Rgba16Be,
+    }
+}
+
+/// Stores `pixels` in `format`, which must be a non-indexed format returned
+/// by [`reduced_format`].
+fn pack_pixels(pixels: &[[u16; 4]], format: &PixelFormat) -> Vec<u8> {
+    let channels: &[usize] = match format {
+        PixelFormat::GrayAlpha8 | PixelFormat::GrayAlpha16Be => &[0, 3],
+        PixelFormat::Rgb8 | PixelFormat::Rgb16Be => &[0, 1, 2],
+        PixelFormat::Rgba8 | PixelFormat::Rgba16Be => &[0, 1, 2, 3],
+        _ => &[0],
+    };
+    let depth = format.bit_depth();
+    let mut out = Vec::with_capacity(pixels.len() * format.bytes_per_pixel());
+    for pixel in pixels {
+        for &channel in channels {
+            let sample = sample_at_depth(pixel[channel], depth)
+                .expect("bug: format was chosen to hold every sample");
+            if depth == 16 {
+                out.extend_from_slice(&sample.to_be_bytes());
+            } else {
+                out.push(sample as u8);
+            }
+        }
+    }
+    out
+}
+
+/// Builds the smallest palette that holds every color of `pixels`, or `None`
+/// if there are more than 256 colors or any sample needs 16 bits.
+fn indexed_candidate(pixels: &[[u16; 4]]) -> Option<(PixelFormat, Vec<u8>)> {
+    let mut colors = BTreeSet::new();
+    for pixel in pixels {
+        let mut color = [0; 4];
+        for (sample, &value) in color.iter_mut().zip(pixel) {
+            *sample = sample_at_depth(value, 8)? as u8;
+        }
+        colors.insert(color);
+        if colors.len() > 256 {
+            return None;
+        }
+    }
+    let depth = match colors.len() {
+        0 => return None,
+        1..=2 => 1,
+        3..=4 => 2,
+        5..=16 => 4,
+        _ => 8,
+    };
+    let (format, index) = exact_palette(colors.into_iter().collect(), depth);
+    let data = pixels
+        .iter()
+        .map(|pixel| index[&pixel.map(|sample| (sample / 257) as u8)])
+        .collect();
+    Some((format, data))
+}
+
+/// Adapts `metadata` to an image re-encoded from `original` into `format`,
+/// dropping the chunks that cannot be carried over.
+fn metadata_for_format(
+    metadata: &Metadata,
+    background: Option<[u16; 3]>,
+    original: &PixelFormat,
+    format: &PixelFormat,
+) -> Metadata {
+    if format == original {
+        return metadata.clone();
+    }
+    let significant_bits = metadata
+        .significant_bits
+        .map(|significant_bits| significant_bits_for_format(significant_bits, format))
+        .filter(|&significant_bits| {
+            let metadata = Metadata {
+                significant_bits: Some(significant_bits),
+                ..Default::default()
+            };
+            validate_metadata(&metadata, format).is_ok()
+        });
+    Metadata {
+        significant_bits,
+        background: background.and_then(|rgb| background_for_format(rgb, format)),
+        histogram: None,
+        ..metadata.clone()
+    }
+}
+
+/// Restates `significant_bits` with the channels of `format`. The result
+/// may still exceed the sample depth of `format`.
+fn significant_bits_for_format(
+    significant_bits: SignificantBits,
+    format: &PixelFormat,
+) -> SignificantBits {
+    let (color, alpha) = match significant_bits {
+        SignificantBits::Gray(gray) => ([gray; 3], None),
+        SignificantBits::GrayAlpha([gray, alpha]) => ([gray; 3], Some(alpha)),
+        SignificantBits::Rgb(rgb) => (rgb, None),
+        SignificantBits::Rgba([red, green, blue, alpha]) => ([red, green, blue], Some(alpha)),
+    };
+    // Formats only gain an alpha channel when the original has transparency.
+    let alpha = alpha.unwrap_or(format.bit_depth());
+    let gray = color[0].max(color[1]).max(color[2]);
+    match format {
+        PixelFormat::GrayAlpha8 | PixelFormat::GrayAlpha16Be => {
+            SignificantBits::GrayAlpha([gray, alpha])
+        }
+        PixelFormat::Rgba8 | PixelFormat::Rgba16Be => {
+            SignificantBits::Rgba([color[0], color[1], color[2], alpha])
+        }
+        PixelFormat::Rgb8
+        | PixelFormat::Rgb16Be
+        | PixelFormat::Indexed1 { .. }
+        | PixelFormat::Indexed2 { .. }
+        | PixelFormat::Indexed4 { .. }
+        | PixelFormat::Indexed8 { .. } => SignificantBits::Rgb(color),
+        _ => SignificantBits::Gray(gray),
+    }
+}
+
+/// Converts a `bKGD` color of an image in `format` to 16-bit RGB.
+fn background_to_rgb16(background: Background, format: &PixelFormat) -> Option<[u16; 3]> {
+    let depth = format.bit_depth();
+    let scale = |sample: u16| {
+        if depth == 16 {
+            sample
+        } else {
+            sample * (u16::MAX / ((1 << depth) - 1))
+        }
+    };
+    match (background, format) {
+        (
+            Background::PaletteIndex(index),
+            PixelFormat::Indexed1 { palette, .. }
+            | PixelFormat::Indexed2 { palette, .. }
+            | PixelFormat::Indexed4 { palette, .. }
+            | PixelFormat::Indexed8 { palette, .. },
+        ) => {
+            let start = usize::from(index) * 3;
+            let color = palette.get(start..start + 3)?;
+            Some([color[0], color[1], color[2]].map(|sample| u16::from(sample) * 257))
+        }
+        (Background::Gray(gray), _) => Some([scale(gray); 3]),
+        (Background::Rgb(rgb), _) => Some(rgb.map(scale)),
+        (Background::PaletteIndex(_), _) => None,
+    }
+}
+
+/// Expresses a 16-bit RGB background in `format`, if it can be stored exactly.
+fn background_for_format(rgb: [u16; 3], format: &PixelFormat) -> Option<Background> {
+    let depth = format.bit_depth();
+    match format {
+        PixelFormat::Indexed1 { palette, .. }
+        | PixelFormat::Indexed2 { palette, .. }
+        | PixelFormat::Indexed4 { palette, .. }
+        | PixelFormat::Indexed8 { palette, .. } => {
+            let (entries, _) = palette.as_chunks::<3>();
+            entries
+                .iter()
+                .position(|entry| entry.map(|sample| u16::from(sample) * 257) == rgb)
+                .map(|index| Background::PaletteIndex(index as u8))
+        }
+        PixelFormat::Rgb8 | PixelFormat::Rgb16Be | PixelFormat::Rgba8 | PixelFormat::Rgba16Be => {
+            let [r, g, b] = rgb.map(|sample| sample_at_depth(sample, depth));
+            Some(Background::Rgb([r?, g?, b?]))
+        }
+        _ if rgb[0] == rgb[1] && rgb[1] == rgb[2] => {
+            sample_at_depth(rgb[0], depth).map(Background::Gray)
+        }
+        _ => None,
+    }
+}
+
+/// Returns `sample` (a 16-bit value) at `depth` bits if it converts back
+/// without loss.
+fn sample_at_depth(sample: u16, depth: u8) -> Option<u16> {
+    if depth == 16 {
+        return Some(sample);
+    }
+    let scale = u16::MAX / ((1 << depth) - 1);
+    sample.is_multiple_of(scale).then_some(sample / scale)
+}
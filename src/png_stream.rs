@@ -0,0 +1,927 @@
+use alloc::vec::Vec;
+
+use crate::chunk::{IdatChunk, IendChunk, IhdrChunk, PlteChunk, TrnsChunk};
+use crate::crc::Crc32;
+use crate::pixel_reformat::{reformat, validate_format_and_data};
+use crate::png::{ImageSpec, PNG_SIGNATURE};
+use crate::png_decode::{
+    ChunkSequence, INFLATE_INPUT_STEP, RowPosition, ScanlineDecoder, append_converted_pixels,
+    check_chunk_crc, check_chunk_length, check_decompressed_len, expected_filtered_len,
+    expected_raw_len,
+};
+use crate::png_encode::{
+    BRUTE_FORCE_CONTEXT_ROWS, append_metadata_chunks, append_palette_metadata_chunks,
+    color_type_for_format, pack_samples_to, validate_metadata, write_filtered_row,
+};
+use crate::png_types::{
+    DecodeLimits, EncodeOptions, Error, ErrorKind, Metadata, PixelFormat, Result,
+};
+use crate::zlib;
+
+/// Incremental PNG encoder that accepts pixel data one row at a time.
+///
+/// Only the current and previous rows are kept in memory, together with a
+/// bounded amount of compressor state, so images far larger than the
+/// available memory can be written. Compressed data is emitted as `IDAT`
+/// chunks of `idat_chunk_size` bytes as soon as enough of it is available.
+///
+/// Output bytes are appended to the `out` buffer passed to each call; the
+/// caller may write them to their final destination and clear the buffer
+/// between calls.
+///
+/// Adam7 interlacing needs the whole image up front and is not supported.
+///
+/// # Examples
+///
+/// ```
+/// let spec = nopng::ImageSpec::new(3, 2, nopng::PixelFormat::Rgb8);
+/// let mut encoder = nopng::StreamEncoder::new(
+///     spec,
+///     nopng::EncodeOptions::default(),
+///     nopng::StreamEncoder::DEFAULT_IDAT_CHUNK_SIZE,
+/// )?;
+///
+/// let mut png_bytes = Vec::new();
+/// encoder.write_row(&[255, 0, 0, 0, 255, 0, 0, 0, 255], &mut png_bytes)?;
+/// encoder.write_row(&[0, 0, 0, 128, 128, 128, 255, 255, 255], &mut png_bytes)?;
+/// encoder.finish(&mut png_bytes)?;
+///
+/// let (spec, pixels) = nopng::decode_image(&png_bytes)?;
+/// assert_eq!(spec.height, 2);
+/// assert_eq!(pixels[..3], [255, 0, 0]);
+/// # Ok::<(), nopng::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct StreamEncoder {
+    spec: ImageSpec,
+    options: EncodeOptions,
+    idat_chunk_size: usize,
+    zlib: Option<zlib::Encoder>,
+    rows_written: u32,
+    raw_row: Vec<u8>,
+    prev_row: Vec<u8>,
+    filtered: Vec<u8>,
+    compressed: Vec<u8>,
+}
+
+impl StreamEncoder {
+    /// A reasonable `IDAT` chunk size for most uses.
+    pub const DEFAULT_IDAT_CHUNK_SIZE: usize = 8192;
+
+    /// Creates an encoder for an image described by `spec`.
+    ///
+    /// Returns an [`ErrorKind::Unsupported`] error if `spec.interlaced` is set,
+    /// and [`ErrorKind::InvalidArgument`] if a dimension or `idat_chunk_size`
+    /// is zero or `options.metadata` cannot be written.
+    pub fn new(spec: ImageSpec, options: EncodeOptions, idat_chunk_size: usize) -> Result<Self> {
+        if spec.width == 0 || spec.height == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidArgument,
+                "image dimensions must be non-zero",
+            ));
+        }
+        if spec.interlaced {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "streaming encoding of interlaced images is not supported",
+            ));
+        }
+        if idat_chunk_size == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidArgument,
+                "IDAT chunk size must be non-zero",
+            ));
+        }
+        validate_metadata(&options.metadata, &spec.pixel_format)?;
+        Ok(Self {
+            spec,
+            options,
+            idat_chunk_size,
+            zlib: None,
+            rows_written: 0,
+            raw_row: Vec::new(),
+            prev_row: Vec::new(),
+            filtered: Vec::new(),
+            compressed: Vec::new(),
+        })
+    }
+
+    /// The spec this encoder was created with.
+    pub fn spec(&self) -> &ImageSpec {
+        &self.spec
+    }
+
+    /// Number of rows written so far.
+    pub fn rows_written(&self) -> u32 {
+        self.rows_written
+    }
+
+    /// Encodes the next row and appends any completed chunks to `out`.
+    ///
+    /// `row` must hold exactly `spec.width` pixels in `spec.pixel_format`.
+    /// The PNG signature and header chunks are emitted with the first row.
+    pub fn write_row(&mut self, row: &[u8], out: &mut Vec<u8>) -> Result<()> {
+        if self.rows_written == self.spec.height {
+            return Err(Error::new(
+                ErrorKind::InvalidArgument,
+                "all rows of the image have already been written",
+            ));
+        }
+        validate_format_and_data(&self.spec.pixel_format, row, self.spec.width, 1)?;
+        if self.zlib.is_none() {
+            self.append_header_chunks(out)?;
+            self.zlib = Some(zlib::Encoder::new(
+                self.options.compression,
+                &mut self.compressed,
+            ));
+        }
+
+        let format = &self.spec.pixel_format;
+        let bit_depth = format.bit_depth();
+        let bpp = if bit_depth < 8 {
+            1
+        } else {
+            format.bytes_per_pixel()
+        };
+        self.raw_row.clear();
+        if let Some(straight_format) = format.straight_alpha() {
+            let straight = reformat(format, row, &straight_format)?;
+            pack_samples_to(&mut self.raw_row, &straight, bit_depth);
+        } else {
+            pack_samples_to(&mut self.raw_row, row, bit_depth);
+        }
+
+        let prev = (self.rows_written > 0).then_some(self.prev_row.as_slice());
+        let start = self.filtered.len();
+        write_filtered_row(&mut self.filtered, &self.raw_row, prev, bpp, &self.options);
+        self.zlib
+            .as_mut()
+            .expect("bug: zlib encoder must be initialized")
+            .write(&self.filtered[start..], &mut self.compressed)?;
+
+        // Keep only the few rows that brute-force filter selection looks back at.
+        let context_len = BRUTE_FORCE_CONTEXT_ROWS * (self.raw_row.len() + 1);
+        if self.filtered.len() > context_len {
+            self.filtered.drain(..self.filtered.len() - context_len);
+        }
+        core::mem::swap(&mut self.raw_row, &mut self.prev_row);
+        self.rows_written += 1;
+
+        self.append_idat_chunks(out, false);
+        Ok(())
+    }
+
+    /// Flushes the remaining compressed data and appends the final `IDAT` and
+    /// `IEND` chunks to `out`.
+    ///
+    /// Returns an [`ErrorKind::InvalidArgument`] error if fewer than
+    /// `spec.height` rows were written.
+    pub fn finish(mut self, out: &mut Vec<u8>) -> Result<()> {
+        if self.rows_written != self.spec.height {
+            return Err(Error::new(
+                ErrorKind::InvalidArgument,
+                "fewer rows were written than the image height",
+            ));
+        }
+        self.zlib
+            .take()
+            .expect("bug: zlib encoder must be initialized")
+            .finish(&mut self.compressed)?;
+        self.append_idat_chunks(out, true);
+        IendChunk.append_to(out);
+        Ok(())
+    }
+
+    fn append_header_chunks(&self, out: &mut Vec<u8>) -> Result<()> {
+        let format = &self.spec.pixel_format;
+        out.extend_from_slice(&PNG_SIGNATURE);
+        IhdrChunk {
+            width: self.spec.width,
+            height: self.spec.height,
+            bit_depth: format.bit_depth(),
+            color_type: color_type_for_format(format),
+            interlace_method: 0,
+        }
+        .append_to(out);
+        append_metadata_chunks(out, &self.options.metadata, self.options.compression)?;
+        if let PixelFormat::Indexed1 { palette, trns }
+        | PixelFormat::Indexed2 { palette, trns }
+        | PixelFormat::Indexed4 { palette, trns }
+        | PixelFormat::Indexed8 { palette, trns } = format
+        {
+            let (palette, _) = palette.as_chunks::<3>();
+            PlteChunk { palette }.append_to(out);
+            if let Some(trns) = trns {
+                TrnsChunk { data: trns }.append_to(out);
+            }
+        }
+        append_palette_metadata_chunks(out, &self.options.metadata);
+        Ok(())
+    }
+
+    fn append_idat_chunks(&mut self, out: &mut Vec<u8>, flush_all: bool) {
+        let mut offset = 0;
+        while self.compressed.len() - offset >= self.idat_chunk_size
+            || (flush_all && offset < self.compressed.len())
+        {
+            let end = self.compressed.len().min(offset + self.idat_chunk_size);
+            IdatChunk {
+                zlib_data: &self.compressed[offset..end],
+            }
+            .append_to(out);
+            offset = end;
+        }
+        self.compressed.drain(..offset);
+    }
+}
+
+/// Incremental PNG decoder that accepts input in pieces and yields rows as
+/// soon as they can be decoded.
+///
+/// Feed bytes with [`write`](Self::write) and call
+/// [`next_row`](Self::next_row) until it returns `None`, then write more input.
+/// Decompression state carries across `IDAT` chunk boundaries and across
+/// writes, so the input may be split anywhere.
+///
+/// Rows are produced in the native [`PixelFormat`] reported by
+/// [`spec`](Self::spec), which matches the output of
+/// [`decode_image`](crate::decode_image). Interlaced images are produced pass
+/// by pass; each [`DecodedRow`] carries the pass and the pixel positions it
+/// covers.
+///
+/// # Examples
+///
+/// ```
+/// # let png_bytes = nopng::encode_image(
+/// #     &nopng::ImageSpec::new(2, 3, nopng::PixelFormat::Gray8),
+/// #     &[0, 1, 2, 3, 4, 5],
+/// # )?;
+/// let mut decoder = nopng::StreamDecoder::new();
+/// let mut pixels = Vec::new();
+/// for piece in png_bytes.chunks(16) {
+///     decoder.write(piece);
+///     while let Some(row) = decoder.next_row()? {
+///         pixels.extend_from_slice(row.pixels);
+///     }
+/// }
+/// decoder.finish()?;
+/// assert_eq!(pixels, [0, 1, 2, 3, 4, 5]);
+/// # Ok::<(), nopng::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct StreamDecoder {
+    input: Vec<u8>,
+    /// Bytes at the start of `input` that have already been decoded.
+    input_start: usize,
+    state: ChunkState,
+    chunks: ChunkSequence,
+    spec: Option<ImageSpec>,
+    zlib: zlib::Decoder,
+    idat_crc: Crc32,
+    expected_filtered: usize,
+    inflated: usize,
+    filtered: Vec<u8>,
+    /// Bytes at the start of `filtered` that have already been unfiltered.
+    filtered_start: usize,
+    scanlines: Option<ScanlineDecoder>,
+    pixels: Vec<u8>,
+    row: RowPosition,
+}
+
+#[derive(Debug)]
+enum ChunkState {
+    Signature,
+    ChunkHeader,
+    ImageData { remaining: usize },
+    ImageDataCrc,
+    End,
+}
+
+/// A row of pixels produced by [`StreamDecoder::next_row`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodedRow<'a> {
+    /// Adam7 pass (`0..7`) for interlaced images, `None` otherwise.
+    pub pass: Option<u8>,
+    /// Image row the pixels belong to.
+    pub y: u32,
+    /// Column of the first pixel in `pixels`.
+    pub x_start: u32,
+    /// Column distance between consecutive pixels (`1` unless interlaced).
+    pub x_step: u32,
+    /// Pixel data in the stream's native pixel format.
+    pub pixels: &'a [u8],
+}
+
+impl Default for StreamDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StreamDecoder {
+    /// Creates a decoder that expects a PNG stream starting with the signature.
+    pub fn new() -> Self {
+        Self::with_limits(DecodeLimits::default())
+    }
+
+    /// Creates a decoder that enforces `limits`, returning
+    /// [`ErrorKind::LimitExceeded`] errors from [`next_row`](Self::next_row) once the
+    /// stream exceeds them.
+    pub fn with_limits(limits: DecodeLimits) -> Self {
+        Self {
+            input: Vec::new(),
+            input_start: 0,
+            state: ChunkState::Signature,
+            chunks: ChunkSequence::new(limits),
+            spec: None,
+            zlib: zlib::Decoder::new(),
+            idat_crc: Crc32::new(),
+            expected_filtered: 0,
+            inflated: 0,
+            filtered: Vec::new(),
+            filtered_start: 0,
+            scanlines: None,
+            pixels: Vec::new(),
+            row: RowPosition::default(),
+        }
+    }
+
+    /// Appends more PNG bytes to the decoder's input.
+    pub fn write(&mut self, bytes: &[u8]) {
+        // Drop decoded input only once it outweighs the rest, so that each
+        // byte is moved at most once on average.
+        if self.input_start > self.input.len() / 2 {
+            self.input.drain(..self.input_start);
+            self.input_start = 0;
+        }
+        self.input.extend_from_slice(bytes);
+    }
+
+    /// The image spec, available once every chunk before the first `IDAT`
+    /// has been read.
+    pub fn spec(&self) -> Option<&ImageSpec> {
+        self.spec.as_ref()
+    }
+
+    /// Color-space information and other ancillary chunks read so far. Every
+    /// chunk that precedes the image data is available once
+    /// [`spec`](Self::spec) is.
+    pub fn metadata(&self) -> &Metadata {
+        &self.chunks.ancillary.metadata
+    }
+
+    /// Returns `true` once `IEND` has been read and every row returned.
+    pub fn is_finished(&self) -> bool {
+        matches!(self.state, ChunkState::End)
+            && self
+                .scanlines
+                .as_ref()
+                .is_none_or(ScanlineDecoder::is_finished)
+    }
+
+    /// Decodes the next row, or returns `None` if more input is needed (or
+    /// the image is complete).
+    pub fn next_row(&mut self) -> Result<Option<DecodedRow<'_>>> {
+        loop {
+            if self.decode_row()? {
+                break;
+            }
+            if !self.advance()? {
+                return Ok(None);
+            }
+        }
+        Ok(Some(DecodedRow {
+            pass: self.row.pass,
+            y: self.row.y,
+            x_start: self.row.x_start,
+            x_step: self.row.x_step,
+            pixels: &self.pixels,
+        }))
+    }
+
+    /// Checks that the whole image was decoded.
+    ///
+    /// Returns an [`ErrorKind::UnexpectedEof`] error if the input ended early,
+    /// and [`ErrorKind::InvalidArgument`] if rows are
+    /// still pending.
+    pub fn finish(self) -> Result<()> {
+        if self.is_finished() {
+            Ok(())
+        } else if matches!(self.state, ChunkState::End) {
+            Err(Error::new(
+                ErrorKind::InvalidArgument,
+                "not every decoded row has been read",
+            ))
+        } else {
+            Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "unexpected end of PNG stream",
+            ))
+        }
+    }
+
+    /// Unfilters and converts one row if enough data has been inflated.
+    fn decode_row(&mut self) -> Result<bool> {
+        let Some(scanlines) = self.scanlines.as_mut() else {
+            return Ok(false);
+        };
+        let Some((position, consumed)) =
+            scanlines.decode_next(&self.filtered[self.filtered_start..])?
+        else {
+            return Ok(false);
+        };
+        self.filtered_start += consumed;
+        let header = self
+            .chunks
+            .header
+            .as_ref()
+            .expect("bug: header must be parsed before image data");
+        self.pixels.clear();
+        append_converted_pixels(
+            &mut self.pixels,
+            header,
+            position.width,
+            scanlines.row(),
+            &self.chunks.ancillary,
+        )?;
+        self.row = position;
+        Ok(true)
+    }
+
+    /// Consumes buffered input. Returns `false` if no progress is possible
+    /// without more input.
+    fn advance(&mut self) -> Result<bool> {
+        let input = &self.input[self.input_start..];
+        match self.state {
+            ChunkState::Signature => {
+                let Some(signature) = input.get(..PNG_SIGNATURE.len()) else {
+                    return Ok(false);
+                };
+                if signature != PNG_SIGNATURE {
+                    return Err(Error::new(ErrorKind::BadSignature, "invalid PNG signature"));
+                }
+                self.input_start += PNG_SIGNATURE.len();
+                self.state = ChunkState::ChunkHeader;
+            }
+            ChunkState::ChunkHeader => {
+                let Some(prefix) = input.get(..8) else {
+                    return Ok(false);
+                };
+                let length = u32::from_be_bytes(
+                    prefix[..4]
+                        .try_into()
+                        .expect("bug: chunk length must be 4 bytes"),
+                ) as usize;
+                let chunk_type: [u8; 4] = prefix[4..]
+                    .try_into()
+                    .expect("bug: chunk type must be 4 bytes");
+                check_chunk_length(&chunk_type, length)?;
+                if &chunk_type == b"IDAT" {
+                    self.chunks.accept(&chunk_type, &[])?;
+                    if self.spec.is_none() {
+                        self.start_image_data()?;
+                    }
+                    self.idat_crc = Crc32::new();
+                    self.idat_crc.update(&chunk_type);
+                    self.input_start += 8;
+                    self.state = ChunkState::ImageData { remaining: length };
+                    return Ok(true);
+                }
+
+                self.chunks.check_limits(&chunk_type, length)?;
+                let total = length + 12;
+                let Some(chunk) = input.get(..total) else {
+                    return Ok(false);
+                };
+                let chunk_data = &chunk[8..total - 4];
+                let expected_crc = u32::from_be_bytes(
+                    chunk[total - 4..]
+                        .try_into()
+                        .expect("bug: chunk CRC must be 4 bytes"),
+                );
+                let actual_crc = crate::crc::calculate(&[&chunk_type[..], chunk_data]);
+                check_chunk_crc(&chunk_type, actual_crc, expected_crc)?;
+                self.chunks.accept(&chunk_type, chunk_data)?;
+                self.input_start += total;
+                if self.chunks.seen_iend {
+                    self.finish_image_data()?;
+                    self.state = ChunkState::End;
+                }
+            }
+            ChunkState::ImageData { remaining } => {
+                if remaining == 0 {
+                    self.state = ChunkState::ImageDataCrc;
+                    return Ok(true);
+                }
+                let len = remaining.min(input.len()).min(INFLATE_INPUT_STEP);
+                if len == 0 {
+                    return Ok(false);
+                }
+                let data = &input[..len];
+                self.idat_crc.update(data);
+                // As with `input`, drop unfiltered rows only once they
+                // outweigh the rest.
+                if self.filtered_start > self.filtered.len() / 2 {
+                    self.filtered.drain(..self.filtered_start);
+                    self.filtered_start = 0;
+                }
+                let start = self.filtered.len();
+                self.zlib.write(data, &mut self.filtered)?;
+                self.inflated += self.filtered.len() - start;
+                if self.inflated > self.expected_filtered {
+                    return Err(Error::new(
+                        ErrorKind::InvalidImageData,
+                        "decompressed image data is larger than the image size",
+                    ));
+                }
+                self.input_start += len;
+                self.state = ChunkState::ImageData {
+                    remaining: remaining - len,
+                };
+            }
+            ChunkState::ImageDataCrc => {
+                let Some(crc) = input.get(..4) else {
+                    return Ok(false);
+                };
+                let expected_crc =
+                    u32::from_be_bytes(crc.try_into().expect("bug: chunk CRC must be 4 bytes"));
+                check_chunk_crc(b"IDAT", self.idat_crc.finish(), expected_crc)?;
+                self.input_start += 4;
+                self.state = ChunkState::ChunkHeader;
+            }
+            ChunkState::End => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    fn start_image_data(&mut self) -> Result<()> {
+        let header = self
+            .chunks
+            .header
+            .expect("bug: IDAT is only accepted after IHDR");
+        self.chunks.ancillary.validate(&header)?;
+        self.expected_filtered = expected_filtered_len(&header)?;
+        check_decompressed_len(self.expected_filtered, &self.chunks.limits)?;
+        expected_raw_len(&header)?;
+        self.zlib = zlib::Decoder::with_output_limit(self.chunks.limits.max_decompressed_bytes);
+        self.spec = Some(ImageSpec::from_header_and_ancillary(
+            &header,
+            &self.chunks.ancillary,
+        ));
+        self.scanlines = Some(ScanlineDecoder::new(header));
+        Ok(())
+    }
+
+    fn finish_image_data(&mut self) -> Result<()> {
+        self.chunks.finish()?;
+        if !self.chunks.seen_idat {
+            return Err(Error::new(
+                ErrorKind::ChunkOrdering {
+                    chunk_type: *b"IDAT",
+                },
+                "missing IDAT chunk",
+            ));
+        }
+        if !self.zlib.is_finished() {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "unexpected end of deflate stream",
+            ));
+        }
+        if self.inflated != self.expected_filtered {
+            return Err(Error::new(
+                ErrorKind::InvalidImageData,
+                "decompressed image data does not match the image size",
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    use super::{StreamDecoder, StreamEncoder};
+    use crate::png::{ImageSpec, decode_image, encode_image, encode_image_with_options};
+    use crate::png_types::{CompressionLevel, DecodeLimits, EncodeOptions, ErrorKind, PixelFormat};
+
+    fn chunk_types(bytes: &[u8]) -> Vec<([u8; 4], usize)> {
+        let mut offset = 8;
+        let mut chunks = Vec::new();
+        while offset + 12 <= bytes.len() {
+            let length = u32::from_be_bytes(
+                bytes[offset..offset + 4]
+                    .try_into()
+                    .expect("bug: chunk length must be 4 bytes"),
+            ) as usize;
+            let chunk_type = bytes[offset + 4..offset + 8]
+                .try_into()
+                .expect("bug: chunk type must be 4 bytes");
+            chunks.push((chunk_type, length));
+            offset += 12 + length;
+        }
+        chunks
+    }
+
+    #[test]
+    fn streamed_rows_roundtrip_with_small_idat_chunks() {
+        let width = 64;
+        let height = 48;
+        let data: Vec<u8> = (0..width * height * 4)
+            .map(|i| (i * 31 + i / 253) as u8)
+            .collect();
+        let spec = ImageSpec::new(width, height, PixelFormat::Rgba8);
+        let mut encoder =
+            StreamEncoder::new(spec.clone(), EncodeOptions::default(), 100).expect("infallible");
+        let mut bytes = Vec::new();
+        for row in data.chunks(width as usize * 4) {
+            encoder.write_row(row, &mut bytes).expect("infallible");
+        }
+        encoder.finish(&mut bytes).expect("infallible");
+
+        let chunks = chunk_types(&bytes);
+        let idat: Vec<usize> = chunks
+            .iter()
+            .filter(|(chunk_type, _)| chunk_type == b"IDAT")
+            .map(|&(_, length)| length)
+            .collect();
+        assert!(idat.len() > 1);
+        assert!(idat[..idat.len() - 1].iter().all(|&length| length == 100));
+        assert_eq!(&chunks.last().expect("infallible").0, b"IEND");
+
+        let (decoded_spec, decoded) = decode_image(&bytes).expect("infallible");
+        assert_eq!(decoded_spec, spec);
+        assert_eq!(decoded, data);
+    }
+
+    #[test]
+    fn streamed_sixteen_bit_rows_roundtrip() {
+        for (format, bytes_per_pixel) in [(PixelFormat::Gray16Be, 2), (PixelFormat::Rgba16Be, 8)] {
+            let spec = ImageSpec::new(7, 5, format);
+            let data: Vec<u8> = (0..7 * 5 * bytes_per_pixel)
+                .map(|i| (i * 37 + i / 11) as u8)
+                .collect();
+            let mut encoder = StreamEncoder::new(spec.clone(), EncodeOptions::default(), 8192)
+                .expect("infallible");
+            let mut bytes = Vec::new();
+            for row in data.chunks(7 * bytes_per_pixel) {
+                encoder.write_row(row, &mut bytes).expect("infallible");
+            }
+            encoder.finish(&mut bytes).expect("infallible");
+            let (decoded_spec, decoded) = decode_image(&bytes).expect("infallible");
+            assert_eq!(decoded_spec, spec);
+            assert_eq!(decoded, data);
+        }
+    }
+
+    #[test]
+    fn streamed_indexed_image_matches_one_shot_pixels() {
+        let spec = ImageSpec::new(
+            5,
+            3,
+            PixelFormat::Indexed2 {
+                palette: alloc::vec![0, 0, 0, 255, 0, 0, 0, 255, 0],
+                trns: Some(alloc::vec![0]),
+            },
+        );
+        let data = [0, 1, 2, 1, 0, 2, 2, 1, 1, 0, 0, 0, 1, 2, 2];
+        let mut encoder =
+            StreamEncoder::new(spec.clone(), EncodeOptions::default(), 8192).expect("infallible");
+        let mut bytes = Vec::new();
+        for row in data.chunks(5) {
+            encoder.write_row(row, &mut bytes).expect("infallible");
+        }
+        encoder.finish(&mut bytes).expect("infallible");
+        let one_shot = encode_image(&spec, &data).expect("infallible");
+        assert_eq!(
+            decode_image(&bytes).expect("infallible"),
+            decode_image(&one_shot).expect("infallible")
+        );
+    }
+
+    #[test]
+    fn rejects_wrong_row_count_and_interlacing() {
+        let spec = ImageSpec::new(2, 2, PixelFormat::Gray8);
+        let mut encoder =
+            StreamEncoder::new(spec.clone(), EncodeOptions::default(), 8192).expect("infallible");
+        let mut bytes = Vec::new();
+        encoder.write_row(&[1, 2], &mut bytes).expect("infallible");
+        assert!(encoder.write_row(&[1, 2, 3], &mut bytes).is_err());
+        let error = encoder.finish(&mut bytes).expect_err("infallible");
+        assert_eq!(error.kind(), ErrorKind::InvalidArgument);
+
+        let interlaced = ImageSpec {
+            interlaced: true,
+            ..spec
+        };
+        let error =
+            StreamEncoder::new(interlaced, EncodeOptions::default(), 8192).expect_err("infallible");
+        assert_eq!(error.kind(), ErrorKind::Unsupported);
+    }
+
+    fn stream_decode(bytes: &[u8], piece_len: usize) -> (ImageSpec, Vec<u8>) {
+        let mut decoder = StreamDecoder::new();
+        let mut rows = Vec::new();
+        for piece in bytes.chunks(piece_len) {
+            decoder.write(piece);
+            while let Some(row) = decoder.next_row().expect("infallible") {
+                rows.push((row.y, row.x_start, row.x_step, row.pixels.to_vec()));
+            }
+        }
+        let spec = decoder.spec().expect("infallible").clone();
+        decoder.finish().expect("infallible");
+
+        let bpp = spec.pixel_format.data_len(1, 1);
+        let mut pixels = alloc::vec![0; spec.data_len()];
+        for (y, x_start, x_step, row) in rows {
+            for (i, pixel) in row.chunks(bpp).enumerate() {
+                let x = (x_start + i as u32 * x_step) as usize;
+                let offset = (y as usize * spec.width as usize + x) * bpp;
+                pixels[offset..offset + bpp].copy_from_slice(pixel);
+            }
+        }
+        (spec, pixels)
+    }
+
+    #[test]
+    fn stream_decoder_matches_decode_image() {
+        let data: Vec<u8> = (0..37 * 23 * 3).map(|i| (i * 7 + i / 97) as u8).collect();
+        let palette: Vec<u8> = (0..48).collect();
+        let indices: Vec<u8> = (0..37 * 23).map(|i| (i % 13) as u8).collect();
+        let images = [
+            (ImageSpec::new(37, 23, PixelFormat::Rgb8), data.clone()),
+            (
+                interlaced(ImageSpec::new(37, 23, PixelFormat::Rgb8)),
+                data.clone(),
+            ),
+            (
+                interlaced(ImageSpec::new(
+                    37,
+                    23,
+                    PixelFormat::Indexed4 {
+                        palette,
+                        trns: Some(alloc::vec![0, 128]),
+                    },
+                )),
+                indices,
+            ),
+            (ImageSpec::new(111, 23, PixelFormat::Gray8), data),
+        ];
+        for (spec, data) in images {
+            let bytes = encode_image(&spec, &data).expect("infallible");
+            let expected = decode_image(&bytes).expect("infallible");
+            for piece_len in [1, 13, bytes.len()] {
+                assert_eq!(stream_decode(&bytes, piece_len), expected, "{spec:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn stream_decoder_accepts_a_large_image_in_one_write() {
+        let spec = ImageSpec::new(512, 512, PixelFormat::Rgba8);
+        let data: Vec<u8> = (0..512 * 512 * 4)
+            .map(|i: u32| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        let options = EncodeOptions {
+            compression: CompressionLevel::Store,
+            ..Default::default()
+        };
+        let bytes = encode_image_with_options(&spec, &data, &options).expect("infallible");
+        assert!(bytes.len() > data.len());
+        assert_eq!(stream_decode(&bytes, bytes.len()), (spec, data));
+    }
+
+    fn interlaced(spec: ImageSpec) -> ImageSpec {
+        ImageSpec {
+            interlaced: true,
+            ..spec
+        }
+    }
+
+    #[test]
+    fn stream_decoder_yields_rows_before_input_ends() {
+        let width = 256;
+        let height = 64;
+        let data: Vec<u8> = (0..width * height)
+            .map(|i: u32| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        let spec = ImageSpec::new(width, height, PixelFormat::Gray8);
+        let mut encoder =
+            StreamEncoder::new(spec.clone(), EncodeOptions::default(), 1024).expect("infallible");
+        let mut bytes = Vec::new();
+        for row in data.chunks(width as usize) {
+            encoder.write_row(row, &mut bytes).expect("infallible");
+        }
+        encoder.finish(&mut bytes).expect("infallible");
+
+        let mut decoder = StreamDecoder::new();
+        decoder.write(&bytes[..bytes.len() / 2]);
+        let mut rows = 0;
+        while let Some(row) = decoder.next_row().expect("infallible") {
+            assert_eq!(row.y, rows);
+            assert_eq!(
+                row.pixels,
+                &data[(rows * width) as usize..][..width as usize]
+            );
+            rows += 1;
+        }
+        assert_eq!(decoder.spec(), Some(&spec));
+        assert!(rows > 0 && rows < height);
+        assert!(!decoder.is_finished());
+        assert!(decoder.finish().is_err());
+    }
+
+    #[test]
+    fn stream_decoder_handles_tall_compressible_images() {
+        // A few compressed bytes inflate to many rows, which must not be
+        // shifted down the buffer one at a time.
+        let height = 1_000_000;
+        let spec = ImageSpec::new(1, height, PixelFormat::Gray8);
+        let bytes = encode_image(&spec, &vec![7; height as usize]).expect("infallible");
+        let mut decoder = StreamDecoder::new();
+        decoder.write(&bytes);
+        let mut rows = 0;
+        while let Some(row) = decoder.next_row().expect("infallible") {
+            assert_eq!(row.pixels, [7]);
+            rows += 1;
+        }
+        assert_eq!(rows, height);
+        decoder.finish().expect("infallible");
+    }
+
+    #[test]
+    fn stream_decoder_rejects_corrupt_image_data() {
+        let spec = ImageSpec::new(4, 4, PixelFormat::Gray8);
+        let mut bytes = encode_image(&spec, &[9; 16]).expect("infallible");
+        let idat = bytes
+            .windows(4)
+            .position(|window| window == b"IDAT")
+            .expect("infallible");
+        bytes[idat + 6] ^= 0xFF;
+        let mut decoder = StreamDecoder::new();
+        decoder.write(&bytes);
+        let error = loop {
+            match decoder.next_row() {
+                Ok(Some(_)) => {}
+                Ok(None) => panic!("corrupt stream must not decode"),
+                Err(error) => break error,
+            }
+        };
+        assert!(matches!(error.kind(), ErrorKind::Deflate { .. }));
+    }
+
+    #[test]
+    fn decoders_reject_oversized_chunk_lengths() {
+        let spec = ImageSpec::new(4, 4, PixelFormat::Gray8);
+        let mut bytes = encode_image(&spec, &[9; 16]).expect("infallible");
+        let iend = bytes.len() - 12;
+        bytes[iend..iend + 4].copy_from_slice(&0x8000_0000u32.to_be_bytes());
+        let oversized = ErrorKind::InvalidChunk {
+            chunk_type: *b"IEND",
+        };
+        assert_eq!(
+            decode_image(&bytes).expect_err("infallible").kind(),
+            oversized
+        );
+
+        let mut decoder = StreamDecoder::new();
+        decoder.write(&bytes);
+        let error = loop {
+            match decoder.next_row() {
+                Ok(Some(_)) => {}
+                Ok(None) => panic!("oversized chunk must not decode"),
+                Err(error) => break error,
+            }
+        };
+        assert_eq!(error.kind(), oversized);
+    }
+
+    #[test]
+    fn stream_decoder_enforces_limits() {
+        let spec = ImageSpec::new(4, 4, PixelFormat::Gray8);
+        let bytes = encode_image(&spec, &[9; 16]).expect("infallible");
+        for limits in [
+            DecodeLimits {
+                max_height: 3,
+                ..Default::default()
+            },
+            DecodeLimits {
+                max_chunks: 2,
+                ..Default::default()
+            },
+        ] {
+            let mut decoder = StreamDecoder::with_limits(limits);
+            decoder.write(&bytes);
+            let error = loop {
+                match decoder.next_row() {
+                    Ok(Some(_)) => {}
+                    Ok(None) => panic!("stream over the limits must not decode"),
+                    Err(error) => break error,
+                }
+            };
+            assert_eq!(error.kind(), ErrorKind::LimitExceeded);
+        }
+    }
+}
@@ -1,35 +1,852 @@
 use alloc::borrow::Cow;
+use alloc::string::String;
+use alloc::vec;
 use alloc::vec::Vec;
 use core::error::Error as CoreError;
 
 /// Errors returned by decoding and encoding operations.
+///
+/// Use [`kind`](Self::kind) to tell failures apart programmatically; the
+/// [`Display`](core::fmt::Display) output adds a human-readable description.
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub enum Error {
-    /// The PNG uses a feature that this crate does not support.
-    Unsupported(Cow<'static, str>),
-    /// The PNG data is malformed or violates the specification.
-    InvalidData(Cow<'static, str>),
+pub struct Error {
+    pub(crate) kind: ErrorKind,
+    pub(crate) message: Cow<'static, str>,
+}
+
+/// The kind of failure behind an [`Error`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// The input does not start with the PNG signature.
+    BadSignature,
+    /// The input ended before the PNG stream was complete.
+    UnexpectedEof,
+    /// A chunk's stored CRC does not match its type and data.
+    CrcMismatch {
+        /// Type of the corrupt chunk.
+        chunk_type: [u8; 4],
+    },
+    /// A chunk is missing, duplicated, or appears out of order.
+    ChunkOrdering {
+        /// Type of the offending (or missing) chunk.
+        chunk_type: [u8; 4],
+    },
+    /// A chunk's data is malformed.
+    InvalidChunk {
+        /// Type of the malformed chunk.
+        chunk_type: [u8; 4],
+    },
+    /// The zlib header of the image data is invalid or uses an unsupported
+    /// feature.
+    ZlibHeader,
+    /// The Adler-32 checksum of the image data does not match.
+    Adler32Mismatch,
+    /// The header of a gzip stream is invalid or uses an unsupported feature.
+    GzipHeader,
+    /// The CRC-32 or size in the trailer of a gzip stream does not match the
+    /// decompressed data.
+    GzipTrailer,
+    /// The DEFLATE stream is malformed.
+    Deflate {
+        /// Position in the DEFLATE stream, in bits, at which the error was
+        /// detected.
+        bit_offset: u64,
+    },
+    /// The decompressed image data has an invalid filter type or size.
+    InvalidImageData,
+    /// An indexed pixel refers to a palette entry that does not exist.
+    PaletteIndexOutOfRange,
+    /// The PNG exceeds one of the configured [`DecodeLimits`].
+    LimitExceeded,
+    /// The PNG or the requested conversion uses a feature that this crate does
+    /// not support.
+    Unsupported,
+    /// Pixel data, a pixel format, or a buffer passed by the caller is
+    /// inconsistent.
+    InvalidArgument,
 }
 
 pub(crate) type Result<T> = core::result::Result<T, Error>;
 
+impl Error {
+    pub(crate) fn new(kind: ErrorKind, message: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+
+    /// The kind of failure.
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    /// Details about the failure, without the description of its kind.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Self {
+        Self::new(kind, "")
+    }
+}
+
 impl core::fmt::Display for Error {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        match self {
-            Self::Unsupported(message) => write!(f, "unsupported: {message}"),
-            Self::InvalidData(message) => write!(f, "invalid data: {message}"),
+        if self.message.is_empty() {
+            write!(f, "{}", self.kind)
+        } else {
+            write!(f, "{}: {}", self.kind, self.message)
         }
     }
 }
 
-impl CoreError for Error {
-    fn source(&self) -> Option<&(dyn CoreError + 'static)> {
+impl CoreError for Error {}
+
+impl core::fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
-            Self::Unsupported(_) | Self::InvalidData(_) => None,
+            Self::BadSignature => f.write_str("invalid PNG signature"),
+            Self::UnexpectedEof => f.write_str("unexpected end of data"),
+            Self::CrcMismatch { chunk_type } => {
+                write!(f, "CRC mismatch for chunk {}", ChunkName(chunk_type))
+            }
+            Self::ChunkOrdering { chunk_type } => {
+                write!(f, "misplaced chunk {}", ChunkName(chunk_type))
+            }
+            Self::InvalidChunk { chunk_type } => {
+                write!(f, "invalid chunk {}", ChunkName(chunk_type))
+            }
+            Self::ZlibHeader => f.write_str("invalid zlib header"),
+            Self::Adler32Mismatch => f.write_str("zlib adler32 checksum mismatch"),
+            Self::GzipHeader => f.write_str("invalid gzip header"),
+            Self::GzipTrailer => f.write_str("gzip trailer mismatch"),
+            Self::Deflate { bit_offset } => {
+                write!(f, "invalid deflate stream at bit {bit_offset}")
+            }
+            Self::InvalidImageData => f.write_str("invalid image data"),
+            Self::PaletteIndexOutOfRange => f.write_str("palette index out of range"),
+            Self::LimitExceeded => f.write_str("limit exceeded"),
+            Self::Unsupported => f.write_str("unsupported"),
+            Self::InvalidArgument => f.write_str("invalid argument"),
+        }
+    }
+}
+
+/// Displays a chunk type as text, or `????` if it is not ASCII.
+struct ChunkName<'a>(&'a [u8; 4]);
+
+impl core::fmt::Display for ChunkName<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(core::str::from_utf8(self.0).unwrap_or("????"))
+    }
+}
+
+/// DEFLATE compression level used when encoding.
+///
+/// Higher levels search harder for repeated byte sequences and produce
+/// smaller files at the cost of encoding time. The chosen level is also
+/// recorded in the `FLEVEL` bits of the zlib header.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionLevel {
+    /// No compression: the data is copied into stored DEFLATE blocks, for
+    /// when encoding latency matters more than size. The PNG encoder also
+    /// skips the filter search of [`FilterStrategy::Adaptive`] and
+    /// [`FilterStrategy::BruteForce`] and writes every scanline unfiltered.
+    Store,
+    /// Short hash chains and fixed Huffman codes, favouring speed over size.
+    /// Data that fixed codes would expand is stored instead.
+    Fast,
+    /// Balanced speed and size: greedy matching over moderate hash chains.
+    /// Each 64 KiB segment is one block, stored or using fixed or dynamic
+    /// Huffman codes, whichever is smallest.
+    #[default]
+    Default,
+    /// Long hash chains with lazy matching, favouring size over speed. Each
+    /// segment is also split into blocks where its statistics change, which
+    /// helps mixed content but makes encoding several times slower than
+    /// [`Default`](Self::Default).
+    Best,
+    /// Optimal parsing in the manner of Zopfli: matches are chosen by the
+    /// bits they cost under the block's Huffman codes, refined over several
+    /// rounds. Much slower than [`Best`](Self::Best), for data that is
+    /// encoded once and served many times.
+    Optimal,
+}
+
+/// How the encoder chooses the per-scanline PNG filter type.
+///
+/// The fixed variants apply the same filter to every scanline (including every
+/// scanline of every Adam7 pass). The remaining variants choose per scanline.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum FilterStrategy {
+    /// Filter type 0: bytes are written unchanged.
+    None,
+    /// Filter type 1: difference from the byte to the left.
+    Sub,
+    /// Filter type 2: difference from the byte above.
+    Up,
+    /// Filter type 3: difference from the average of left and above.
+    Average,
+    /// Filter type 4: difference from the Paeth predictor.
+    Paeth,
+    /// Pick the filter with the minimum sum of absolute differences.
+    #[default]
+    Adaptive,
+    /// Compress every candidate and keep the smallest. Much slower than
+    /// [`Adaptive`](Self::Adaptive), but usually produces smaller output.
+    BruteForce,
+}
+
+/// Options controlling how [`encode_image_with_options`](crate::encode_image_with_options)
+/// writes a PNG stream.
+///
+/// ```
+/// let options = nopng::EncodeOptions {
+///     compression: nopng::CompressionLevel::Fast,
+///     ..Default::default()
+/// };
+/// let spec = nopng::ImageSpec::new(1, 1, nopng::PixelFormat::Gray8);
+/// let png_bytes = nopng::encode_image_with_options(&spec, &[128], &options)?;
+/// # Ok::<(), nopng::Error>(())
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct EncodeOptions {
+    /// DEFLATE compression level for the image data.
+    pub compression: CompressionLevel,
+    /// Scanline filter selection strategy.
+    pub filter: FilterStrategy,
+    /// Ancillary chunks written before the image data.
+    pub metadata: Metadata,
+}
+
+/// Dithering applied by [`reformat_image`](crate::reformat_image) when the
+/// target format cannot represent every source color: when converting to an
+/// indexed format, to a lower bit depth (such as 16-bit to 8-bit samples or
+/// grayscale to 1, 2 or 4 bits), or from color to grayscale at 8 bits or
+/// fewer. Colors the target can represent exactly are never changed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Dithering {
+    /// Map every pixel to the closest representable color.
+    #[default]
+    None,
+    /// Floyd–Steinberg error diffusion, which trades banding for fine noise.
+    FloydSteinberg,
+    /// Atkinson error diffusion. Only part of the error is passed on, so
+    /// highlights and shadows keep more contrast than with Floyd–Steinberg.
+    Atkinson,
+    /// Ordered dithering with a 4×4 Bayer matrix. Produces a regular
+    /// pattern, and each pixel depends only on its own value and position.
+    Bayer,
+}
+
+/// How stored sample values map to light intensity, which
+/// [`reformat_image`](crate::reformat_image) needs to know to mix colors
+/// correctly.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TransferFunction {
+    /// Treat stored values as linear and mix them directly, as
+    /// [`reformat_pixels`](crate::reformat_pixels) does.
+    #[default]
+    Linear,
+    /// The sRGB transfer curve.
+    Srgb,
+    /// A power law given by a `gAMA` value: the image gamma multiplied by
+    /// 100000, such as `45455` for a gamma of 1/2.2. Must be non-zero.
+    Gamma(u32),
+}
+
+impl TransferFunction {
+    /// Picks the transfer function described by the color chunks of an
+    /// image: sRGB if it has an `sRGB` chunk, otherwise the power law of its
+    /// `gAMA` chunk. Images with neither are assumed to be sRGB.
+    ///
+    /// ```
+    /// let metadata = nopng::Metadata {
+    ///     gamma: Some(100000),
+    ///     ..Default::default()
+    /// };
+    /// assert_eq!(
+    ///     nopng::TransferFunction::from_metadata(&metadata),
+    ///     nopng::TransferFunction::Gamma(100000),
+    /// );
+    /// ```
+    pub fn from_metadata(metadata: &Metadata) -> Self {
+        match (metadata.srgb, metadata.gamma) {
+            (None, Some(gamma)) if gamma != 0 => Self::Gamma(gamma),
+            _ => Self::Srgb,
         }
     }
 }
 
+/// Weights of the red, green and blue channels when computing gray levels.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LumaCoefficients {
+    /// ITU-R BT.601: `0.299 R + 0.587 G + 0.114 B`.
+    #[default]
+    Bt601,
+    /// ITU-R BT.709, matching sRGB primaries: `0.2126 R + 0.7152 G +
+    /// 0.0722 B`.
+    Bt709,
+}
+
+/// Options for [`reformat_image`](crate::reformat_image).
+///
+/// The defaults give the same result as
+/// [`reformat_pixels`](crate::reformat_pixels).
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ReformatOptions {
+    /// Dithering used when the conversion loses colors.
+    pub dithering: Dithering,
+    /// Transfer function of the samples. Converting color to gray and
+    /// compositing over [`background`](Self::background) work on linear
+    /// light, and the result is encoded with the same function.
+    pub transfer: TransferFunction,
+    /// Channel weights used when converting color to gray.
+    pub luma: LumaCoefficients,
+    /// 16-bit RGB color to composite translucent pixels over when the
+    /// target format has no alpha channel. `None` drops the alpha channel.
+    pub background: Option<[u16; 3]>,
+}
+
+/// Settings for [`optimize_png`](crate::optimize_png).
+///
+/// Every combination of a candidate pixel format, filter strategy and
+/// compression level is encoded, so the work grows with the length of both
+/// lists.
+///
+/// ```
+/// let options = nopng::OptimizeOptions {
+///     filters: vec![nopng::FilterStrategy::BruteForce],
+///     ..Default::default()
+/// };
+/// # let png_bytes = nopng::encode_image(
+/// #     &nopng::ImageSpec::new(1, 1, nopng::PixelFormat::Rgba8),
+/// #     &[1, 2, 3, 255],
+/// # )?;
+/// let smaller = nopng::optimize_png(&png_bytes, &options)?;
+/// assert!(smaller.len() <= png_bytes.len());
+/// # Ok::<(), nopng::Error>(())
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OptimizeOptions {
+    /// Filter strategies to try.
+    pub filters: Vec<FilterStrategy>,
+    /// Compression levels to try.
+    pub compression_levels: Vec<CompressionLevel>,
+    /// Drop text, Exif and modification time chunks. Chunks that affect how
+    /// the pixels are displayed, such as `gAMA` or `iCCP`, are always kept.
+    pub strip_metadata: bool,
+}
+
+impl Default for OptimizeOptions {
+    fn default() -> Self {
+        Self {
+            filters: vec![FilterStrategy::None, FilterStrategy::Adaptive],
+            compression_levels: vec![CompressionLevel::Best],
+            strip_metadata: false,
+        }
+    }
+}
+
+/// Animation settings for [`encode_animation`](crate::encode_animation).
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct AnimationOptions {
+    /// Number of times to play the animation, or `0` to loop forever.
+    pub num_plays: u32,
+    /// Shrink every frame after the first to the bounding box of the pixels
+    /// that it changes on the canvas, which usually makes the file much
+    /// smaller. Frames disposed of with [`DisposeOp::Background`] are kept
+    /// as they are, since cropping them would change what gets cleared.
+    pub crop_to_changes: bool,
+}
+
+/// Ancillary information stored alongside the pixels of a PNG.
+///
+/// Decoding fills in every chunk that is present, and encoding writes every
+/// field that is set. Values are kept as stored in the file; in particular,
+/// nopng does not apply gamma or color-space conversions to the pixels.
+///
+/// ```
+/// let options = nopng::EncodeOptions {
+///     metadata: nopng::Metadata {
+///         srgb: Some(nopng::RenderingIntent::Perceptual),
+///         gamma: Some(45455),
+///         ..Default::default()
+///     },
+///     ..Default::default()
+/// };
+/// let spec = nopng::ImageSpec::new(1, 1, nopng::PixelFormat::Gray8);
+/// let png_bytes = nopng::encode_image_with_options(&spec, &[128], &options)?;
+///
+/// let image = nopng::decode_image_with_options(&png_bytes, &Default::default())?;
+/// assert_eq!(image.metadata, options.metadata);
+/// # Ok::<(), nopng::Error>(())
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    /// Image gamma from the `gAMA` chunk, multiplied by 100000 (`45455` for
+    /// a gamma of 1/2.2). Must be non-zero.
+    pub gamma: Option<u32>,
+    /// Primary chromaticities and white point from the `cHRM` chunk.
+    pub chromaticities: Option<Chromaticities>,
+    /// Rendering intent from the `sRGB` chunk, present if the image is in the
+    /// sRGB color space.
+    pub srgb: Option<RenderingIntent>,
+    /// Embedded ICC profile from the `iCCP` chunk.
+    pub icc_profile: Option<IccProfile>,
+    /// Coding-independent code points from the `cICP` chunk.
+    pub cicp: Option<Cicp>,
+    /// Textual information from `tEXt`, `zTXt` and `iTXt` chunks, in file
+    /// order.
+    pub text: Vec<TextEntry>,
+    /// Pixel size or aspect ratio from the `pHYs` chunk.
+    pub physical_dimensions: Option<PhysicalDimensions>,
+    /// Image position on a page from the `oFFs` chunk.
+    pub offset: Option<ImageOffset>,
+    /// Time of the last image modification from the `tIME` chunk.
+    pub modification_time: Option<Timestamp>,
+    /// Number of significant bits per channel from the `sBIT` chunk.
+    pub significant_bits: Option<SignificantBits>,
+    /// Default background color from the `bKGD` chunk.
+    pub background: Option<Background>,
+    /// Usage frequency of each palette entry from the `hIST` chunk. Only
+    /// valid for indexed images, with one entry per palette entry.
+    pub histogram: Option<Vec<u16>>,
+    /// Suggested palettes from `sPLT` chunks, in file order.
+    pub suggested_palettes: Vec<SuggestedPalette>,
+    /// Raw Exif data from the `eXIf` chunk.
+    pub exif: Option<Vec<u8>>,
+}
+
+/// CIE 1931 `(x, y)` chromaticities of the display primaries and white
+/// point, each multiplied by 100000.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Chromaticities {
+    /// White point.
+    pub white: [u32; 2],
+    /// Red primary.
+    pub red: [u32; 2],
+    /// Green primary.
+    pub green: [u32; 2],
+    /// Blue primary.
+    pub blue: [u32; 2],
+}
+
+/// ICC rendering intent stored in the `sRGB` chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenderingIntent {
+    /// For images preferring good adaptation to the output device gamut.
+    Perceptual,
+    /// For images requiring colour appearance matching.
+    RelativeColorimetric,
+    /// For images preferring preservation of saturation.
+    Saturation,
+    /// For images requiring preservation of absolute colorimetry.
+    AbsoluteColorimetric,
+}
+
+/// An embedded ICC profile.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct IccProfile {
+    /// Profile name: 1 to 79 printable Latin-1 characters without leading,
+    /// trailing or consecutive spaces.
+    pub name: String,
+    /// The uncompressed profile.
+    pub profile: Vec<u8>,
+}
+
+/// A keyword and text pair, such as `Author` or `Software`.
+///
+/// Which chunk holds the text depends on the other fields: `tEXt` if the text
+/// is neither compressed nor international, `zTXt` if it is only compressed,
+/// and `iTXt` if it is international.
+///
+/// ```
+/// let text = nopng::TextEntry {
+///     keyword: "Software".into(),
+///     text: "nopng".into(),
+///     ..Default::default()
+/// };
+/// let options = nopng::EncodeOptions {
+///     metadata: nopng::Metadata {
+///         text: vec![text],
+///         ..Default::default()
+///     },
+///     ..Default::default()
+/// };
+/// let spec = nopng::ImageSpec::new(1, 1, nopng::PixelFormat::Gray8);
+/// let png_bytes = nopng::encode_image_with_options(&spec, &[128], &options)?;
+///
+/// let image = nopng::decode_image_with_options(&png_bytes, &Default::default())?;
+/// assert_eq!(image.metadata.text[0].text, "nopng");
+/// # Ok::<(), nopng::Error>(())
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TextEntry {
+    /// Keyword: 1 to 79 printable Latin-1 characters without leading,
+    /// trailing or consecutive spaces.
+    pub keyword: String,
+    /// The text. Unless `international` is set, it must only contain Latin-1
+    /// characters.
+    pub text: String,
+    /// `true` if the text is stored zlib-compressed.
+    pub compressed: bool,
+    /// Language and translated keyword of UTF-8 text stored in an `iTXt`
+    /// chunk, or `None` for Latin-1 text.
+    pub international: Option<InternationalText>,
+}
+
+/// Language information of an `iTXt` chunk.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct InternationalText {
+    /// RFC 3066 language tag such as `en-GB`, or empty if unknown.
+    pub language_tag: String,
+    /// The keyword translated into the language, or empty.
+    pub translated_keyword: String,
+}
+
+/// Intended pixel size or aspect ratio, stored in the `pHYs` chunk.
+///
+/// ```
+/// let dimensions = nopng::PhysicalDimensions::from_dpi(300, 300);
+/// assert_eq!(dimensions.x, 11811); // pixels per meter
+/// assert_eq!(dimensions.dpi(), Some([300, 300]));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PhysicalDimensions {
+    /// Pixels per unit along the x axis.
+    pub x: u32,
+    /// Pixels per unit along the y axis.
+    pub y: u32,
+    /// Unit of `x` and `y`.
+    pub unit: PhysicalUnit,
+}
+
+/// Unit of [`PhysicalDimensions`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PhysicalUnit {
+    /// Only the aspect ratio `x:y` is known.
+    Unknown,
+    /// Pixels per meter.
+    Meter,
+}
+
+impl PhysicalDimensions {
+    /// Dimensions for the given resolution in dots (pixels) per inch.
+    pub fn from_dpi(x_dpi: u32, y_dpi: u32) -> Self {
+        let per_meter = |dpi: u32| ((u64::from(dpi) * 10_000 + 127) / 254) as u32;
+        Self {
+            x: per_meter(x_dpi),
+            y: per_meter(y_dpi),
+            unit: PhysicalUnit::Meter,
+        }
+    }
+
+    /// Resolution in dots (pixels) per inch, rounded to the nearest integer,
+    /// or `None` if the unit is unknown.
+    pub fn dpi(&self) -> Option<[u32; 2]> {
+        let per_inch = |per_meter: u32| ((u64::from(per_meter) * 254 + 5_000) / 10_000) as u32;
+        match self.unit {
+            PhysicalUnit::Unknown => None,
+            PhysicalUnit::Meter => Some([per_inch(self.x), per_inch(self.y)]),
+        }
+    }
+}
+
+/// Position of the image on a page, stored in the `oFFs` chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageOffset {
+    /// Distance of the left edge from the left of the page.
+    pub x: i32,
+    /// Distance of the top edge from the top of the page.
+    pub y: i32,
+    /// Unit of `x` and `y`.
+    pub unit: OffsetUnit,
+}
+
+/// Unit of [`ImageOffset`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OffsetUnit {
+    /// Pixels.
+    Pixel,
+    /// Micrometers.
+    Micrometer,
+}
+
+/// A UTC time stored in the `tIME` chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timestamp {
+    /// Full year, such as `2024`.
+    pub year: u16,
+    /// Month (`1..=12`).
+    pub month: u8,
+    /// Day of the month (`1..=31`).
+    pub day: u8,
+    /// Hour (`0..=23`).
+    pub hour: u8,
+    /// Minute (`0..=59`).
+    pub minute: u8,
+    /// Second (`0..=60`, allowing for leap seconds).
+    pub second: u8,
+}
+
+impl Timestamp {
+    pub(crate) fn is_valid(&self) -> bool {
+        (1..=12).contains(&self.month)
+            && (1..=31).contains(&self.day)
+            && self.hour <= 23
+            && self.minute <= 59
+            && self.second <= 60
+    }
+}
+
+/// Number of significant bits in each channel of the original image data,
+/// stored in the `sBIT` chunk.
+///
+/// The variant must match the pixel format of the image, and each value must
+/// be between 1 and the sample depth (8 for indexed images). Decoding reports
+/// it for the decoded format: a grayscale or truecolor image with `tRNS`
+/// gains an alpha entry with every bit significant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignificantBits {
+    /// Grayscale images.
+    Gray(u8),
+    /// Grayscale images with alpha: `[gray, alpha]`.
+    GrayAlpha([u8; 2]),
+    /// Truecolor and indexed images: `[red, green, blue]`.
+    Rgb([u8; 3]),
+    /// Truecolor images with alpha: `[red, green, blue, alpha]`.
+    Rgba([u8; 4]),
+}
+
+/// Default background color, stored in the `bKGD` chunk.
+///
+/// The variant must match the pixel format of the image. Samples use the
+/// format's bit depth, so decoding scales the gray level of a low-bit
+/// grayscale image with `tRNS` to the 8 bits it decodes to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Background {
+    /// Gray level, for grayscale images with or without alpha.
+    Gray(u16),
+    /// `[red, green, blue]`, for truecolor images with or without alpha.
+    Rgb([u16; 3]),
+    /// Palette index, for indexed images.
+    PaletteIndex(u8),
+}
+
+/// A suggested reduced palette, stored in an `sPLT` chunk.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SuggestedPalette {
+    /// Palette name, following the same rules as [`TextEntry::keyword`].
+    pub name: String,
+    /// Sample depth of the entries: 8 or 16.
+    pub sample_depth: u8,
+    /// Palette entries. With a sample depth of 8, every sample must fit in a
+    /// byte.
+    pub entries: Vec<SuggestedPaletteEntry>,
+}
+
+/// An entry of a [`SuggestedPalette`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SuggestedPaletteEntry {
+    /// Red sample.
+    pub red: u16,
+    /// Green sample.
+    pub green: u16,
+    /// Blue sample.
+    pub blue: u16,
+    /// Alpha sample (0 is fully transparent).
+    pub alpha: u16,
+    /// Relative usage frequency of the entry.
+    pub frequency: u16,
+}
+
+/// Animation parameters stored in the APNG `acTL` chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AnimationControl {
+    /// Number of frames in the animation.
+    pub num_frames: u32,
+    /// Number of times to play the animation, or `0` to loop forever.
+    pub num_plays: u32,
+}
+
+/// Placement and timing of one animation frame, stored in the APNG `fcTL`
+/// chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameControl {
+    /// Frame width in pixels.
+    pub width: u32,
+    /// Frame height in pixels.
+    pub height: u32,
+    /// Distance of the frame from the left edge of the canvas.
+    pub x_offset: u32,
+    /// Distance of the frame from the top edge of the canvas.
+    pub y_offset: u32,
+    /// Numerator of the frame delay in seconds.
+    pub delay_num: u16,
+    /// Denominator of the frame delay in seconds; `0` means 100.
+    pub delay_den: u16,
+    /// How the frame's region is disposed of before the next frame.
+    pub dispose_op: DisposeOp,
+    /// How the frame is drawn over the canvas.
+    pub blend_op: BlendOp,
+}
+
+impl FrameControl {
+    /// Frame delay rounded to whole milliseconds.
+    ///
+    /// ```
+    /// let control = nopng::FrameControl {
+    ///     delay_num: 1,
+    ///     delay_den: 30,
+    ///     ..nopng::FrameControl::full_canvas(16, 16)
+    /// };
+    /// assert_eq!(control.delay_millis(), 33);
+    /// ```
+    pub fn delay_millis(&self) -> u32 {
+        let den = if self.delay_den == 0 {
+            100
+        } else {
+            u32::from(self.delay_den)
+        };
+        (u32::from(self.delay_num) * 1000 + den / 2) / den
+    }
+
+    /// A frame covering a `width` x `height` canvas with no delay, replacing
+    /// the canvas contents and leaving them in place afterwards.
+    pub const fn full_canvas(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            x_offset: 0,
+            y_offset: 0,
+            delay_num: 0,
+            delay_den: 0,
+            dispose_op: DisposeOp::None,
+            blend_op: BlendOp::Source,
+        }
+    }
+}
+
+/// What happens to a frame's region of the canvas before the next frame is
+/// drawn.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DisposeOp {
+    /// The canvas is left as it is.
+    #[default]
+    None,
+    /// The region is cleared to fully transparent black.
+    Background,
+    /// The region is restored to what it was before the frame was drawn.
+    Previous,
+}
+
+/// How a frame is drawn over its region of the canvas.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BlendOp {
+    /// The frame replaces the region, alpha included.
+    #[default]
+    Source,
+    /// The frame is alpha-composited over the region.
+    Over,
+}
+
+/// Coding-independent code points (ITU-T H.273) describing the color space
+/// of the image, as used for HDR and wide-gamut content.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Cicp {
+    /// Colour primaries (`1` for BT.709, `9` for BT.2020, `12` for Display P3).
+    pub color_primaries: u8,
+    /// Transfer characteristics (`13` for sRGB, `16` for PQ, `18` for HLG).
+    pub transfer_function: u8,
+    /// Matrix coefficients. PNG only supports RGB, so this must be `0`.
+    pub matrix_coefficients: u8,
+    /// `true` for full-range samples, `false` for narrow (video) range.
+    pub full_range: bool,
+}
+
+/// Resource limits applied while decoding untrusted PNG data.
+///
+/// Decoding fails with [`ErrorKind::LimitExceeded`] as soon as any limit is
+/// exceeded, before the corresponding memory is allocated. The default
+/// imposes no limits.
+///
+/// ```
+/// let limits = nopng::DecodeLimits {
+///     max_pixels: 4096 * 4096,
+///     max_ancillary_bytes: 1 << 20,
+///     ..Default::default()
+/// };
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeLimits {
+    /// Maximum image width in pixels.
+    pub max_width: u32,
+    /// Maximum image height in pixels.
+    pub max_height: u32,
+    /// Maximum number of pixels (`width * height`).
+    pub max_pixels: u64,
+    /// Maximum number of bytes the compressed image data may inflate to.
+    pub max_decompressed_bytes: usize,
+    /// Maximum number of chunks, including `IHDR` and `IEND`.
+    pub max_chunks: usize,
+    /// Maximum combined data length of all ancillary chunks, plus the
+    /// decompressed size of compressed text and ICC profiles.
+    pub max_ancillary_bytes: usize,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        Self {
+            max_width: u32::MAX,
+            max_height: u32::MAX,
+            max_pixels: u64::MAX,
+            max_decompressed_bytes: usize::MAX,
+            max_chunks: usize::MAX,
+            max_ancillary_bytes: usize::MAX,
+        }
+    }
+}
+
+/// Options controlling how [`decode_image_with_options`](crate::decode_image_with_options)
+/// reads a PNG stream.
+///
+/// ```
+/// # let png_bytes = nopng::encode_image(
+/// #     &nopng::ImageSpec::new(1, 1, nopng::PixelFormat::Gray8),
+/// #     &[128],
+/// # )?;
+/// let options = nopng::DecodeOptions {
+///     limits: nopng::DecodeLimits {
+///         max_width: 8192,
+///         max_height: 8192,
+///         ..Default::default()
+///     },
+///     lenient: true,
+/// };
+/// let image = nopng::decode_image_with_options(&png_bytes, &options)?;
+/// assert!(image.warnings.is_empty());
+/// # Ok::<(), nopng::Error>(())
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DecodeOptions {
+    /// Resource limits for the decoded image.
+    pub limits: DecodeLimits,
+    /// Recover from common corruption instead of failing.
+    ///
+    /// Ancillary chunks that are corrupt, malformed or misplaced are dropped,
+    /// a missing `IEND` chunk and an Adler-32 mismatch are ignored, and if the
+    /// image data is truncated or corrupt the rows decoded so far are returned
+    /// with the rest left zeroed. Every problem worked around is reported in
+    /// [`DecodedImage::warnings`](crate::DecodedImage::warnings). Limit
+    /// violations are still errors.
+    pub lenient: bool,
+}
+
 /// Describes the pixel layout of image data in a flat `&[u8]` buffer.
 ///
 /// Each variant fully specifies the color model, bit depth, and byte layout of
@@ -127,6 +944,19 @@ pub enum PixelFormat {
     /// 16-bit RGBA, big-endian (8 bytes per pixel:
     /// `[r_hi, r_lo, g_hi, g_lo, b_hi, b_lo, a_hi, a_lo]`).
     Rgba16Be,
+    /// 8-bit RGBA with premultiplied alpha (4 bytes per pixel: `[r, g, b, a]`,
+    /// where each color sample is already scaled by `a / 255`).
+    ///
+    /// PNG stores straight alpha, so encoding un-premultiplies the samples
+    /// (rounding to the nearest value) and decoding premultiplies them. Color
+    /// is lost where alpha is 0. Encoding rejects a color sample greater than
+    /// alpha with [`ErrorKind::InvalidArgument`], while
+    /// [`reformat_pixels`](crate::reformat_pixels) clamps it to alpha.
+    Rgba8Premultiplied,
+    /// 16-bit RGBA with premultiplied alpha, big-endian (8 bytes per pixel,
+    /// laid out like [`Rgba16Be`](Self::Rgba16Be)). See
+    /// [`Rgba8Premultiplied`](Self::Rgba8Premultiplied).
+    Rgba16BePremultiplied,
     /// 1-bit indexed color (1 byte per index, unpacked). Valid index values: 0–1.
     Indexed1 {
         /// Flat RGB triplets (`[r, g, b, r, g, b, ...]`). Length must be a
@@ -172,8 +1002,27 @@ impl PixelFormat {
             Self::Gray1 | Self::Indexed1 { .. } => 1,
             Self::Gray2 | Self::Indexed2 { .. } => 2,
             Self::Gray4 | Self::Indexed4 { .. } => 4,
-            Self::Gray8 | Self::GrayAlpha8 | Self::Rgb8 | Self::Rgba8 | Self::Indexed8 { .. } => 8,
-            Self::Gray16Be | Self::GrayAlpha16Be | Self::Rgb16Be | Self::Rgba16Be => 16,
+            Self::Gray8
+            | Self::GrayAlpha8
+            | Self::Rgb8
+            | Self::Rgba8
+            | Self::Rgba8Premultiplied
+            | Self::Indexed8 { .. } => 8,
+            Self::Gray16Be
+            | Self::GrayAlpha16Be
+            | Self::Rgb16Be
+            | Self::Rgba16Be
+            | Self::Rgba16BePremultiplied => 16,
+        }
+    }
+
+    /// Straight-alpha counterpart of a premultiplied format, which is how
+    /// its pixels are stored in a PNG.
+    pub(crate) fn straight_alpha(&self) -> Option<Self> {
+        match self {
+            Self::Rgba8Premultiplied => Some(Self::Rgba8),
+            Self::Rgba16BePremultiplied => Some(Self::Rgba16Be),
+            _ => None,
         }
     }
 
@@ -190,9 +1039,9 @@ impl PixelFormat {
             | Self::Indexed8 { .. } => 1,
             Self::Gray16Be | Self::GrayAlpha8 => 2,
             Self::Rgb8 => 3,
-            Self::GrayAlpha16Be | Self::Rgba8 => 4,
+            Self::GrayAlpha16Be | Self::Rgba8 | Self::Rgba8Premultiplied => 4,
             Self::Rgb16Be => 6,
-            Self::Rgba16Be => 8,
+            Self::Rgba16Be | Self::Rgba16BePremultiplied => 8,
         }
     }
 
@@ -1,10 +1,280 @@
-#[expect(clippy::unusual_byte_groupings)]
-pub const ZLIB_HEADER: [u8; 2] = [
-    // [CINFO=0111] 32k window size
-    // [CM=1000] DEFLATE algorithm
-    0b0111_1000,
-    // [FLEVEL=10] Default compression level
+use alloc::vec::Vec;
+
+use crate::adler32::{self, Adler32};
+use crate::deflate::{self, Compressor, Inflater};
+use crate::png_types::{CompressionLevel, Error, ErrorKind, Result};
+
+// [CINFO=0111] 32k window size
+// [CM=1000] DEFLATE algorithm
+const CMF: u8 = 0b0111_1000;
+
+/// Returns the two-byte zlib header for a stream compressed at `level`.
+pub fn header(level: CompressionLevel) -> [u8; 2] {
+    // [FLEVEL] compression level hint (0 = fastest .. 3 = maximum)
     // [FDICT=0] no dictionary
-    // [FCHECK=11100] check bits
-    0b10_0_11100,
-];
+    let flevel: u8 = match level {
+        CompressionLevel::Store => 0,
+        CompressionLevel::Fast => 1,
+        CompressionLevel::Default => 2,
+        CompressionLevel::Best | CompressionLevel::Optimal => 3,
+    };
+    let flg = flevel << 6;
+    // [FCHECK] makes CMF*256 + FLG a multiple of 31
+    let fcheck = (31 - (u16::from(CMF) << 8 | u16::from(flg)) % 31) % 31;
+    [CMF, flg | fcheck as u8]
+}
+
+/// Compresses `data` into a complete zlib stream (header, DEFLATE data, Adler-32).
+pub fn compress(data: &[u8], level: CompressionLevel) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    out.extend_from_slice(&header(level));
+    deflate::compress_to(data, level, &mut out)?;
+    out.extend_from_slice(&adler32::calculate(data).to_be_bytes());
+    Ok(out)
+}
+
+/// Incremental zlib encoder built on [`Compressor`].
+#[derive(Debug)]
+pub struct Encoder {
+    compressor: Compressor,
+    checksum: Adler32,
+}
+
+impl Encoder {
+    /// Creates an encoder and appends the zlib header to `out`.
+    pub fn new(level: CompressionLevel, out: &mut Vec<u8>) -> Self {
+        out.extend_from_slice(&header(level));
+        Self {
+            compressor: Compressor::new(level),
+            checksum: Adler32::new(),
+        }
+    }
+
+    /// Feeds `data` into the encoder, appending any completed output bytes to `out`.
+    pub fn write(&mut self, data: &[u8], out: &mut Vec<u8>) -> Result<()> {
+        self.checksum.update(data);
+        self.compressor.write(data, out)
+    }
+
+    /// Finishes the DEFLATE stream and appends the Adler-32 trailer to `out`.
+    pub fn finish(self, out: &mut Vec<u8>) -> Result<()> {
+        self.compressor.finish(out)?;
+        out.extend_from_slice(&self.checksum.finish().to_be_bytes());
+        Ok(())
+    }
+}
+
+/// Incremental zlib decoder built on [`Inflater`].
+///
+/// The header, DEFLATE data and trailer may be split across any number of
+/// [`write`](Self::write) calls.
+#[derive(Debug)]
+pub struct Decoder {
+    header: Vec<u8>,
+    inflater: Inflater,
+    checksum: Adler32,
+    trailer: Vec<u8>,
+    finished: bool,
+}
+
+impl Decoder {
+    /// Creates a decoder without an output limit.
+    pub fn new() -> Self {
+        Self::with_output_limit(usize::MAX)
+    }
+
+    /// Creates a decoder that fails with [`ErrorKind::LimitExceeded`] once the
+    /// stream inflates to more than `output_limit` bytes.
+    pub fn with_output_limit(output_limit: usize) -> Self {
+        Self {
+            header: Vec::with_capacity(2),
+            inflater: Inflater::with_output_limit(output_limit),
+            checksum: Adler32::new(),
+            trailer: Vec::with_capacity(4),
+            finished: false,
+        }
+    }
+
+    /// Returns `true` once the Adler-32 trailer has been read and verified.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Feeds `input` into the decoder, appending the decompressed bytes to `out`.
+    ///
+    /// Bytes following the trailer are ignored.
+    pub fn write(&mut self, input: &[u8], out: &mut Vec<u8>) -> Result<()> {
+        let consumed = self.read_header(input)?;
+        let input = &input[consumed..];
+        if self.header.len() < 2 {
+            return Ok(());
+        }
+
+        if !self.inflater.is_finished() {
+            let start = out.len();
+            self.inflater.write(input, out)?;
+            self.checksum.update(&out[start..]);
+            if self.inflater.is_finished() {
+                let trailing = self.inflater.remaining_input().to_vec();
+                self.read_trailer(&trailing)?;
+            }
+        } else {
+            self.read_trailer(input)?;
+        }
+        Ok(())
+    }
+
+    /// Decodes as much of `input` as fits into `output`, returning the number
+    /// of input bytes consumed and output bytes written.
+    ///
+    /// See [`Inflater::inflate`] for when decoding stops. Bytes following the
+    /// trailer are not consumed.
+    #[cfg(feature = "compression")]
+    pub fn inflate(&mut self, input: &[u8], output: &mut [u8]) -> Result<(usize, usize)> {
+        let mut consumed = self.read_header(input)?;
+        let mut written = 0;
+        if self.header.len() < 2 {
+            return Ok((consumed, written));
+        }
+
+        if !self.inflater.is_finished() {
+            let (used, produced) = self.inflater.inflate(&input[consumed..], output)?;
+            consumed += used;
+            written = produced;
+            self.checksum.update(&output[..written]);
+            if !self.inflater.is_finished() {
+                return Ok((consumed, written));
+            }
+            let trailing = self.inflater.remaining_input().to_vec();
+            self.read_trailer(&trailing)?;
+        }
+        consumed += self.read_trailer(&input[consumed..])?;
+        Ok((consumed, written))
+    }
+
+    /// Reads the two header bytes from the start of `input`, returning how
+    /// many were taken.
+    fn read_header(&mut self, input: &[u8]) -> Result<usize> {
+        if self.header.len() == 2 {
+            return Ok(0);
+        }
+        let take = (2 - self.header.len()).min(input.len());
+        self.header.extend_from_slice(&input[..take]);
+        if self.header.len() == 2 {
+            check_header(self.header[0], self.header[1])?;
+        }
+        Ok(take)
+    }
+
+    /// Reads trailer bytes from the start of `input`, returning how many were
+    /// taken.
+    fn read_trailer(&mut self, input: &[u8]) -> Result<usize> {
+        if self.finished {
+            return Ok(0);
+        }
+        let take = (4 - self.trailer.len()).min(input.len());
+        self.trailer.extend_from_slice(&input[..take]);
+        if self.trailer.len() == 4 {
+            let expected_adler = u32::from_be_bytes(
+                self.trailer[..]
+                    .try_into()
+                    .expect("bug: zlib trailer must be 4 bytes"),
+            );
+            if self.checksum.finish() != expected_adler {
+                return Err(ErrorKind::Adler32Mismatch.into());
+            }
+            self.finished = true;
+        }
+        Ok(take)
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn check_header(cmf: u8, flg: u8) -> Result<()> {
+    let header = u16::from(cmf) << 8 | u16::from(flg);
+    let message = if header % 31 != 0 {
+        "header check bits are invalid"
+    } else if cmf & 0x0F != 8 {
+        "unsupported compression method"
+    } else if cmf >> 4 > 7 {
+        "window size is too large"
+    } else if (flg & 0x20) != 0 {
+        "preset dictionary is not supported"
+    } else {
+        return Ok(());
+    };
+    Err(Error::new(ErrorKind::ZlibHeader, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::{Decoder, compress, header};
+    use crate::png_types::{CompressionLevel, ErrorKind, Result};
+
+    fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+        let mut decoder = Decoder::new();
+        let mut decoded = Vec::new();
+        decoder.write(data, &mut decoded)?;
+        assert!(decoder.is_finished());
+        Ok(decoded)
+    }
+
+    #[test]
+    fn header_check_bits_are_valid_for_every_level() {
+        for (level, flevel) in [
+            (CompressionLevel::Store, 0),
+            (CompressionLevel::Fast, 1),
+            (CompressionLevel::Default, 2),
+            (CompressionLevel::Best, 3),
+            (CompressionLevel::Optimal, 3),
+        ] {
+            let [cmf, flg] = header(level);
+            assert_eq!((u16::from(cmf) << 8 | u16::from(flg)) % 31, 0);
+            assert_eq!(flg >> 6, flevel);
+            assert_eq!(flg & 0x20, 0);
+        }
+        assert_eq!(header(CompressionLevel::Default), [0x78, 0x9C]);
+    }
+
+    #[test]
+    fn decoder_accepts_input_split_at_any_byte() {
+        let input = b"zlib streams may be split anywhere ".repeat(50);
+        let encoded = compress(&input, CompressionLevel::Default).expect("infallible");
+        assert_eq!(decompress(&encoded).expect("infallible"), input);
+        for piece_len in [1, 3, 64] {
+            let mut decoder = Decoder::new();
+            let mut decoded = Vec::new();
+            for piece in encoded.chunks(piece_len) {
+                assert!(!decoder.is_finished());
+                decoder.write(piece, &mut decoded).expect("infallible");
+            }
+            assert!(decoder.is_finished());
+            assert_eq!(decoded, input);
+        }
+    }
+
+    #[test]
+    fn decoder_rejects_checksum_mismatch() {
+        let mut encoded = compress(b"checksum", CompressionLevel::Fast).expect("infallible");
+        *encoded.last_mut().expect("infallible") ^= 1;
+        let error = decompress(&encoded).expect_err("infallible");
+        assert_eq!(error.kind(), ErrorKind::Adler32Mismatch);
+        let mut decoded = Vec::new();
+        assert!(Decoder::new().write(&encoded, &mut decoded).is_err());
+    }
+
+    #[test]
+    fn decoder_rejects_invalid_header() {
+        for header in [[0x78, 0x9D], [0x79, 0x18], [0x88, 0x1C], [0x78, 0x20]] {
+            let error = decompress(&header).expect_err("infallible");
+            assert_eq!(error.kind(), ErrorKind::ZlibHeader, "{header:?}");
+        }
+    }
+}
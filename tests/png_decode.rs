@@ -1,5 +1,14 @@
 use nopng::{
-    Error, ImageSpec, PixelFormat, decode_image, encode_image, inspect_image, reformat_pixels,
+    AnimationControl, AnimationDecoder, AnimationFrame, AnimationOptions, Background, BlendOp,
+    Chromaticities, ChunkReader, ChunkWriter, Cicp, CompressionLevel, DecodeLimits, DecodeOptions,
+    DisposeOp, Dithering, EncodeOptions, Error, ErrorKind, FrameCompositor, FrameControl,
+    IccProfile, ImageOffset, ImageSpec, InternationalText, LumaCoefficients, Metadata, OffsetUnit,
+    OptimizeOptions, PhysicalDimensions, PixelFormat, ReformatOptions, RenderingIntent,
+    SignificantBits, StreamDecoder, StreamEncoder, SuggestedPalette, SuggestedPaletteEntry,
+    TextEntry, Timestamp, TransferFunction, decode_image, decode_image_as,
+    decode_image_as_with_options, decode_image_into_with_options, decode_image_with_options,
+    encode_animation, encode_image, encode_image_with_options, inspect_image, optimize_png,
+    reformat_image, reformat_pixels,
 };
 
 fn rgba8(bytes: &[u8]) -> Vec<u8> {
@@ -173,7 +182,13 @@ fn rejects_crc_mismatch() {
     let index = bytes.len() - 1;
     bytes[index] ^= 0x01;
     let error = decode_image(&bytes).expect_err("infallible");
-    assert!(matches!(error, Error::InvalidData(message) if message.contains("CRC mismatch")));
+    assert_eq!(
+        error.kind(),
+        ErrorKind::CrcMismatch {
+            chunk_type: *b"IEND"
+        }
+    );
+    assert_eq!(error.to_string(), "CRC mismatch for chunk IEND");
 }
 
 #[test]
@@ -182,7 +197,1930 @@ fn rejects_missing_plte_for_palette_image() {
     remove_chunk(&mut bytes, b"PLTE");
     remove_chunk(&mut bytes, b"tRNS");
     let error = decode_image(&bytes).expect_err("infallible");
-    assert!(matches!(error, Error::InvalidData(message) if message.contains("missing PLTE")));
+    assert_eq!(
+        error.kind(),
+        ErrorKind::ChunkOrdering {
+            chunk_type: *b"PLTE"
+        }
+    );
+    assert!(error.message().contains("missing PLTE"));
+}
+
+#[test]
+fn stream_decoder_matches_decode_image_for_fixtures() {
+    let fixtures: [&[u8]; 6] = [
+        include_bytes!("data/gray_1bit_filters.png"),
+        include_bytes!("data/gray16_trns.png"),
+        include_bytes!("data/palette_8bit_split_idat.png"),
+        include_bytes!("data/palette_interlaced.png"),
+        include_bytes!("data/rgba_interlaced.png"),
+        include_bytes!("data/rgba_paeth_split_idat.png"),
+    ];
+    for bytes in fixtures {
+        let (spec, pixels) = decode_image(bytes).expect("infallible");
+        let bpp = spec.pixel_format.data_len(1, 1);
+        let mut streamed = vec![0; pixels.len()];
+        let mut decoder = StreamDecoder::new();
+        for byte in bytes {
+            decoder.write(std::slice::from_ref(byte));
+            while let Some(row) = decoder.next_row().expect("infallible") {
+                for (i, pixel) in row.pixels.chunks(bpp).enumerate() {
+                    let x = (row.x_start + i as u32 * row.x_step) as usize;
+                    let offset = (row.y as usize * spec.width as usize + x) * bpp;
+                    streamed[offset..offset + bpp].copy_from_slice(pixel);
+                }
+            }
+        }
+        assert_eq!(decoder.spec(), Some(&spec));
+        decoder.finish().expect("infallible");
+        assert_eq!(streamed, pixels);
+    }
+}
+
+#[test]
+fn stream_decoder_rejects_crc_mismatch() {
+    let mut bytes = include_bytes!("data/gray_filters.png").to_vec();
+    let index = bytes.len() - 1;
+    bytes[index] ^= 0x01;
+    let mut decoder = StreamDecoder::new();
+    decoder.write(&bytes);
+    let error = loop {
+        match decoder.next_row() {
+            Ok(Some(_)) => {}
+            Ok(None) => panic!("corrupt stream must not decode"),
+            Err(error) => break error,
+        }
+    };
+    assert_eq!(
+        error.kind(),
+        ErrorKind::CrcMismatch {
+            chunk_type: *b"IEND"
+        }
+    );
+    assert_eq!(error.to_string(), "CRC mismatch for chunk IEND");
+}
+
+fn lenient() -> DecodeOptions {
+    DecodeOptions {
+        lenient: true,
+        ..Default::default()
+    }
+}
+
+fn warning_kinds(warnings: &[Error]) -> Vec<ErrorKind> {
+    warnings.iter().map(|warning| warning.kind()).collect()
+}
+
+#[test]
+fn lenient_decode_drops_ancillary_chunk_with_bad_crc() {
+    let mut bytes = include_bytes!("data/palette_4bit_trns.png").to_vec();
+    let trns = bytes
+        .windows(4)
+        .position(|window| window == b"tRNS")
+        .expect("infallible");
+    let length = u32::from_be_bytes(bytes[trns - 4..trns].try_into().expect("infallible"));
+    bytes[trns + 4 + length as usize] ^= 0x01;
+
+    let error = decode_image(&bytes).expect_err("infallible");
+    let trns_crc = ErrorKind::CrcMismatch {
+        chunk_type: *b"tRNS",
+    };
+    assert_eq!(error.kind(), trns_crc);
+
+    let image = decode_image_with_options(&bytes, &lenient()).expect("infallible");
+    let kinds = warning_kinds(&image.warnings);
+    assert_eq!(kinds, [trns_crc]);
+    assert!(matches!(
+        image.spec.pixel_format,
+        PixelFormat::Indexed4 { trns: None, .. }
+    ));
+}
+
+#[test]
+fn lenient_decode_tolerates_missing_iend() {
+    let original = include_bytes!("data/rgb_sub_up.png");
+    let mut bytes = original.to_vec();
+    remove_chunk(&mut bytes, b"IEND");
+    let missing_iend = ErrorKind::ChunkOrdering {
+        chunk_type: *b"IEND",
+    };
+    assert_eq!(
+        decode_image(&bytes).expect_err("infallible").kind(),
+        missing_iend
+    );
+
+    let image = decode_image_with_options(&bytes, &lenient()).expect("infallible");
+    let kinds = warning_kinds(&image.warnings);
+    assert_eq!(kinds, [missing_iend]);
+    assert_eq!(image.pixels, decode_image(original).expect("infallible").1);
+}
+
+#[test]
+fn lenient_decode_as_and_into_report_warnings() {
+    let original = include_bytes!("data/rgb_sub_up.png");
+    let mut bytes = original.to_vec();
+    remove_chunk(&mut bytes, b"IEND");
+    let missing_iend = ErrorKind::ChunkOrdering {
+        chunk_type: *b"IEND",
+    };
+    assert_eq!(
+        decode_image_as(&bytes, &PixelFormat::Rgba8)
+            .expect_err("infallible")
+            .kind(),
+        missing_iend
+    );
+
+    let image =
+        decode_image_as_with_options(&bytes, &PixelFormat::Rgba8, &lenient()).expect("infallible");
+    let kinds = warning_kinds(&image.warnings);
+    assert_eq!(kinds, [missing_iend]);
+    assert_eq!(image.spec.pixel_format, PixelFormat::Rgba8);
+    assert_eq!(
+        image.pixels,
+        decode_image_as(original, &PixelFormat::Rgba8)
+            .expect("infallible")
+            .1
+    );
+
+    let (expected_spec, expected_pixels) = decode_image(original).expect("infallible");
+    let mut pixels = vec![0; expected_pixels.len()];
+    let decoded =
+        decode_image_into_with_options(&bytes, &mut pixels, &lenient()).expect("infallible");
+    let kinds = warning_kinds(&decoded.warnings);
+    assert_eq!(kinds, [missing_iend]);
+    assert_eq!(decoded.spec, expected_spec);
+    assert_eq!(pixels, expected_pixels);
+}
+
+#[test]
+fn lenient_decode_ignores_adler32_mismatch() {
+    let original = include_bytes!("data/gray_filters.png");
+    let mut chunks = collect_chunks(original);
+    let idat = chunks
+        .iter_mut()
+        .rev()
+        .find(|chunk| &chunk.chunk_type == b"IDAT")
+        .expect("infallible");
+    *idat.data.last_mut().expect("infallible") ^= 0x01;
+    let bytes = rebuild_png(&chunks);
+    assert_eq!(
+        decode_image(&bytes).expect_err("infallible").kind(),
+        ErrorKind::Adler32Mismatch
+    );
+
+    let image = decode_image_with_options(&bytes, &lenient()).expect("infallible");
+    let kinds = warning_kinds(&image.warnings);
+    assert_eq!(kinds, [ErrorKind::Adler32Mismatch]);
+    assert_eq!(image.pixels, decode_image(original).expect("infallible").1);
+}
+
+#[test]
+fn lenient_decode_returns_rows_before_truncation() {
+    let spec = ImageSpec::new(16, 16, PixelFormat::Gray8);
+    let pixels: Vec<u8> = (0..256).map(|i| (i % 255 + 1) as u8).collect();
+    let options = EncodeOptions {
+        compression: CompressionLevel::Store,
+        ..Default::default()
+    };
+    let bytes = encode_image_with_options(&spec, &pixels, &options).expect("infallible");
+    let truncated = &bytes[..bytes.len() / 2];
+    assert_eq!(
+        decode_image(truncated).expect_err("infallible").kind(),
+        ErrorKind::UnexpectedEof
+    );
+
+    let image = decode_image_with_options(truncated, &lenient()).expect("infallible");
+    assert!(warning_kinds(&image.warnings).contains(&ErrorKind::UnexpectedEof));
+    let rows: Vec<_> = image.pixels.chunks(16).zip(pixels.chunks(16)).collect();
+    assert_eq!(rows[0].0, rows[0].1);
+    assert!(rows[15].0.iter().all(|&pixel| pixel == 0));
+    for (decoded, expected) in rows {
+        assert!(decoded == expected || decoded.iter().all(|&pixel| pixel == 0));
+    }
+
+    // Rows missing from a caller's buffer are zeroed too.
+    let mut out = vec![0xAA; 256];
+    let decoded =
+        decode_image_into_with_options(truncated, &mut out, &lenient()).expect("infallible");
+    assert_eq!(decoded.warnings, image.warnings);
+    assert_eq!(out, image.pixels);
+}
+
+fn color_metadata() -> Metadata {
+    Metadata {
+        gamma: Some(45455),
+        chromaticities: Some(Chromaticities {
+            white: [31270, 32900],
+            red: [64000, 33000],
+            green: [30000, 60000],
+            blue: [15000, 6000],
+        }),
+        srgb: Some(RenderingIntent::RelativeColorimetric),
+        icc_profile: Some(IccProfile {
+            name: "Display P3 \u{e9}dition".into(),
+            profile: b"not really an ICC profile ".repeat(40),
+        }),
+        cicp: Some(Cicp {
+            color_primaries: 12,
+            transfer_function: 13,
+            matrix_coefficients: 0,
+            full_range: true,
+        }),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn color_metadata_roundtrips() {
+    let palette = vec![0, 0, 0, 255, 0, 0, 0, 255, 0];
+    let spec = ImageSpec::new(
+        3,
+        1,
+        PixelFormat::Indexed8 {
+            palette,
+            trns: None,
+        },
+    );
+    let options = EncodeOptions {
+        metadata: color_metadata(),
+        ..Default::default()
+    };
+    let bytes = encode_image_with_options(&spec, &[0, 1, 2], &options).expect("infallible");
+    let chunk_types: Vec<_> = collect_chunks(&bytes)
+        .iter()
+        .map(|chunk| chunk.chunk_type)
+        .collect();
+    let plte = chunk_types
+        .iter()
+        .position(|chunk_type| chunk_type == b"PLTE")
+        .expect("infallible");
+    for chunk_type in [b"gAMA", b"cHRM", b"sRGB", b"iCCP", b"cICP"] {
+        let position = chunk_types.iter().position(|t| t == chunk_type);
+        assert!(position.expect("infallible") < plte);
+    }
+    let image = decode_image_with_options(&bytes, &DecodeOptions::default()).expect("infallible");
+    assert_eq!(image.metadata, options.metadata);
+    assert_eq!(image.pixels, [0, 1, 2]);
+
+    let spec = ImageSpec::new(1, 1, PixelFormat::Rgb8);
+    let mut encoder = StreamEncoder::new(spec, options.clone(), 64).expect("infallible");
+    let mut bytes = Vec::new();
+    encoder
+        .write_row(&[1, 2, 3], &mut bytes)
+        .expect("infallible");
+    encoder.finish(&mut bytes).expect("infallible");
+    let mut decoder = StreamDecoder::new();
+    decoder.write(&bytes);
+    decoder.next_row().expect("infallible").expect("infallible");
+    assert_eq!(decoder.metadata(), &options.metadata);
+}
+
+#[test]
+fn rejects_misplaced_or_invalid_color_chunks() {
+    let original = include_bytes!("data/rgb_sub_up.png");
+    let chunks = collect_chunks(original);
+    let idat = chunks
+        .iter()
+        .position(|chunk| &chunk.chunk_type == b"IDAT")
+        .expect("infallible");
+    let gamma = 45455u32.to_be_bytes().to_vec();
+    let cases: [(usize, &[u8; 4], Vec<u8>, ErrorKind); 6] = [
+        (
+            idat + 1,
+            b"gAMA",
+            gamma.clone(),
+            ErrorKind::ChunkOrdering {
+                chunk_type: *b"gAMA",
+            },
+        ),
+        (
+            1,
+            b"gAMA",
+            gamma.clone(),
+            ErrorKind::ChunkOrdering {
+                chunk_type: *b"gAMA",
+            },
+        ),
+        (
+            1,
+            b"gAMA",
+            vec![0; 4],
+            ErrorKind::InvalidChunk {
+                chunk_type: *b"gAMA",
+            },
+        ),
+        (
+            1,
+            b"sRGB",
+            vec![4],
+            ErrorKind::InvalidChunk {
+                chunk_type: *b"sRGB",
+            },
+        ),
+        (
+            1,
+            b"cICP",
+            vec![1, 13, 1, 1],
+            ErrorKind::InvalidChunk {
+                chunk_type: *b"cICP",
+            },
+        ),
+        (
+            1,
+            b"iCCP",
+            b"name\0\x01\x78\x9C".to_vec(),
+            ErrorKind::InvalidChunk {
+                chunk_type: *b"iCCP",
+            },
+        ),
+    ];
+    for (case, (index, chunk_type, data, kind)) in cases.into_iter().enumerate() {
+        let mut chunks = collect_chunks(original);
+        chunks.insert(
+            index,
+            Chunk {
+                chunk_type: *chunk_type,
+                data,
+            },
+        );
+        // The second case repeats a valid gAMA chunk.
+        if case == 1 {
+            chunks.insert(
+                index,
+                Chunk {
+                    chunk_type: *b"gAMA",
+                    data: gamma.clone(),
+                },
+            );
+        }
+        let bytes = rebuild_png(&chunks);
+        let error = decode_image(&bytes).expect_err("infallible");
+        assert_eq!(error.kind(), kind, "case {case}");
+
+        let image = decode_image_with_options(&bytes, &lenient()).expect("infallible");
+        let kinds = warning_kinds(&image.warnings);
+        assert_eq!(kinds, [kind], "case {case}");
+        let expected_gamma = (case == 1).then_some(45455);
+        assert_eq!(image.metadata.gamma, expected_gamma, "case {case}");
+        assert_eq!(image.pixels, decode_image(original).expect("infallible").1);
+    }
+}
+
+fn text_entries() -> Vec<TextEntry> {
+    vec![
+        TextEntry {
+            keyword: "Author".into(),
+            text: "Ren\u{e9}e".into(),
+            ..Default::default()
+        },
+        TextEntry {
+            keyword: "Description".into(),
+            text: "compressed ".repeat(20),
+            compressed: true,
+            ..Default::default()
+        },
+        TextEntry {
+            keyword: "Title".into(),
+            text: "\u{732b}\u{306e}\u{5199}\u{771f}".into(),
+            compressed: false,
+            international: Some(InternationalText {
+                language_tag: "ja".into(),
+                translated_keyword: "\u{984c}\u{540d}".into(),
+            }),
+        },
+        TextEntry {
+            keyword: "Comment".into(),
+            text: "\u{1f5bc} ".repeat(20),
+            compressed: true,
+            international: Some(InternationalText::default()),
+        },
+    ]
+}
+
+#[test]
+fn text_chunks_roundtrip() {
+    let spec = ImageSpec::new(2, 1, PixelFormat::Gray8);
+    let options = EncodeOptions {
+        metadata: Metadata {
+            text: text_entries(),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let bytes = encode_image_with_options(&spec, &[1, 2], &options).expect("infallible");
+    let chunk_types: Vec<_> = collect_chunks(&bytes)
+        .iter()
+        .map(|chunk| chunk.chunk_type)
+        .filter(|chunk_type| chunk_type[0].is_ascii_lowercase())
+        .collect();
+    assert_eq!(chunk_types, [*b"tEXt", *b"zTXt", *b"iTXt", *b"iTXt"]);
+    let image = decode_image_with_options(&bytes, &DecodeOptions::default()).expect("infallible");
+    assert_eq!(image.metadata.text, text_entries());
+
+    // Text chunks may also follow the image data.
+    let mut chunks = collect_chunks(&bytes);
+    let trailing = Chunk {
+        chunk_type: *b"tEXt",
+        data: b"Source\0camera".to_vec(),
+    };
+    chunks.insert(chunks.len() - 1, trailing);
+    let bytes = rebuild_png(&chunks);
+    let image = decode_image_with_options(&bytes, &DecodeOptions::default()).expect("infallible");
+    let last = image.metadata.text.last().expect("infallible");
+    assert_eq!((&*last.keyword, &*last.text), ("Source", "camera"));
+    let mut decoder = StreamDecoder::new();
+    decoder.write(&bytes);
+    while decoder.next_row().expect("infallible").is_some() {}
+    assert_eq!(decoder.metadata(), &image.metadata);
+}
+
+#[test]
+fn rejects_invalid_text_chunks() {
+    let original = include_bytes!("data/gray_filters.png");
+    let cases: [(&[u8; 4], &[u8]); 6] = [
+        (b"tEXt", b"no keyword terminator"),
+        (b"tEXt", b" Title\0leading space"),
+        (b"tEXt", b"Two  Spaces\0text"),
+        (b"zTXt", b"Title\0\x01\x78\x9C"),
+        (b"iTXt", b"Title\0\x00\x00en\0\0\xFF"),
+        (b"iTXt", b"Title\0\x02\x00en\0\0text"),
+    ];
+    for (chunk_type, data) in cases {
+        let mut chunks = collect_chunks(original);
+        chunks.insert(
+            1,
+            Chunk {
+                chunk_type: *chunk_type,
+                data: data.to_vec(),
+            },
+        );
+        let bytes = rebuild_png(&chunks);
+        let kind = ErrorKind::InvalidChunk {
+            chunk_type: *chunk_type,
+        };
+        let error = decode_image(&bytes).expect_err("infallible");
+        assert_eq!(error.kind(), kind, "{data:?}");
+
+        let image = decode_image_with_options(&bytes, &lenient()).expect("infallible");
+        let kinds = warning_kinds(&image.warnings);
+        assert_eq!(kinds, [kind]);
+        assert!(image.metadata.text.is_empty());
+    }
+}
+
+#[test]
+fn decode_limits_bound_decompressed_ancillary_data() {
+    let spec = ImageSpec::new(1, 1, PixelFormat::Gray8);
+    let icc_profile = Metadata {
+        icc_profile: Some(IccProfile {
+            name: "zeros".into(),
+            profile: vec![0; 1 << 16],
+        }),
+        ..Default::default()
+    };
+    // Each chunk fits the limit on its own, but not together.
+    let text = Metadata {
+        text: vec![
+            TextEntry {
+                keyword: "Comment".into(),
+                text: "a".repeat(600),
+                compressed: true,
+                ..Default::default()
+            };
+            2
+        ],
+        ..Default::default()
+    };
+    for metadata in [icc_profile, text] {
+        let options = EncodeOptions {
+            metadata,
+            ..Default::default()
+        };
+        let bytes = encode_image_with_options(&spec, &[0], &options).expect("infallible");
+        assert!(bytes.len() < 1024);
+        assert_decode_limit_exceeded(&bytes);
+    }
+}
+
+fn assert_decode_limit_exceeded(bytes: &[u8]) {
+    for lenient in [false, true] {
+        let options = DecodeOptions {
+            limits: DecodeLimits {
+                max_ancillary_bytes: 1024,
+                ..Default::default()
+            },
+            lenient,
+        };
+        let error = decode_image_with_options(bytes, &options).expect_err("infallible");
+        assert_eq!(error.kind(), ErrorKind::LimitExceeded);
+    }
+}
+
+#[test]
+fn encode_rejects_invalid_metadata() {
+    let spec = ImageSpec::new(1, 1, PixelFormat::Gray8);
+    let icc_profile = |name: &str| {
+        Some(IccProfile {
+            name: name.into(),
+            profile: Vec::new(),
+        })
+    };
+    for metadata in [
+        Metadata {
+            gamma: Some(0),
+            ..Default::default()
+        },
+        Metadata {
+            icc_profile: icc_profile(""),
+            ..Default::default()
+        },
+        Metadata {
+            icc_profile: icc_profile(" leading space"),
+            ..Default::default()
+        },
+        Metadata {
+            icc_profile: icc_profile("two  spaces"),
+            ..Default::default()
+        },
+        Metadata {
+            icc_profile: icc_profile("not latin-1 \u{20ac}"),
+            ..Default::default()
+        },
+        Metadata {
+            icc_profile: icc_profile(&"x".repeat(80)),
+            ..Default::default()
+        },
+        Metadata {
+            cicp: Some(Cicp {
+                matrix_coefficients: 1,
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+        Metadata {
+            text: vec![TextEntry::default()],
+            ..Default::default()
+        },
+        Metadata {
+            text: vec![TextEntry {
+                keyword: "Title".into(),
+                text: "not latin-1 \u{20ac}".into(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        Metadata {
+            text: vec![TextEntry {
+                keyword: "Title".into(),
+                international: Some(InternationalText {
+                    language_tag: "en\0".into(),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+    ] {
+        let options = EncodeOptions {
+            metadata,
+            ..Default::default()
+        };
+        let error = encode_image_with_options(&spec, &[0], &options).expect_err("infallible");
+        assert_eq!(error.kind(), ErrorKind::InvalidArgument);
+        let error = StreamEncoder::new(spec.clone(), options, 64).expect_err("infallible");
+        assert_eq!(error.kind(), ErrorKind::InvalidArgument);
+    }
+}
+
+fn image_metadata() -> Metadata {
+    Metadata {
+        physical_dimensions: Some(PhysicalDimensions::from_dpi(300, 150)),
+        offset: Some(ImageOffset {
+            x: -12,
+            y: 34,
+            unit: OffsetUnit::Micrometer,
+        }),
+        modification_time: Some(Timestamp {
+            year: 2024,
+            month: 2,
+            day: 29,
+            hour: 23,
+            minute: 59,
+            second: 60,
+        }),
+        significant_bits: Some(SignificantBits::Rgb([5, 6, 5])),
+        background: Some(Background::PaletteIndex(2)),
+        histogram: Some(vec![1, 0, 65535]),
+        suggested_palettes: vec![
+            SuggestedPalette {
+                name: "web".into(),
+                sample_depth: 8,
+                entries: vec![SuggestedPaletteEntry {
+                    red: 255,
+                    green: 128,
+                    blue: 0,
+                    alpha: 255,
+                    frequency: 7,
+                }],
+            },
+            SuggestedPalette {
+                name: "deep".into(),
+                sample_depth: 16,
+                entries: vec![SuggestedPaletteEntry {
+                    red: 65535,
+                    green: 256,
+                    blue: 1,
+                    alpha: 0,
+                    frequency: 1,
+                }],
+            },
+        ],
+        exif: Some(b"MM\0\x2a\0\0\0\x08\0\0".to_vec()),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn image_metadata_roundtrips() {
+    let palette = vec![0, 0, 0, 255, 0, 0, 0, 255, 0];
+    let spec = ImageSpec::new(
+        3,
+        1,
+        PixelFormat::Indexed8 {
+            palette,
+            trns: None,
+        },
+    );
+    let options = EncodeOptions {
+        metadata: image_metadata(),
+        ..Default::default()
+    };
+    let bytes = encode_image_with_options(&spec, &[0, 1, 2], &options).expect("infallible");
+    let chunk_types: Vec<_> = collect_chunks(&bytes)
+        .iter()
+        .map(|chunk| chunk.chunk_type)
+        .collect();
+    let position = |chunk_type: &[u8; 4]| {
+        chunk_types
+            .iter()
+            .position(|t| t == chunk_type)
+            .expect("infallible")
+    };
+    assert!(position(b"sBIT") < position(b"PLTE"));
+    assert!(position(b"PLTE") < position(b"bKGD"));
+    assert!(position(b"PLTE") < position(b"hIST"));
+    assert!(position(b"hIST") < position(b"IDAT"));
+    let image = decode_image_with_options(&bytes, &DecodeOptions::default()).expect("infallible");
+    assert_eq!(image.metadata, options.metadata);
+    assert_eq!(
+        image.metadata.physical_dimensions.and_then(|p| p.dpi()),
+        Some([300, 150])
+    );
+    let mut pixels = [0; 3];
+    let decoded = decode_image_into_with_options(&bytes, &mut pixels, &DecodeOptions::default())
+        .expect("infallible");
+    assert_eq!(decoded.metadata, options.metadata);
+    assert_eq!(pixels, [0, 1, 2]);
+
+    let spec = ImageSpec::new(1, 1, PixelFormat::Rgb16Be);
+    let options = EncodeOptions {
+        metadata: Metadata {
+            significant_bits: Some(SignificantBits::Rgb([10, 12, 16])),
+            background: Some(Background::Rgb([1, 2, 65535])),
+            ..image_metadata()
+        },
+        ..Default::default()
+    };
+    assert!(encode_image_with_options(&spec, &[0; 6], &options).is_err());
+    let options = EncodeOptions {
+        metadata: Metadata {
+            histogram: None,
+            ..options.metadata
+        },
+        ..Default::default()
+    };
+    let mut encoder = StreamEncoder::new(spec, options.clone(), 64).expect("infallible");
+    let mut bytes = Vec::new();
+    encoder.write_row(&[0; 6], &mut bytes).expect("infallible");
+    encoder.finish(&mut bytes).expect("infallible");
+    let mut decoder = StreamDecoder::new();
+    decoder.write(&bytes);
+    decoder.next_row().expect("infallible").expect("infallible");
+    assert_eq!(decoder.metadata(), &options.metadata);
+}
+
+#[test]
+fn transparency_metadata_roundtrips_through_encode() {
+    let rgb = insert_before_idat(
+        include_bytes!("data/rgb_sub_up.png"),
+        [
+            (*b"sBIT", vec![5, 6, 5]),
+            (*b"tRNS", vec![0, 0, 0, 0, 0, 0]),
+            (*b"bKGD", vec![0, 10, 0, 20, 0, 30]),
+        ],
+    );
+    let gray = insert_before_idat(
+        include_bytes!("data/gray_2bit_trns.png"),
+        [(*b"sBIT", vec![1]), (*b"bKGD", vec![0, 2])],
+    );
+    for (bytes, format, significant_bits, background) in [
+        (
+            rgb,
+            PixelFormat::Rgba8,
+            SignificantBits::Rgba([5, 6, 5, 8]),
+            Background::Rgb([10, 20, 30]),
+        ),
+        (
+            gray,
+            PixelFormat::GrayAlpha8,
+            SignificantBits::GrayAlpha([1, 8]),
+            Background::Gray(170),
+        ),
+    ] {
+        let image =
+            decode_image_with_options(&bytes, &DecodeOptions::default()).expect("infallible");
+        assert_eq!(image.spec.pixel_format, format);
+        assert_eq!(image.metadata.significant_bits, Some(significant_bits));
+        assert_eq!(image.metadata.background, Some(background));
+
+        let options = EncodeOptions {
+            metadata: image.metadata.clone(),
+            ..Default::default()
+        };
+        let bytes =
+            encode_image_with_options(&image.spec, &image.pixels, &options).expect("infallible");
+        let reencoded =
+            decode_image_with_options(&bytes, &DecodeOptions::default()).expect("infallible");
+        assert_eq!(reencoded, image);
+    }
+}
+
+#[test]
+fn rejects_misplaced_or_invalid_image_metadata_chunks() {
+    let original = include_bytes!("data/rgb_sub_up.png");
+    let idat = collect_chunks(original)
+        .iter()
+        .position(|chunk| &chunk.chunk_type == b"IDAT")
+        .expect("infallible");
+    let ordering = |chunk_type: &[u8; 4]| ErrorKind::ChunkOrdering {
+        chunk_type: *chunk_type,
+    };
+    let invalid = |chunk_type: &[u8; 4]| ErrorKind::InvalidChunk {
+        chunk_type: *chunk_type,
+    };
+    let cases: [(usize, &[u8; 4], &[u8], ErrorKind); 11] = [
+        (
+            idat + 1,
+            b"pHYs",
+            &[0, 0, 0, 1, 0, 0, 0, 1, 1],
+            ordering(b"pHYs"),
+        ),
+        (1, b"hIST", &[0, 1], ordering(b"hIST")),
+        (1, b"pHYs", &[0, 0, 0, 1, 0, 0, 0, 1, 2], invalid(b"pHYs")),
+        (1, b"oFFs", &[0; 8], invalid(b"oFFs")),
+        (1, b"tIME", &[7, 232, 13, 1, 0, 0, 0], invalid(b"tIME")),
+        (1, b"sBIT", &[8, 8], invalid(b"sBIT")),
+        (1, b"sBIT", &[8, 9, 8], invalid(b"sBIT")),
+        (1, b"bKGD", &[0, 1], invalid(b"bKGD")),
+        (1, b"bKGD", &[0, 0, 1, 0, 0, 0], invalid(b"bKGD")),
+        (1, b"sPLT", b"name\0\x04", invalid(b"sPLT")),
+        (1, b"sPLT", b"name\0\x08\0\0\0\0\0", invalid(b"sPLT")),
+    ];
+    for (index, chunk_type, data, kind) in cases {
+        let mut chunks = collect_chunks(original);
+        chunks.insert(
+            index,
+            Chunk {
+                chunk_type: *chunk_type,
+                data: data.to_vec(),
+            },
+        );
+        let bytes = rebuild_png(&chunks);
+        let error = decode_image(&bytes).expect_err("infallible");
+        assert_eq!(error.kind(), kind, "{data:?}");
+
+        let image = decode_image_with_options(&bytes, &lenient()).expect("infallible");
+        let kinds = warning_kinds(&image.warnings);
+        assert_eq!(kinds, [kind], "{data:?}");
+        assert_eq!(image.metadata, Metadata::default());
+    }
+
+    // A palette index must refer to an existing entry.
+    let spec = ImageSpec::new(
+        1,
+        1,
+        PixelFormat::Indexed8 {
+            palette: vec![0; 6],
+            trns: None,
+        },
+    );
+    let bytes = encode_image(&spec, &[0]).expect("infallible");
+    let mut chunks = collect_chunks(&bytes);
+    let plte = chunks
+        .iter()
+        .position(|chunk| &chunk.chunk_type == b"PLTE")
+        .expect("infallible");
+    for (chunk_type, data) in [(b"bKGD", vec![2]), (b"hIST", vec![0; 6])] {
+        chunks.insert(
+            plte + 1,
+            Chunk {
+                chunk_type: *chunk_type,
+                data,
+            },
+        );
+        let error = decode_image(&rebuild_png(&chunks)).expect_err("infallible");
+        assert_eq!(error.kind(), invalid(chunk_type));
+        chunks.remove(plte + 1);
+    }
+}
+
+#[test]
+fn encode_rejects_metadata_that_does_not_match_the_format() {
+    let indexed = PixelFormat::Indexed8 {
+        palette: vec![0; 6],
+        trns: None,
+    };
+    let cases = [
+        (
+            PixelFormat::Gray8,
+            Metadata {
+                significant_bits: Some(SignificantBits::Rgb([8, 8, 8])),
+                ..Default::default()
+            },
+        ),
+        (
+            PixelFormat::Gray8,
+            Metadata {
+                significant_bits: Some(SignificantBits::Gray(9)),
+                ..Default::default()
+            },
+        ),
+        (
+            PixelFormat::Gray8,
+            Metadata {
+                background: Some(Background::Gray(256)),
+                ..Default::default()
+            },
+        ),
+        (
+            PixelFormat::Gray8,
+            Metadata {
+                histogram: Some(vec![0]),
+                ..Default::default()
+            },
+        ),
+        (
+            indexed.clone(),
+            Metadata {
+                background: Some(Background::PaletteIndex(2)),
+                ..Default::default()
+            },
+        ),
+        (
+            indexed.clone(),
+            Metadata {
+                histogram: Some(vec![0; 3]),
+                ..Default::default()
+            },
+        ),
+        (
+            PixelFormat::Gray8,
+            Metadata {
+                modification_time: Some(Timestamp {
+                    year: 2024,
+                    month: 0,
+                    day: 1,
+                    hour: 0,
+                    minute: 0,
+                    second: 0,
+                }),
+                ..Default::default()
+            },
+        ),
+        (
+            PixelFormat::Gray8,
+            Metadata {
+                suggested_palettes: vec![SuggestedPalette {
+                    name: "four".into(),
+                    sample_depth: 4,
+                    entries: Vec::new(),
+                }],
+                ..Default::default()
+            },
+        ),
+        (
+            PixelFormat::Gray8,
+            Metadata {
+                suggested_palettes: vec![SuggestedPalette {
+                    name: "depth".into(),
+                    sample_depth: 8,
+                    entries: vec![SuggestedPaletteEntry {
+                        red: 256,
+                        ..Default::default()
+                    }],
+                }],
+                ..Default::default()
+            },
+        ),
+    ];
+    for (format, metadata) in cases {
+        let spec = ImageSpec::new(1, 1, format);
+        let options = EncodeOptions {
+            metadata,
+            ..Default::default()
+        };
+        let error = encode_image_with_options(&spec, &[0], &options).expect_err("infallible");
+        assert_eq!(error.kind(), ErrorKind::InvalidArgument, "{options:?}");
+        let error = StreamEncoder::new(spec, options, 64).expect_err("infallible");
+        assert_eq!(error.kind(), ErrorKind::InvalidArgument);
+    }
+}
+
+/// RGBA8 animation frames and their pixels.
+type Frames = Vec<(FrameControl, Vec<u8>)>;
+
+/// Builds an RGBA8 APNG from `frames`. The first frame is the default image,
+/// unless a separate `hidden_default` image is given.
+fn build_apng(
+    width: u32,
+    height: u32,
+    frames: &[(FrameControl, Vec<u8>)],
+    hidden_default: Option<&[u8]>,
+) -> Vec<u8> {
+    let image_data = |width, height, pixels: &[u8]| {
+        let spec = ImageSpec::new(width, height, PixelFormat::Rgba8);
+        let bytes = encode_image(&spec, pixels).expect("infallible");
+        collect_chunks(&bytes)
+            .into_iter()
+            .filter(|chunk| &chunk.chunk_type == b"IDAT")
+            .map(|chunk| chunk.data)
+            .collect::<Vec<_>>()
+    };
+    let default_pixels = hidden_default.unwrap_or(&frames[0].1);
+    let spec = ImageSpec::new(width, height, PixelFormat::Rgba8);
+    let mut chunks = collect_chunks(&encode_image(&spec, default_pixels).expect("infallible"));
+    chunks.retain(|chunk| &chunk.chunk_type == b"IHDR");
+    let mut actl = (frames.len() as u32).to_be_bytes().to_vec();
+    actl.extend_from_slice(&0u32.to_be_bytes());
+    chunks.push(Chunk {
+        chunk_type: *b"acTL",
+        data: actl,
+    });
+    let mut sequence_number = 0u32;
+    if hidden_default.is_some() {
+        for data in image_data(width, height, default_pixels) {
+            chunks.push(Chunk {
+                chunk_type: *b"IDAT",
+                data,
+            });
+        }
+    }
+    for (index, (control, pixels)) in frames.iter().enumerate() {
+        let mut fctl = sequence_number.to_be_bytes().to_vec();
+        sequence_number += 1;
+        for value in [
+            control.width,
+            control.height,
+            control.x_offset,
+            control.y_offset,
+        ] {
+            fctl.extend_from_slice(&value.to_be_bytes());
+        }
+        fctl.extend_from_slice(&control.delay_num.to_be_bytes());
+        fctl.extend_from_slice(&control.delay_den.to_be_bytes());
+        fctl.push(match control.dispose_op {
+            DisposeOp::None => 0,
+            DisposeOp::Background => 1,
+            DisposeOp::Previous => 2,
+        });
+        fctl.push(match control.blend_op {
+            BlendOp::Source => 0,
+            BlendOp::Over => 1,
+        });
+        chunks.push(Chunk {
+            chunk_type: *b"fcTL",
+            data: fctl,
+        });
+        for data in image_data(control.width, control.height, pixels) {
+            if index == 0 && hidden_default.is_none() {
+                chunks.push(Chunk {
+                    chunk_type: *b"IDAT",
+                    data,
+                });
+            } else {
+                let mut fdat = sequence_number.to_be_bytes().to_vec();
+                sequence_number += 1;
+                fdat.extend_from_slice(&data);
+                chunks.push(Chunk {
+                    chunk_type: *b"fdAT",
+                    data: fdat,
+                });
+            }
+        }
+    }
+    chunks.push(Chunk {
+        chunk_type: *b"IEND",
+        data: Vec::new(),
+    });
+    rebuild_png(&chunks)
+}
+
+/// Four frames on a 3x2 canvas exercising every blend and dispose operation,
+/// with the canvas expected after each one.
+fn animation_frames() -> (Frames, Vec<Vec<u8>>) {
+    const RED: [u8; 4] = [255, 0, 0, 255];
+    const CLEAR: [u8; 4] = [0; 4];
+    let frames = vec![
+        (
+            FrameControl {
+                delay_num: 1,
+                delay_den: 10,
+                ..FrameControl::full_canvas(3, 2)
+            },
+            RED.repeat(6),
+        ),
+        (
+            FrameControl {
+                x_offset: 1,
+                dispose_op: DisposeOp::Previous,
+                blend_op: BlendOp::Over,
+                ..FrameControl::full_canvas(2, 1)
+            },
+            [[0, 0, 255, 128], CLEAR].concat(),
+        ),
+        (
+            FrameControl {
+                y_offset: 1,
+                dispose_op: DisposeOp::Background,
+                ..FrameControl::full_canvas(1, 1)
+            },
+            vec![0, 255, 0, 255],
+        ),
+        (
+            FrameControl {
+                x_offset: 2,
+                y_offset: 1,
+                ..FrameControl::full_canvas(1, 1)
+            },
+            CLEAR.to_vec(),
+        ),
+    ];
+    let canvases = vec![
+        RED.repeat(6),
+        [RED, [127, 0, 128, 255], RED, RED, RED, RED].concat(),
+        [RED, RED, RED, [0, 255, 0, 255], RED, RED].concat(),
+        [RED, RED, RED, CLEAR, RED, CLEAR].concat(),
+    ];
+    (frames, canvases)
+}
+
+#[test]
+fn decodes_and_composites_apng_frames() {
+    let (frames, canvases) = animation_frames();
+    let hidden = [0, 0, 0, 255].repeat(6);
+    for hidden_default in [None, Some(&hidden[..])] {
+        let bytes = build_apng(3, 2, &frames, hidden_default);
+        let decoder = AnimationDecoder::new(&bytes, &DecodeOptions::default()).expect("infallible");
+        assert_eq!(
+            decoder.animation_control(),
+            Some(&AnimationControl {
+                num_frames: 4,
+                num_plays: 0,
+            })
+        );
+        assert_eq!(decoder.spec(), &ImageSpec::new(3, 2, PixelFormat::Rgba8));
+        assert_eq!(decoder.len(), 4);
+        let mut compositor = FrameCompositor::new(decoder.spec());
+        let decoded: Vec<_> = decoder.map(|frame| frame.expect("infallible")).collect();
+        for ((frame, (control, pixels)), canvas) in decoded.iter().zip(&frames).zip(&canvases) {
+            assert_eq!(&frame.control, control);
+            assert_eq!(&frame.pixels, pixels);
+            assert_eq!(compositor.compose(frame).expect("infallible"), canvas);
+        }
+        assert_eq!(decoded[0].control.delay_millis(), 100);
+
+        // Decoders of still images only see the default image.
+        let (_, pixels) = decode_image(&bytes).expect("infallible");
+        assert_eq!(pixels, hidden_default.unwrap_or(&frames[0].1));
+    }
+
+    let bytes = include_bytes!("data/rgb_sub_up.png");
+    let mut decoder = AnimationDecoder::new(bytes, &DecodeOptions::default()).expect("infallible");
+    assert_eq!(decoder.animation_control(), None);
+    let frame = decoder.next().expect("infallible").expect("infallible");
+    assert_eq!(frame.control, FrameControl::full_canvas(2, 2));
+    assert_eq!(frame.pixels, decode_image(bytes).expect("infallible").1);
+    assert!(decoder.next().is_none());
+}
+
+#[test]
+fn rejects_malformed_apng_chunks() {
+    let (frames, _) = animation_frames();
+    let original = collect_chunks(&build_apng(3, 2, &frames, None));
+    let position = |chunk_type: &[u8; 4], nth: usize| {
+        original
+            .iter()
+            .enumerate()
+            .filter(|(_, chunk)| &chunk.chunk_type == chunk_type)
+            .nth(nth)
+            .expect("infallible")
+            .0
+    };
+    let ordering = |chunk_type: &[u8; 4]| ErrorKind::ChunkOrdering {
+        chunk_type: *chunk_type,
+    };
+    let invalid = |chunk_type: &[u8; 4]| ErrorKind::InvalidChunk {
+        chunk_type: *chunk_type,
+    };
+    let cases: [(usize, usize, u8, ErrorKind); 5] = [
+        // The sequence number of the first fdAT chunk.
+        (position(b"fdAT", 0), 3, 9, ordering(b"fdAT")),
+        // The width of the second frame, which then extends past the canvas.
+        (position(b"fcTL", 1), 7, 3, invalid(b"fcTL")),
+        // The x offset of the default image.
+        (position(b"fcTL", 0), 15, 1, invalid(b"fcTL")),
+        (position(b"fcTL", 2), 24, 3, invalid(b"fcTL")),
+        (position(b"acTL", 0), 3, 0, invalid(b"acTL")),
+    ];
+    for (index, byte, value, kind) in cases {
+        let mut chunks = collect_chunks(&rebuild_png(&original));
+        chunks[index].data[byte] = value;
+        let bytes = rebuild_png(&chunks);
+        let error =
+            AnimationDecoder::new(&bytes, &DecodeOptions::default()).expect_err("infallible");
+        assert_eq!(error.kind(), kind);
+        let error = decode_image(&bytes).expect_err("infallible");
+        assert_eq!(error.kind(), kind);
+        let image = decode_image_with_options(&bytes, &lenient()).expect("infallible");
+        assert_eq!(image.warnings[0].kind(), kind);
+    }
+
+    // A missing frame is only an error for animation decoding.
+    let mut chunks = collect_chunks(&rebuild_png(&original));
+    chunks.drain(position(b"fcTL", 3)..position(b"IEND", 0));
+    let bytes = rebuild_png(&chunks);
+    let error = AnimationDecoder::new(&bytes, &DecodeOptions::default()).expect_err("infallible");
+    assert_eq!(error.kind(), ordering(b"fcTL"));
+    let decoder = AnimationDecoder::new(&bytes, &lenient()).expect("infallible");
+    let kinds = warning_kinds(decoder.warnings());
+    assert_eq!(kinds, [ordering(b"fcTL")]);
+    assert_eq!(decoder.count(), 3);
+    assert!(decode_image(&bytes).is_ok());
+}
+
+fn to_animation_frames(frames: Frames) -> Vec<AnimationFrame> {
+    frames
+        .into_iter()
+        .map(|(control, pixels)| AnimationFrame { control, pixels })
+        .collect()
+}
+
+#[test]
+fn encode_animation_roundtrips() {
+    let (frames, canvases) = animation_frames();
+    let frames = to_animation_frames(frames);
+    let spec = ImageSpec::new(3, 2, PixelFormat::Rgba8);
+    for crop_to_changes in [false, true] {
+        let animation = AnimationOptions {
+            num_plays: 3,
+            crop_to_changes,
+        };
+        let bytes = encode_animation(&spec, &frames, &EncodeOptions::default(), &animation)
+            .expect("infallible");
+        let sequence_numbers: Vec<_> = collect_chunks(&bytes)
+            .iter()
+            .filter(|chunk| matches!(&chunk.chunk_type, b"fcTL" | b"fdAT"))
+            .map(|chunk| u32::from_be_bytes(chunk.data[..4].try_into().expect("infallible")))
+            .collect();
+        assert_eq!(sequence_numbers, (0..7).collect::<Vec<_>>());
+
+        let decoder = AnimationDecoder::new(&bytes, &DecodeOptions::default()).expect("infallible");
+        assert_eq!(
+            decoder.animation_control(),
+            Some(&AnimationControl {
+                num_frames: 4,
+                num_plays: 3,
+            })
+        );
+        let mut compositor = FrameCompositor::new(decoder.spec());
+        for (frame, canvas) in decoder.zip(&canvases) {
+            let frame = frame.expect("infallible");
+            assert_eq!(compositor.compose(&frame).expect("infallible"), canvas);
+        }
+        let (_, pixels) = decode_image(&bytes).expect("infallible");
+        assert_eq!(pixels, frames[0].pixels);
+    }
+}
+
+#[test]
+fn encode_animation_crops_frames_to_changes() {
+    let palette = vec![0, 0, 0, 255, 255, 255, 255, 0, 0];
+    let spec = ImageSpec::new(
+        4,
+        3,
+        PixelFormat::Indexed8 {
+            palette,
+            trns: None,
+        },
+    );
+    let frame = |pixels: [u8; 12], dispose_op| AnimationFrame {
+        control: FrameControl {
+            dispose_op,
+            ..FrameControl::full_canvas(4, 3)
+        },
+        pixels: pixels.to_vec(),
+    };
+    let frames = [
+        frame([0; 12], DisposeOp::None),
+        frame([0, 0, 0, 0, 0, 0, 1, 0, 0, 2, 0, 0], DisposeOp::None),
+        frame([0, 0, 0, 0, 0, 0, 1, 0, 0, 2, 0, 0], DisposeOp::Previous),
+        frame([1, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 1], DisposeOp::Background),
+        frame([1, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 1], DisposeOp::None),
+    ];
+    let animation = AnimationOptions {
+        crop_to_changes: true,
+        ..Default::default()
+    };
+    let bytes = encode_animation(&spec, &frames, &EncodeOptions::default(), &animation)
+        .expect("infallible");
+    let decoder = AnimationDecoder::new(&bytes, &DecodeOptions::default()).expect("infallible");
+    let mut compositor = FrameCompositor::new(decoder.spec());
+    let mut regions = Vec::new();
+    for (frame, original) in decoder.zip(&frames) {
+        let frame = frame.expect("infallible");
+        let control = frame.control;
+        regions.push([
+            control.x_offset,
+            control.y_offset,
+            control.width,
+            control.height,
+        ]);
+        let expected = reformat_pixels(&spec.pixel_format, &original.pixels, &PixelFormat::Rgba8)
+            .expect("infallible");
+        assert_eq!(compositor.compose(&frame).expect("infallible"), expected);
+    }
+    assert_eq!(
+        regions,
+        [
+            [0, 0, 4, 3],
+            [1, 1, 2, 2],
+            // Nothing changes, so a single unchanged pixel is stored.
+            [0, 0, 1, 1],
+            // Frames disposed of to the background are not cropped.
+            [0, 0, 4, 3],
+            [0, 0, 4, 3],
+        ]
+    );
+}
+
+#[test]
+fn encode_animation_rejects_invalid_frames() {
+    let spec = ImageSpec::new(2, 2, PixelFormat::Gray8);
+    let full = AnimationFrame {
+        control: FrameControl::full_canvas(2, 2),
+        pixels: vec![0; 4],
+    };
+    let partial = |x_offset, width, pixels: usize| AnimationFrame {
+        control: FrameControl {
+            x_offset,
+            ..FrameControl::full_canvas(width, 2)
+        },
+        pixels: vec![0; pixels],
+    };
+    for frames in [
+        Vec::new(),
+        vec![partial(0, 1, 2)],
+        vec![full.clone(), partial(1, 2, 4)],
+        vec![full.clone(), partial(1, 1, 4)],
+        vec![full.clone(), partial(0, 0, 0)],
+    ] {
+        let error = encode_animation(
+            &spec,
+            &frames,
+            &EncodeOptions::default(),
+            &AnimationOptions::default(),
+        )
+        .expect_err("infallible");
+        assert_eq!(error.kind(), ErrorKind::InvalidArgument);
+    }
+}
+
+#[test]
+fn chunk_reader_returns_every_chunk_with_its_crc_status() {
+    let original = include_bytes!("data/rgba_paeth_split_idat.png");
+    let mut chunks = collect_chunks(original);
+    chunks.insert(
+        1,
+        Chunk {
+            chunk_type: *b"nhEX",
+            data: b"private".to_vec(),
+        },
+    );
+    let mut bytes = rebuild_png(&chunks);
+    // Corrupt the CRC of the private chunk and append trailing data.
+    let crc_end = 8 + (12 + 13) + (12 + 7);
+    bytes[crc_end - 1] ^= 1;
+    bytes.extend_from_slice(b"trailing");
+
+    let mut reader = ChunkReader::new(&bytes).expect("infallible");
+    let read: Vec<_> = reader
+        .by_ref()
+        .map(|chunk| chunk.expect("infallible"))
+        .collect();
+    let types: Vec<_> = read.iter().map(|chunk| chunk.chunk_type).collect();
+    let expected: Vec<_> = chunks.iter().map(|chunk| chunk.chunk_type).collect();
+    assert_eq!(types, expected);
+    for (chunk, expected) in read.iter().zip(&chunks) {
+        assert_eq!(chunk.data, expected.data);
+        assert_eq!(chunk.crc_ok, &chunk.chunk_type != b"nhEX");
+    }
+    assert!(read[1].is_ancillary() && !read[1].is_safe_to_copy());
+    assert!(!read[0].is_ancillary());
+    assert_eq!(reader.remaining(), b"trailing");
+
+    // Writing the chunks back repairs the CRC.
+    let mut writer = ChunkWriter::new();
+    for chunk in &read {
+        writer
+            .write_chunk(&chunk.chunk_type, chunk.data)
+            .expect("infallible");
+    }
+    assert_eq!(writer.finish(), rebuild_png(&chunks));
+
+    let truncated = &original[..original.len() - 20];
+    let mut reader = ChunkReader::new(truncated).expect("infallible");
+    let error = reader.find_map(|chunk| chunk.err()).expect("infallible");
+    assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    assert!(reader.next().is_none());
+    assert!(!reader.remaining().is_empty());
+
+    let mut oversized = original.to_vec();
+    let iend = oversized.len() - 12;
+    oversized[iend..iend + 4].copy_from_slice(&0x8000_0000u32.to_be_bytes());
+    let mut reader = ChunkReader::new(&oversized).expect("infallible");
+    let error = reader.find_map(|chunk| chunk.err()).expect("infallible");
+    assert_eq!(
+        error.kind(),
+        ErrorKind::InvalidChunk {
+            chunk_type: *b"IEND"
+        }
+    );
+    assert_eq!(reader.remaining(), &oversized[iend..]);
+
+    let error = ChunkReader::new(&original[1..]).expect_err("infallible");
+    assert_eq!(error.kind(), ErrorKind::BadSignature);
+}
+
+#[test]
+fn chunk_writer_rejects_invalid_chunk_types() {
+    let mut writer = ChunkWriter::new();
+    for chunk_type in [b"nh3X", b"n X ", b"\xC3\xA9XY"] {
+        let error = writer.write_chunk(chunk_type, &[]).expect_err("infallible");
+        assert_eq!(error.kind(), ErrorKind::InvalidArgument);
+    }
+    assert_eq!(writer.bytes(), b"\x89PNG\r\n\x1a\n");
+}
+
+fn rgba16(bytes: &[u8]) -> Vec<u8> {
+    let (spec, data) = decode_image(bytes).expect("infallible");
+    reformat_pixels(&spec.pixel_format, &data, &PixelFormat::Rgba16Be).expect("infallible")
+}
+
+#[test]
+fn optimize_png_picks_smaller_lossless_formats() {
+    let gradient: Vec<u8> = (0..16u8)
+        .flat_map(|v| [v * 17, v * 17, v * 17, 255])
+        .collect();
+    let colors: Vec<u8> = (0..17 * 17u16)
+        .flat_map(|i| [i as u8, (i >> 8) as u8 * 100, 7])
+        .collect();
+    let translucent: Vec<u8> = (0..17 * 17u16)
+        .flat_map(|i| {
+            let (gray, alpha) = (i as u8, (i >> 4) as u8);
+            [gray, gray, gray, gray, gray, gray, alpha, alpha]
+        })
+        .collect();
+    let two_colors: Vec<u8> = (0..256)
+        .flat_map(|i: u32| {
+            if i.wrapping_mul(0x9E37_79B9) >> 31 == 0 {
+                [255, 0, 0]
+            } else {
+                [0, 0, 255]
+            }
+        })
+        .collect();
+    let cases = [
+        (4, 4, PixelFormat::Rgba8, gradient, PixelFormat::Gray4),
+        (
+            2,
+            1,
+            PixelFormat::Rgba16Be,
+            vec![0xFF; 16],
+            PixelFormat::Gray1,
+        ),
+        (
+            17,
+            17,
+            PixelFormat::Rgba8,
+            reformat_pixels(&PixelFormat::Rgb8, &colors, &PixelFormat::Rgba8).expect("infallible"),
+            PixelFormat::Rgb8,
+        ),
+        (
+            17,
+            17,
+            PixelFormat::Rgba16Be,
+            translucent,
+            PixelFormat::GrayAlpha8,
+        ),
+    ];
+    for (width, height, format, data, expected) in cases {
+        let spec = ImageSpec::new(width, height, format);
+        let bytes = encode_image(&spec, &data).expect("infallible");
+        let optimized = optimize_png(&bytes, &OptimizeOptions::default()).expect("infallible");
+        assert!(optimized.len() < bytes.len());
+        assert_eq!(
+            inspect_image(&optimized).expect("infallible").pixel_format,
+            expected
+        );
+        assert_eq!(rgba16(&optimized), rgba16(&bytes));
+    }
+
+    // Few colors are stored as a palette, and interlacing is dropped.
+    let spec = ImageSpec {
+        interlaced: true,
+        ..ImageSpec::new(16, 16, PixelFormat::Rgb8)
+    };
+    let bytes = encode_image(&spec, &two_colors).expect("infallible");
+    let optimized = optimize_png(&bytes, &OptimizeOptions::default()).expect("infallible");
+    let spec = inspect_image(&optimized).expect("infallible");
+    assert!(!spec.interlaced);
+    assert!(matches!(spec.pixel_format, PixelFormat::Indexed1 { .. }));
+    assert_eq!(rgba16(&optimized), rgba16(&bytes));
+
+    // An image that cannot be made smaller is returned as it is.
+    let bytes = optimize_png(&optimized, &OptimizeOptions::default()).expect("infallible");
+    assert_eq!(bytes, optimized);
+}
+
+#[test]
+fn optimize_png_keeps_or_strips_metadata() {
+    let spec = ImageSpec::new(3, 1, PixelFormat::Rgb16Be);
+    let data = [[0x1212, 0x3434, 0x5656], [0xFFFF; 3], [0; 3]]
+        .iter()
+        .flatten()
+        .flat_map(|sample: &u16| sample.to_be_bytes())
+        .collect::<Vec<_>>();
+    let metadata = Metadata {
+        gamma: Some(45455),
+        text: vec![TextEntry {
+            keyword: "Title".to_string(),
+            text: "optimized".to_string(),
+            compressed: false,
+            international: None,
+        }],
+        modification_time: Some(Timestamp {
+            year: 2024,
+            month: 1,
+            day: 2,
+            hour: 3,
+            minute: 4,
+            second: 5,
+        }),
+        significant_bits: Some(SignificantBits::Rgb([16, 16, 16])),
+        background: Some(Background::Rgb([0x3434; 3])),
+        exif: Some(b"MM\0*".to_vec()),
+        ..Default::default()
+    };
+    let options = EncodeOptions {
+        metadata: metadata.clone(),
+        ..Default::default()
+    };
+    let bytes = encode_image_with_options(&spec, &data, &options).expect("infallible");
+
+    let optimized = optimize_png(&bytes, &OptimizeOptions::default()).expect("infallible");
+    let image =
+        decode_image_with_options(&optimized, &DecodeOptions::default()).expect("infallible");
+    assert!(matches!(
+        image.spec.pixel_format,
+        PixelFormat::Indexed2 { .. }
+    ));
+    assert_eq!(rgba16(&optimized), rgba16(&bytes));
+    // sBIT no longer fits the bit depth, and the background is not in the
+    // palette.
+    assert_eq!(
+        image.metadata,
+        Metadata {
+            significant_bits: None,
+            background: None,
+            ..metadata.clone()
+        }
+    );
+
+    let options = OptimizeOptions {
+        strip_metadata: true,
+        ..Default::default()
+    };
+    let optimized = optimize_png(&bytes, &options).expect("infallible");
+    let image =
+        decode_image_with_options(&optimized, &DecodeOptions::default()).expect("infallible");
+    assert_eq!(
+        image.metadata,
+        Metadata {
+            gamma: Some(45455),
+            ..Default::default()
+        }
+    );
+
+    // A background that the reduced format can hold is converted.
+    let spec = ImageSpec::new(3, 1, PixelFormat::Rgba8);
+    let options = EncodeOptions {
+        metadata: Metadata {
+            background: Some(Background::Rgb([85, 85, 85])),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let bytes = encode_image_with_options(
+        &spec,
+        &[0, 0, 0, 255, 85, 85, 85, 255, 255, 255, 255, 255],
+        &options,
+    )
+    .expect("infallible");
+    let optimized = optimize_png(&bytes, &OptimizeOptions::default()).expect("infallible");
+    let image =
+        decode_image_with_options(&optimized, &DecodeOptions::default()).expect("infallible");
+    assert_eq!(image.spec.pixel_format, PixelFormat::Gray2);
+    assert_eq!(image.metadata.background, Some(Background::Gray(1)));
+}
+
+#[test]
+fn optimize_png_keeps_metadata_of_images_with_trns() {
+    let rgb = insert_before_idat(
+        include_bytes!("data/rgb_sub_up.png"),
+        [
+            (*b"sBIT", vec![5, 6, 5]),
+            (*b"tRNS", vec![0, 0, 0, 0, 0, 0]),
+        ],
+    );
+    let gray = insert_before_idat(
+        include_bytes!("data/gray_2bit_trns.png"),
+        [(*b"bKGD", vec![0, 2])],
+    );
+    // No pixel matches the tRNS color, so the RGB image loses its alpha
+    // channel and sBIT follows.
+    for (bytes, format, metadata) in [
+        (
+            rgb,
+            PixelFormat::Rgb8,
+            Metadata {
+                significant_bits: Some(SignificantBits::Rgb([5, 6, 5])),
+                ..Default::default()
+            },
+        ),
+        (
+            gray,
+            PixelFormat::GrayAlpha8,
+            Metadata {
+                background: Some(Background::Gray(170)),
+                ..Default::default()
+            },
+        ),
+    ] {
+        let optimized = optimize_png(&bytes, &OptimizeOptions::default()).expect("infallible");
+        let image =
+            decode_image_with_options(&optimized, &DecodeOptions::default()).expect("infallible");
+        assert_eq!(image.spec.pixel_format, format);
+        assert_eq!(image.metadata, metadata);
+        assert_eq!(rgba16(&optimized), rgba16(&bytes));
+    }
+}
+
+#[test]
+fn optimize_png_rejects_animations_and_empty_settings() {
+    let spec = ImageSpec::new(1, 1, PixelFormat::Gray8);
+    let frames = [AnimationFrame {
+        control: FrameControl::full_canvas(1, 1),
+        pixels: vec![0],
+    }];
+    let bytes = encode_animation(
+        &spec,
+        &frames,
+        &EncodeOptions::default(),
+        &AnimationOptions::default(),
+    )
+    .expect("infallible");
+    let error = optimize_png(&bytes, &OptimizeOptions::default()).expect_err("infallible");
+    assert_eq!(error.kind(), ErrorKind::Unsupported);
+
+    let bytes = encode_image(&spec, &[0]).expect("infallible");
+    let options = OptimizeOptions {
+        filters: Vec::new(),
+        ..Default::default()
+    };
+    let error = optimize_png(&bytes, &options).expect_err("infallible");
+    assert_eq!(error.kind(), ErrorKind::InvalidArgument);
+}
+
+#[test]
+fn reformat_image_quantizes_to_indexed_formats() {
+    // A smooth gradient of 64 colors, quantized to 16.
+    let spec = ImageSpec::new(64, 1, PixelFormat::Rgb8);
+    let gradient: Vec<u8> = (0..64u8).flat_map(|i| [i * 4, 255 - i * 4, 128]).collect();
+    let target = PixelFormat::Indexed4 {
+        palette: Vec::new(),
+        trns: None,
+    };
+    let (indexed_spec, indices) =
+        reformat_image(&spec, &gradient, &target, &ReformatOptions::default()).expect("infallible");
+    let PixelFormat::Indexed4 { palette, trns } = &indexed_spec.pixel_format else {
+        panic!("unexpected format {:?}", indexed_spec.pixel_format);
+    };
+    assert_eq!(palette.len(), 16 * 3);
+    assert_eq!(*trns, None);
+    let rgb = reformat_pixels(&indexed_spec.pixel_format, &indices, &PixelFormat::Rgb8)
+        .expect("infallible");
+    let max_error = rgb.iter().zip(&gradient).map(|(a, b)| a.abs_diff(*b)).max();
+    assert!(max_error <= Some(8), "max error {max_error:?}");
+
+    // Dithering a flat mid-gray onto black and white keeps its average
+    // brightness instead of rounding every pixel the same way.
+    let spec = ImageSpec::new(16, 16, PixelFormat::Gray8);
+    let target = PixelFormat::Indexed1 {
+        palette: vec![0, 0, 0, 255, 255, 255],
+        trns: None,
+    };
+    let white = |dithering| {
+        let options = ReformatOptions {
+            dithering,
+            ..Default::default()
+        };
+        let (_, indices) =
+            reformat_image(&spec, &[100; 256], &target, &options).expect("infallible");
+        indices.iter().filter(|&&index| index == 1).count()
+    };
+    assert_eq!(white(Dithering::None), 0);
+    assert!((95..=105).contains(&white(Dithering::FloydSteinberg)));
+
+    // Without a palette, reformat_pixels cannot return the one it would build.
+    let target = PixelFormat::Indexed8 {
+        palette: Vec::new(),
+        trns: None,
+    };
+    let error = reformat_pixels(&PixelFormat::Gray8, &[0], &target).expect_err("infallible");
+    assert_eq!(error.kind(), ErrorKind::InvalidArgument);
+}
+
+#[test]
+fn reformat_image_dithers_when_reducing_bit_depth() {
+    let ditherings = [
+        Dithering::FloydSteinberg,
+        Dithering::Atkinson,
+        Dithering::Bayer,
+    ];
+    let reduce = |spec: &ImageSpec, data: &[u8], target: PixelFormat, dithering| {
+        let options = ReformatOptions {
+            dithering,
+            ..Default::default()
+        };
+        let (reduced_spec, reduced) =
+            reformat_image(spec, data, &target, &options).expect("infallible");
+        assert_eq!(reduced_spec.pixel_format, target);
+        reduced
+    };
+
+    // A flat gray between black and white becomes a mix of both, with about
+    // the same average brightness.
+    let spec = ImageSpec::new(16, 16, PixelFormat::Gray8);
+    let gray = [100; 256];
+    assert_eq!(
+        reduce(&spec, &gray, PixelFormat::Gray1, Dithering::None),
+        [0; 256]
+    );
+    for dithering in ditherings {
+        let white = reduce(&spec, &gray, PixelFormat::Gray1, dithering)
+            .iter()
+            .filter(|&&sample| sample == 1)
+            .count();
+        assert!((85..=115).contains(&white), "{dithering:?}: {white}");
+    }
+
+    // 16-bit samples keep their fractional part on average.
+    let spec = ImageSpec::new(16, 16, PixelFormat::Rgba16Be);
+    let data: Vec<u8> = (0..256 * 4).flat_map(|_| [0x12, 0x80]).collect();
+    let mean = |samples: &[u8]| {
+        samples.iter().map(|&sample| f64::from(sample)).sum::<f64>() / samples.len() as f64
+    };
+    assert_eq!(
+        reduce(&spec, &data, PixelFormat::Rgba8, Dithering::None),
+        [0x12; 1024]
+    );
+    for dithering in ditherings {
+        let rgba = reduce(&spec, &data, PixelFormat::Rgba8, dithering);
+        let expected = f64::from(0x1280) / 257.0;
+        assert!((mean(&rgba) - expected).abs() < 0.1, "{dithering:?}");
+    }
+
+    // Values that the target holds exactly are left alone.
+    let spec = ImageSpec::new(16, 1, PixelFormat::Gray8);
+    let levels: Vec<u8> = (0..16).map(|level| level * 17).collect();
+    for dithering in ditherings {
+        let reduced = reduce(&spec, &levels, PixelFormat::Gray4, dithering);
+        assert_eq!(reduced, (0..16).collect::<Vec<u8>>(), "{dithering:?}");
+        let spec = ImageSpec::new(2, 1, PixelFormat::Rgb8);
+        let target = PixelFormat::Indexed1 {
+            palette: vec![0, 0, 0, 255, 255, 255],
+            trns: None,
+        };
+        let (_, indices) = reformat_image(
+            &spec,
+            &[0, 0, 0, 255, 255, 255],
+            &target,
+            &ReformatOptions {
+                dithering,
+                ..Default::default()
+            },
+        )
+        .expect("infallible");
+        assert_eq!(indices, [0, 1], "{dithering:?}");
+    }
+
+    // Without dithering, low bit depths are rounded.
+    let gray2 = reformat_pixels(
+        &PixelFormat::Gray8,
+        &[0, 42, 43, 128, 255],
+        &PixelFormat::Gray2,
+    )
+    .expect("infallible");
+    assert_eq!(gray2, [0, 0, 1, 2, 3]);
+}
+
+#[test]
+fn reformat_image_mixes_colors_in_linear_light() {
+    let convert = |format: PixelFormat, data: &[u8], target: PixelFormat, options| {
+        let spec = ImageSpec::new(1, 1, format);
+        let (_, converted) = reformat_image(&spec, data, &target, &options).expect("infallible");
+        converted
+    };
+    let red = [255, 0, 0];
+    let gray = |transfer, luma| {
+        let options = ReformatOptions {
+            transfer,
+            luma,
+            ..Default::default()
+        };
+        convert(PixelFormat::Rgb8, &red, PixelFormat::Gray8, options)[0]
+    };
+    // The defaults match reformat_pixels.
+    let expected =
+        reformat_pixels(&PixelFormat::Rgb8, &red, &PixelFormat::Gray8).expect("infallible");
+    assert_eq!(
+        gray(TransferFunction::Linear, LumaCoefficients::Bt601),
+        expected[0]
+    );
+    assert_eq!(gray(TransferFunction::Linear, LumaCoefficients::Bt709), 54);
+    assert_eq!(gray(TransferFunction::Srgb, LumaCoefficients::Bt709), 127);
+    assert_eq!(
+        gray(TransferFunction::Gamma(45455), LumaCoefficients::Bt709),
+        126
+    );
+
+    // Half-transparent white over black is a mid gray in linear light.
+    let white = [255, 255, 255, 128];
+    let over_black = |transfer| ReformatOptions {
+        transfer,
+        background: Some([0; 3]),
+        ..Default::default()
+    };
+    let composited = convert(
+        PixelFormat::Rgba8,
+        &white,
+        PixelFormat::Rgb8,
+        over_black(TransferFunction::Linear),
+    );
+    assert_eq!(composited, [128; 3]);
+    let composited = convert(
+        PixelFormat::Rgba8,
+        &white,
+        PixelFormat::Rgb8,
+        over_black(TransferFunction::Srgb),
+    );
+    assert_eq!(composited, [188; 3]);
+    let composited = convert(
+        PixelFormat::GrayAlpha8,
+        &[255, 128],
+        PixelFormat::Gray8,
+        over_black(TransferFunction::Srgb),
+    );
+    assert_eq!(composited, [188]);
+    // Without a background, alpha is dropped.
+    let dropped = convert(
+        PixelFormat::Rgba8,
+        &white,
+        PixelFormat::Rgb8,
+        ReformatOptions::default(),
+    );
+    assert_eq!(dropped, [255; 3]);
+
+    let metadata = Metadata {
+        srgb: Some(RenderingIntent::Perceptual),
+        gamma: Some(45455),
+        ..Default::default()
+    };
+    assert_eq!(
+        TransferFunction::from_metadata(&metadata),
+        TransferFunction::Srgb
+    );
+    assert_eq!(
+        TransferFunction::from_metadata(&Metadata::default()),
+        TransferFunction::Srgb
+    );
+
+    let spec = ImageSpec::new(1, 1, PixelFormat::Rgb8);
+    let options = ReformatOptions {
+        transfer: TransferFunction::Gamma(0),
+        ..Default::default()
+    };
+    let error = reformat_image(&spec, &red, &PixelFormat::Gray8, &options).expect_err("infallible");
+    assert_eq!(error.kind(), ErrorKind::InvalidArgument);
+}
+
+#[test]
+fn premultiplied_formats_convert_on_encode_and_decode() {
+    let straight = [200, 100, 50, 128, 255, 255, 255, 0, 10, 20, 30, 255];
+    let premultiplied = [100, 50, 25, 128, 0, 0, 0, 0, 10, 20, 30, 255];
+    let converted = reformat_pixels(
+        &PixelFormat::Rgba8,
+        &straight,
+        &PixelFormat::Rgba8Premultiplied,
+    )
+    .expect("infallible");
+    assert_eq!(converted, premultiplied);
+
+    // Encoding stores straight alpha, rounded to the nearest value.
+    let spec = ImageSpec::new(3, 1, PixelFormat::Rgba8Premultiplied);
+    let bytes = encode_image(&spec, &premultiplied).expect("infallible");
+    let (decoded_spec, decoded) = decode_image(&bytes).expect("infallible");
+    assert_eq!(decoded_spec.pixel_format, PixelFormat::Rgba8);
+    assert_eq!(decoded, [199, 100, 50, 128, 0, 0, 0, 0, 10, 20, 30, 255]);
+    let (_, decoded) =
+        decode_image_as(&bytes, &PixelFormat::Rgba8Premultiplied).expect("infallible");
+    assert_eq!(decoded, premultiplied);
+
+    // The streaming encoder un-premultiplies rows the same way.
+    let mut encoder = StreamEncoder::new(
+        spec,
+        EncodeOptions::default(),
+        StreamEncoder::DEFAULT_IDAT_CHUNK_SIZE,
+    )
+    .expect("infallible");
+    let mut streamed = Vec::new();
+    encoder
+        .write_row(&premultiplied, &mut streamed)
+        .expect("infallible");
+    encoder.finish(&mut streamed).expect("infallible");
+    assert_eq!(rgba8(&streamed), rgba8(&bytes));
+
+    // 16-bit data converts between the premultiplied formats without
+    // going through straight alpha.
+    let wide = reformat_pixels(
+        &PixelFormat::Rgba8Premultiplied,
+        &premultiplied,
+        &PixelFormat::Rgba16BePremultiplied,
+    )
+    .expect("infallible");
+    assert_eq!(&wide[..8], [100, 100, 50, 50, 25, 25, 128, 128]);
+    let spec = ImageSpec::new(3, 1, PixelFormat::Rgba16BePremultiplied);
+    let bytes = encode_image(&spec, &wide).expect("infallible");
+    let (_, decoded) =
+        decode_image_as(&bytes, &PixelFormat::Rgba16BePremultiplied).expect("infallible");
+    assert_eq!(decoded, wide);
+
+    // Samples above alpha are clamped when un-premultiplying.
+    let straight = reformat_pixels(
+        &PixelFormat::Rgba8Premultiplied,
+        &[200, 0, 0, 100],
+        &PixelFormat::Rgba8,
+    )
+    .expect("infallible");
+    assert_eq!(straight, [255, 0, 0, 100]);
+
+    // Encoding rejects them.
+    let spec = ImageSpec::new(1, 1, PixelFormat::Rgba8Premultiplied);
+    let error = encode_image(&spec, &[255, 255, 255, 10]).expect_err("infallible");
+    assert_eq!(error.kind(), ErrorKind::InvalidArgument);
+    let spec = ImageSpec::new(1, 1, PixelFormat::Rgba16BePremultiplied);
+    let error = encode_image(&spec, &[0, 0, 0, 0, 1, 0, 0, 255]).expect_err("infallible");
+    assert_eq!(error.kind(), ErrorKind::InvalidArgument);
+    let mut encoder = StreamEncoder::new(
+        spec,
+        EncodeOptions::default(),
+        StreamEncoder::DEFAULT_IDAT_CHUNK_SIZE,
+    )
+    .expect("infallible");
+    let error = encoder
+        .write_row(&[0, 0, 0, 0, 1, 0, 0, 255], &mut Vec::new())
+        .expect_err("infallible");
+    assert_eq!(error.kind(), ErrorKind::InvalidArgument);
 }
 
 fn remove_chunk(bytes: &mut Vec<u8>, chunk_type: &[u8; 4]) {
@@ -194,6 +2132,21 @@ fn remove_chunk(bytes: &mut Vec<u8>, chunk_type: &[u8; 4]) {
     *bytes = rebuild_png(&kept);
 }
 
+fn insert_before_idat<const N: usize>(bytes: &[u8], extra: [([u8; 4], Vec<u8>); N]) -> Vec<u8> {
+    let mut chunks = collect_chunks(bytes);
+    let idat = chunks
+        .iter()
+        .position(|chunk| &chunk.chunk_type == b"IDAT")
+        .expect("infallible");
+    chunks.splice(
+        idat..idat,
+        extra
+            .into_iter()
+            .map(|(chunk_type, data)| Chunk { chunk_type, data }),
+    );
+    rebuild_png(&chunks)
+}
+
 struct Chunk {
     chunk_type: [u8; 4],
     data: Vec<u8>,